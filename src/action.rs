@@ -0,0 +1,552 @@
+use crate::config::{ActionConfig, Config, InputConfig};
+use crate::error::NanobotError;
+use chrono::prelude::Local;
+use hmac::{Hmac, Mac};
+use ontodev_valve::valve::Valve;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+
+/// The values available for substitution into action inputs and commands, e.g. `{username}`.
+pub type ActionValues = HashMap<String, String>;
+
+/// The outcome of running a single action command.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandResult {
+    pub command: String,
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The output stream that a line of command output was written to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of output from a running command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CommandOutput {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Get the values that every action can use: the GitHub username (if the `gh` tool is
+/// available) and today's date.
+pub fn get_default_values() -> ActionValues {
+    let mut values = ActionValues::new();
+    if let Ok(output) = Command::new("gh")
+        .args(["api", "user", "--jq", ".login"])
+        .output()
+    {
+        let stdout = std::str::from_utf8(&output.stdout)
+            .unwrap_or_default()
+            .trim();
+        values.insert("username".into(), stdout.into());
+    }
+    values.insert("today".into(), Local::now().format("%Y-%m-%d").to_string());
+    values
+}
+
+/// Replace every `{name}` in the given text with the matching value,
+/// or with the empty string when there is no such value.
+pub fn substitute(text: &str, values: &ActionValues) -> String {
+    let re = Regex::new(r"\{(\w+)\}").unwrap();
+    re.replace_all(text, |caps: &Captures| match values.get(&caps[1]) {
+        Some(v) => v.to_string(),
+        None => "".to_string(),
+    })
+    .to_string()
+}
+
+/// Add the submitted values for the action's inputs to `values`.
+/// Return true if every input has a non-empty value.
+pub fn collect_inputs(
+    action: &ActionConfig,
+    params: &HashMap<String, String>,
+    values: &mut ActionValues,
+) -> bool {
+    let mut satisfied = true;
+    for input in action.inputs.clone().unwrap_or_default() {
+        match params.get(&input.name) {
+            Some(v) if !v.trim().is_empty() => {
+                values.insert(input.name, v.into());
+            }
+            _ => satisfied = false,
+        }
+    }
+    satisfied
+}
+
+/// Add values for the action's inputs to `values`, taking them from `params` when given and
+/// falling back to their (substituted) defaults otherwise.
+/// Return an error listing the inputs that have neither.
+pub fn collect_inputs_or_defaults(
+    action: &ActionConfig,
+    params: &HashMap<String, String>,
+    values: &mut ActionValues,
+) -> Result<(), String> {
+    collect_inputs(action, params, values);
+    let mut missing = vec![];
+    for input in fill_inputs(action, values) {
+        match input.value {
+            Some(value) if !value.trim().is_empty() => {
+                values.insert(input.name, value);
+            }
+            _ => missing.push(input.name),
+        }
+    }
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Missing inputs: {}", missing.join(", ")))
+    }
+}
+
+/// Check the submitted value of each input against its `test`, which is either the name of a
+/// Valve datatype or a regular expression that must match the whole value.
+/// Return a map from input name to error message for each input that fails.
+pub fn validate_inputs(
+    action: &ActionConfig,
+    values: &ActionValues,
+    valve: Option<&Valve>,
+) -> HashMap<String, String> {
+    let mut errors = HashMap::new();
+    for input in action.inputs.clone().unwrap_or_default() {
+        let test = match &input.test {
+            Some(test) if !test.trim().is_empty() => test,
+            _ => continue,
+        };
+        let value = match values.get(&input.name) {
+            Some(value) => value,
+            None => continue,
+        };
+        let condition = valve.and_then(|v| v.datatype_conditions.get(test));
+        let valid = match condition {
+            Some(condition) => (condition.compiled)(value.as_str()),
+            None => match Regex::new(&format!("^(?:{})$", test)) {
+                Ok(re) => re.is_match(value),
+                Err(e) => {
+                    tracing::warn!("Invalid test '{}' for input '{}': {}", test, input.name, e);
+                    errors.insert(
+                        input.name.to_string(),
+                        format!("The test '{}' for this input is not valid", test),
+                    );
+                    continue;
+                }
+            },
+        };
+        if !valid {
+            let message = match condition {
+                Some(_) => format!("'{}' is not a valid {}", value, test),
+                None => format!("'{}' does not match the pattern '{}'", value, test),
+            };
+            errors.insert(input.name.to_string(), message);
+        }
+    }
+    errors
+}
+
+/// Check a webhook signature of the form `sha256=HEX`, as sent by GitHub in the
+/// `X-Hub-Signature-256` header, against the HMAC-SHA256 of the body using the shared secret.
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match signature
+        .strip_prefix("sha256=")
+        .and_then(|s| hex::decode(s).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Get the action's inputs with their current values filled in,
+/// falling back to their (substituted) defaults.
+pub fn fill_inputs(action: &ActionConfig, values: &ActionValues) -> Vec<InputConfig> {
+    let mut inputs = vec![];
+    for mut input in action.inputs.clone().unwrap_or_default() {
+        if let Some(v) = values.get(&input.name) {
+            if !v.trim().is_empty() {
+                input.value = Some(v.to_string());
+            }
+        } else if let Some(default) = &input.default {
+            input.value = Some(substitute(default, values));
+        }
+        inputs.push(input);
+    }
+    inputs
+}
+
+/// Substitute the values into each of the action's commands,
+/// returning each command as a list of program and arguments.
+pub fn get_commands(action: &ActionConfig, values: &ActionValues) -> Vec<Vec<String>> {
+    action
+        .commands
+        .iter()
+        .map(|command| command.iter().map(|arg| substitute(arg, values)).collect())
+        .collect()
+}
+
+/// Substitute the values into each of the action's commands,
+/// returning each command as a single line of text, as it will be run.
+pub fn get_command_lines(action: &ActionConfig, values: &ActionValues) -> Vec<String> {
+    get_commands(action, values)
+        .iter()
+        .filter(|parts| !parts.is_empty())
+        .map(|parts| parts.join(" "))
+        .collect()
+}
+
+/// Run the action's commands in order, stopping at the first command that fails.
+/// `on_start` is called with the index and text of each command before it is run.
+pub fn run_commands<F>(
+    action: &ActionConfig,
+    values: &ActionValues,
+    on_start: F,
+) -> Vec<CommandResult>
+where
+    F: FnMut(usize, &str),
+{
+    run_commands_streaming(action, values, on_start, |_, _| ())
+}
+
+/// Like [run_commands], but also call `on_output` with the index of the running command and
+/// each line of its output as soon as the line is written.
+pub fn run_commands_streaming<F, G>(
+    action: &ActionConfig,
+    values: &ActionValues,
+    mut on_start: F,
+    mut on_output: G,
+) -> Vec<CommandResult>
+where
+    F: FnMut(usize, &str),
+    G: FnMut(usize, &CommandOutput),
+{
+    let mut results = vec![];
+    for (step, parts) in get_commands(action, values).iter().enumerate() {
+        if parts.is_empty() {
+            continue;
+        }
+        let command = parts.join(" ");
+        tracing::debug!("RUNNING {:?}", parts);
+        on_start(step, &command);
+        let result = run_command(command, parts, |output| on_output(step, output));
+        tracing::debug!("RESULT {:?}", result);
+        let failed = result.status != 0;
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    results
+}
+
+fn run_command<G>(command: String, parts: &Vec<String>, mut on_output: G) -> CommandResult
+where
+    G: FnMut(&CommandOutput),
+{
+    let mut child = match Command::new(&parts[0])
+        .args(&parts[1..])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            return CommandResult {
+                command,
+                status: -1,
+                stdout: String::new(),
+                stderr: e.to_string(),
+            }
+        }
+    };
+
+    // Read stdout and stderr on their own threads so that neither can block the other,
+    // and collect their lines in the order they arrive.
+    let (sender, receiver) = mpsc::channel();
+    let mut readers = vec![];
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(spawn_reader(stdout, OutputStream::Stdout, sender.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(spawn_reader(stderr, OutputStream::Stderr, sender.clone()));
+    }
+    drop(sender);
+
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    for output in receiver {
+        on_output(&output);
+        let text = match output.stream {
+            OutputStream::Stdout => &mut stdout,
+            OutputStream::Stderr => &mut stderr,
+        };
+        text.push_str(&output.line);
+        text.push('\n');
+    }
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    let status = match child.wait() {
+        Ok(status) => status.code().unwrap_or(-1),
+        Err(e) => {
+            stderr.push_str(&e.to_string());
+            -1
+        }
+    };
+    CommandResult {
+        command,
+        status,
+        stdout,
+        stderr,
+    }
+}
+
+fn spawn_reader<R>(
+    reader: R,
+    stream: OutputStream,
+    sender: mpsc::Sender<CommandOutput>,
+) -> thread::JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if sender.send(CommandOutput { stream, line }).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Run the named action from the command line. Each input is given as a `key=value` string.
+/// The inputs are validated just as they are for the web form, then the commands are run and
+/// their output is returned. If a command fails, the output is returned as an error.
+/// When `dry_run` is true the substituted commands are returned without being run.
+/// When the action has `confirm = true` the user is asked to approve the commands first,
+/// unless `confirmed` is true.
+pub fn run_action(
+    config: &Config,
+    name: &str,
+    inputs: &Vec<String>,
+    dry_run: bool,
+    confirmed: bool,
+) -> Result<String, NanobotError> {
+    let action = config
+        .actions
+        .get(name)
+        .ok_or(format!("Undefined action '{}'", name))?;
+
+    let mut params = HashMap::new();
+    for input in inputs {
+        match input.split_once('=') {
+            Some((key, value)) => params.insert(key.trim().to_string(), value.to_string()),
+            None => return Err(format!("Input '{}' is not of the form key=value", input).into()),
+        };
+    }
+
+    // Inputs that are not given fall back to their defaults, as they do in the web form.
+    let mut values = get_default_values();
+    collect_inputs_or_defaults(action, &params, &mut values)
+        .map_err(|e| format!("Could not run action '{}': {}", name, e))?;
+    tracing::info!("VALUES {values:?}");
+
+    let errors = validate_inputs(action, &values, config.valve.as_ref());
+    if !errors.is_empty() {
+        let mut errors = errors
+            .iter()
+            .map(|(input, error)| format!("{}: {}", input, error))
+            .collect::<Vec<_>>();
+        errors.sort();
+        return Err(format!(
+            "Invalid inputs for action '{}':\n{}",
+            name,
+            errors.join("\n")
+        )
+        .into());
+    }
+
+    let commands = get_command_lines(action, &values);
+    if dry_run {
+        return Ok(commands.join("\n"));
+    }
+    if action.confirm.unwrap_or_default() && !confirmed {
+        println!("Action '{}' will run these commands:", name);
+        for command in &commands {
+            println!("  {}", command);
+        }
+        print!("Run them? [y/N] ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .map_err(|e| e.to_string())?;
+        if !["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+            return Err(format!("Action '{}' was cancelled", name).into());
+        }
+    }
+
+    let results = run_commands(action, &values, |step, command| {
+        tracing::info!(
+            "Running command {} for action '{}': {}",
+            step + 1,
+            name,
+            command
+        )
+    });
+    let mut output = vec![];
+    for result in &results {
+        output.push(format!("> {}", result.command));
+        if !result.stdout.is_empty() {
+            output.push(result.stdout.trim_end().to_string());
+        }
+        if !result.stderr.is_empty() {
+            output.push(result.stderr.trim_end().to_string());
+        }
+    }
+    let output = output.join("\n");
+    match results.last() {
+        Some(result) if result.status != 0 => Err(format!(
+            "{}\nAction '{}' failed with exit status {}",
+            output, name, result.status
+        )
+        .into()),
+        _ => Ok(output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from GitHub's documentation on validating webhook deliveries.
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn test_verify_signature() {
+        assert!(verify_signature(SECRET, BODY, SIGNATURE));
+        assert!(!verify_signature(SECRET, b"Hello, World?", SIGNATURE));
+        assert!(!verify_signature("another secret", BODY, SIGNATURE));
+        let tampered = SIGNATURE.replace("757107", "757108");
+        assert!(!verify_signature(SECRET, BODY, &tampered));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &SIGNATURE.replace("sha256=", "sha1=")
+        ));
+        assert!(!verify_signature(SECRET, BODY, "sha256=not-hex"));
+        assert!(!verify_signature(SECRET, BODY, ""));
+    }
+
+    /// An action that greets someone, then fails.
+    fn greet() -> ActionConfig {
+        ActionConfig {
+            label: "Greet".to_string(),
+            inputs: Some(vec![InputConfig {
+                name: "name".to_string(),
+                label: "Name".to_string(),
+                default: Some("{username}".to_string()),
+                ..Default::default()
+            }]),
+            commands: vec![
+                vec!["echo".to_string(), "Hello, {name}!".to_string()],
+                vec!["false".to_string()],
+                vec!["echo".to_string(), "unreachable".to_string()],
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_run_commands() {
+        let action = greet();
+        let mut values = ActionValues::from([("username".to_string(), "alice".to_string())]);
+        // The form shows the substituted default until a value is submitted.
+        assert_eq!(
+            fill_inputs(&action, &values)[0].value,
+            Some("alice".to_string())
+        );
+        assert!(!collect_inputs(&action, &HashMap::new(), &mut values));
+        let params = HashMap::from([("name".to_string(), "Bob".to_string())]);
+        assert!(collect_inputs(&action, &params, &mut values));
+        assert_eq!(substitute("{name} and {nobody}", &values), "Bob and ");
+
+        // The commands run in order, stopping at the first that fails.
+        let mut started = vec![];
+        let results = run_commands(&action, &values, |step, _| started.push(step));
+        assert_eq!(started, [0, 1]);
+        assert_eq!(results[0].command, "echo Hello, Bob!");
+        assert_eq!(results[0].stdout.trim_end(), "Hello, Bob!");
+        assert_eq!(results[0].status, 0);
+        assert_ne!(results[1].status, 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_action_defaults() {
+        let mut config = Config::from_path("missing.toml").await.unwrap();
+        let mut action = greet();
+        action.inputs.as_mut().unwrap()[0].default = Some("World".to_string());
+        action.inputs.as_mut().unwrap().push(InputConfig {
+            name: "greeting".to_string(),
+            label: "Greeting".to_string(),
+            ..Default::default()
+        });
+        config.actions.insert("greet".to_string(), action);
+
+        // An input that is left out takes its default, as it does in the web form.
+        let inputs = vec!["greeting=Hi".to_string()];
+        let commands = run_action(&config, "greet", &inputs, true, true).unwrap();
+        assert_eq!(commands.lines().next(), Some("echo Hello, World!"));
+        let inputs = vec!["name=Bob".to_string()];
+        assert_eq!(
+            run_action(&config, "greet", &inputs, true, true)
+                .unwrap_err()
+                .to_string(),
+            "Could not run action 'greet': Missing inputs: greeting"
+        );
+    }
+
+    #[test]
+    fn test_validate_inputs() {
+        let mut action = greet();
+        action.inputs.as_mut().unwrap()[0].test = Some("[A-Z][a-z]+".to_string());
+        let values = ActionValues::from([("name".to_string(), "Bob".to_string())]);
+        assert!(validate_inputs(&action, &values, None).is_empty());
+
+        // The pattern must match the whole value.
+        let values = ActionValues::from([("name".to_string(), "Bob; rm -rf /".to_string())]);
+        assert_eq!(
+            validate_inputs(&action, &values, None)["name"],
+            "'Bob; rm -rf /' does not match the pattern '[A-Z][a-z]+'"
+        );
+
+        // An invalid pattern is reported rather than passing every value.
+        action.inputs.as_mut().unwrap()[0].test = Some("[".to_string());
+        assert_eq!(
+            validate_inputs(&action, &values, None)["name"],
+            "The test '[' for this input is not valid"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::{check_table_names, Config};
+use crate::error::NanobotError;
+use crate::get::{self, GetQuery, GetRowsOptions};
+use enquote::unquote;
+use ontodev_sqlrest::Select;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+/// Tables that hold Nanobot's own records rather than data to browse.
+const SKIPPED_TABLES: [&str; 2] = ["message", "history"];
+
+/// A query to time, with the name it is reported under.
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    pub name: String,
+    pub select: Select,
+}
+
+/// The timings of the runs of a benchmark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchResult {
+    pub name: String,
+    /// The number of rows that each run returned.
+    pub rows: usize,
+    /// The time of each run, fastest first.
+    pub times: Vec<Duration>,
+}
+
+impl BenchResult {
+    /// The time that the given fraction of the runs finished within, e.g. 0.95 for p95.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        percentile(&self.times, fraction)
+    }
+
+    /// The rows returned per second, over all of the runs.
+    pub fn rows_per_second(&self) -> f64 {
+        let total = self.times.iter().sum::<Duration>().as_secs_f64();
+        match total > 0.0 {
+            true => (self.rows * self.times.len()) as f64 / total,
+            false => 0.0,
+        }
+    }
+}
+
+/// The nearest-rank percentile of the sorted times.
+fn percentile(times: &[Duration], fraction: f64) -> Duration {
+    if times.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (fraction * times.len() as f64).ceil() as usize;
+    times[rank.clamp(1, times.len()) - 1]
+}
+
+/// The default page of each of the given tables, or of every table,
+/// with the same number of rows as `serve` shows.
+pub fn table_benchmarks(
+    config: &Config,
+    tables: &[String],
+) -> Result<Vec<Benchmark>, NanobotError> {
+    check_table_names(config, tables)?;
+    let tables = match tables {
+        [] => config
+            .valve
+            .as_ref()
+            .ok_or(NanobotError::NotInitialized("Valve"))?
+            .config
+            .table_order
+            .iter()
+            .filter(|t| !SKIPPED_TABLES.contains(&t.as_str()))
+            .cloned()
+            .collect::<Vec<_>>(),
+        tables => tables.to_vec(),
+    };
+    Ok(tables
+        .into_iter()
+        .map(|table| {
+            let mut select = Select::new(format!("\"{}\"", table));
+            select.limit(config.results_per_page_for(&table));
+            Benchmark {
+                name: table,
+                select,
+            }
+        })
+        .collect())
+}
+
+/// The saved query in the file, named after the file.
+pub fn query_benchmark(config: &Config, path: &str) -> Result<Benchmark, NanobotError> {
+    let mut select = GetQuery::load(path)?.to_select()?;
+    let table = unquote(&select.table).unwrap_or(select.table.to_string());
+    if select.limit.is_none() {
+        select.limit(config.results_per_page_for(&table));
+    }
+    Ok(Benchmark {
+        name: path.to_string(),
+        select,
+    })
+}
+
+/// Run each benchmark `warmup` times without timing it, then `runs` times,
+/// getting its rows in the given shape, "page" (with the counts, as `serve` does) or
+/// "value_rows" (just the rows).
+pub async fn run_benchmarks(
+    config: &Config,
+    benchmarks: &[Benchmark],
+    shape: &str,
+    runs: usize,
+    warmup: usize,
+) -> Result<Vec<BenchResult>, NanobotError> {
+    if !["page", "value_rows"].contains(&shape) {
+        return Err(format!("Invalid shape '{}': use one of page, value_rows", shape).into());
+    }
+    if runs == 0 {
+        return Err("The number of runs must be at least 1".into());
+    }
+    let options = GetRowsOptions::default();
+    let mut results = vec![];
+    for benchmark in benchmarks {
+        tracing::info!("Running benchmark '{}'", benchmark.name);
+        for _ in 0..warmup {
+            get::get_rows(config, &benchmark.select, shape, "json", &options).await?;
+        }
+        let mut times = vec![];
+        let mut rows = 0;
+        for _ in 0..runs {
+            let start = Instant::now();
+            let json = get::get_rows(config, &benchmark.select, shape, "json", &options).await?;
+            times.push(start.elapsed());
+            // Count the rows outside of the timing, since serving them doesn't parse them.
+            let value: Value = serde_json::from_str(&json)?;
+            rows = match shape {
+                "page" => get::page_to_value_rows(&value).len(),
+                _ => value.as_array().map_or(0, |rows| rows.len()),
+            };
+        }
+        times.sort();
+        results.push(BenchResult {
+            name: benchmark.name.to_string(),
+            rows,
+            times,
+        });
+    }
+    Ok(results)
+}
+
+/// Report the results as "text", with a line for each benchmark, or as "json".
+pub fn report(results: &[BenchResult], format: &str) -> Result<String, NanobotError> {
+    let millis = |d: Duration| d.as_micros() as f64 / 1000.0;
+    match format {
+        "json" => Ok(serde_json::to_string_pretty(&json!(results
+            .iter()
+            .map(|r| json!({
+                "name": r.name,
+                "runs": r.times.len(),
+                "rows": r.rows,
+                "p50_ms": millis(r.percentile(0.5)),
+                "p95_ms": millis(r.percentile(0.95)),
+                "rows_per_second": r.rows_per_second(),
+            }))
+            .collect::<Vec<_>>()))?),
+        "text" => {
+            let width = results
+                .iter()
+                .map(|r| r.name.len())
+                .chain(["NAME".len()])
+                .max()
+                .unwrap_or_default();
+            let mut lines = vec![format!(
+                "{:width$}  {:>5}  {:>6}  {:>10}  {:>10}  {:>10}",
+                "NAME", "RUNS", "ROWS", "P50 (ms)", "P95 (ms)", "ROWS/S"
+            )];
+            for r in results {
+                lines.push(format!(
+                    "{:width$}  {:>5}  {:>6}  {:>10.2}  {:>10.2}  {:>10.0}",
+                    r.name,
+                    r.times.len(),
+                    r.rows,
+                    millis(r.percentile(0.5)),
+                    millis(r.percentile(0.95)),
+                    r.rows_per_second()
+                ));
+            }
+            Ok(lines.join("\n"))
+        }
+        _ => Err(format!("Invalid format '{}': use one of text, json", format).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> BenchResult {
+        BenchResult {
+            name: "penguin".to_string(),
+            rows: 10,
+            times: (1..=20).map(Duration::from_millis).collect(),
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let result = result();
+        assert_eq!(result.percentile(0.5), Duration::from_millis(10));
+        assert_eq!(result.percentile(0.95), Duration::from_millis(19));
+        assert_eq!(result.percentile(1.0), Duration::from_millis(20));
+        assert_eq!(result.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rows_per_second() {
+        // 20 runs of 10 rows in 210 ms.
+        let rate = result().rows_per_second();
+        assert!((rate - 200.0 / 0.21).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_report() {
+        let text = report(&[result()], "text").unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("NAME      RUNS"));
+        assert!(lines[1].starts_with("penguin     20      10       10.00       19.00"));
+        let json: Value = serde_json::from_str(&report(&[result()], "json").unwrap()).unwrap();
+        assert_eq!(json[0]["p95_ms"], json!(19.0));
+        assert!(report(&[], "yaml").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use axum::body::Bytes;
+use indexmap::map::IndexMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A rendered response, with the media type that it is served as.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedResponse {
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+/// The versions of the data that a response was rendered from.
+/// With no tables, the response depends on every table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stamp {
+    tables: Option<Vec<(String, u64)>>,
+    epoch: u64,
+}
+
+/// Rendered responses for identical requests, kept until the tables they were rendered from
+/// change. Each table has a version that is bumped when its rows are loaded or edited,
+/// and a response is only served from the cache while the versions it was rendered from
+/// are current. The least recently used responses are dropped first.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Bumped whenever any table changes.
+    epoch: u64,
+    /// Bumped when all of the tables may have changed, e.g. after an undo.
+    reset: u64,
+    versions: HashMap<String, u64>,
+    entries: IndexMap<String, (Stamp, CachedResponse)>,
+}
+
+impl CacheState {
+    fn stamp(&self, tables: Option<&[String]>) -> Stamp {
+        match tables {
+            None => Stamp {
+                tables: None,
+                epoch: self.epoch,
+            },
+            Some(tables) => Stamp {
+                tables: Some(
+                    tables
+                        .iter()
+                        .map(|t| (t.to_string(), self.versions.get(t).copied().unwrap_or(0)))
+                        .collect(),
+                ),
+                epoch: self.reset,
+            },
+        }
+    }
+}
+
+impl ResponseCache {
+    /// A cache of at most `capacity` responses. A capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> ResponseCache {
+        ResponseCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Record that the rows of the table have changed.
+    pub fn bump(&self, table: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.versions.entry(table.to_string()).or_default() += 1;
+        state.epoch += 1;
+    }
+
+    /// Record that any of the tables may have changed.
+    pub fn bump_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reset += 1;
+        state.epoch += 1;
+    }
+
+    /// The current versions of the given tables, or of all tables.
+    /// Take the stamp before rendering a response, so that a change during rendering
+    /// means that the response is never served from the cache.
+    pub fn stamp(&self, tables: Option<&[String]>) -> Stamp {
+        self.state.lock().unwrap().stamp(tables)
+    }
+
+    /// Get the response for the key, if the data it was rendered from has not changed since.
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let (stamp, response) = state.entries.shift_remove(key)?;
+        let tables = stamp.tables.as_ref().map(|t| {
+            t.iter()
+                .map(|(table, _)| table.to_string())
+                .collect::<Vec<_>>()
+        });
+        if state.stamp(tables.as_deref()) != stamp {
+            return None;
+        }
+        state
+            .entries
+            .insert(key.to_string(), (stamp, response.clone()));
+        Some(response)
+    }
+
+    /// Keep the response for the key, rendered from the data with the given stamp.
+    pub fn insert(&self, key: &str, stamp: Stamp, response: CachedResponse) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.entries.shift_remove(key);
+        state.entries.insert(key.to_string(), (stamp, response));
+        while state.entries.len() > self.capacity {
+            state.entries.shift_remove_index(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            content_type: "text/plain".to_string(),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let cache = ResponseCache::new(10);
+        let penguin = vec!["penguin".to_string()];
+        cache.insert("penguin.json", cache.stamp(Some(&penguin)), response("1"));
+        cache.insert("penguin", cache.stamp(None), response("2"));
+        assert_eq!(cache.get("penguin.json"), Some(response("1")));
+
+        cache.bump("island");
+        assert_eq!(cache.get("penguin.json"), Some(response("1")));
+        assert_eq!(cache.get("penguin"), None);
+
+        cache.bump("penguin");
+        assert_eq!(cache.get("penguin.json"), None);
+
+        cache.insert("penguin.json", cache.stamp(Some(&penguin)), response("3"));
+        cache.bump_all();
+        assert_eq!(cache.get("penguin.json"), None);
+    }
+
+    #[test]
+    fn test_stale_stamp() {
+        let cache = ResponseCache::new(10);
+        let stamp = cache.stamp(None);
+        cache.bump("penguin");
+        cache.insert("penguin", stamp, response("1"));
+        assert_eq!(cache.get("penguin"), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = ResponseCache::new(2);
+        cache.insert("a", cache.stamp(None), response("a"));
+        cache.insert("b", cache.stamp(None), response("b"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c", cache.stamp(None), response("c"));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(ResponseCache::new(0).get("a").is_none());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::config::Config;
+use crate::error::NanobotError;
+use crate::get::get_hierarchy_columns;
+use crate::sql::get_table_from_pool;
+use ontodev_sqlrest::Select;
+use serde_json::{json, Map, Value};
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+
+/// The classes of an ontology and their subclass relations,
+/// either from the `rdfs:subClassOf` statements of an LDTab table
+/// or from a VALVE table with a parent column.
+#[derive(Clone, Debug, Default)]
+pub struct ClassTree {
+    parents: HashMap<String, Vec<String>>,
+    children: HashMap<String, Vec<String>>,
+    labels: HashMap<String, String>,
+    roots: Vec<String>,
+}
+
+impl ClassTree {
+    /// Build the tree from the classes and the (subclass, superclass) pairs.
+    /// Classes that are only in the pairs are included, and the roots are the classes
+    /// without a superclass.
+    pub fn new(
+        classes: Vec<String>,
+        pairs: Vec<(String, String)>,
+        labels: HashMap<String, String>,
+    ) -> ClassTree {
+        let mut tree = ClassTree {
+            labels,
+            ..Default::default()
+        };
+        let mut seen = HashSet::new();
+        let mut all = vec![];
+        for class in classes.into_iter().chain(
+            pairs
+                .iter()
+                .flat_map(|(c, p)| [c.to_string(), p.to_string()]),
+        ) {
+            if seen.insert(class.to_string()) {
+                all.push(class);
+            }
+        }
+        for (child, parent) in pairs {
+            if child == parent {
+                continue;
+            }
+            let parents = tree.parents.entry(child.to_string()).or_default();
+            if !parents.contains(&parent) {
+                parents.push(parent.to_string());
+                tree.children.entry(parent).or_default().push(child);
+            }
+        }
+        for children in tree.children.values_mut() {
+            children.sort_by(|a, b| sort_key(&tree.labels, a).cmp(&sort_key(&tree.labels, b)));
+        }
+        tree.roots = all
+            .into_iter()
+            .filter(|c| !tree.parents.contains_key(c))
+            .collect();
+        tree.roots
+            .sort_by(|a, b| sort_key(&tree.labels, a).cmp(&sort_key(&tree.labels, b)));
+        tree
+    }
+
+    pub fn contains(&self, class: &str) -> bool {
+        self.parents.contains_key(class)
+            || self.children.contains_key(class)
+            || self.roots.iter().any(|r| r == class)
+    }
+
+    pub fn label(&self, class: &str) -> Option<&String> {
+        self.labels.get(class)
+    }
+
+    /// The number of classes under a class, counting each of them once.
+    pub fn descendant_count(&self, class: &str) -> usize {
+        let mut seen = HashSet::new();
+        let mut stack = vec![class];
+        while let Some(next) = stack.pop() {
+            for child in self.children.get(next).into_iter().flatten() {
+                if child != class && seen.insert(child.as_str()) {
+                    stack.push(child);
+                }
+            }
+        }
+        seen.len()
+    }
+
+    /// The class and all of its superclasses.
+    fn ancestors(&self, class: &str) -> HashSet<String> {
+        let mut seen = HashSet::from([class.to_string()]);
+        let mut stack = vec![class];
+        while let Some(next) = stack.pop() {
+            for parent in self.parents.get(next).into_iter().flatten() {
+                if seen.insert(parent.to_string()) {
+                    stack.push(parent);
+                }
+            }
+        }
+        seen
+    }
+
+    /// The tree as JSON nodes, starting from the roots, each with its `id`, `label`,
+    /// the `count` of classes under it, and whether it has `children`.
+    /// With a class, its superclasses are `open` with their subclasses in `children`,
+    /// down to the class itself and its subclasses, so that the class is shown in context.
+    pub fn to_json(&self, class: Option<&str>) -> Vec<Value> {
+        let open = match class {
+            Some(class) => self.ancestors(class),
+            None => HashSet::new(),
+        };
+        let mut path = vec![];
+        self.roots
+            .iter()
+            .map(|root| self.node(root, class, &open, &mut path))
+            .collect()
+    }
+
+    fn node(
+        &self,
+        id: &str,
+        class: Option<&str>,
+        open: &HashSet<String>,
+        path: &mut Vec<String>,
+    ) -> Value {
+        let children = self.children.get(id).cloned().unwrap_or_default();
+        // A cycle would otherwise open forever, so only open each class once on a path.
+        let is_open = open.contains(id) && !path.iter().any(|p| p == id);
+        let nodes = match is_open {
+            true => {
+                path.push(id.to_string());
+                let nodes = children
+                    .iter()
+                    .map(|child| self.node(child, class, open, path))
+                    .collect::<Vec<_>>();
+                path.pop();
+                nodes
+            }
+            false => vec![],
+        };
+        json!({
+            "id": id,
+            "label": self.labels.get(id),
+            "count": self.descendant_count(id),
+            "has_children": !children.is_empty(),
+            "current": Some(id) == class,
+            "open": is_open && !children.is_empty(),
+            "children": nodes,
+        })
+    }
+}
+
+/// Sort classes by label, then by ID, with unlabelled classes last.
+fn sort_key<'a>(labels: &'a HashMap<String, String>, class: &'a str) -> (bool, String, &'a str) {
+    match labels.get(class) {
+        Some(label) => (false, label.to_lowercase(), class),
+        None => (true, String::new(), class),
+    }
+}
+
+/// Get the class tree for a table: a VALVE table with a parent column that refers to
+/// the same table, or else an LDTab table, using its named superclasses.
+pub async fn get_class_tree(config: &Config, table: &str) -> Result<ClassTree, NanobotError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Pool"))?;
+    if valve.config.table.contains_key(table) {
+        let (child, parent) = get_hierarchy_columns(config, table).ok_or(format!(
+            "The '{}' table has no parent column that refers to the same table",
+            table
+        ))?;
+        let label = valve.config.table.get(table).and_then(|t| {
+            ["label", "name", "title"]
+                .iter()
+                .find(|c| t.column_order.contains(&c.to_string()) && **c != child)
+                .map(|c| c.to_string())
+        });
+        let rows = get_table_from_pool(pool, &Select::new(format!("\"{}\"", table))).await?;
+        let text = |row: &Map<String, Value>, column: &str| match row.get(column) {
+            Some(Value::String(s)) => s.to_string(),
+            None | Some(Value::Null) => String::new(),
+            Some(v) => v.to_string(),
+        };
+        let mut classes = vec![];
+        let mut pairs = vec![];
+        let mut labels = HashMap::new();
+        for row in &rows {
+            let id = text(row, &child);
+            if id.is_empty() {
+                continue;
+            }
+            let parent = text(row, &parent);
+            if !parent.is_empty() {
+                pairs.push((id.to_string(), parent));
+            }
+            if let Some(label) = label.as_ref().map(|l| text(row, l)) {
+                if !label.is_empty() {
+                    labels.insert(id.to_string(), label);
+                }
+            }
+            classes.push(id);
+        }
+        return Ok(ClassTree::new(classes, pairs, labels));
+    }
+
+    if !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid table '{}'", table).into());
+    }
+    let sql = format!(
+        r#"SELECT "subject", "predicate", "object" FROM "{}"
+        WHERE "retraction" = 0
+          AND ("predicate" = 'rdfs:subClassOf'
+            OR "predicate" = 'rdfs:label'
+            OR ("predicate" = 'rdf:type' AND "object" = 'owl:Class'))"#,
+        table
+    );
+    let mut classes = vec![];
+    let mut pairs = vec![];
+    let mut labels = HashMap::new();
+    for row in sqlx::query(&sql).fetch_all(pool).await? {
+        let subject: String = row.try_get("subject")?;
+        let predicate: String = row.try_get("predicate")?;
+        let object: String = row.try_get("object")?;
+        match predicate.as_str() {
+            "rdfs:label" => {
+                labels.insert(subject, object);
+            }
+            "rdf:type" => classes.push(subject),
+            // Skip anonymous superclasses, which LDTab stores as JSON.
+            _ if object.starts_with('{') || subject.starts_with("_:") => (),
+            _ => pairs.push((subject, object)),
+        }
+    }
+    // Labels of properties and individuals are not needed.
+    let known = classes
+        .iter()
+        .chain(pairs.iter().flat_map(|(c, p)| [c, p]))
+        .cloned()
+        .collect::<HashSet<_>>();
+    labels.retain(|class, _| known.contains(class));
+    Ok(ClassTree::new(classes, pairs, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> ClassTree {
+        let pairs = vec![
+            ("B".to_string(), "A".to_string()),
+            ("C".to_string(), "A".to_string()),
+            ("D".to_string(), "B".to_string()),
+            ("D".to_string(), "C".to_string()),
+        ];
+        let labels = HashMap::from([
+            ("A".to_string(), "animal".to_string()),
+            ("B".to_string(), "bird".to_string()),
+            ("C".to_string(), "cat".to_string()),
+        ]);
+        ClassTree::new(vec!["E".to_string()], pairs, labels)
+    }
+
+    #[test]
+    fn test_descendant_count() {
+        let tree = tree();
+        assert_eq!(tree.descendant_count("A"), 3);
+        assert_eq!(tree.descendant_count("B"), 1);
+        assert_eq!(tree.descendant_count("D"), 0);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let nodes = tree().to_json(Some("B"));
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0]["id"], json!("A"));
+        assert_eq!(nodes[0]["open"], json!(true));
+        assert_eq!(nodes[0]["children"][0]["id"], json!("B"));
+        assert_eq!(nodes[0]["children"][0]["current"], json!(true));
+        assert_eq!(nodes[0]["children"][0]["children"][0]["id"], json!("D"));
+        assert_eq!(nodes[0]["children"][1]["open"], json!(false));
+        assert_eq!(nodes[0]["children"][1]["has_children"], json!(true));
+        assert_eq!(nodes[1]["id"], json!("E"));
+        assert_eq!(nodes[1]["label"], Value::Null);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let pairs = vec![
+            ("A".to_string(), "B".to_string()),
+            ("B".to_string(), "A".to_string()),
+        ];
+        let tree = ClassTree::new(vec![], pairs, HashMap::new());
+        assert!(tree.contains("A"));
+        assert_eq!(tree.descendant_count("A"), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use lazy_static::lazy_static;
+use ontodev_valve::valve::Valve;
+use serde_json::{Map, Value};
+use tokio::sync::{Mutex, MutexGuard};
+
+lazy_static! {
+    /// Held while the rows of the tables are being changed, so that one batch of changes
+    /// cannot interleave with another, or with a single edit.
+    static ref EDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Wait until no other changes are being made to the tables, and hold the lock until the
+/// guard is dropped.
+pub async fn lock() -> MutexGuard<'static, ()> {
+    EDIT_LOCK.lock().await
+}
+
+/// A batch of changes to the rows of the tables that are all kept or all undone.
+/// The batch holds the edit lock from `begin()` until it is committed or rolled back,
+/// so VALVE's history has only its own changes on top, and rolling back undoes exactly those.
+pub struct Edits<'a> {
+    valve: &'a Valve,
+    applied: usize,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl<'a> Edits<'a> {
+    /// Wait for the edit lock and start a batch.
+    pub async fn begin(valve: &'a Valve) -> Edits<'a> {
+        Edits {
+            valve,
+            applied: 0,
+            _lock: lock().await,
+        }
+    }
+
+    /// The number of changes made so far.
+    pub fn applied(&self) -> usize {
+        self.applied
+    }
+
+    /// Insert a row and return its row number.
+    pub async fn insert(&mut self, table: &str, row: &Map<String, Value>) -> Result<u32, String> {
+        let (row_number, _) = self
+            .valve
+            .insert_row(table, row)
+            .await
+            .map_err(|e| format!("Could not insert a row into '{}': {:?}", table, e))?;
+        self.applied += 1;
+        Ok(row_number)
+    }
+
+    /// Update the row with the given row number.
+    pub async fn update(
+        &mut self,
+        table: &str,
+        row_number: &u32,
+        row: &Map<String, Value>,
+    ) -> Result<(), String> {
+        self.valve
+            .update_row(table, row_number, row)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Could not update row {} of '{}': {:?}",
+                    row_number, table, e
+                )
+            })?;
+        self.applied += 1;
+        Ok(())
+    }
+
+    /// Delete the row with the given row number.
+    pub async fn delete(&mut self, table: &str, row_number: &u32) -> Result<(), String> {
+        self.valve
+            .delete_row(table, row_number)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Could not delete row {} of '{}': {:?}",
+                    row_number, table, e
+                )
+            })?;
+        self.applied += 1;
+        Ok(())
+    }
+
+    /// Keep the changes and release the edit lock. Return the number of changes.
+    pub fn commit(self) -> usize {
+        self.applied
+    }
+
+    /// Undo the changes, newest first, because of the given error, and release the edit lock.
+    /// Return the error, along with the error from undoing the changes if that failed too,
+    /// so that the reason for the rollback is not lost.
+    pub async fn rollback(self, error: String) -> String {
+        tracing::warn!("{}: undoing {} changes", error, self.applied);
+        for undone in 0..self.applied {
+            if let Err(e) = self.valve.undo().await {
+                tracing::error!("Could not undo change {}: {:?}", self.applied - undone, e);
+                return format!(
+                    "{} (and undoing the {} changes before it failed after {}: {:?})",
+                    error, self.applied, undone, e
+                );
+            }
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock() {
+        let guard = lock().await;
+        assert!(EDIT_LOCK.try_lock().is_err());
+        drop(guard);
+        assert!(EDIT_LOCK.try_lock().is_ok());
+    }
+}
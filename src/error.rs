@@ -1,41 +1,80 @@
 use ontodev_valve::valve::ValveError;
-use std::error::Error;
-use std::fmt;
+use thiserror::Error;
 
-#[derive(Debug)]
+/// An error from any part of Nanobot. Its message says what went wrong,
+/// so the CLI and the server can show it as it is.
+#[derive(Error, Debug)]
 pub enum NanobotError {
+    #[error("{0}")]
     GeneralError(String),
+    #[error("VALVE error: {0:?}")]
     ValveError(ValveError),
-    TomlError(toml::de::Error),
-    GetError(GetError),
+    #[error("Could not parse the configuration: {0}")]
+    TomlError(#[from] toml::de::Error),
+    #[error("Could not write the configuration: {0}")]
+    TomlWriteError(#[from] toml::ser::Error),
+    #[error("{0}")]
+    GetError(#[from] GetError),
+    #[error("{0:#}")]
     AnyhowError(anyhow::Error),
-}
-
+    #[error("Database error: {0}")]
+    SqlError(#[from] sqlx::Error),
+    #[error("{0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid JSON: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// The configuration has no VALVE or no pool yet, e.g. before [crate::config::build_valve].
+    #[error("{0} is not initialized.")]
+    NotInitialized(&'static str),
+    #[error("No database named '{name}': add a [databases.{name}] section to {config_path}")]
+    UnknownDatabase { name: String, config_path: String },
+    #[error("Could not load database '{name}': {source}")]
+    DatabaseError {
+        name: String,
+        source: Box<NanobotError>,
+    },
+    #[error("Unknown table(s) '{}': use one of '{}'", .tables.join("', '"), .known.join("', '"))]
+    UnknownTables {
+        tables: Vec<String>,
+        known: Vec<String>,
+    },
+    /// The database password could not be read or added to the connection.
+    #[error("{0}")]
+    PasswordError(String),
+    /// The server could not listen, or failed while serving.
+    #[error("{0}")]
+    ServerError(String),
+}
+
+// anyhow::Error does not implement std::error::Error, so it cannot be a #[from] source;
+// ValveError is only guaranteed to implement Debug.
 impl From<ValveError> for NanobotError {
     fn from(e: ValveError) -> Self {
         Self::ValveError(e)
     }
 }
 
-impl From<toml::de::Error> for NanobotError {
-    fn from(e: toml::de::Error) -> Self {
-        Self::TomlError(e)
+impl From<anyhow::Error> for NanobotError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::AnyhowError(e)
     }
 }
 
-impl From<GetError> for NanobotError {
-    fn from(e: GetError) -> Self {
-        Self::GetError(e)
+/// Most helpers report errors as messages, so `?` turns them into a [NanobotError].
+impl From<String> for NanobotError {
+    fn from(e: String) -> Self {
+        Self::GeneralError(e)
     }
 }
 
-impl From<anyhow::Error> for NanobotError {
-    fn from(e: anyhow::Error) -> Self {
-        Self::AnyhowError(e)
+impl From<&str> for NanobotError {
+    fn from(e: &str) -> Self {
+        Self::GeneralError(e.to_string())
     }
 }
 
-#[derive(Debug)]
+#[derive(Error, Debug)]
+#[error("{details}")]
 pub struct GetError {
     details: String,
 }
@@ -46,18 +85,6 @@ impl GetError {
     }
 }
 
-impl fmt::Display for GetError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
-    }
-}
-
-impl Error for GetError {
-    fn description(&self) -> &str {
-        &self.details
-    }
-}
-
 impl From<String> for GetError {
     fn from(error: String) -> GetError {
         GetError::new(error)
@@ -66,36 +93,54 @@ impl From<String> for GetError {
 
 impl From<std::io::Error> for GetError {
     fn from(error: std::io::Error) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(error.to_string())
     }
 }
 
 impl From<csv::Error> for GetError {
     fn from(error: csv::Error) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(format!("CSV error: {}", error))
     }
 }
 
 impl From<serde_json::Error> for GetError {
     fn from(error: serde_json::Error) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(format!("Invalid JSON: {}", error))
+    }
+}
+
+impl From<rust_xlsxwriter::XlsxError> for GetError {
+    fn from(error: rust_xlsxwriter::XlsxError) -> GetError {
+        GetError::new(format!("Could not write the spreadsheet: {}", error))
+    }
+}
+
+impl From<serde_yaml::Error> for GetError {
+    fn from(error: serde_yaml::Error) -> GetError {
+        GetError::new(format!("Invalid YAML: {}", error))
     }
 }
 
 impl From<sqlx::Error> for GetError {
     fn from(error: sqlx::Error) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(format!("Database error: {}", error))
     }
 }
 
 impl From<git2::Error> for GetError {
     fn from(error: git2::Error) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(format!("Git error: {}", error))
     }
 }
 
 impl From<std::time::SystemTimeError> for GetError {
     fn from(error: std::time::SystemTimeError) -> GetError {
-        GetError::new(format!("{:?}", error))
+        GetError::new(error.to_string())
+    }
+}
+
+impl From<minijinja::Error> for GetError {
+    fn from(error: minijinja::Error) -> GetError {
+        GetError::new(format!("Template error: {}", error))
     }
 }
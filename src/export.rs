@@ -0,0 +1,107 @@
+use crate::config::{check_table_names, Config};
+use crate::error::NanobotError;
+use crate::get::{stream_value_rows, value_rows_to_xlsx, write_value_rows};
+use futures::TryStreamExt;
+use ontodev_sqlrest::Select;
+use std::fs;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// The formats that tables can be exported in.
+pub const EXPORT_FORMATS: [&str; 4] = ["tsv", "csv", "json", "xlsx"];
+
+/// The types of the VALVE tables that describe the other tables.
+const SCHEMA_TABLE_TYPES: [&str; 3] = ["table", "column", "datatype"];
+
+/// The tables that hold Nanobot's own records of the data.
+const RECORD_TABLES: [&str; 2] = ["message", "history"];
+
+/// The workbook that all of the tables are exported to in the xlsx format.
+const WORKBOOK: &str = "tables.xlsx";
+
+/// Write every table, or the given tables, from the database to files in `dir`:
+/// one file per table named after it, such as `penguin.tsv`,
+/// or one workbook with a sheet per table for xlsx.
+/// Without `meta`, leave out VALVE's schema tables and the message and history tables.
+/// The rows of editable tables are read from their `_view`, so that rows which conflict with
+/// others, e.g. by repeating a primary key, are exported along with the rest.
+pub async fn export(
+    config: &Config,
+    dir: &str,
+    format: &str,
+    tables: &[String],
+    meta: bool,
+) -> Result<String, NanobotError> {
+    if !EXPORT_FORMATS.contains(&format) {
+        return Err(format!(
+            "Invalid format '{}': use one of {}",
+            format,
+            EXPORT_FORMATS.join(", ")
+        )
+        .into());
+    }
+    check_table_names(config, tables)?;
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    let tables = match tables {
+        [] => valve
+            .config
+            .table_order
+            .iter()
+            .filter(|t| {
+                let is_meta = RECORD_TABLES.contains(&t.as_str())
+                    || valve.config.table.get(*t).map_or(false, |c| {
+                        SCHEMA_TABLE_TYPES.contains(&c.table_type.as_str())
+                    });
+                meta || !is_meta
+            })
+            .cloned()
+            .collect::<Vec<_>>(),
+        tables => tables.to_vec(),
+    };
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir).map_err(|e| format!("Could not create '{}': {}", dir.display(), e))?;
+
+    let mut report = vec![];
+    if format == "xlsx" {
+        let mut sheets = vec![];
+        for table in &tables {
+            // Stream the rows, so that max_results_per_page does not apply.
+            // stream_value_rows() selects from the table's view.
+            let select = Select::new(format!("\"{}\"", table));
+            let rows: Vec<_> = stream_value_rows(config, &select)?.try_collect().await?;
+            let columns = valve
+                .config
+                .table
+                .get(table)
+                .map(|t| t.column_order.to_vec())
+                .unwrap_or_default();
+            sheets.push((table.to_string(), columns, rows));
+        }
+        let path = dir.join(WORKBOOK);
+        fs::write(&path, value_rows_to_xlsx(&sheets)?)
+            .map_err(|e| format!("Could not write '{}': {}", path.display(), e))?;
+        report.push(format!(
+            "Exported {} tables to '{}'",
+            tables.len(),
+            path.display()
+        ));
+    } else {
+        for table in &tables {
+            let path = dir.join(format!("{}.{}", table, format));
+            let file = fs::File::create(&path)
+                .map_err(|e| format!("Could not write '{}': {}", path.display(), e))?;
+            let select = Select::new(format!("\"{}\"", table));
+            let count = write_value_rows(config, &select, format, BufWriter::new(file)).await?;
+            report.push(format!(
+                "Exported {} rows of '{}' to '{}'",
+                count,
+                table,
+                path.display()
+            ));
+        }
+    }
+    Ok(report.join("\n"))
+}
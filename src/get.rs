@@ -2,50 +2,376 @@
 // we can use the valve config (which is now available) instead of running db requests. But do
 // this later.
 
-use crate::config::{Config, SerdeMap};
+use crate::config::{ColumnFormat, Config, LabelsConfig, SerdeMap};
 use crate::error::GetError;
+use crate::prefix::{contract_iri, expand_curie, get_prefix_map, is_curie_datatype};
 use crate::sql::{
-    get_count_from_pool, get_message_counts_from_pool, get_table_from_pool, get_total_from_pool,
-    LIMIT_MAX,
+    get_batch_from_pool, get_count_from_pool, get_labels_from_pool, get_message_counts_from_pool,
+    get_row_history_from_pool, get_row_messages_from_pool, get_row_numbers_from_pool,
+    get_sql_from_pool, get_table_from_pool, get_total_from_pool, rows_to_map,
 };
-use chrono::prelude::{DateTime, Utc};
-use csv::WriterBuilder;
+use chrono::prelude::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use csv::{Terminator, WriterBuilder};
 use enquote::unquote;
 use futures::executor::block_on;
+use futures::stream::{self, Stream, StreamExt};
 use git2::Repository;
+use indexmap::map::IndexMap;
+use lazy_static::lazy_static;
 use minijinja::{Environment, Source};
-use ontodev_sqlrest::{Direction, OrderByColumn, Select};
+use ontodev_sqlrest::{parse, Direction, Filter, OrderByColumn, Select};
 use ontodev_valve::{
     toolkit,
     valve::{ValveChange, ValveColumnConfig, ValveMessage},
 };
 use regex::Regex;
+use rust_xlsxwriter::{Format, Workbook};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, to_string_pretty, Map, Value};
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::path::Path;
-use tabwriter::TabWriter;
-use urlencoding::decode;
+use std::sync::{Arc, PoisonError, RwLock};
+use tracing::Instrument;
+use urlencoding::{decode, encode};
+
+lazy_static! {
+    /// The compiled templates for each template directory and read-only setting,
+    /// so that templates are parsed once rather than for every page.
+    static ref TEMPLATES: RwLock<HashMap<(Option<String>, bool), Arc<Environment<'static>>>> =
+        RwLock::new(HashMap::new());
+}
 
-pub async fn get_table(
-    config: &Config,
-    table: &str,
-    shape: &str,
-    format: &str,
-) -> Result<String, GetError> {
-    let table = unquote(table).unwrap_or(table.to_string());
-    let mut select = Select::new(format!("\"{}\"", table));
-    select.limit(usize::from(config.results_per_page));
-    get_rows(config, &select, shape, format).await
+/// The page sizes offered in the HTML table view, in addition to the current limit.
+const PAGE_SIZES: [usize; 6] = [10, 20, 50, 100, 500, 1000];
+
+/// The maximum number of characters shown in a cell of the text format.
+const TEXT_MAX_WIDTH: usize = 40;
+
+/// The number of rows fetched from the database at a time when streaming.
+const STREAM_BATCH_SIZE: usize = 1000;
+
+/// The formats of rows in the "value_rows" shape, see format_value_rows().
+pub const VALUE_ROWS_FORMATS: [&str; 7] =
+    ["text", "json", "pretty.json", "jsonl", "yaml", "csv", "tsv"];
+
+/// The formats that rows can be streamed in, see stream_value_text().
+pub const STREAM_FORMATS: [&str; 4] = ["csv", "tsv", "json", "jsonl"];
+
+/// A query for rows of a table, built from the options of the `get` command
+/// or loaded from a saved JSON or TOML file.
+/// Filters are `COLUMN=OPERATOR.VALUE` conditions, as in the query strings used by `serve`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct GetQuery {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub filters: Vec<String>,
+    pub order: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl GetQuery {
+    /// Load a saved query from a TOML file (with a .toml extension) or a JSON file.
+    pub fn load(path: &str) -> Result<GetQuery, GetError> {
+        let text = fs::read_to_string(path)?;
+        if path.ends_with(".toml") {
+            toml::from_str(&text).map_err(|e| GetError::new(format!("{}: {}", path, e)))
+        } else {
+            serde_json::from_str(&text).map_err(|e| GetError::new(format!("{}: {}", path, e)))
+        }
+    }
+
+    /// Parse the query into a Select, in the same way as `serve` parses query strings.
+    pub fn to_select(&self) -> Result<Select, GetError> {
+        if self.table.is_empty() {
+            return Err(GetError::new("No table given".to_string()));
+        }
+        let table = unquote(&self.table).unwrap_or(self.table.to_string());
+        if let Some(filter) = self.filters.iter().find(|f| !f.contains('=')) {
+            return Err(GetError::new(format!(
+                "Invalid filter '{}': expected COLUMN=OPERATOR.VALUE",
+                filter
+            )));
+        }
+        let mut params = self.filters.clone();
+        if let Some(order) = &self.order {
+            params.push(format!("order={}", order));
+        }
+        if let Some(limit) = self.limit {
+            params.push(format!("limit={}", limit));
+        }
+        if let Some(offset) = self.offset {
+            params.push(format!("offset={}", offset));
+        }
+        let url = match params.is_empty() {
+            true => table,
+            false => format!("{}?{}", table, params.join("&")),
+        };
+        let mut select = parse(&url)?;
+        if !self.columns.is_empty() {
+            let columns = self
+                .columns
+                .iter()
+                .map(|c| format!("\"{}\"", c.trim()))
+                .collect::<Vec<_>>();
+            select.select(columns);
+        }
+        Ok(select)
+    }
+}
+
+/// The options of get_rows(), besides the shape and format. The defaults get the rows as they are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetRowsOptions {
+    /// The column that the `map` shape is keyed on, by default the primary key.
+    pub key: Option<String>,
+    /// Add a `_meta` object to JSON and YAML rows, with the row's validation messages
+    /// and its last change.
+    pub meta: bool,
+    /// Expand the CURIEs in columns of the "curie" datatype to IRIs using the prefix table,
+    /// except in the `page` shape.
+    pub iris: bool,
+    /// Get every selected row, or all of the given limit, even beyond `max_results_per_page`.
+    pub all: bool,
 }
 
+/// Get the selected rows in the given shape and format.
 pub async fn get_rows(
     config: &Config,
     base_select: &Select,
     shape: &str,
     format: &str,
+    options: &GetRowsOptions,
+) -> Result<String, GetError> {
+    let (select, column_configs) = table_select(config, base_select, options.all)?;
+    let meta = options.meta && ["json", "pretty.json", "yaml", "jsonl"].contains(&format);
+    let iris = options.iris;
+
+    match shape {
+        "map" | "columns" => {
+            let value_rows = match meta {
+                true => get_value_rows_with_meta(config, &select).await?,
+                false => get_value_rows(config, &select).await?,
+            };
+            let value_rows = match iris {
+                true => expand_value_rows(config, &column_configs, value_rows).await?,
+                false => value_rows,
+            };
+            let shaped = if shape == "map" {
+                let key = match &options.key {
+                    Some(key) => key.to_string(),
+                    None => default_key(&column_configs)?,
+                };
+                if !column_configs.iter().any(|c| c.column == key) {
+                    return Err(GetError::new(format!("Invalid key column '{}'", key)));
+                }
+                json!(rows_to_map(value_rows, &key)?)
+            } else {
+                json!(value_rows_to_columns(&column_configs, &value_rows))
+            };
+            match format {
+                "json" => Ok(shaped.to_string()),
+                "pretty.json" => Ok(to_string_pretty(&shaped)?),
+                "yaml" => Ok(serde_yaml::to_string(&shaped)?),
+                &_ => Err(GetError::new(format!(
+                    "Shape '{}' does not support format '{}'",
+                    shape, format
+                ))),
+            }
+        }
+        "value_rows" | "objects" => {
+            let value_rows = match meta {
+                true => get_value_rows_with_meta(config, &select).await?,
+                false => get_value_rows(config, &select).await?,
+            };
+            let value_rows = match iris {
+                true => expand_value_rows(config, &column_configs, value_rows).await?,
+                false => value_rows,
+            };
+            let value_rows = match format {
+                "json" | "pretty.json" | "yaml" | "jsonl" => {
+                    let table = unquote(&select.table).unwrap_or(select.table.to_string());
+                    add_label_fields(config, &table, value_rows).await?
+                }
+                _ => value_rows,
+            };
+            if !VALUE_ROWS_FORMATS.contains(&format) {
+                return Err(GetError::new(format!(
+                    "Shape '{}' does not support format '{}'",
+                    shape, format
+                )));
+            }
+            format_value_rows(&value_rows, format)
+        }
+        "page" => {
+            let page = match get_page(&config, &select, &column_configs).await {
+                Ok(page) => page,
+                Err(e) => return Err(GetError::new(e.to_string())),
+            };
+            match format {
+                "json" => Ok(page.to_string()),
+                "pretty.json" => match to_string_pretty(&page) {
+                    Ok(pretty_json) => Ok(pretty_json),
+                    Err(e) => return Err(GetError::new(e.to_string())),
+                },
+                "html" => page_to_html(&config, "table", &page),
+                "yaml" => Ok(serde_yaml::to_string(&page)?),
+                "csv" => value_rows_to_csv(&page_to_value_rows(&page)),
+                "tsv" => value_rows_to_tsv(&page_to_value_rows(&page)),
+                &_ => Err(GetError::new(format!(
+                    "Shape '{}' does not support format '{}'",
+                    shape, format
+                ))),
+            }
+        }
+        _ => Err(GetError::new(format!("Invalid shape '{}'", shape))),
+    }
+}
+
+/// Format rows of values in one of the VALUE_ROWS_FORMATS.
+pub fn format_value_rows(
+    value_rows: &Vec<Map<String, Value>>,
+    format: &str,
 ) -> Result<String, GetError> {
-    // Get all the tables
+    match format {
+        "tsv" => value_rows_to_tsv(value_rows),
+        "csv" => value_rows_to_csv(value_rows),
+        "text" => value_rows_to_text(value_rows),
+        "json" => Ok(json!(value_rows).to_string()),
+        "pretty.json" => match to_string_pretty(&json!(value_rows)) {
+            Ok(pretty_json) => Ok(pretty_json),
+            Err(e) => Err(GetError::new(e.to_string())),
+        },
+        "yaml" => Ok(serde_yaml::to_string(value_rows)?),
+        "jsonl" => Ok(value_rows
+            .iter()
+            .map(|row| json!(row).to_string())
+            .collect::<Vec<_>>()
+            .join("\n")),
+        &_ => Err(GetError::new(format!(
+            "Invalid format '{}': use one of {}",
+            format,
+            VALUE_ROWS_FORMATS.join(", ")
+        ))),
+    }
+}
+
+/// Expand the CURIEs in the columns of the "curie" datatype to IRIs using the prefix table,
+/// leaving values with unknown prefixes as they are.
+async fn expand_value_rows(
+    config: &Config,
+    column_configs: &Vec<ValveColumnConfig>,
+    rows: Vec<Map<String, Value>>,
+) -> Result<Vec<Map<String, Value>>, GetError> {
+    let prefixes = get_prefix_map(config)
+        .await
+        .map_err(|e| GetError::new(e.to_string()))?;
+    let curie_columns = column_configs
+        .iter()
+        .filter(|c| is_curie_datatype(config, &c.datatype))
+        .map(|c| c.column.as_str())
+        .collect::<Vec<_>>();
+    if prefixes.is_empty() || curie_columns.is_empty() {
+        return Ok(rows);
+    }
+    Ok(rows
+        .into_iter()
+        .map(|mut row| {
+            for column in &curie_columns {
+                let iri = row
+                    .get(*column)
+                    .and_then(|v| v.as_str())
+                    .and_then(|curie| expand_curie(&prefixes, curie));
+                if let Some(iri) = iri {
+                    row.insert(column.to_string(), json!(iri));
+                }
+            }
+            row
+        })
+        .collect())
+}
+
+/// Use the primary key column as the key for the `map` shape, or else the first column.
+fn default_key(column_configs: &Vec<ValveColumnConfig>) -> Result<String, GetError> {
+    column_configs
+        .iter()
+        .find(|c| c.structure == "primary")
+        .or(column_configs.first())
+        .map(|c| c.column.to_string())
+        .ok_or(GetError::new("No columns to use as a key".to_string()))
+}
+
+/// Convert the rows to a map from each column name to the list of values in that column.
+fn value_rows_to_columns(
+    column_configs: &Vec<ValveColumnConfig>,
+    rows: &Vec<Map<String, Value>>,
+) -> Map<String, Value> {
+    let mut columns = Map::new();
+    for column_config in column_configs {
+        let column = &column_config.column;
+        let values = rows
+            .iter()
+            .map(|row| row.get(column).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<_>>();
+        columns.insert(column.to_string(), json!(values));
+    }
+    columns
+}
+
+/// Count the selected rows, ignoring any limit and offset, without fetching them.
+pub async fn get_count(config: &Config, base_select: &Select) -> Result<usize, GetError> {
+    let (select, _) = table_select(config, base_select, false)?;
+    let select = value_select(config, &select)?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    Ok(get_count_from_pool(pool, &select, false).await?)
+}
+
+/// Get the SQL query for the selected rows, without running it.
+pub fn get_sql(config: &Config, base_select: &Select) -> Result<String, GetError> {
+    let (select, _) = table_select(config, base_select, false)?;
+    let select = value_select(config, &select)?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    Ok(get_sql_from_pool(pool, &select)?)
+}
+
+/// Get an Excel workbook with one sheet for each of the selected tables.
+/// With `all`, the sheets have every selected row, even beyond `max_results_per_page`.
+pub async fn get_xlsx(
+    config: &Config,
+    base_selects: &[Select],
+    all: bool,
+) -> Result<Vec<u8>, GetError> {
+    let mut sheets = vec![];
+    for base_select in base_selects {
+        let (select, column_configs) = table_select(config, base_select, all)?;
+        let table = unquote(&base_select.table).unwrap_or(base_select.table.to_string());
+        let columns = column_configs
+            .iter()
+            .map(|c| c.column.to_string())
+            .collect::<Vec<_>>();
+        let value_rows = get_value_rows(config, &select).await?;
+        sheets.push((table, columns, value_rows));
+    }
+    value_rows_to_xlsx(&sheets)
+}
+
+/// Check that the selected table exists, and select all of its columns,
+/// using the default limit when none is given, and at most `max_results_per_page`.
+/// With `all`, the limit is kept as it is, or left out to select every row.
+fn table_select(
+    config: &Config,
+    base_select: &Select,
+    all: bool,
+) -> Result<(Select, Vec<ValveColumnConfig>), GetError> {
     let valve = config
         .valve
         .as_ref()
@@ -74,6 +400,25 @@ pub async fn get_rows(
         (columns_config, column_order.to_vec())
     };
 
+    // When particular columns were requested, select just those, in the order requested.
+    let requested = base_select
+        .select
+        .iter()
+        .map(|s| unquote(&s.expression).unwrap_or(s.expression.to_string()))
+        .filter(|s| s != "*")
+        .collect::<Vec<_>>();
+    let unquoted_columns = if requested.is_empty() {
+        unquoted_columns
+    } else {
+        if let Some(column) = requested.iter().find(|c| !unquoted_columns.contains(c)) {
+            return Err(GetError::new(format!(
+                "Invalid column '{}' for table '{}'",
+                column, unquoted_table
+            )));
+        }
+        requested
+    };
+
     let mut columns = vec![];
     let mut column_configs = vec![];
     for column in unquoted_columns {
@@ -88,62 +433,286 @@ pub async fn get_rows(
 
     let mut select = Select::clone(&base_select);
     select.select(columns);
+    let max = config.max_results_per_page_for(&unquoted_table);
     match select.limit {
-        Some(l) if l > LIMIT_MAX => select.limit(LIMIT_MAX),
+        // Keep the limit, or the lack of one, to get every row.
+        _ if all => &mut select,
+        Some(l) if l > max => {
+            tracing::warn!(
+                "Limiting {} rows of '{}' to max_results_per_page {}",
+                l,
+                unquoted_table,
+                max
+            );
+            select.limit(max)
+        }
         Some(l) if l > 0 => select.limit(l),
-        _ => select.limit(usize::from(config.results_per_page)),
+        _ => select.limit(config.results_per_page_for(&unquoted_table)),
     };
+    Ok((select, column_configs))
+}
 
-    match shape {
-        "value_rows" => {
-            if table_config.options.contains("edit") {
-                // use the *_view table
-                select.table(format!("\"{unquoted_table}_view\""));
-            }
-            tracing::debug!("VALUE SELECT {select:?}");
-            let pool = &config
-                .pool
-                .as_ref()
-                .ok_or("Connection pool is not initialized.".to_string())?;
-            let value_rows = match get_table_from_pool(&pool, &select).await {
-                Ok(value_rows) => value_rows,
-                Err(e) => return Err(GetError::new(e.to_string())),
-            };
-            match format {
-                "tsv" => value_rows_to_tsv(&value_rows),
-                "csv" => value_rows_to_csv(&value_rows),
-                "text" => value_rows_to_text(&value_rows),
-                "json" => Ok(json!(value_rows).to_string()),
-                "pretty.json" => match to_string_pretty(&json!(value_rows)) {
-                    Ok(pretty_json) => Ok(pretty_json),
-                    Err(e) => return Err(GetError::new(e.to_string())),
-                },
-                &_ => Err(GetError::new(format!(
-                    "Shape '{}' does not support format '{}'",
-                    shape, format
-                ))),
+/// Get the rows for a select built by table_select().
+async fn get_value_rows(
+    config: &Config,
+    select: &Select,
+) -> Result<Vec<Map<String, Value>>, GetError> {
+    let select = value_select(config, select)?;
+    tracing::debug!("VALUE SELECT {select:?}");
+    let pool = &config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    match get_table_from_pool(&pool, &select).await {
+        Ok(value_rows) => Ok(value_rows),
+        Err(e) => Err(GetError::new(e.to_string())),
+    }
+}
+
+/// Get the rows for a select built by table_select(), adding a `_meta` object to each row
+/// with its row number, its validation messages, and the last change made to it, if any.
+async fn get_value_rows_with_meta(
+    config: &Config,
+    select: &Select,
+) -> Result<Vec<Map<String, Value>>, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    let unquoted_table = unquote(&select.table).unwrap_or(select.table.to_string());
+    let editable = match valve.config.table.get(&unquoted_table) {
+        Some(table_config) => table_config.options.contains("edit"),
+        None => false,
+    };
+
+    // Explicitly include the row_number, and the message and history columns from the view:
+    let mut meta_select = value_select(config, select)?;
+    let columns = meta_select.select.to_vec();
+    meta_select.select(vec!["row_number"]);
+    for column in &columns {
+        meta_select.add_explicit_select(column);
+    }
+    if editable {
+        meta_select.add_select("message");
+        meta_select.add_select("history");
+    }
+    tracing::debug!("META SELECT {meta_select:?}");
+    let rows = get_table_from_pool(&pool, &meta_select).await?;
+
+    // Tables without a view have no message column, so look up their messages:
+    let mut messages = match editable {
+        true => HashMap::new(),
+        false => {
+            let row_numbers = rows
+                .iter()
+                .filter_map(|r| r.get("row_number").and_then(|n| n.as_i64()))
+                .collect::<Vec<_>>();
+            get_row_messages_from_pool(&pool, &unquoted_table, &row_numbers).await?
+        }
+    };
+
+    let mut value_rows = vec![];
+    for row in rows {
+        let mut value_row = Map::new();
+        let mut meta = Map::new();
+        for (column, value) in row {
+            match column.as_str() {
+                "row_number" => {
+                    let row_messages = value
+                        .as_i64()
+                        .and_then(|n| messages.remove(&n))
+                        .unwrap_or_default();
+                    meta.insert("row_number".to_string(), value);
+                    if !editable {
+                        meta.insert("messages".to_string(), json!(row_messages));
+                    }
+                }
+                "message" if editable => {
+                    meta.insert("messages".to_string(), parse_json_column(value));
+                }
+                "history" if editable => {
+                    let last_change = match parse_json_column(value) {
+                        Value::Array(changes) => changes.last().cloned().unwrap_or(Value::Null),
+                        _ => Value::Null,
+                    };
+                    meta.insert("last_change".to_string(), last_change);
+                }
+                _ => {
+                    value_row.insert(column, value);
+                }
             }
         }
-        "page" => {
-            let page = match get_page(&config, &select, &column_configs).await {
-                Ok(page) => page,
-                Err(e) => return Err(GetError::new(e.to_string())),
+        value_row.insert("_meta".to_string(), Value::Object(meta));
+        value_rows.push(value_row);
+    }
+    Ok(value_rows)
+}
+
+/// View columns such as message and history may hold JSON as text: parse it if so.
+fn parse_json_column(value: Value) -> Value {
+    match value {
+        Value::String(text) => serde_json::from_str(&text).unwrap_or(Value::Null),
+        Value::Null => json!([]),
+        value => value,
+    }
+}
+
+/// Read editable tables from their view, which includes the latest changes.
+fn value_select(config: &Config, select: &Select) -> Result<Select, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let unquoted_table = unquote(&select.table).unwrap_or(select.table.to_string());
+    let mut select = select.clone();
+    if let Some(table_config) = valve.config.table.get(&unquoted_table) {
+        if table_config.options.contains("edit") {
+            // use the *_view table
+            select.table(format!("\"{unquoted_table}_view\""));
+        }
+    }
+    Ok(select)
+}
+
+/// Write the selected rows in one of the STREAM_FORMATS as they are fetched,
+/// so that the whole result never has to be held in memory. Returns the number of rows written.
+pub async fn write_value_rows<W: Write>(
+    config: &Config,
+    select: &Select,
+    format: &str,
+    mut writer: W,
+) -> Result<usize, GetError> {
+    check_stream_format(format)?;
+    let rows = stream_value_rows(config, select)?;
+    futures::pin_mut!(rows);
+    let mut count = 0;
+    if format == "json" {
+        write!(writer, "[")?;
+    }
+    while let Some(row) = rows.next().await {
+        writer.write_all(row_to_chunk(&row?, format, count)?.as_bytes())?;
+        count += 1;
+    }
+    if format == "json" {
+        write!(writer, "]")?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Stream the selected rows as text in one of the STREAM_FORMATS,
+/// with the same text as get_rows() for the "value_rows" shape, one chunk per row.
+pub fn stream_value_text(
+    config: &Config,
+    select: &Select,
+    format: &str,
+) -> Result<impl Stream<Item = Result<String, GetError>>, GetError> {
+    check_stream_format(format)?;
+    let rows = stream_value_rows(config, select)?;
+    let format = format.to_string();
+    let (open, close) = match format.as_str() {
+        "json" => (Some(Ok("[".to_string())), Some(Ok("]".to_string()))),
+        _ => (None, None),
+    };
+    let chunks = rows
+        .enumerate()
+        .map(move |(index, row)| row.and_then(|row| row_to_chunk(&row, &format, index)));
+    Ok(stream::iter(open).chain(chunks).chain(stream::iter(close)))
+}
+
+fn check_stream_format(format: &str) -> Result<(), GetError> {
+    match STREAM_FORMATS.contains(&format) {
+        true => Ok(()),
+        false => Err(GetError::new(format!(
+            "Cannot stream rows in format '{}'",
+            format
+        ))),
+    }
+}
+
+/// The text for the row at the given index of a streamed table.
+/// The first row of a CSV or TSV table also has the header line.
+fn row_to_chunk(row: &Map<String, Value>, format: &str, index: usize) -> Result<String, GetError> {
+    let text = match format {
+        "jsonl" => return Ok(format!("{}\n", json!(row))),
+        "json" if index == 0 => return Ok(json!(row).to_string()),
+        "json" => return Ok(format!(",{}", json!(row))),
+        "csv" => value_rows_to_csv(&vec![row.clone()])?,
+        _ => value_rows_to_tsv(&vec![row.clone()])?,
+    };
+    match index {
+        0 => Ok(text),
+        _ => Ok(text
+            .split_once('\n')
+            .map(|(_, line)| line.to_string())
+            .unwrap_or_default()),
+    }
+}
+
+/// Choose an output format from the extension of a file path, e.g. 'csv' for 'table.csv'.
+pub fn format_from_path(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "csv" => Some("csv"),
+        "tsv" | "tab" => Some("tsv"),
+        "json" => Some("json"),
+        "jsonl" | "ndjson" => Some("jsonl"),
+        "yaml" | "yml" => Some("yaml"),
+        "xlsx" => Some("xlsx"),
+        "ofn" => Some("ofn"),
+        "ttl" => Some("ttl"),
+        "html" => Some("html"),
+        "txt" => Some("text"),
+        _ => None,
+    }
+}
+
+/// Stream the selected rows, fetching them from the database in batches.
+/// Unlike get_rows(), there is no default limit: without one, the whole table is streamed.
+pub fn stream_value_rows(
+    config: &Config,
+    base_select: &Select,
+) -> Result<impl Stream<Item = Result<Map<String, Value>, GetError>>, GetError> {
+    let (select, _) = table_select(config, base_select, true)?;
+    let select = value_select(config, &select)?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?
+        .clone();
+    let start = base_select.offset.unwrap_or(0);
+    let end = base_select.limit.filter(|l| *l > 0).map(|l| start + l);
+
+    let batches = stream::unfold(Some(start), move |offset| {
+        let pool = pool.clone();
+        let select = select.clone();
+        async move {
+            let offset = offset?;
+            let limit = match end {
+                Some(end) => STREAM_BATCH_SIZE.min(end - offset),
+                None => STREAM_BATCH_SIZE,
             };
-            match format {
-                "json" => Ok(page.to_string()),
-                "pretty.json" => match to_string_pretty(&page) {
-                    Ok(pretty_json) => Ok(pretty_json),
-                    Err(e) => return Err(GetError::new(e.to_string())),
-                },
-                "html" => page_to_html(&config, "table", &page),
-                &_ => Err(GetError::new(format!(
-                    "Shape '{}' does not support format '{}'",
-                    shape, format
-                ))),
+            if limit == 0 {
+                return None;
+            }
+            match get_batch_from_pool(&pool, &select, offset, limit).await {
+                Ok(rows) => {
+                    let next = match rows.len() < limit {
+                        true => None,
+                        false => Some(offset + rows.len()),
+                    };
+                    let rows = rows.into_iter().map(Ok).collect::<Vec<_>>();
+                    Some((stream::iter(rows), next))
+                }
+                Err(e) => Some((stream::iter(vec![Err(GetError::new(e))]), None)),
             }
         }
-        _ => Err(GetError::new(format!("Invalid shape '{}'", shape))),
-    }
+    });
+    Ok(batches.flatten())
 }
 
 async fn get_page(
@@ -151,7 +720,7 @@ async fn get_page(
     select: &Select,
     column_configs: &Vec<ValveColumnConfig>,
 ) -> Result<Value, GetError> {
-    let table = &unquote(&select.table).unwrap();
+    let table = &unquote(&select.table).unwrap_or(select.table.to_string());
     let pool = &config
         .pool
         .as_ref()
@@ -349,17 +918,67 @@ async fn get_page(
         }
     }
 
+    // Count the rows while selecting them. Fetching the rows blocks until they arrive,
+    // so the counts are polled first, to send their queries on other connections before that.
+    let conflict = table_config.options.contains("edit");
+    let conflict_table = format!("{}_conflict", unquoted_table);
+    let counts = async {
+        let count = async {
+            // The count of the rows with messages comes from the message counts.
+            match unquoted_table != "message" && filter_messages {
+                true => Ok(None),
+                false => get_count_from_pool(&pool, &select, conflict)
+                    .await
+                    .map(Some),
+            }
+        };
+        let conflicts = async {
+            match conflict {
+                true => get_total_from_pool(&pool, &conflict_table, false)
+                    .await
+                    .map(Some),
+                false => Ok(None),
+            }
+        };
+        futures::try_join!(
+            count,
+            get_total_from_pool(&pool, &unquoted_table, conflict),
+            conflicts,
+            get_message_counts_from_pool(&pool, &unquoted_table),
+        )
+    }
+    .instrument(tracing::debug_span!("page_counts", table = %unquoted_table));
+
     // Use the view to select the data
     tracing::debug!("VIEW SELECT {view_select:?}");
-    let value_rows = match get_table_from_pool(&pool, &view_select).await {
-        Ok(value_rows) => value_rows,
-        Err(e) => return Err(GetError::new(e.to_string())),
-    };
-    // Get the number of messages of each type:
-    let message_counts = match get_message_counts_from_pool(&pool, &unquoted_table).await {
-        Ok(message_counts) => message_counts,
-        Err(e) => return Err(GetError::new(e.to_string())),
-    };
+    let rows = get_table_from_pool(&pool, &view_select)
+        .instrument(tracing::debug_span!("page_rows", table = %unquoted_table));
+    let (counts, rows) = futures::join!(counts, rows);
+    let (count, total, conflicts, message_counts) =
+        counts.map_err(|e| GetError::new(e.to_string()))?;
+    let mut value_rows = rows.map_err(GetError::new)?;
+    // Tables without a view have no message column,
+    // so look up the messages for the rows on this page in the message table:
+    if unquoted_table != "message" && !table_config.options.contains("edit") {
+        let row_numbers = value_rows
+            .iter()
+            .filter_map(|r| r.get("row_number").and_then(|n| n.as_i64()))
+            .collect::<Vec<_>>();
+        let mut messages =
+            match get_row_messages_from_pool(&pool, &unquoted_table, &row_numbers).await {
+                Ok(messages) => messages,
+                Err(e) => return Err(GetError::new(e.to_string())),
+            };
+        for row in value_rows.iter_mut() {
+            let row_messages = row
+                .get("row_number")
+                .and_then(|n| n.as_i64())
+                .and_then(|n| messages.remove(&n));
+            if let Some(row_messages) = row_messages {
+                row.insert("message".to_string(), json!(row_messages));
+            }
+        }
+    }
 
     // convert value_rows to cell_rows
     let table_type = config
@@ -371,38 +990,42 @@ async fn get_page(
         .get(&unquoted_table)
         .and_then(|t| Some(t.table_type.to_string()))
         .unwrap_or_default();
-    let cell_rows: Vec<Map<String, Value>> = value_rows
+    let formats = config.tables.get(&unquoted_table).map(|t| &t.formats);
+    let labels = get_column_labels(config, &unquoted_table, &value_rows).await?;
+    let mut cell_rows: Vec<Map<String, Value>> = value_rows
         .iter()
-        .map(|r| decorate_row(&unquoted_table, &table_type, &column_map, r))
+        .map(|r| {
+            decorate_row(
+                &unquoted_table,
+                &table_type,
+                &column_map,
+                formats,
+                &labels,
+                r,
+            )
+        })
         .collect();
+    link_foreign_cells(config, &unquoted_table, &value_rows, &mut cell_rows).await?;
+    link_prefixed_cells(config, column_configs, &mut cell_rows).await?;
 
     let mut counts = Map::new();
-    let conflict = table_config.options.contains("edit");
-    let count = {
-        if unquoted_table != "message" && filter_messages {
-            match message_counts.get("message_row").and_then(|m| m.as_u64()) {
-                Some(m) => m as usize,
-                None => {
-                    return Err(GetError::new(format!(
-                        "No 'nessage_row' in {:?}",
-                        message_counts
-                    )))
-                }
+    let count = match count {
+        Some(count) => count,
+        None => match message_counts.get("message_row").and_then(|m| m.as_u64()) {
+            Some(m) => m as usize,
+            None => {
+                return Err(GetError::new(format!(
+                    "No 'nessage_row' in {:?}",
+                    message_counts
+                )))
             }
-        } else {
-            match get_count_from_pool(&pool, &select, conflict).await {
-                Ok(count) => count,
-                Err(e) => return Err(GetError::new(e.to_string())),
-            }
-        }
+        },
     };
     counts.insert("count".to_string(), json!(count));
-
-    let total = match get_total_from_pool(&pool, &unquoted_table, conflict).await {
-        Ok(total) => total,
-        Err(e) => return Err(GetError::new(e.to_string())),
-    };
     counts.insert("total".to_string(), json!(total));
+    if let Some(conflicts) = conflicts {
+        counts.insert("conflict".to_string(), json!(conflicts));
+    }
     for (k, v) in message_counts {
         counts.insert(k, v.into());
     }
@@ -410,21 +1033,33 @@ async fn get_page(
     let end = select.offset.unwrap_or(0) + cell_rows.len();
 
     // Start with the VALVE table config, minus 'column' and 'column_order'.
-    let this_table_config = valve.config.table.get(&unquoted_table).unwrap();
+    let this_table_config = valve
+        .config
+        .table
+        .get(&unquoted_table)
+        .ok_or(format!("Undefined table '{}'", unquoted_table))?;
     let this_table_config = json!(this_table_config);
-    let mut this_table_config = this_table_config.as_object().unwrap().clone();
+    let mut this_table_config = this_table_config.as_object().cloned().unwrap_or_default();
     this_table_config.remove("column");
     this_table_config.remove("column_order");
     // Try to get Nanobot table config: will fail for "message" and "history" tables.
     let mut this_table = config
         .table
         .iter()
-        .filter(|x| x.get("table").unwrap() == &json!(unquoted_table))
+        .filter(|x| x.get("table") == Some(&json!(unquoted_table)))
         .next()
         .unwrap_or(&this_table_config)
         .clone();
     this_table.insert("table".to_string(), json!(unquoted_table.clone()));
+    this_table.insert(
+        "label".to_string(),
+        json!(get_table_label(config, &unquoted_table)),
+    );
     this_table.insert("href".to_string(), json!(unquoted_table.clone()));
+    this_table.insert(
+        "hierarchy".to_string(),
+        json!(get_hierarchy_columns(config, &unquoted_table).is_some()),
+    );
     this_table.insert("start".to_string(), json!(select.offset.unwrap_or(0) + 1));
     this_table.insert("end".to_string(), json!(end));
     this_table.insert("counts".to_string(), json!(counts));
@@ -590,13 +1225,39 @@ async fn get_page(
         this_table.insert("last".to_string(), json!(href));
     }
 
-    let mut tables = Map::new();
-    for key in &valve.config.table_order {
-        if key == "history" {
-            continue;
-        }
-        tables.insert(key.clone(), Value::String(key.clone()));
+    // Page sizes, keeping the first row of the current page in view:
+    let limit = select.limit.unwrap_or(0);
+    let limit_max = config.max_results_per_page_for(&unquoted_table);
+    let mut page_sizes = vec![];
+    let mut sizes = PAGE_SIZES
+        .iter()
+        .filter(|s| **s <= limit_max)
+        .cloned()
+        .collect::<Vec<_>>();
+    if limit > 0 && !sizes.contains(&limit) {
+        sizes.push(limit);
+        sizes.sort();
     }
+    for size in sizes {
+        let offset = select.offset.unwrap_or(0);
+        let href = match select_offset
+            .limit(size)
+            .offset(offset - offset % size)
+            .to_url()
+        {
+            Ok(url) => url,
+            Err(e) => return Err(GetError::new(e.to_string())),
+        };
+        let href = match decode(&href) {
+            Ok(href) => href,
+            Err(e) => return Err(GetError::new(e.to_string())),
+        };
+        page_sizes.push(json!({"size": size, "href": href, "active": size == limit}));
+    }
+    this_table.insert("page_sizes".to_string(), json!(page_sizes));
+    this_table.insert("limit_max".to_string(), json!(limit_max));
+
+    let tables = get_table_map(config);
 
     let mut select2 = select.clone();
     let empty: Vec<String> = Vec::new();
@@ -607,7 +1268,7 @@ async fn get_page(
         "page": {
             "project_name": "Nanobot",
             "tables": tables,
-            "title": unquoted_table,
+            "title": get_table_label(config, &unquoted_table),
             "url": select2.to_url().unwrap_or_default(),
             "select": select,
             "select_params": select2.to_params().unwrap_or_default(),
@@ -625,12 +1286,13 @@ async fn get_page(
     Ok(result)
 }
 
-// Given a table type, a column map, a cell value, and message list,
+// Given a table type, a column map, a column format, a cell value, and message list,
 // return a JSON value representing this cell.
 fn decorate_cell(
     table_type: &str,
     column_name: &str,
     column: &Value,
+    format: Option<&ColumnFormat>,
     value: &Value,
     messages: &Vec<ValveMessage>,
     history: &Vec<Vec<ValveChange>>,
@@ -685,6 +1347,21 @@ fn decorate_cell(
         cell.insert("messages".to_string(), json!(output_messages));
     }
 
+    // Highlight invalid cells, with a tooltip listing the rule and message for each problem:
+    if max_level > level_to_int(&"update".to_string()) {
+        classes.push(format!(
+            "table-{}",
+            level_to_bootstrap(message_level.to_string())
+        ));
+        let title = messages
+            .iter()
+            .filter(|m| m.column == column_name && m.level != "update")
+            .map(|m| format!("{}: {}", m.rule, m.message))
+            .collect::<Vec<_>>()
+            .join("\n");
+        cell.insert("title".to_string(), json!(title));
+    }
+
     let mut changes = vec![];
     for record in history.iter() {
         for change in record.iter().filter(|c| c.column == column_name) {
@@ -696,6 +1373,10 @@ fn decorate_cell(
         cell.insert("history".to_string(), json!(changes));
     }
 
+    if let Some(format) = format {
+        format_cell(format, value, &mut cell);
+    }
+
     if cell.get("value").unwrap().is_null() {
         classes.push("null".to_string());
     }
@@ -711,6 +1392,8 @@ fn decorate_row(
     table: &str,
     table_type: &str,
     column_map: &Map<String, Value>,
+    formats: Option<&IndexMap<String, ColumnFormat>>,
+    labels: &HashMap<String, HashMap<String, String>>,
     row: &Map<String, Value>,
 ) -> Map<String, Value> {
     // tracing::debug!("Decorate Row: table {table}");
@@ -751,12 +1434,361 @@ fn decorate_row(
             "datatype": "integer",
         });
         let column = column_map.get(column_name).unwrap_or(&default_column);
-        let cell = decorate_cell(table_type, column_name, column, value, &messages, &history);
+        let format = formats.and_then(|f| f.get(column_name));
+        let mut cell = decorate_cell(
+            table_type,
+            column_name,
+            column,
+            format,
+            value,
+            &messages,
+            &history,
+        );
+        if let (Some(config), Some(labels)) = (
+            format.and_then(|f| f.labels.as_ref()),
+            labels.get(column_name),
+        ) {
+            label_cell(config, labels, value, &mut cell);
+        }
         cell_row.insert(column_name.to_string(), serde_json::Value::Object(cell));
     }
     cell_row
 }
 
+/// Add the `display` text, `link`, and `badge` color for a non-null cell with a column format.
+fn format_cell(format: &ColumnFormat, value: &Value, cell: &mut Map<String, Value>) {
+    let text = match value_text(value) {
+        Some(text) => text,
+        None => return,
+    };
+    if let Some(url) = &format.url {
+        let href = url.replace("{value}", &encode(&text));
+        cell.insert("link".to_string(), json!(href));
+    }
+    if let Some(badges) = &format.badges {
+        if let Some(color) = badges.get(&text).or_else(|| badges.get("*")) {
+            cell.insert("badge".to_string(), json!(color));
+        }
+    }
+    if let Some(date_format) = &format.date {
+        if let Some(display) = format_date(&text, date_format) {
+            cell.insert("display".to_string(), json!(display));
+        }
+    }
+    if let Some(precision) = format.precision {
+        if let Ok(number) = text.trim().parse::<f64>() {
+            cell.insert(
+                "display".to_string(),
+                json!(format!("{:.*}", precision, number)),
+            );
+        }
+    }
+}
+
+/// Show the label for the ID in a cell, with the ID as its tooltip
+/// unless the cell already has one for its messages,
+/// and link to the ID's row in the labels table unless the column format has a `url`.
+fn label_cell(
+    config: &LabelsConfig,
+    labels: &HashMap<String, String>,
+    value: &Value,
+    cell: &mut Map<String, Value>,
+) {
+    let text = match value_text(value) {
+        Some(text) => text,
+        None => return,
+    };
+    if let Some(label) = labels.get(&text) {
+        cell.insert("display".to_string(), json!(label));
+        if !cell.contains_key("title") {
+            cell.insert("title".to_string(), json!(text));
+        }
+        if !cell.contains_key("link") {
+            let href = format!(
+                "{}?{}=eq.{}",
+                config.table,
+                config.id,
+                encode(&format!("\"{}\"", text))
+            );
+            cell.insert("link".to_string(), json!(href));
+        }
+    }
+}
+
+/// Link the values of the columns that take their values `from()` another table
+/// to the pages of the rows that they refer to. Cells that already have a link are left as they are.
+async fn link_foreign_cells(
+    config: &Config,
+    table: &str,
+    value_rows: &Vec<Map<String, Value>>,
+    cell_rows: &mut Vec<Map<String, Value>>,
+) -> Result<(), GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    for constraint in valve.config.constraint.foreign.values().flatten() {
+        if constraint.table != table {
+            continue;
+        }
+        let mut values = value_rows
+            .iter()
+            .filter_map(|row| row.get(&constraint.column).and_then(value_text))
+            .collect::<Vec<_>>();
+        values.sort();
+        values.dedup();
+        let row_numbers =
+            get_row_numbers_from_pool(pool, &constraint.ftable, &constraint.fcolumn, &values)
+                .await?;
+        for row in cell_rows.iter_mut() {
+            let cell = match row
+                .get_mut(&constraint.column)
+                .and_then(|c| c.as_object_mut())
+            {
+                Some(cell) if !cell.contains_key("link") => cell,
+                _ => continue,
+            };
+            let row_number = cell
+                .get("value")
+                .and_then(value_text)
+                .and_then(|value| row_numbers.get(&value));
+            if let Some(row_number) = row_number {
+                let href = format!("{}/row/{}", constraint.ftable, row_number);
+                cell.insert("link".to_string(), json!(href));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count the rows of the tables that refer to a row through a column that takes its values
+/// `from()` this table. Each reference has the `table`, `column`, and `value` that refer to
+/// the row and the `count` of rows, and columns without any rows are left out.
+pub async fn get_row_references(
+    config: &Config,
+    table: &str,
+    row_number: u32,
+) -> Result<Vec<Value>, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    let constraints = valve
+        .config
+        .constraint
+        .foreign
+        .values()
+        .flatten()
+        .filter(|c| c.ftable == table)
+        .collect::<Vec<_>>();
+    let mut references = vec![];
+    if constraints.is_empty() {
+        return Ok(references);
+    }
+
+    let mut select = Select::new(format!("\"{}\"", table));
+    select.filter(vec![Filter::new(
+        "row_number",
+        "eq",
+        json!(format!("{}", row_number)),
+    )?]);
+    let row = match get_table_from_pool(pool, &select).await?.into_iter().next() {
+        Some(row) => row,
+        None => return Ok(references),
+    };
+    for constraint in constraints {
+        let value = match row.get(&constraint.fcolumn).and_then(value_text) {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut select = Select::new(format!("\"{}\"", constraint.table));
+        select.filter(vec![Filter::new(
+            &constraint.column,
+            "eq",
+            json!(format!("\"{}\"", value.replace('"', ""))),
+        )?]);
+        let count = get_count_from_pool(pool, &select, false).await?;
+        if count > 0 {
+            references.push(json!({
+                "table": constraint.table,
+                "column": constraint.column,
+                "value": value,
+                "count": count,
+            }));
+        }
+    }
+    Ok(references)
+}
+
+/// Link the CURIEs in the columns of the "curie" datatype to their IRIs,
+/// and show the IRIs in any column as CURIEs linked to the IRIs, using the prefix table.
+/// Cells that already have a link or a display text are left as they are.
+async fn link_prefixed_cells(
+    config: &Config,
+    column_configs: &Vec<ValveColumnConfig>,
+    cell_rows: &mut Vec<Map<String, Value>>,
+) -> Result<(), GetError> {
+    let prefixes = get_prefix_map(config)
+        .await
+        .map_err(|e| GetError::new(e.to_string()))?;
+    if prefixes.is_empty() {
+        return Ok(());
+    }
+    let curie_columns = column_configs
+        .iter()
+        .filter(|c| is_curie_datatype(config, &c.datatype))
+        .map(|c| c.column.as_str())
+        .collect::<Vec<_>>();
+    for row in cell_rows.iter_mut() {
+        for (column, cell) in row.iter_mut() {
+            let cell = match cell.as_object_mut() {
+                Some(cell) if !cell.contains_key("link") && !cell.contains_key("display") => cell,
+                _ => continue,
+            };
+            let text = match cell.get("value").and_then(|v| v.as_str()) {
+                Some(text) => text.to_string(),
+                None => continue,
+            };
+            if curie_columns.contains(&column.as_str()) {
+                if let Some(iri) = expand_curie(&prefixes, &text) {
+                    cell.insert("link".to_string(), json!(iri));
+                }
+            } else if text.starts_with("http") {
+                if let Some(curie) = contract_iri(&prefixes, &text) {
+                    cell.insert("display".to_string(), json!(curie));
+                    cell.insert("link".to_string(), json!(text));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The text of a non-null value, without quotes for strings.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.to_string()),
+        _ => Some(value.to_string()),
+    }
+}
+
+/// Get the labels for the values in the rows of each column of the table
+/// that has `labels` in its column format, keyed by column and then by value.
+async fn get_column_labels(
+    config: &Config,
+    table: &str,
+    rows: &Vec<Map<String, Value>>,
+) -> Result<HashMap<String, HashMap<String, String>>, GetError> {
+    let mut column_labels = HashMap::new();
+    let formats = match config.tables.get(table) {
+        Some(table_config) => &table_config.formats,
+        None => return Ok(column_labels),
+    };
+    for (column, format) in formats {
+        let labels = match &format.labels {
+            Some(labels) => labels,
+            None => continue,
+        };
+        let pool = config
+            .pool
+            .as_ref()
+            .ok_or("Connection pool is not initialized.".to_string())?;
+        let mut ids = rows
+            .iter()
+            .filter_map(|row| row.get(column).and_then(value_text))
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        let found = get_labels_from_pool(pool, &labels.table, &labels.id, &labels.label, &ids)
+            .await
+            .map_err(|e| {
+                GetError::new(format!(
+                    "Could not get labels for '{}' from '{}': {}",
+                    column, labels.table, e
+                ))
+            })?;
+        column_labels.insert(column.to_string(), found);
+    }
+    Ok(column_labels)
+}
+
+/// Whether JSON rows of the table get `COLUMN_label` fields, see add_label_fields().
+pub fn has_label_fields(config: &Config, table: &str) -> bool {
+    config.tables.get(table).map_or(false, |table_config| {
+        table_config
+            .formats
+            .values()
+            .any(|f| f.labels.as_ref().map_or(false, |l| l.json))
+    })
+}
+
+/// Add a `COLUMN_label` field after each column that has `labels` with `json = true`
+/// in its column format, with the label of the value or null.
+async fn add_label_fields(
+    config: &Config,
+    table: &str,
+    rows: Vec<Map<String, Value>>,
+) -> Result<Vec<Map<String, Value>>, GetError> {
+    let formats = match config.tables.get(table) {
+        Some(table_config) if has_label_fields(config, table) => &table_config.formats,
+        _ => return Ok(rows),
+    };
+    let column_labels = get_column_labels(config, table, &rows).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let mut labeled_row = Map::new();
+            for (column, value) in row {
+                let label = formats
+                    .get(&column)
+                    .and_then(|f| f.labels.as_ref())
+                    .filter(|l| l.json)
+                    .and_then(|_| column_labels.get(&column))
+                    .map(|labels| {
+                        value_text(&value)
+                            .and_then(|text| labels.get(&text))
+                            .map_or(Value::Null, |label| json!(label))
+                    });
+                labeled_row.insert(column.to_string(), value);
+                if let Some(label) = label {
+                    labeled_row.insert(format!("{}_label", column), label);
+                }
+            }
+            labeled_row
+        })
+        .collect())
+}
+
+/// Format a date, or a date and time, in one of the common ISO 8601 forms.
+/// Return None when the text is not a date, or the format does not apply to it,
+/// such as a time format for a date.
+fn format_date(text: &str, date_format: &str) -> Option<String> {
+    let text = text.trim();
+    let formatted = if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+        datetime.format(date_format)
+    } else if let Ok(datetime) = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S"))
+    {
+        datetime.format(date_format)
+    } else {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()?
+            .format(date_format)
+    };
+    let mut display = String::new();
+    std::fmt::write(&mut display, format_args!("{}", formatted)).ok()?;
+    Some(display)
+}
+
 // Get the undo message, or None.
 pub fn get_undo_message(config: &Config) -> Option<String> {
     let valve = match config.valve.as_ref() {
@@ -783,6 +1815,376 @@ pub fn get_redo_message(config: &Config) -> Option<String> {
     Some(String::from(format!("Redo '{}'", change.message)))
 }
 
+/// Get the datatype hierarchy as a map from each datatype to its row of the datatype table,
+/// with its `ancestors`, from its parent up to the root, and its `children`.
+pub fn get_datatype_tree(config: &Config) -> Map<String, Value> {
+    let mut tree = Map::new();
+    for row in &config.datatype {
+        let name = row
+            .get("datatype")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default();
+        let mut datatype = row.clone();
+        datatype.insert(
+            "ancestors".into(),
+            json!(get_datatype_ancestors(config, name)),
+        );
+        let children = config
+            .datatype
+            .iter()
+            .filter(|r| r.get("parent").and_then(|p| p.as_str()) == Some(name))
+            .filter_map(|r| r.get("datatype").cloned())
+            .collect::<Vec<_>>();
+        datatype.insert("children".into(), json!(children));
+        tree.insert(name.to_string(), Value::Object(datatype));
+    }
+    tree
+}
+
+/// The ancestors of a datatype, from its parent up to the root of the hierarchy.
+fn get_datatype_ancestors(config: &Config, datatype: &str) -> Vec<String> {
+    let mut ancestors: Vec<String> = vec![];
+    let mut current = datatype.to_string();
+    loop {
+        let parent = config
+            .datatype
+            .iter()
+            .find(|r| r.get("datatype").and_then(|d| d.as_str()) == Some(current.as_str()))
+            .and_then(|r| r.get("parent"))
+            .and_then(|p| p.as_str())
+            .unwrap_or_default();
+        // Stop at the root, and at a cycle, which VALVE would report.
+        if parent.is_empty() || parent == datatype || ancestors.iter().any(|a| a == parent) {
+            return ancestors;
+        }
+        ancestors.push(parent.to_string());
+        current = parent.to_string();
+    }
+}
+
+/// Get the structure of each column of the table, in order, for building forms in the browser:
+/// the column's configuration from the column table, with the `conditions` that its values must
+/// meet, from its datatype and that datatype's ancestors, its `sql_type`, and its foreign keys.
+pub fn get_column_metadata(config: &Config, table: &str) -> Result<Map<String, Value>, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    let table_config = valve
+        .config
+        .table
+        .get(table)
+        .ok_or(GetError::new(format!("No table named '{}'", table)))?;
+    let datatypes = get_datatype_tree(config);
+    let mut columns = Map::new();
+    for column in &table_config.column_order {
+        let column_config = match table_config.column.get(column) {
+            Some(c) => c,
+            None => continue,
+        };
+        let mut entry = json!(column_config)
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        let datatype = entry
+            .get("datatype")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let mut conditions = vec![];
+        let mut lineage = vec![datatype.clone()];
+        lineage.append(&mut get_datatype_ancestors(config, &datatype));
+        for name in &lineage {
+            let condition = datatypes
+                .get(name)
+                .and_then(|d| d.get("condition"))
+                .and_then(|c| c.as_str())
+                .unwrap_or_default();
+            if !condition.is_empty() {
+                conditions.push(json!({"datatype": name, "condition": condition}));
+            }
+        }
+        entry.insert("datatypes".into(), json!(lineage));
+        entry.insert("conditions".into(), json!(conditions));
+        entry.insert(
+            "sql_type".into(),
+            json!(toolkit::get_sql_type_from_global_config(
+                &valve.config,
+                table,
+                column,
+                pool
+            )),
+        );
+        for constraints in valve.config.constraint.foreign.values() {
+            for constraint in constraints.iter() {
+                if constraint.table == table && &constraint.column == column {
+                    entry.insert(
+                        "from".into(),
+                        json!({
+                            "table": constraint.ftable.clone(),
+                            "column": constraint.fcolumn.clone()
+                        }),
+                    );
+                }
+            }
+        }
+        columns.insert(column.to_string(), Value::Object(entry));
+    }
+    Ok(columns)
+}
+
+/// The columns that make the table a hierarchy, as (child, parent), e.g. ("id", "parent"),
+/// when a parent column takes its values `from()` another column of the same table.
+pub fn get_hierarchy_columns(config: &Config, table: &str) -> Option<(String, String)> {
+    let valve = config.valve.as_ref()?;
+    valve
+        .config
+        .constraint
+        .foreign
+        .values()
+        .flatten()
+        .find(|c| c.table == table && c.ftable == table)
+        .map(|c| (c.fcolumn.clone(), c.column.clone()))
+}
+
+/// Get the rows of a hierarchical table as a tree of nodes, each with its `id`, `label`,
+/// `row_number`, and `children`, starting from the rows without a parent in the table.
+pub async fn get_hierarchy(config: &Config, table: &str) -> Result<Vec<Value>, GetError> {
+    let (child, parent) = get_hierarchy_columns(config, table).ok_or_else(|| {
+        GetError::new(format!(
+            "The '{}' table has no parent column that refers to the same table",
+            table
+        ))
+    })?;
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    // Show a label next to each ID, if the table has a column for one.
+    let label = valve.config.table.get(table).and_then(|t| {
+        ["label", "name", "title"]
+            .iter()
+            .find(|c| t.column_order.contains(&c.to_string()) && **c != child)
+            .map(|c| c.to_string())
+    });
+
+    let rows = get_table_from_pool(pool, &Select::new(format!("\"{}\"", table))).await?;
+    let text = |row: &Map<String, Value>, column: &str| match row.get(column) {
+        Some(Value::String(s)) => s.to_string(),
+        None | Some(Value::Null) => String::new(),
+        Some(v) => v.to_string(),
+    };
+    let ids = rows.iter().map(|r| text(r, &child)).collect::<Vec<_>>();
+    let mut children: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut roots = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let parent = text(row, &parent);
+        if parent.is_empty() || parent == ids[i] || !ids.contains(&parent) {
+            roots.push(i);
+        } else {
+            children.entry(parent).or_default().push(i);
+        }
+    }
+
+    fn node(
+        i: usize,
+        rows: &[Map<String, Value>],
+        ids: &[String],
+        labels: &[String],
+        children: &HashMap<String, Vec<usize>>,
+        visited: &mut [bool],
+    ) -> Value {
+        visited[i] = true;
+        let mut nodes = vec![];
+        for &c in children.get(&ids[i]).into_iter().flatten() {
+            if !visited[c] {
+                nodes.push(node(c, rows, ids, labels, children, visited));
+            }
+        }
+        json!({
+            "id": ids[i],
+            "label": labels[i],
+            "row_number": rows[i].get("row_number"),
+            "children": nodes,
+        })
+    }
+    let labels = rows
+        .iter()
+        .map(|r| label.as_ref().map(|l| text(r, l)).unwrap_or_default())
+        .collect::<Vec<_>>();
+    let mut visited = vec![false; rows.len()];
+    let mut tree = vec![];
+    for i in roots {
+        tree.push(node(i, &rows, &ids, &labels, &children, &mut visited));
+    }
+    // Rows in a cycle have no root, so show each cycle from its first row.
+    for i in 0..rows.len() {
+        if !visited[i] {
+            tree.push(node(i, &rows, &ids, &labels, &children, &mut visited));
+        }
+    }
+    Ok(tree)
+}
+
+/// Get the rows of an editable table's conflict table, which holds the rows that VALVE
+/// could not put in the table itself because they violate a primary or unique key.
+/// Each row has its `row_number`, its `values` by column, its `messages`,
+/// and the `titles` that sum up the messages for each column.
+/// Invalid values are stored as null, so take them from their messages instead.
+pub async fn get_conflict_rows(config: &Config, table: &str) -> Result<Vec<Value>, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let table_config = valve
+        .config
+        .table
+        .get(table)
+        .filter(|t| t.options.contains("edit"))
+        .ok_or_else(|| GetError::new(format!("The '{}' table has no conflict table", table)))?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+
+    let select = Select::new(format!("\"{}_conflict\"", table));
+    let rows = get_table_from_pool(pool, &select).await?;
+    let row_numbers = rows
+        .iter()
+        .filter_map(|r| r.get("row_number").and_then(|n| n.as_i64()))
+        .collect::<Vec<_>>();
+    let mut messages = get_row_messages_from_pool(pool, table, &row_numbers)
+        .await
+        .map_err(|e| GetError::new(e.to_string()))?;
+
+    let mut conflicts = vec![];
+    for row in rows {
+        let row_number = row
+            .get("row_number")
+            .and_then(|n| n.as_i64())
+            .unwrap_or_default();
+        let row_messages = messages.remove(&row_number).unwrap_or_default();
+        let mut values = Map::new();
+        for column in &table_config.column_order {
+            let value = match row.get(column) {
+                Some(Value::Null) | None => row_messages
+                    .iter()
+                    .find(|m| m["column"] == json!(column))
+                    .map(|m| m["value"].clone())
+                    .unwrap_or(Value::Null),
+                Some(value) => value.clone(),
+            };
+            values.insert(column.to_string(), value);
+        }
+        // A tooltip for each column with messages:
+        let mut titles = Map::new();
+        for message in &row_messages {
+            let column = message["column"].as_str().unwrap_or_default().to_string();
+            let line = format!(
+                "{}: {}",
+                message["rule"].as_str().unwrap_or_default(),
+                message["message"].as_str().unwrap_or_default()
+            );
+            match titles.get_mut(&column) {
+                Some(Value::String(title)) => *title = format!("{}\n{}", title, line),
+                _ => {
+                    titles.insert(column, json!(line));
+                }
+            }
+        }
+        conflicts.push(json!({
+            "row_number": row_number,
+            "values": values,
+            "messages": row_messages,
+            "titles": titles,
+        }));
+    }
+    Ok(conflicts)
+}
+
+/// Get the changes to one row of a table from VALVE's history table, oldest first.
+/// Each record says whether the row was inserted, updated, or deleted, by whom and when,
+/// which cells changed from what to what, and who undid the change, if anyone.
+pub async fn get_row_history(
+    config: &Config,
+    table: &str,
+    row_number: u32,
+) -> Result<Vec<Value>, GetError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    if !valve.config.table.contains_key(table) || ["message", "history"].contains(&table) {
+        return Err(GetError::new(format!(
+            "The '{}' table has no row history",
+            table
+        )));
+    }
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Connection pool is not initialized.".to_string())?;
+    let records = get_row_history_from_pool(pool, table, row_number)
+        .await
+        .map_err(|e| GetError::new(e.to_string()))?;
+    Ok(records
+        .into_iter()
+        .map(|mut record| {
+            let action = match (&record["from"], &record["to"]) {
+                (Value::Null, _) => "insert",
+                (_, Value::Null) => "delete",
+                _ => "update",
+            };
+            let changes = match record.remove("summary") {
+                Some(Value::Array(changes)) => changes,
+                _ => vec![],
+            };
+            record.insert("action".to_string(), json!(action));
+            record.insert("changes".to_string(), json!(changes));
+            Value::Object(record)
+        })
+        .collect())
+}
+
+/// The label of the given table from its `[tables.NAME]` section, or else the table's name.
+pub fn get_table_label(config: &Config, table: &str) -> String {
+    match config.tables.get(table).and_then(|t| t.label.as_ref()) {
+        Some(label) => label.to_string(),
+        None => table.to_string(),
+    }
+}
+
+/// The tables for the navigation menu, from their names to their labels,
+/// leaving out the history table and hidden tables.
+/// This is computed once when VALVE is built, see [Config::table_map].
+pub fn get_table_map(config: &Config) -> SerdeMap {
+    match &config.table_map {
+        Some(table_map) => table_map.clone(),
+        None => build_table_map(config),
+    }
+}
+
+pub fn build_table_map(config: &Config) -> SerdeMap {
+    let mut table_map = SerdeMap::new();
+    if let Some(valve) = &config.valve {
+        for table in &valve.config.table_order {
+            if table == "history" || config.tables.get(table).map_or(false, |t| t.hidden) {
+                continue;
+            }
+            table_map.insert(table.to_string(), json!(get_table_label(config, table)));
+        }
+    }
+    table_map
+}
+
 pub fn get_action_map(config: &Config) -> Result<SerdeMap, GetError> {
     let action_map: SerdeMap = config
         .actions
@@ -902,10 +2304,15 @@ fn value_rows_to_strings(rows: &Vec<Map<String, Value>>) -> Result<Vec<Vec<Strin
     Ok(lines)
 }
 
-fn value_rows_to_xsv(rows: &Vec<Map<String, Value>>, delimiter: u8) -> Result<String, GetError> {
+fn value_rows_to_xsv(
+    rows: &Vec<Map<String, Value>>,
+    delimiter: u8,
+    terminator: Terminator,
+) -> Result<String, GetError> {
     let lines = value_rows_to_strings(rows)?;
     let mut writer = WriterBuilder::new()
         .delimiter(delimiter)
+        .terminator(terminator)
         .from_writer(vec![]);
     for line in lines {
         writer.write_record(line)?;
@@ -920,33 +2327,138 @@ fn value_rows_to_xsv(rows: &Vec<Map<String, Value>>, delimiter: u8) -> Result<St
     }
 }
 
+/// Write the rows as RFC 4180 CSV: fields with commas, quotes, or line breaks are quoted,
+/// and every record ends with CRLF.
 fn value_rows_to_csv(rows: &Vec<Map<String, Value>>) -> Result<String, GetError> {
-    value_rows_to_xsv(rows, b',')
+    value_rows_to_xsv(rows, b',', Terminator::CRLF)
 }
 
+/// Write the rows as TSV following the conventions of Valve's source files,
+/// so that the output can be loaded back into the schema directory:
+/// nothing is quoted, and backslashes, tabs, and line breaks in values are escaped.
 fn value_rows_to_tsv(rows: &Vec<Map<String, Value>>) -> Result<String, GetError> {
-    value_rows_to_xsv(rows, b'\t')
+    let mut tsv = String::new();
+    for line in value_rows_to_strings(rows)? {
+        let cells = line.iter().map(|cell| escape_tsv(cell)).collect::<Vec<_>>();
+        tsv.push_str(&cells.join("\t"));
+        tsv.push('\n');
+    }
+    Ok(tsv)
+}
+
+fn escape_tsv(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Get the rows of a page with just the value of each cell.
+pub(crate) fn page_to_value_rows(page: &Value) -> Vec<Map<String, Value>> {
+    let rows = match page.get("row").and_then(|r| r.as_array()) {
+        Some(rows) => rows,
+        None => return vec![],
+    };
+    rows.iter()
+        .filter_map(|row| row.as_object())
+        .map(|row| {
+            row.iter()
+                .map(|(column, cell)| {
+                    let value = cell.get("value").cloned().unwrap_or(Value::Null);
+                    (column.to_string(), value)
+                })
+                .collect()
+        })
+        .collect()
 }
 
+/// Render the rows as a table with aligned columns, a header, and a line under the header,
+/// like the SQLite shell's column mode. Cells longer than TEXT_MAX_WIDTH are truncated.
 fn value_rows_to_text(rows: &Vec<Map<String, Value>>) -> Result<String, GetError> {
-    let tsv = value_rows_to_tsv(rows).unwrap_or_default();
+    let lines = value_rows_to_strings(rows)?
+        .iter()
+        .map(|line| {
+            line.iter()
+                .map(|cell| truncate(&escape_tsv(cell), TEXT_MAX_WIDTH))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let header = match lines.first() {
+        Some(header) => header,
+        None => return Ok(String::new()),
+    };
 
-    // Format using elastic tabstops
-    let mut tw = TabWriter::new(vec![]);
-    if let Err(e) = write!(&mut tw, "{}", tsv) {
-        return Err(GetError::new(e.to_string()));
+    let mut widths = vec![0; header.len()];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
     }
-    if let Err(e) = tw.flush() {
-        return Err(GetError::new(e.to_string()));
+    let rule = widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>();
+
+    let mut text = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        text.push_str(&align(line, &widths));
+        if i == 0 {
+            text.push_str(&align(&rule, &widths));
+        }
     }
+    Ok(text)
+}
 
-    match tw.into_inner() {
-        Ok(tw) => match String::from_utf8(tw) {
-            Ok(s) => Ok(s),
-            Err(e) => Err(GetError::new(e.to_string())),
-        },
-        Err(e) => Err(GetError::new(e.to_string())),
+/// Pad each cell to the width of its column, separating columns with two spaces.
+fn align(line: &Vec<String>, widths: &Vec<usize>) -> String {
+    let cells = line
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+        .collect::<Vec<_>>();
+    format!("{}\n", cells.join("  ").trim_end())
+}
+
+fn truncate(value: &str, max: usize) -> String {
+    if value.chars().count() <= max {
+        return value.to_string();
     }
+    let mut value = value.chars().take(max - 1).collect::<String>();
+    value.push('…');
+    value
+}
+
+/// Write each (table, columns, rows) triple to its own sheet of an Excel workbook,
+/// with a frozen header row. Numbers and booleans are written as typed cells.
+pub(crate) fn value_rows_to_xlsx(
+    sheets: &[(String, Vec<String>, Vec<Map<String, Value>>)],
+) -> Result<Vec<u8>, GetError> {
+    let mut workbook = Workbook::new();
+    let bold = Format::new().set_bold();
+    for (table, columns, rows) in sheets {
+        let worksheet = workbook.add_worksheet();
+        // Sheet names are limited to 31 characters.
+        worksheet.set_name(&table.chars().take(31).collect::<String>())?;
+        for (c, column) in columns.iter().enumerate() {
+            worksheet.write_string_with_format(0, c as u16, column, &bold)?;
+        }
+        worksheet.set_freeze_panes(1, 0)?;
+        for (r, row) in rows.iter().enumerate() {
+            let r = r as u32 + 1;
+            for (c, column) in columns.iter().enumerate() {
+                let c = c as u16;
+                match row.get(column) {
+                    Some(Value::Number(n)) => match n.as_f64() {
+                        Some(n) => worksheet.write_number(r, c, n)?,
+                        None => worksheet.write_string(r, c, &n.to_string())?,
+                    },
+                    Some(Value::Bool(b)) => worksheet.write_boolean(r, c, *b)?,
+                    Some(Value::String(s)) => worksheet.write_string(r, c, s)?,
+                    Some(Value::Null) | None => continue,
+                    Some(value) => worksheet.write_string(r, c, &value.to_string())?,
+                };
+            }
+        }
+    }
+    Ok(workbook.save_to_buffer()?)
 }
 
 fn level_to_int(level: &String) -> usize {
@@ -969,56 +2481,105 @@ fn level_to_bootstrap(level: String) -> String {
     .to_string()
 }
 
+/// Format a number with commas between each group of three digits, e.g. 1,234.
+fn thousands(number: i64) -> String {
+    let digits = number.unsigned_abs().to_string();
+    let mut groups = vec![];
+    let mut end = digits.len();
+    while end > 3 {
+        groups.push(&digits[end - 3..end]);
+        end -= 3;
+    }
+    groups.push(&digits[..end]);
+    groups.reverse();
+    let sign = if number < 0 { "-" } else { "" };
+    format!("{}{}", sign, groups.join(","))
+}
+
 fn name_to_id(name: String) -> String {
     let re: Regex = Regex::new(r"\W").unwrap();
     re.replace_all(&name, "-").to_string()
 }
 
-// TODO: Don't rebuild the Minijinja environment on every call!
-pub fn page_to_html(config: &Config, template: &str, page: &Value) -> Result<String, GetError> {
-    tracing::info!("page_to_html {:?} {}", config.template_path, template);
-    let page_html = include_str!("resources/page.html");
-    let table_html = include_str!("resources/table.html");
-    let form_html = include_str!("resources/form.html");
-    let tree_html = include_str!("resources/tree.html");
-    let action_html = include_str!("resources/action.html");
+/// Forget the compiled templates, so that they are read again on the next page,
+/// e.g. when they change in development mode.
+pub fn clear_templates() {
+    TEMPLATES
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
 
+/// Get the compiled templates for the configuration, compiling them the first time.
+/// A panic while another thread held the lock leaves the cache as it was, so it is still used.
+fn get_templates(config: &Config) -> Result<Arc<Environment<'static>>, GetError> {
+    let key = (config.template_path.clone(), config.read_only);
+    if let Some(env) = TEMPLATES
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&key)
+    {
+        return Ok(env.clone());
+    }
+    let env = Arc::new(build_templates(config)?);
+    TEMPLATES
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(key, env.clone());
+    Ok(env)
+}
+
+/// The name of each template, with the default template used when the configured template
+/// directory does not have it.
+const DEFAULT_TEMPLATES: [(&str, &str); 14] = [
+    ("page.html", include_str!("resources/page.html")),
+    ("table.html", include_str!("resources/table.html")),
+    ("form.html", include_str!("resources/form.html")),
+    ("tree.html", include_str!("resources/tree.html")),
+    ("action.html", include_str!("resources/action.html")),
+    ("job.html", include_str!("resources/job.html")),
+    ("upload.html", include_str!("resources/upload.html")),
+    ("messages.html", include_str!("resources/messages.html")),
+    ("index.html", include_str!("resources/index.html")),
+    ("error.html", include_str!("resources/error.html")),
+    ("hierarchy.html", include_str!("resources/hierarchy.html")),
+    ("history.html", include_str!("resources/history.html")),
+    ("conflicts.html", include_str!("resources/conflicts.html")),
+    ("class_tree.html", include_str!("resources/class_tree.html")),
+];
+
+/// Compile the templates from the configured template directory,
+/// falling back to the default templates for those that it does not have.
+fn build_templates(config: &Config) -> Result<Environment<'static>, GetError> {
     let mut env = Environment::new();
     env.add_filter("level_to_bootstrap", level_to_bootstrap);
     env.add_filter("id", name_to_id);
+    env.add_filter("thousands", thousands);
+    env.add_global("read_only", minijinja::value::Value::from(config.read_only));
 
-    if let Some(t) = &config.template_path {
-        tracing::info!("Adding template source {}", t);
-        env.set_source(Source::from_path(t));
-        let path = Path::new(t).join("page.html");
-        if !path.is_file() {
-            env.add_template("page.html", page_html).unwrap();
-        }
-        let path = Path::new(t).join("table.html");
-        if !path.is_file() {
-            env.add_template("table.html", table_html).unwrap();
-        }
-        let path = Path::new(t).join("form.html");
-        if !path.is_file() {
-            env.add_template("form.html", form_html).unwrap();
+    match &config.template_path {
+        Some(t) => {
+            tracing::info!("Adding template source {}", t);
+            env.set_source(Source::from_path(t));
         }
-        let path = Path::new(t).join("tree.html");
-        if !path.is_file() {
-            env.add_template("tree.html", tree_html).unwrap();
-        }
-        let path = Path::new(t).join("action.html");
-        if !path.is_file() {
-            env.add_template("action.html", action_html).unwrap();
+        None => tracing::info!("Adding default templates"),
+    }
+    for (name, source) in DEFAULT_TEMPLATES {
+        let configured = config
+            .template_path
+            .as_ref()
+            .map_or(false, |t| Path::new(t).join(name).is_file());
+        if !configured {
+            env.add_template(name, source)?;
         }
-    } else {
-        tracing::info!("Adding default templates");
-        env.add_template("page.html", page_html).unwrap();
-        env.add_template("table.html", table_html).unwrap();
-        env.add_template("form.html", form_html).unwrap();
-        env.add_template("tree.html", tree_html).unwrap();
-        env.add_template("action.html", action_html).unwrap();
     }
 
+    Ok(env)
+}
+
+pub fn page_to_html(config: &Config, template: &str, page: &Value) -> Result<String, GetError> {
+    tracing::info!("page_to_html {:?} {}", config.template_path, template);
+    let env = get_templates(config)?;
     let template = match env.get_template(format!("{}.html", template).as_str()) {
         Ok(t) => t,
         Err(e) => return Err(GetError::new(e.to_string())),
@@ -1028,3 +2589,304 @@ pub fn page_to_html(config: &Config, template: &str, page: &Value) -> Result<Str
         Err(e) => return Err(GetError::new(e.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestSchema;
+
+    /// Penguins that live on the islands of another table.
+    async fn islands() -> crate::testing::TestDatabase {
+        TestSchema::new()
+            .table("island", "name\nBiscoe\nDream\n")
+            .table("penguin", "id\tisland\n1\tDream\n2\tDream\n3\tBiscoe\n")
+            .column("island", "name", "text", "primary")
+            .column("penguin", "island", "text", "from(island.name)")
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_link_foreign_cells() {
+        let database = islands().await;
+        let values = ["Dream", "Atlantis", "Biscoe"];
+        let value_rows = values
+            .iter()
+            .map(|v| json!({ "island": v }).as_object().unwrap().clone())
+            .collect::<Vec<_>>();
+        let mut cell_rows = values
+            .iter()
+            .map(|v| {
+                json!({ "island": { "value": v } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        // A link from the column format is kept.
+        cell_rows[2]["island"]["link"] = json!("https://example.com/Biscoe");
+        link_foreign_cells(&database.config, "penguin", &value_rows, &mut cell_rows)
+            .await
+            .unwrap();
+        assert_eq!(cell_rows[0]["island"]["link"], json!("island/row/2"));
+        assert!(cell_rows[1]["island"].get("link").is_none());
+        assert_eq!(
+            cell_rows[2]["island"]["link"],
+            json!("https://example.com/Biscoe")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_page_counts() {
+        // The counts are fetched alongside the rows, and agree with them however many pages
+        // are requested at once.
+        let database = islands().await;
+        let mut select = Select::new("\"penguin\"");
+        select.filter(vec![
+            Filter::new("island", "eq", json!("\"Dream\"")).unwrap()
+        ]);
+        select.limit(1);
+        let options = GetRowsOptions::default();
+        let pages = futures::future::try_join_all(
+            (0..5).map(|_| get_rows(&database.config, &select, "page", "json", &options)),
+        )
+        .await
+        .unwrap();
+        for page in pages {
+            let page: Value = serde_json::from_str(&page).unwrap();
+            assert_eq!(page["table"]["counts"]["count"], json!(2));
+            assert_eq!(page["table"]["counts"]["total"], json!(3));
+            assert_eq!(page["table"]["end"], json!(1));
+            let rows = page_to_value_rows(&page);
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0]["island"], json!("Dream"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_page_sizes() {
+        let database = islands().await;
+        let mut select = Select::new("\"penguin\"");
+        select.limit(2).offset(2);
+        let options = GetRowsOptions::default();
+        let page = get_rows(&database.config, &select, "page", "json", &options)
+            .await
+            .unwrap();
+        let page: Value = serde_json::from_str(&page).unwrap();
+        let page_sizes = page["table"]["page_sizes"].as_array().unwrap();
+        // The current limit is offered along with the usual sizes, and is the active one.
+        assert_eq!(page_sizes[0]["size"], json!(2));
+        assert_eq!(page_sizes[0]["active"], json!(true));
+        assert!(page_sizes[0]["href"].as_str().unwrap().contains("offset=2"));
+        assert_eq!(page_sizes[1]["size"], json!(10));
+        assert_eq!(page_sizes[1]["active"], json!(false));
+        assert!(page_sizes[1]["href"].as_str().unwrap().contains("limit=10"));
+        assert!(page_sizes
+            .iter()
+            .all(|s| s["size"].as_u64() <= page["table"]["limit_max"].as_u64()));
+    }
+
+    #[test]
+    fn test_thousands() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1234), "1,234");
+        assert_eq!(thousands(-1234567), "-1,234,567");
+    }
+
+    #[tokio::test]
+    async fn test_get_row_references() {
+        let database = islands().await;
+        let references = get_row_references(&database.config, "island", 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            references,
+            [json!({"table": "penguin", "column": "island", "value": "Dream", "count": 2})]
+        );
+        let references = get_row_references(&database.config, "island", 1)
+            .await
+            .unwrap();
+        assert_eq!(references[0]["count"], json!(1));
+        // No such row, and no tables that refer to this one.
+        assert!(get_row_references(&database.config, "island", 99)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(get_row_references(&database.config, "penguin", 1)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            format_date("2024-03-05", "%B %-d, %Y"),
+            Some("March 5, 2024".to_string())
+        );
+        assert_eq!(
+            format_date(" 2024-03-05T14:30:00 ", "%Y/%m/%d %H:%M"),
+            Some("2024/03/05 14:30".to_string())
+        );
+        assert_eq!(
+            format_date("2024-03-05 14:30:00", "%H:%M"),
+            Some("14:30".to_string())
+        );
+        assert_eq!(
+            format_date("2024-03-05T14:30:00+02:00", "%H:%M %z"),
+            Some("14:30 +0200".to_string())
+        );
+        // Not dates, and a time format for a date without a time.
+        assert_eq!(format_date("2024-02-30", "%Y"), None);
+        assert_eq!(format_date("March 5", "%Y"), None);
+        assert_eq!(format_date("", "%Y"), None);
+        assert_eq!(format_date("2024-03-05", "%H:%M"), None);
+    }
+
+    #[test]
+    fn test_format_cell() {
+        let format = ColumnFormat {
+            url: Some("https://example.com/{value}".to_string()),
+            badges: Some(IndexMap::from([
+                ("active".to_string(), "success".to_string()),
+                ("*".to_string(), "secondary".to_string()),
+            ])),
+            ..Default::default()
+        };
+        let mut cell = Map::new();
+        format_cell(&format, &json!("active"), &mut cell);
+        assert_eq!(cell["link"], json!("https://example.com/active"));
+        assert_eq!(cell["badge"], json!("success"));
+        let mut cell = Map::new();
+        format_cell(&format, &json!("a b"), &mut cell);
+        assert_eq!(cell["link"], json!("https://example.com/a%20b"));
+        assert_eq!(cell["badge"], json!("secondary"));
+
+        let format = ColumnFormat {
+            date: Some("%B %-d, %Y".to_string()),
+            precision: Some(2),
+            ..Default::default()
+        };
+        let mut cell = Map::new();
+        format_cell(&format, &json!("2024-03-05"), &mut cell);
+        assert_eq!(cell["display"], json!("March 5, 2024"));
+        let mut cell = Map::new();
+        format_cell(&format, &json!(3.14159), &mut cell);
+        assert_eq!(cell["display"], json!("3.14"));
+
+        // Invalid dates and empty cells are shown as they are.
+        for value in [json!("2024-13-01"), json!(""), Value::Null] {
+            let mut cell = Map::new();
+            format_cell(&format, &value, &mut cell);
+            assert!(cell.is_empty(), "{} should not be formatted", value);
+        }
+    }
+
+    /// Rows with values that need quoting or escaping, and a number and a null.
+    fn awkward_rows() -> Vec<Map<String, Value>> {
+        [
+            json!({"species": "Adelie, \"Pygoscelis\"", "note": "two\nlines", "mass": 3750}),
+            json!({"species": "Gentoo", "note": "tab\there, back\\slash", "mass": null}),
+        ]
+        .iter()
+        .map(|row| row.as_object().unwrap().clone())
+        .collect()
+    }
+
+    #[test]
+    fn test_value_rows_to_csv() {
+        assert_eq!(
+            value_rows_to_csv(&awkward_rows()).unwrap(),
+            "species,note,mass\r\n\
+             \"Adelie, \"\"Pygoscelis\"\"\",\"two\nlines\",3750\r\n\
+             Gentoo,\"tab\there, back\\slash\",\r\n"
+        );
+        assert_eq!(value_rows_to_csv(&Vec::new()).unwrap(), "");
+    }
+
+    #[test]
+    fn test_value_rows_to_tsv() {
+        // Nothing is quoted, and tabs, line breaks, and backslashes are escaped.
+        assert_eq!(
+            value_rows_to_tsv(&awkward_rows()).unwrap(),
+            "species\tnote\tmass\n\
+             Adelie, \"Pygoscelis\"\ttwo\\nlines\t3750\n\
+             Gentoo\ttab\\there, back\\\\slash\t\n"
+        );
+    }
+
+    #[test]
+    fn test_value_rows_to_text() {
+        let text = value_rows_to_text(&awkward_rows()).unwrap();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                "species               note                    mass",
+                "--------------------  ----------------------  ----",
+                "Adelie, \"Pygoscelis\"  two\\nlines              3750",
+                "Gentoo                tab\\there, back\\\\slash",
+            ]
+        );
+
+        // Long cells are cut short, so that one cell cannot stretch the whole table.
+        let long = json!({ "note": "x".repeat(100) });
+        let text = value_rows_to_text(&vec![long.as_object().unwrap().clone()]).unwrap();
+        let cell = text.lines().last().unwrap();
+        assert_eq!(cell.chars().count(), TEXT_MAX_WIDTH);
+        assert!(cell.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn test_build_table_map() {
+        let mut database = islands().await;
+        // Tables that share a label are both in the menu.
+        for table in ["island", "penguin"] {
+            let table_config = crate::config::TableConfig {
+                label: Some("Field sites".to_string()),
+                ..Default::default()
+            };
+            database
+                .config
+                .tables
+                .insert(table.to_string(), table_config);
+        }
+        let table_map = build_table_map(&database.config);
+        assert_eq!(table_map["island"], json!("Field sites"));
+        assert_eq!(table_map["penguin"], json!("Field sites"));
+
+        database.config.tables["penguin"].hidden = true;
+        assert!(!build_table_map(&database.config).contains_key("penguin"));
+    }
+
+    #[tokio::test]
+    async fn test_templates() {
+        let dir = std::env::temp_dir().join(format!("nanobot-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("error.html"), "custom {{ message }}").unwrap();
+        let mut config = Config::from_path("missing.toml").await.unwrap();
+        config.template_path = Some(dir.to_str().unwrap().to_string());
+
+        // The templates are compiled once, with the defaults for those the directory lacks.
+        let first = get_templates(&config).unwrap();
+        assert!(Arc::ptr_eq(&first, &get_templates(&config).unwrap()));
+        assert!(first.get_template("page.html").is_ok());
+        let page = json!({"message": "oops"});
+        assert_eq!(
+            page_to_html(&config, "error", &page).unwrap(),
+            "custom oops"
+        );
+
+        // Clearing them reads the changed template again.
+        fs::write(dir.join("error.html"), "changed {{ message }}").unwrap();
+        clear_templates();
+        let second = get_templates(&config).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            page_to_html(&config, "error", &page).unwrap(),
+            "changed oops"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
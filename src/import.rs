@@ -0,0 +1,244 @@
+use crate::config::Config;
+use crate::edit::Edits;
+use crate::error::NanobotError;
+use crate::sql::get_all_row_numbers_from_pool;
+use crate::validate::get_validation_errors;
+use ontodev_valve::valve::Valve;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Tables that hold Nanobot's own records, which cannot be imported.
+const SKIPPED_TABLES: [&str; 2] = ["message", "history"];
+
+/// The delimiter of a file that can be imported, from its extension.
+fn delimiter(path: &Path) -> Option<u8> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("tsv") | Some("tab") => Some(b'\t'),
+        Some("csv") => Some(b','),
+        _ => None,
+    }
+}
+
+/// Read the rows of a CSV or TSV file with a header row for the given table.
+/// Columns of the table that are not in the file are empty,
+/// and columns of the file that are not in the table are an error.
+pub fn read_rows(
+    valve: &Valve,
+    table: &str,
+    delimiter: u8,
+    data: &[u8],
+) -> Result<Vec<Map<String, Value>>, String> {
+    let columns = valve
+        .config
+        .table
+        .get(table)
+        .map(|t| t.column_order.to_vec())
+        .ok_or(format!("Undefined table '{}'", table))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(data);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let unknown = headers
+        .iter()
+        .filter(|h| !columns.contains(&h.to_string()))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "The file has columns that are not in the '{}' table: {}",
+            table,
+            unknown.join(", ")
+        ));
+    }
+
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let mut row = Map::new();
+        for column in &columns {
+            let value = headers
+                .iter()
+                .position(|h| h == column)
+                .and_then(|i| record.get(i))
+                .unwrap_or_default();
+            row.insert(column.to_string(), json!(value));
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// The files to import: the CSV and TSV files in each directory, and the other paths as they are.
+fn find_files(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && delimiter(p).is_some())
+                .collect::<Vec<_>>();
+            entries.sort();
+            files.extend(entries);
+        } else if delimiter(path).is_some() {
+            files.push(path.to_path_buf());
+        } else {
+            return Err(format!(
+                "Cannot import '{}': expected a directory, or a .csv, .tsv, or .tab file",
+                path.display()
+            ));
+        }
+    }
+    if files.is_empty() {
+        return Err("Found no CSV or TSV files to import".to_string());
+    }
+    Ok(files)
+}
+
+/// Import CSV and TSV files into the tables that they are named after, e.g. `penguin.tsv` into
+/// the `penguin` table, from the given files and from the files in the given directories.
+/// The tables are imported in dependency order, so that foreign keys to the rows of another
+/// imported table are valid. Each row is validated, and rows with errors are rejected;
+/// the other rows are appended to the table, or replace its rows when `replace` is true.
+/// The import holds the edit lock from the first change to the last, and if a change fails,
+/// the changes that were already made are undone, so the tables are left as they were.
+/// Return a summary of the rows inserted and rejected for each table,
+/// with the validation errors of the rejected rows.
+pub async fn import(
+    config: &Config,
+    paths: &[String],
+    replace: bool,
+) -> Result<String, NanobotError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Pool"))?;
+
+    // Read every file before changing anything.
+    let mut imports: Vec<(String, PathBuf, Vec<Map<String, Value>>)> = vec![];
+    for path in find_files(paths)? {
+        let table = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default()
+            .to_string();
+        if !valve.config.table.contains_key(&table) || SKIPPED_TABLES.contains(&table.as_str()) {
+            return Err(format!(
+                "Cannot import '{}': there is no '{}' table to import it into",
+                path.display(),
+                table
+            )
+            .into());
+        }
+        if let Some((_, other, _)) = imports.iter().find(|(t, _, _)| *t == table) {
+            return Err(format!(
+                "Both '{}' and '{}' would be imported into '{}'",
+                other.display(),
+                path.display(),
+                table
+            )
+            .into());
+        }
+        let data =
+            fs::read(&path).map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        let rows = read_rows(valve, &table, delimiter(&path).unwrap_or(b','), &data)
+            .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        imports.push((table, path, rows));
+    }
+    let order = |table: &str| {
+        valve
+            .sorted_table_list
+            .iter()
+            .position(|t| t == table)
+            .unwrap_or(usize::MAX)
+    };
+    imports.sort_by_key(|(table, _, _)| order(table.as_str()));
+
+    let mut edits = Edits::begin(valve).await;
+    let mut lines = vec![];
+    let (mut inserted, mut rejected) = (0, 0);
+    for (table, path, rows) in &imports {
+        let mut deleted = 0;
+        if replace {
+            let row_numbers = match get_all_row_numbers_from_pool(pool, table).await {
+                Ok(row_numbers) => row_numbers,
+                Err(e) => {
+                    let error = format!("Could not read '{}': {}", table, e);
+                    return Err(edits.rollback(error).await.into());
+                }
+            };
+            for row_number in row_numbers {
+                if let Err(e) = edits.delete(table, &row_number).await {
+                    return Err(edits.rollback(e).await.into());
+                }
+                deleted += 1;
+            }
+        }
+
+        // Validate each row after the tables before it are imported,
+        // so that foreign keys are checked against the new rows.
+        let mut errors = vec![];
+        let mut table_inserted = 0;
+        for (i, row) in rows.iter().enumerate() {
+            let row_errors = match get_validation_errors(table, row, None, valve).await {
+                Ok(row_errors) => row_errors,
+                Err(e) => return Err(edits.rollback(e).await.into()),
+            };
+            if row_errors.iter().any(|e| e["level"] == "error") {
+                // Rows are numbered from 1, after the header.
+                errors.extend(row_errors.into_iter().map(|mut e| {
+                    e.insert("row".to_string(), json!(i + 1));
+                    e
+                }));
+                rejected += 1;
+                continue;
+            }
+            if let Err(e) = edits.insert(table, row).await {
+                return Err(edits.rollback(e).await.into());
+            }
+            table_inserted += 1;
+        }
+        inserted += table_inserted;
+
+        let mut line = format!(
+            "{}: inserted {}, rejected {} from '{}'",
+            table,
+            plural(table_inserted, "row"),
+            plural(rows.len() - table_inserted, "row"),
+            path.display()
+        );
+        if replace {
+            line.push_str(&format!(", replacing {}", plural(deleted, "row")));
+        }
+        lines.push(line);
+        for error in errors {
+            lines.push(format!(
+                "  row {}, column '{}', value '{}': {}",
+                error["row"],
+                error["column"].as_str().unwrap_or_default(),
+                error["value"].as_str().unwrap_or_default(),
+                error["message"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+    edits.commit();
+    lines.push(format!(
+        "Imported {} into {}, and rejected {}",
+        plural(inserted, "row"),
+        plural(imports.len(), "table"),
+        plural(rejected, "row")
+    ));
+    Ok(lines.join("\n"))
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
@@ -1,11 +1,19 @@
-use crate::config::{to_toml, Config, LoggingLevel};
+use crate::config::{redact_password, to_toml, Config, LoggingLevel, TomlConfig, ValveTomlConfig};
+use crate::error::NanobotError;
+use crate::job::JOB_DIR;
+use crate::schema::{check_schema, Tsv};
+use crate::shell::HISTORY_FILE;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
 use ontodev_valve::valve::Valve;
-use std::error;
+use sqlx::{postgres::PgPoolOptions, Row};
+use std::env;
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{prelude::*, BufReader};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
 
 fn add_to_gitignore(input: &str) -> Result<String, String> {
     if Path::new(".gitignore").exists() {
@@ -92,40 +100,696 @@ fn add_to_gitignore(input: &str) -> Result<String, String> {
             Ok(String::from("Found"))
         }
     } else {
-        Ok(String::from("No .gitignore"))
+        let contents = format!("# Generated by nanobot\n{}\n", input);
+        if let Err(e) = fs::write(".gitignore", contents) {
+            return Err(e.to_string());
+        }
+        Ok(String::from("Created"))
     }
 }
 
-fn create_table_tsv(path: &Path) -> Result<(), Box<dyn error::Error>> {
-    let data = include_str!("resources/table.tsv");
-    fs::write(&path, data).expect("Unable to write file");
-    Ok(())
+/// How `init` treats a project that already exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InitMode {
+    /// Create whatever is missing, then (re)load the database.
+    Default,
+    /// Recreate the configuration, schema files, and database from scratch.
+    Force,
+    /// Only add what is missing, keeping existing files and the data in an existing database.
+    Merge,
+}
+
+/// What `init` fills a new project with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InitSource {
+    /// Only the VALVE schema tables.
+    Empty,
+    /// The schema tables and some example data tables, with a few invalid values on purpose.
+    Demo,
+    /// A data table for each CSV or TSV file in a directory, with an inferred schema.
+    Directory(PathBuf),
+    /// The schema and a few example rows for a common kind of project.
+    Template(ProjectTemplate),
+    /// The tables listed in a table table that is downloaded from a URL.
+    Url(Url),
+}
+
+/// Presets for the schema of a new project, for different communities of users.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectTemplate {
+    /// Ontology terms, one per row, with a table of prefixes for their CURIEs.
+    Ontology,
+    /// A flat registry of entries with IDs, statuses, and contact information.
+    Registry,
+    /// Survey questions, respondents, and their responses.
+    Survey,
+}
+
+impl FromStr for ProjectTemplate {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ProjectTemplate, String> {
+        match name {
+            "ontology" => Ok(ProjectTemplate::Ontology),
+            "registry" => Ok(ProjectTemplate::Registry),
+            "survey" => Ok(ProjectTemplate::Survey),
+            _ => Err(format!(
+                "Unknown project template '{}': use ontology, registry, or survey",
+                name
+            )),
+        }
+    }
+}
+
+/// Files for deploying a project, which `init` can generate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeployTarget {
+    /// A systemd unit that runs `nanobot serve`.
+    Systemd,
+    /// A Dockerfile and a Compose file.
+    Docker,
+    /// A CGI wrapper script.
+    Cgi,
+}
+
+impl FromStr for DeployTarget {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DeployTarget, String> {
+        match name {
+            "systemd" => Ok(DeployTarget::Systemd),
+            "docker" => Ok(DeployTarget::Docker),
+            "cgi" => Ok(DeployTarget::Cgi),
+            _ => Err(format!(
+                "Unknown deployment target '{}': use systemd, docker, or cgi",
+                name
+            )),
+        }
+    }
+}
+
+/// The directory for data tables created by `init`.
+const DATA_DIR: &str = "src/data";
+
+/// The directories that `init` creates for a new project.
+const PROJECT_DIRS: [&str; 4] = ["src/schema", DATA_DIR, "assets", "templates"];
+
+/// The contents of the VALVE schema tables for a new project, and the data files to create.
+struct Scaffold {
+    table: String,
+    column: String,
+    datatype: String,
+    data: Vec<(PathBuf, String)>,
+}
+
+impl Default for Scaffold {
+    fn default() -> Scaffold {
+        Scaffold {
+            table: include_str!("resources/table.tsv").to_string(),
+            column: include_str!("resources/column.tsv").to_string(),
+            datatype: include_str!("resources/datatype.tsv").to_string(),
+            data: vec![],
+        }
+    }
+}
+
+impl Scaffold {
+    fn demo() -> Scaffold {
+        let dir = Path::new(DATA_DIR);
+        Scaffold {
+            table: include_str!("resources/demo/table.tsv").to_string(),
+            column: include_str!("resources/demo/column.tsv").to_string(),
+            datatype: include_str!("resources/demo/datatype.tsv").to_string(),
+            data: vec![
+                (
+                    dir.join("island.tsv"),
+                    include_str!("resources/demo/island.tsv").to_string(),
+                ),
+                (
+                    dir.join("penguin.tsv"),
+                    include_str!("resources/demo/penguin.tsv").to_string(),
+                ),
+            ],
+        }
+    }
+
+    fn template(template: ProjectTemplate) -> Scaffold {
+        let data = Path::new(DATA_DIR);
+        let schema = Path::new("src/schema");
+        match template {
+            ProjectTemplate::Ontology => Scaffold {
+                table: include_str!("resources/ontology/table.tsv").to_string(),
+                column: include_str!("resources/ontology/column.tsv").to_string(),
+                datatype: include_str!("resources/ontology/datatype.tsv").to_string(),
+                data: vec![
+                    (
+                        schema.join("prefix.tsv"),
+                        include_str!("resources/ontology/prefix.tsv").to_string(),
+                    ),
+                    (
+                        data.join("term.tsv"),
+                        include_str!("resources/ontology/term.tsv").to_string(),
+                    ),
+                ],
+            },
+            ProjectTemplate::Registry => Scaffold {
+                table: include_str!("resources/registry/table.tsv").to_string(),
+                column: include_str!("resources/registry/column.tsv").to_string(),
+                datatype: include_str!("resources/registry/datatype.tsv").to_string(),
+                data: vec![(
+                    data.join("entry.tsv"),
+                    include_str!("resources/registry/entry.tsv").to_string(),
+                )],
+            },
+            ProjectTemplate::Survey => Scaffold {
+                table: include_str!("resources/survey/table.tsv").to_string(),
+                column: include_str!("resources/survey/column.tsv").to_string(),
+                datatype: include_str!("resources/survey/datatype.tsv").to_string(),
+                data: vec![
+                    (
+                        schema.join("question.tsv"),
+                        include_str!("resources/survey/question.tsv").to_string(),
+                    ),
+                    (
+                        data.join("respondent.tsv"),
+                        include_str!("resources/survey/respondent.tsv").to_string(),
+                    ),
+                    (
+                        data.join("response.tsv"),
+                        include_str!("resources/survey/response.tsv").to_string(),
+                    ),
+                ],
+            },
+        }
+    }
+
+    /// Download a table table from a URL, then the column, datatype, and other tables it lists.
+    /// Paths in the table table are relative to the project directory, so when the URL ends
+    /// with the path of the table table, the other paths are resolved against the rest of it;
+    /// otherwise they are resolved against the directory of the table table.
+    async fn download(url: &Url) -> Result<Scaffold, String> {
+        let table = download(url).await?;
+        let tsv = Tsv::parse(&table);
+        let table_path = tsv
+            .rows
+            .iter()
+            .find(|row| tsv.get(row, "type") == "table")
+            .map(|row| tsv.get(row, "path"))
+            .ok_or(format!("'{}' has no row with the type 'table'", url))?;
+        let resolve = |path: &str| -> Result<Url, String> {
+            let relative = match url.as_str().strip_suffix(table_path) {
+                Some(base) => Url::parse(base).and_then(|base| base.join(path)),
+                None => {
+                    let dir = Path::new(table_path).parent().unwrap_or(Path::new(""));
+                    match Path::new(path).strip_prefix(dir) {
+                        Ok(relative) => url.join(&relative.to_string_lossy()),
+                        Err(_) => {
+                            return Err(format!(
+                                "Could not find '{}' relative to '{}': \
+                                 use a URL that ends with '{}'",
+                                path, url, table_path
+                            ))
+                        }
+                    }
+                }
+            };
+            relative.map_err(|e| format!("Could not resolve '{}' from '{}': {}", path, url, e))
+        };
+
+        let mut scaffold = Scaffold {
+            table: table.clone(),
+            column: String::new(),
+            datatype: String::new(),
+            data: vec![],
+        };
+        for row in &tsv.rows {
+            let (table_type, path) = (tsv.get(row, "type"), tsv.get(row, "path"));
+            if table_type == "table" || !path.ends_with(".tsv") {
+                continue;
+            }
+            let data_path = project_path(path)?;
+            let data = download(&resolve(path)?).await?;
+            match table_type {
+                "column" => scaffold.column = data,
+                "datatype" => scaffold.datatype = data,
+                _ => scaffold.data.push((data_path, data)),
+            }
+        }
+        if scaffold.column.is_empty() || scaffold.datatype.is_empty() {
+            return Err(format!(
+                "'{}' must have rows with the types 'column' and 'datatype'",
+                url
+            ));
+        }
+        Ok(scaffold)
+    }
+
+    /// Build a scaffold with a table for each CSV or TSV file in the given directory.
+    /// Each file is converted to a TSV file in the data directory, with its column names
+    /// made safe for SQL and its original headers kept as column labels.
+    fn infer(dir: &Path) -> Result<Scaffold, String> {
+        let mut scaffold = Scaffold::default();
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>(),
+            Err(e) => return Err(format!("Could not read '{}': {}", dir.display(), e)),
+        };
+        paths.sort();
+
+        let mut tables = vec![
+            "table".to_string(),
+            "column".to_string(),
+            "datatype".to_string(),
+        ];
+        let mut datatypes = vec![];
+        for path in paths {
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            let delimiter = match extension.as_str() {
+                "csv" => b',',
+                "tsv" | "tab" => b'\t',
+                _ => continue,
+            };
+            let stem = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            let table = to_identifier(stem);
+            if tables.contains(&table) {
+                return Err(format!(
+                    "Could not add '{}' as table '{}': that table already exists",
+                    path.display(),
+                    table
+                ));
+            }
+
+            let mut reader = ReaderBuilder::new()
+                .delimiter(delimiter)
+                .flexible(true)
+                .from_path(&path)
+                .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+            let headers = reader
+                .headers()
+                .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?
+                .clone();
+            let rows = reader
+                .records()
+                .collect::<Result<Vec<StringRecord>, _>>()
+                .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+
+            let mut columns = vec![];
+            for (i, header) in headers.iter().enumerate() {
+                let label = header.trim();
+                let mut column = to_identifier(label);
+                let mut n = 2;
+                while columns.contains(&column) {
+                    column = format!("{}_{}", to_identifier(label), n);
+                    n += 1;
+                }
+                let values = rows
+                    .iter()
+                    .map(|row| row.get(i).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                let nulltype = if values.iter().any(|v| v.is_empty()) {
+                    "empty"
+                } else {
+                    ""
+                };
+                let datatype = infer_datatype(&values);
+                if !datatypes.contains(&datatype) {
+                    datatypes.push(datatype);
+                }
+                // Use the first column as the primary key when its values are distinct.
+                let mut distinct = values.clone();
+                distinct.sort();
+                distinct.dedup();
+                let structure = if i == 0
+                    && !values.is_empty()
+                    && nulltype.is_empty()
+                    && distinct.len() == values.len()
+                {
+                    "primary"
+                } else {
+                    ""
+                };
+                let label = if label == column { "" } else { label };
+                scaffold.column.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t\n",
+                    table, column, label, nulltype, datatype, structure
+                ));
+                columns.push(column);
+            }
+
+            let data_path = Path::new(DATA_DIR).join(format!("{}.tsv", table));
+            let data = rows_to_tsv(&columns, &rows)
+                .map_err(|e| format!("Could not convert '{}': {}", path.display(), e))?;
+            scaffold.table.push_str(&format!(
+                "{}\t{}\t\tconverted from {}\n",
+                table,
+                data_path.display(),
+                path.display()
+            ));
+            scaffold.data.push((data_path, data));
+            tables.push(table);
+        }
+        if tables.len() == 3 {
+            return Err(format!("No CSV or TSV files found in '{}'", dir.display()));
+        }
+
+        if datatypes.contains(&"integer") {
+            scaffold
+                .datatype
+                .push_str("integer\ttrimmed_line\tmatch(/-?\\d+/)\t\t\ta whole number\tINT\t\n");
+        }
+        if datatypes.contains(&"decimal") {
+            scaffold.datatype.push_str(
+                "decimal\ttrimmed_line\tmatch(/-?\\d+(\\.\\d+)?/)\t\t\ta number that may have decimal places\t\t\n",
+            );
+        }
+        Ok(scaffold)
+    }
+}
+
+/// Download the text of a file.
+async fn download(url: &Url) -> Result<String, String> {
+    tracing::info!("Downloading '{}'", url);
+    let response = reqwest::get(url.as_str())
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Could not download '{}': {}", url, e))?;
+    response
+        .text()
+        .await
+        .map_err(|e| format!("Could not download '{}': {}", url, e))
 }
 
-fn create_column_tsv(path: &Path) -> Result<(), Box<dyn error::Error>> {
-    let data = include_str!("resources/column.tsv");
-    fs::write(&path, data).expect("Unable to write file");
+/// Check that a path from a table table stays inside the project directory:
+/// it must be relative, with no `..` components.
+fn project_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "Invalid path '{}': paths must be relative to the project directory \
+             and must not contain '..'",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// Write rows as TSV under the given header, padding or truncating them to its length.
+fn rows_to_tsv(columns: &[String], rows: &[StringRecord]) -> Result<String, String> {
+    let mut writer = WriterBuilder::new().delimiter(b'\t').from_writer(vec![]);
+    writer.write_record(columns).map_err(|e| e.to_string())?;
+    for row in rows {
+        let mut row = row.iter().collect::<Vec<_>>();
+        row.resize(columns.len(), "");
+        writer.write_record(&row).map_err(|e| e.to_string())?;
+    }
+    let data = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(data).map_err(|e| e.to_string())
+}
+
+/// Write the files for deploying this project to the given target,
+/// filled in with the project's name, directory, port, and database.
+fn write_deploy_files(
+    config: &Config,
+    target: DeployTarget,
+    mode: InitMode,
+    report: &mut Vec<String>,
+) -> Result<(), String> {
+    let project = env::current_dir().map_err(|e| e.to_string())?;
+    let nanobot = env::current_exe().map_err(|e| e.to_string())?;
+    let name = project
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(to_identifier)
+        .unwrap_or(String::from("nanobot"));
+    let fill = |template: &str, path: &str| {
+        let database = if config.is_postgres() {
+            String::new()
+        } else {
+            config.connection.clone()
+        };
+        template
+            .replace("{name}", &name)
+            .replace("{path}", path)
+            .replace("{project}", &project.display().to_string())
+            .replace("{nanobot}", &nanobot.display().to_string())
+            .replace("{port}", &config.port.to_string())
+            .replace("{database}", &database)
+    };
+
+    let files = match target {
+        DeployTarget::Systemd => vec![(
+            "deploy/nanobot.service",
+            include_str!("resources/deploy/nanobot.service"),
+        )],
+        DeployTarget::Docker => vec![
+            ("Dockerfile", include_str!("resources/deploy/Dockerfile")),
+            (
+                "docker-compose.yml",
+                include_str!("resources/deploy/docker-compose.yml"),
+            ),
+            (
+                ".dockerignore",
+                include_str!("resources/deploy/dockerignore"),
+            ),
+        ],
+        DeployTarget::Cgi => vec![(
+            "deploy/nanobot.cgi",
+            include_str!("resources/deploy/nanobot.cgi"),
+        )],
+    };
+    for (path, template) in files {
+        let path = Path::new(path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(format!("Could not create '{}': {}", dir.display(), e));
+            }
+        }
+        write_file(
+            path,
+            &fill(template, &path.display().to_string()),
+            mode,
+            report,
+        )?;
+    }
+
+    // The CGI wrapper must be executable by the web server.
+    #[cfg(unix)]
+    if target == DeployTarget::Cgi {
+        use std::os::unix::fs::PermissionsExt;
+        let path = Path::new("deploy/nanobot.cgi");
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o755)) {
+            return Err(format!(
+                "Could not make '{}' executable: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
     Ok(())
 }
 
-fn create_datatype_tsv(path: &Path) -> Result<(), Box<dyn error::Error>> {
-    let data = include_str!("resources/datatype.tsv");
-    fs::write(&path, data).expect("Unable to write file");
+/// Convert a file name or column header into a lowercase name that is safe to use in SQL.
+fn to_identifier(name: &str) -> String {
+    let mut identifier = String::new();
+    for c in name.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            identifier.push(c);
+        } else if !identifier.is_empty() && !identifier.ends_with('_') {
+            identifier.push('_');
+        }
+    }
+    let identifier = identifier.trim_end_matches('_');
+    match identifier.chars().next() {
+        None => "column".to_string(),
+        Some(c) if c.is_ascii_digit() => format!("_{}", identifier),
+        _ => identifier.to_string(),
+    }
+}
+
+/// Choose the most specific datatype that all of the non-empty values match.
+fn infer_datatype(values: &[&str]) -> &'static str {
+    let values = values
+        .iter()
+        .copied()
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        "text"
+    } else if values.iter().all(|v| is_integer(v)) {
+        "integer"
+    } else if values.iter().all(|v| is_decimal(v)) {
+        "decimal"
+    } else if values
+        .iter()
+        .all(|v| v.chars().all(|c| c.is_alphanumeric() || c == '_'))
+    {
+        "word"
+    } else if values.iter().all(|v| !v.contains('\n') && v.trim() == *v) {
+        "trimmed_line"
+    } else if values.iter().all(|v| !v.contains('\n')) {
+        "line"
+    } else {
+        "text"
+    }
+}
+
+fn is_integer(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_decimal(value: &str) -> bool {
+    match value.split_once('.') {
+        Some((whole, fraction)) => {
+            is_integer(whole)
+                && !fraction.is_empty()
+                && fraction.chars().all(|c| c.is_ascii_digit())
+        }
+        None => is_integer(value),
+    }
+}
+
+/// Connect to a PostgreSQL database and check that the current user can create tables in
+/// the current schema, returning whether the VALVE tables already exist there.
+/// PostgreSQL databases are not created by `init`: they must be created beforehand.
+async fn check_postgres(connection: &str) -> Result<bool, String> {
+    let database = redact_password(connection);
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(connection)
+        .await
+        .map_err(|e| {
+            format!(
+                "Could not connect to PostgreSQL database '{}': {}. \
+                 Check that the server is running and the database has been created.",
+                database, e
+            )
+        })?;
+    let row = sqlx::query(
+        r#"SELECT current_schema()::TEXT AS "schema",
+             has_schema_privilege(current_schema(), 'CREATE') AS "create",
+             EXISTS (
+               SELECT 1 FROM information_schema.tables
+               WHERE table_schema = current_schema() AND table_name = 'table'
+             ) AS "existed""#,
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| format!("Could not query PostgreSQL database '{}': {}", database, e))?;
+    pool.close().await;
+
+    let schema: Option<String> = row.try_get("schema").map_err(|e| e.to_string())?;
+    let schema = match schema {
+        Some(schema) => schema,
+        None => {
+            return Err(format!(
+                "PostgreSQL database '{}' has no current schema: check the search_path",
+                database
+            ))
+        }
+    };
+    let create: Option<bool> = row.try_get("create").map_err(|e| e.to_string())?;
+    if create != Some(true) {
+        return Err(format!(
+            "The PostgreSQL user for '{}' does not have permission to create tables \
+             in schema '{}'. Grant it with: GRANT CREATE ON SCHEMA {} TO <user>;",
+            database, schema, schema
+        ));
+    }
+    row.try_get("existed").map_err(|e| e.to_string())
+}
+
+/// Write a file unless it already exists (or the mode is Force), recording what was done.
+fn write_file(
+    path: &Path,
+    data: &str,
+    mode: InitMode,
+    report: &mut Vec<String>,
+) -> Result<(), String> {
+    let action = match (path.exists(), mode) {
+        (false, _) => "Created",
+        (true, InitMode::Force) => "Overwrote",
+        (true, _) => {
+            report.push(format!("Skipped '{}': already exists", path.display()));
+            return Ok(());
+        }
+    };
+    if let Err(e) = fs::write(&path, data) {
+        return Err(format!("Could not create '{}': {}", path.display(), e));
+    }
+    tracing::info!("{} '{}'", action, path.display());
+    report.push(format!("{} '{}'", action, path.display()));
     Ok(())
 }
 
-pub async fn init(config: &mut Config) -> Result<String, String> {
+/// Initialize a Nanobot project in the current directory.
+pub async fn init(
+    config: &mut Config,
+    mode: InitMode,
+    source: &InitSource,
+    deploy: &[DeployTarget],
+) -> Result<String, NanobotError> {
+    let mut report = vec![];
+
     // Create nanobot.toml if it does not exist.
-    let path = Path::new("nanobot.toml");
-    if !path.exists() {
-        // Create default config nanobot.toml
-        let path = Path::new("nanobot.toml");
-        let toml = to_toml(config);
-        match toml.write_non_defaults(&path) {
-            Err(_) => return Err(format!("Could not create '{}'", path.display())),
-            _ => (),
-        };
-        tracing::info!("Created config file '{}'", path.display());
+    // When forced, replace it with the defaults, keeping the database and schema paths.
+    let path = Path::new(&config.config_path);
+    let toml = match mode {
+        InitMode::Force => {
+            let mut toml = TomlConfig::default();
+            toml.database = to_toml(config).database;
+            toml.valve = Some(ValveTomlConfig {
+                path: Some(config.valve_path.clone()),
+            });
+            toml
+        }
+        _ => to_toml(config),
+    };
+    match (path.exists(), mode) {
+        (true, InitMode::Force) | (false, _) => {
+            let action = if path.exists() {
+                "Overwrote"
+            } else {
+                "Created"
+            };
+            // Follow the configured options with the other options, commented out.
+            let contents = format!(
+                "{}\n{}",
+                toml.non_defaults_to_string()?,
+                include_str!("resources/nanobot.toml")
+            );
+            if let Err(e) = fs::write(&path, contents) {
+                return Err(format!("Could not create '{}': {}", path.display(), e).into());
+            }
+            tracing::info!("{} config file '{}'", action, path.display());
+            report.push(format!("{} '{}'", action, path.display()));
+        }
+        (true, _) => report.push(format!("Skipped '{}': already exists", path.display())),
+    };
+
+    // Create the conventional project directories, if they don't exist.
+    for dir in PROJECT_DIRS {
+        let path = Path::new(dir);
+        if !path.exists() {
+            if let Err(e) = fs::create_dir_all(&path) {
+                return Err(format!("Could not create '{}': {}", path.display(), e).into());
+            }
+            tracing::info!("Created '{}' directory", path.display());
+            report.push(format!("Created '{}'", path.display()));
+        }
     }
 
     // Create files for the basic VALVE schema tables, if they don't exist
@@ -133,62 +797,135 @@ pub async fn init(config: &mut Config) -> Result<String, String> {
     let path = Path::new(valve_path).parent().unwrap();
     if !path.exists() {
         match fs::create_dir_all(&path) {
-            Err(_x) => return Err(format!("Could not create '{}'", path.display())),
+            Err(_x) => return Err(format!("Could not create '{}'", path.display()).into()),
             Ok(_x) => {}
         };
         tracing::info!("Created '{}' directory", path.display());
     }
 
-    let path = Path::new(valve_path);
-    if !path.exists() {
-        match create_table_tsv(&path) {
-            Err(_x) => return Err(format!("Could not create '{}'", path.display())),
-            Ok(_x) => {}
-        };
-        tracing::info!("Created '{}' file", path.display());
-    }
-
-    let path = Path::new(valve_path).parent().unwrap().join("column.tsv");
-    if !path.exists() {
-        match create_column_tsv(&path.as_path()) {
-            Err(_x) => return Err(format!("Could not create '{}'", path.display())),
-            Ok(_x) => {}
-        };
-        tracing::info!("Created '{}' file", path.display());
+    let scaffold = match source {
+        InitSource::Empty => Scaffold::default(),
+        InitSource::Demo => Scaffold::demo(),
+        InitSource::Directory(dir) => Scaffold::infer(dir)?,
+        InitSource::Template(template) => Scaffold::template(*template),
+        InitSource::Url(url) => Scaffold::download(url).await?,
+    };
+    // Write the column and datatype tables where the table table says they are.
+    let table = Tsv::parse(&scaffold.table);
+    let meta_path = |table_type: &str| match table
+        .rows
+        .iter()
+        .find(|row| table.get(row, "type") == table_type)
+    {
+        Some(row) => project_path(table.get(row, "path")),
+        None => Ok(Path::new(valve_path)
+            .parent()
+            .unwrap()
+            .join(format!("{}.tsv", table_type))),
+    };
+    let mut files = vec![
+        (PathBuf::from(valve_path), &scaffold.table),
+        (meta_path("column")?, &scaffold.column),
+        (meta_path("datatype")?, &scaffold.datatype),
+    ];
+    files.extend(
+        scaffold
+            .data
+            .iter()
+            .map(|(path, data)| (path.clone(), data)),
+    );
+    for (path, data) in files {
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                if let Err(e) = fs::create_dir_all(&dir) {
+                    return Err(format!("Could not create '{}': {}", dir.display(), e).into());
+                }
+                tracing::info!("Created '{}' directory", dir.display());
+            }
+        }
+        write_file(&path, data, mode, &mut report)?;
     }
 
-    let path = Path::new(valve_path).parent().unwrap().join("datatype.tsv");
-    if !path.exists() {
-        match create_datatype_tsv(&path.as_path()) {
-            Err(_x) => return Err(format!("Could not create '{}'", path.display())),
-            Ok(_x) => {}
-        };
-        tracing::info!("Created '{}' file", path.display());
+    for target in deploy {
+        write_deploy_files(config, *target, mode, &mut report)?;
     }
 
-    //create database file
+    // Create the database, or check that we can use an existing PostgreSQL database.
     let database = config.connection.to_owned();
-    let path = Path::new(&database);
-    if !path.exists() {
-        match File::create(&database) {
-            Err(_x) => return Err(String::from("Couldn't create database")),
+    let database_existed = if config.is_postgres() {
+        let database = redact_password(&database);
+        let existed = check_postgres(&config.connection).await?;
+        if existed && mode == InitMode::Force {
+            report.push(format!("Recreated tables in '{}'", database));
+        }
+        existed
+    } else {
+        let path = Path::new(&database);
+        let existed = path.exists();
+        if existed && mode == InitMode::Force {
+            for suffix in ["", "-shm", "-wal"] {
+                let file = format!("{}{}", database, suffix);
+                if Path::new(&file).exists() {
+                    if let Err(e) = fs::remove_file(&file) {
+                        return Err(format!("Could not remove '{}': {}", file, e).into());
+                    }
+                }
+            }
+            report.push(format!("Recreated database '{}'", database));
+        }
+        if !path.exists() {
+            match File::create(&database) {
+                Err(_x) => return Err(String::from("Couldn't create database").into()),
+                Ok(_x) => {}
+            }
+            if !existed {
+                report.push(format!("Created database '{}'", database));
+            }
+        }
+
+        //add database file to .gitignore
+        match add_to_gitignore(format!("{}*", &database).as_str()) {
+            Err(x) => return Err(x.into()),
             Ok(_x) => {}
         }
+        existed
+    };
+
+    //add directory of job records to .gitignore
+    match add_to_gitignore(&format!(
+        "{}/",
+        JOB_DIR.split('/').next().unwrap_or(JOB_DIR)
+    )) {
+        Err(x) => return Err(x.into()),
+        Ok(_x) => {}
     }
 
-    //add database file to .gitignore
-    match add_to_gitignore(format!("{}*", &database).as_str()) {
-        Err(x) => return Err(x),
+    //add the history of `nanobot shell` to .gitignore
+    match add_to_gitignore(HISTORY_FILE) {
+        Err(x) => return Err(x.into()),
         Ok(_x) => {}
     }
 
+    // Check the schema first, since VALVE errors do not say how to fix the problem.
+    let problems = check_schema(valve_path)?;
+    if !problems.is_empty() {
+        return Err(format!(
+            "Found {} problem(s) in the VALVE schema:\n{}",
+            problems.len(),
+            problems.join("\n")
+        )
+        .into());
+    }
+
     (config.valve, config.pool) = {
         let mut valve = Valve::build(&valve_path, &config.connection)
             .await
-            .expect(&format!(
-                "VALVE failed to load configuration for '{}'",
-                valve_path
-            ));
+            .map_err(|e| {
+                format!(
+                    "VALVE failed to load configuration for '{}': {:?}",
+                    valve_path, e
+                )
+            })?;
         if config.logging_level == LoggingLevel::DEBUG {
             valve.set_verbose(true);
         }
@@ -200,24 +937,97 @@ pub async fn init(config: &mut Config) -> Result<String, String> {
     match &config.valve {
         None => unreachable!("Valve is not initialized."),
         Some(valve) => {
-            if config.create_only {
+            if mode == InitMode::Merge && database_existed {
+                // Keep the existing data, and only create the tables that are missing.
+                if let Err(e) = valve.create_all_tables().await {
+                    return Err(
+                        format!("VALVE error while creating from {}: {:?}", valve_path, e).into(),
+                    );
+                }
+                report.push(format!(
+                    "Created missing tables in '{}'",
+                    redact_password(&database)
+                ));
+            } else if config.create_only {
                 if let Err(e) = valve.create_all_tables().await {
-                    return Err(format!(
-                        "VALVE error while creating from {}: {:?}",
-                        valve_path, e
-                    ));
+                    return Err(
+                        format!("VALVE error while creating from {}: {:?}", valve_path, e).into(),
+                    );
                 }
             } else {
                 if let Err(e) = valve.load_all_tables(true).await {
-                    return Err(format!(
-                        "VALVE error while loading from {}: {:?}",
-                        valve_path, e
-                    ));
+                    return Err(
+                        format!("VALVE error while loading from {}: {:?}", valve_path, e).into(),
+                    );
                 }
             }
         }
     };
 
-    tracing::info!("Initialized '{}' using '{}'", database, valve_path);
-    Ok(String::from("Initialized a Nanobot project"))
+    tracing::info!(
+        "Initialized '{}' using '{}'",
+        redact_password(&database),
+        valve_path
+    );
+    match mode {
+        InitMode::Default if source == &InitSource::Demo => Ok(String::from(
+            "Initialized a Nanobot demo project: run `nanobot serve` and open the 'penguin' table",
+        )),
+        InitMode::Default => Ok(String::from("Initialized a Nanobot project")),
+        _ => {
+            report.push(String::from("Initialized a Nanobot project"));
+            Ok(report.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_identifier() {
+        assert_eq!(to_identifier("Sample Number"), "sample_number");
+        assert_eq!(to_identifier("Culmen Length (mm)"), "culmen_length_mm");
+        assert_eq!(to_identifier("2023 results"), "_2023_results");
+        assert_eq!(to_identifier("???"), "column");
+    }
+
+    #[test]
+    fn test_infer_datatype() {
+        assert_eq!(infer_datatype(&["1", "-2", ""]), "integer");
+        assert_eq!(infer_datatype(&["1", "2.5"]), "decimal");
+        assert_eq!(infer_datatype(&["MALE", "FEMALE"]), "word");
+        assert_eq!(
+            infer_datatype(&["Adelie Penguin", "Gentoo"]),
+            "trimmed_line"
+        );
+        assert_eq!(infer_datatype(&[" padded"]), "line");
+        assert_eq!(infer_datatype(&["two\nlines"]), "text");
+        assert_eq!(infer_datatype(&["", ""]), "text");
+    }
+
+    #[test]
+    fn test_project_path() {
+        assert_eq!(
+            project_path("src/data/penguin.tsv"),
+            Ok(PathBuf::from("src/data/penguin.tsv"))
+        );
+        assert_eq!(
+            project_path("./src/schema/column.tsv"),
+            Ok(PathBuf::from("./src/schema/column.tsv"))
+        );
+        assert!(project_path("/etc/passwd").is_err());
+        assert!(project_path("../outside.tsv").is_err());
+        assert!(project_path("src/../../outside.tsv").is_err());
+        assert!(project_path("").is_err());
+    }
+
+    // Nanobot runs on tokio, where a blocking download would panic rather than fail.
+    #[tokio::test]
+    async fn test_download_error() {
+        let url = Url::parse("http://127.0.0.1:1/src/schema/table.tsv").unwrap();
+        let error = download(&url).await.unwrap_err();
+        assert!(error.starts_with("Could not download 'http://127.0.0.1:1/src/schema/table.tsv'"));
+    }
 }
@@ -0,0 +1,365 @@
+use crate::action::{self, ActionValues, CommandResult, OutputStream};
+use crate::config::ActionConfig;
+use crate::serve::{ProgressEvent, EVENT_CHANNEL_CAPACITY};
+use chrono::prelude::Local;
+use indexmap::map::IndexMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fs, thread};
+use tokio::sync::broadcast;
+
+/// The directory where job records are persisted, relative to the project directory.
+pub const JOB_DIR: &str = ".nanobot/jobs";
+
+lazy_static! {
+    /// The queue for each job directory, shared by the servers that this process starts.
+    static ref QUEUES: Mutex<HashMap<PathBuf, Arc<JobQueue>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A record of a single run of an action, persisted as JSON so that it can be looked up after
+/// the server restarts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub action: String,
+    pub label: String,
+    pub status: JobStatus,
+    pub submitted: String,
+    pub started: Option<String>,
+    pub finished: Option<String>,
+    pub results: Vec<CommandResult>,
+}
+
+#[derive(Debug)]
+struct Task {
+    id: u64,
+    action: ActionConfig,
+    values: ActionValues,
+}
+
+/// An in-process queue that runs submitted actions one at a time on a worker thread.
+#[derive(Debug)]
+pub struct JobQueue {
+    jobs: Arc<Mutex<IndexMap<u64, Job>>>,
+    sender: Mutex<mpsc::Sender<Task>>,
+    next_id: Mutex<u64>,
+    dir: PathBuf,
+    progress: broadcast::Sender<ProgressEvent>,
+}
+
+impl JobQueue {
+    /// Create a queue that persists its jobs to the given directory, loading any jobs that were
+    /// persisted there previously, and start its worker thread. Progress of running jobs is sent
+    /// to `progress` under the operation name `job:ID`.
+    pub fn new(dir: &Path, progress: broadcast::Sender<ProgressEvent>) -> JobQueue {
+        let jobs = Arc::new(Mutex::new(load_jobs(dir)));
+        let next_id = jobs.lock().unwrap().keys().max().unwrap_or(&0) + 1;
+        let (sender, receiver) = mpsc::channel::<Task>();
+
+        let worker_jobs = jobs.clone();
+        let worker_dir = dir.to_path_buf();
+        let worker_progress = progress.clone();
+        thread::spawn(move || {
+            for task in receiver {
+                run_task(&task, &worker_jobs, &worker_dir, &worker_progress);
+            }
+        });
+
+        JobQueue {
+            jobs,
+            sender: Mutex::new(sender),
+            next_id: Mutex::new(next_id),
+            dir: dir.to_path_buf(),
+            progress,
+        }
+    }
+
+    /// Get the queue for the given directory, creating it the first time.
+    /// There is one queue per directory in a process, so that restarting the server,
+    /// e.g. when the configuration changes, keeps the jobs that are running,
+    /// rather than loading them again and marking them as failed.
+    pub fn shared(dir: &Path) -> Arc<JobQueue> {
+        let mut queues = QUEUES.lock().unwrap_or_else(|e| e.into_inner());
+        queues
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let (progress, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+                Arc::new(JobQueue::new(dir, progress))
+            })
+            .clone()
+    }
+
+    /// The channel that the progress of the jobs is sent to.
+    pub fn progress(&self) -> broadcast::Sender<ProgressEvent> {
+        self.progress.clone()
+    }
+
+    /// Add a run of the named action to the queue, returning the ID of the new job.
+    pub fn submit(
+        &self,
+        name: &str,
+        action: &ActionConfig,
+        values: ActionValues,
+    ) -> Result<u64, String> {
+        let id = {
+            let mut next_id = self.next_id.lock().map_err(|e| e.to_string())?;
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+        let job = Job {
+            id,
+            action: name.to_string(),
+            label: action.label.to_string(),
+            status: JobStatus::Queued,
+            submitted: now(),
+            started: None,
+            finished: None,
+            results: vec![],
+        };
+        save_job(&self.dir, &job);
+        self.jobs.lock().map_err(|e| e.to_string())?.insert(id, job);
+
+        let task = Task {
+            id,
+            action: action.clone(),
+            values,
+        };
+        self.sender
+            .lock()
+            .map_err(|e| e.to_string())?
+            .send(task)
+            .map_err(|e| format!("Could not queue job {}: {}", id, e))?;
+        tracing::info!("Queued job {} for action '{}'", id, name);
+        Ok(id)
+    }
+
+    /// Get a copy of the job with the given ID, if there is one.
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().ok()?.get(&id).cloned()
+    }
+}
+
+fn now() -> String {
+    Local::now().to_rfc3339()
+}
+
+fn run_task(
+    task: &Task,
+    jobs: &Mutex<IndexMap<u64, Job>>,
+    dir: &Path,
+    progress: &broadcast::Sender<ProgressEvent>,
+) {
+    let operation = format!("job:{}", task.id);
+    let total = task.action.commands.len();
+    update_job(jobs, dir, task.id, |job| {
+        job.status = JobStatus::Running;
+        job.started = Some(now());
+    });
+
+    // Keep the output of the running command in memory so that it can be shown while the job
+    // runs; the complete results are persisted when the job finishes.
+    let results = action::run_commands_streaming(
+        &task.action,
+        &task.values,
+        |step, command| {
+            let _ = progress.send(ProgressEvent::new(&operation, step, total, command));
+            if let Ok(mut jobs) = jobs.lock() {
+                if let Some(job) = jobs.get_mut(&task.id) {
+                    job.results.push(CommandResult {
+                        command: command.to_string(),
+                        ..Default::default()
+                    });
+                }
+            }
+        },
+        |step, output| {
+            let _ = progress.send(ProgressEvent::output(&operation, step, total, output));
+            if let Ok(mut jobs) = jobs.lock() {
+                if let Some(result) = jobs.get_mut(&task.id).and_then(|j| j.results.last_mut()) {
+                    let text = match output.stream {
+                        OutputStream::Stdout => &mut result.stdout,
+                        OutputStream::Stderr => &mut result.stderr,
+                    };
+                    text.push_str(&output.line);
+                    text.push('\n');
+                }
+            }
+        },
+    );
+
+    let succeeded = results.iter().all(|r| r.status == 0);
+    let message = match results.last() {
+        Some(result) if !succeeded => format!("Failed with status {}", result.status),
+        _ => "Done".to_string(),
+    };
+    update_job(jobs, dir, task.id, |job| {
+        job.status = if succeeded {
+            JobStatus::Succeeded
+        } else {
+            JobStatus::Failed
+        };
+        job.finished = Some(now());
+        job.results = results.clone();
+    });
+    let _ = progress.send(ProgressEvent::new(&operation, total, total, &message));
+    tracing::info!("Finished job {}: {}", task.id, message);
+}
+
+fn update_job<F>(jobs: &Mutex<IndexMap<u64, Job>>, dir: &Path, id: u64, update: F)
+where
+    F: FnOnce(&mut Job),
+{
+    let mut jobs = match jobs.lock() {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            tracing::error!("Could not update job {}: {}", id, e);
+            return;
+        }
+    };
+    if let Some(job) = jobs.get_mut(&id) {
+        update(job);
+        save_job(dir, job);
+    }
+}
+
+fn save_job(dir: &Path, job: &Job) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        tracing::warn!("Could not create job directory '{}': {}", dir.display(), e);
+        return;
+    }
+    let path = dir.join(format!("{}.json", job.id));
+    match serde_json::to_string_pretty(job) {
+        Ok(json) => {
+            if let Err(e) = fs::write(&path, json) {
+                tracing::warn!("Could not write job file '{}': {}", path.display(), e);
+            }
+        }
+        Err(e) => tracing::warn!("Could not serialize job {}: {}", job.id, e),
+    }
+}
+
+/// Load the jobs persisted in the given directory, ordered by ID.
+/// Jobs that were queued or running when the server stopped are marked as failed.
+fn load_jobs(dir: &Path) -> IndexMap<u64, Job> {
+    let mut jobs = vec![];
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let job = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str::<Job>(&s).map_err(|e| e.to_string()));
+            match job {
+                Ok(mut job) => {
+                    if job.status == JobStatus::Queued || job.status == JobStatus::Running {
+                        job.status = JobStatus::Failed;
+                        save_job(dir, &job);
+                    }
+                    jobs.push(job);
+                }
+                Err(e) => tracing::warn!("Could not load job file '{}': {}", path.display(), e),
+            }
+        }
+    }
+    jobs.sort_by_key(|job| job.id);
+    jobs.into_iter().map(|job| (job.id, job)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ActionConfig;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nanobot-jobs-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn echo_action() -> ActionConfig {
+        ActionConfig {
+            label: "Echo".to_string(),
+            commands: vec![vec!["echo".to_string(), "hello".to_string()]],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_run_and_persist_jobs() {
+        let dir = test_dir("run");
+        let (progress, mut receiver) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let queue = JobQueue::new(&dir, progress);
+        let id = queue
+            .submit("echo", &echo_action(), ActionValues::new())
+            .unwrap();
+        assert_eq!(id, 1);
+        loop {
+            let event = receiver.blocking_recv().unwrap();
+            if event.operation == "job:1" && event.done {
+                break;
+            }
+        }
+        let job = queue.get(id).unwrap();
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.results[0].stdout, "hello\n");
+
+        // A new queue loads the finished job and continues its numbering.
+        let (progress, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let queue = JobQueue::new(&dir, progress);
+        assert_eq!(queue.get(1).unwrap().status, JobStatus::Succeeded);
+        let id = queue
+            .submit("echo", &echo_action(), ActionValues::new())
+            .unwrap();
+        assert_eq!(id, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_interrupted_jobs() {
+        let dir = test_dir("load");
+        for (id, status) in [(2, JobStatus::Running), (1, JobStatus::Succeeded)] {
+            let job = Job {
+                id,
+                action: "echo".to_string(),
+                label: "Echo".to_string(),
+                status,
+                submitted: now(),
+                started: None,
+                finished: None,
+                results: vec![],
+            };
+            save_job(&dir, &job);
+        }
+        let jobs = load_jobs(&dir);
+        assert_eq!(jobs.keys().copied().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(jobs[&1].status, JobStatus::Succeeded);
+        assert_eq!(jobs[&2].status, JobStatus::Failed);
+        // The change is saved.
+        assert_eq!(load_jobs(&dir)[&2].status, JobStatus::Failed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_queue() {
+        let dir = test_dir("shared");
+        let queue = JobQueue::shared(&dir);
+        assert!(Arc::ptr_eq(&queue, &JobQueue::shared(&dir)));
+        assert!(!Arc::ptr_eq(&queue, &JobQueue::shared(&test_dir("other"))));
+    }
+}
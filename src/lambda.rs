@@ -0,0 +1,57 @@
+use crate::config::{build_valve, Config};
+use crate::error::NanobotError;
+use crate::serve::build_router;
+use axum::Router;
+use lambda_http::{service_fn, tower::ServiceExt, Body, Error, Request, Response};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// Serve the HTTP events of an AWS Lambda function, e.g. from API Gateway or a function URL,
+/// with the same routes as the server.
+/// VALVE is built for the first request rather than at startup,
+/// so a cold start that only runs the Lambda init phase stays fast,
+/// and the router is kept for the later requests to the same instance.
+pub async fn run(config: Config) -> Result<(), NanobotError> {
+    let config = Arc::new(config);
+    let router: Arc<OnceCell<Router>> = Arc::new(OnceCell::new());
+    lambda_http::run(service_fn(move |request: Request| {
+        let config = config.clone();
+        let router = router.clone();
+        async move {
+            let router = router
+                .get_or_try_init(|| async {
+                    let mut config = Config::clone(&config);
+                    build_valve(&mut config).await?;
+                    build_router(config).await
+                })
+                .await;
+            match router {
+                Ok(router) => handle(router.clone(), request).await,
+                Err(e) => {
+                    tracing::error!("Could not start Nanobot: {}", e);
+                    Ok(Response::builder()
+                        .status(500)
+                        .header("content-type", "text/plain")
+                        .body(Body::Text(e.to_string()))?)
+                }
+            }
+        }
+    }))
+    .await
+    .map_err(|e| NanobotError::GeneralError(format!("Lambda runtime error: {}", e)))
+}
+
+/// Convert the Lambda request for axum, run it through the router,
+/// and convert the response back, buffering its body.
+async fn handle(router: Router, request: Request) -> Result<Response<Body>, Error> {
+    let (parts, body) = request.into_parts();
+    let request = axum::http::Request::from_parts(parts, hyper::Body::from(body.to_vec()));
+    let response = router.oneshot(request).await?;
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let body = match String::from_utf8(body.to_vec()) {
+        Ok(text) => Body::Text(text),
+        Err(e) => Body::Binary(e.into_bytes()),
+    };
+    Ok(Response::from_parts(parts, body))
+}
@@ -1,5 +1,6 @@
 use indexmap::IndexMap;
 use ontodev_hiccup::hiccup;
+use ontodev_sqlrest::{Filter, Select};
 use serde_json::{from_str, json, Map, Value};
 use sqlx::any::{AnyPool, AnyRow};
 use sqlx::Row;
@@ -26,6 +27,7 @@ pub enum Error {
     SerdeError(SerdeError),
     SQLError(sqlx::Error),
     LDTabError(LDTabError),
+    SelectError(String),
 }
 
 impl From<sqlx::Error> for Error {
@@ -399,9 +401,11 @@ fn ldtab_iri_2_hiccup(
     property: &str,
     value: &Value,
     iri_2_label: &HashMap<String, String>,
-) -> Value {
+) -> Result<Value, Error> {
     //get object
-    let entity = value["object"].as_str().unwrap();
+    let entity = value["object"].as_str().ok_or_else(|| {
+        LDTabError::DataFormatViolation(format!("Expected an IRI as the object of: {}", value))
+    })?;
 
     //get label
     let label = match iri_2_label.get(entity) {
@@ -409,7 +413,7 @@ fn ldtab_iri_2_hiccup(
         None => String::from(entity),
     };
     //hiccup-style encoding
-    json!(["a", {"property" : property, "resource" : value["object"]}, encode_iri(&label) ])
+    Ok(json!(["a", {"property" : property, "resource" : value["object"]}, encode_iri(&label) ]))
 }
 
 /// Given a property, an LDTab value with type _JSON,
@@ -455,7 +459,7 @@ fn ldtab_datatype_2_hiccup(datatype: &str) -> Value {
 fn ldtab_annotation_2_hiccup(
     annotation: &Map<String, Value>,
     iri_2_label: &HashMap<String, String>,
-) -> Value {
+) -> Result<Value, Error> {
     let mut outer_list = Vec::new();
     outer_list.push(json!("ul"));
 
@@ -483,7 +487,8 @@ fn ldtab_annotation_2_hiccup(
                     match datatype {
                         Value::String(x) => match x.as_str() {
                             "_IRI" => {
-                                inner_list_element.push(ldtab_iri_2_hiccup(key, v, iri_2_label));
+                                let link = ldtab_iri_2_hiccup(key, v, iri_2_label)?;
+                                inner_list_element.push(link);
                             }
                             "_JSON" => {} //TODO nested annotations
                             _ => {
@@ -501,7 +506,7 @@ fn ldtab_annotation_2_hiccup(
         outer_list_element.push(Value::Array(inner_list));
         outer_list.push(Value::Array(outer_list_element));
     }
-    Value::Array(outer_list)
+    Ok(Value::Array(outer_list))
 }
 
 /// Given a property, a value, and a map from CURIEs/IRIs to labels
@@ -526,7 +531,7 @@ fn ldtab_value_2_hiccup(
     match datatype {
         Value::String(x) => match x.as_str() {
             "_IRI" => {
-                list_element.push(ldtab_iri_2_hiccup(property, value, iri_2_label));
+                list_element.push(ldtab_iri_2_hiccup(property, value, iri_2_label)?);
             }
             "_JSON" => {
                 list_element.push(ldtab_json_2_hiccup(
@@ -551,7 +556,7 @@ fn ldtab_value_2_hiccup(
     };
 
     match annotation {
-        Value::Object(x) => list_element.push(ldtab_annotation_2_hiccup(x, iri_2_label)),
+        Value::Object(x) => list_element.push(ldtab_annotation_2_hiccup(x, iri_2_label)?),
         _ => {} //there is no annotation -- so do nothing
     }
 
@@ -857,6 +862,92 @@ pub async fn get_subject_map(subject: &str, table: &str, pool: &AnyPool) -> Resu
     Ok(Value::Object(json_map))
 }
 
+// ################################################
+// ############### statements #####################
+// ################################################
+
+/// Given an LDTab table and optional subject, predicate, and object,
+/// return a Select for the statements that match all of the given ones,
+/// leaving out retracted statements.
+/// The object is compared with the object column as LDTab stores it,
+/// e.g. "gill" for a literal or "obo:ZFA_0000272" for an IRI.
+///
+/// # Examples
+///
+/// statement_select("statement", Some("obo:ZFA_0000354"), Some("rdfs:label"), None)
+/// selects the rows of the statement table for the rdfs:label of obo:ZFA_0000354.
+pub fn statement_select(
+    table: &str,
+    subject: Option<&str>,
+    predicate: Option<&str>,
+    object: Option<&str>,
+) -> Result<Select, Error> {
+    let filter = |column: &str, value: &str| {
+        Filter::new(column, "eq", json!(format!("\"{}\"", value))).map_err(Error::SelectError)
+    };
+    let mut filters = vec![Filter::new("retraction", "eq", json!(0)).map_err(Error::SelectError)?];
+    for (column, value) in [
+        ("subject", subject),
+        ("predicate", predicate),
+        ("object", object),
+    ] {
+        if let Some(value) = value {
+            filters.push(filter(column, value)?);
+        }
+    }
+    let mut select = Select::new(format!("\"{}\"", table));
+    select
+        .filter(filters)
+        .order_by(vec!["subject", "predicate", "object"]);
+    Ok(select)
+}
+
+/// Given a Select for an LDTab table, e.g. from statement_select(), and an LDTab database,
+/// return the statements as JSON objects, with the object and annotation as JSON values
+/// rather than the strings that LDTab stores.
+///
+/// # Examples
+///
+/// For the rdfs:label of obo:ZFA_0000354, return
+///
+/// [{"subject":"obo:ZFA_0000354",
+///   "predicate":"rdfs:label",
+///   "object":"gill",
+///   "datatype":"xsd:string",
+///   "graph":"graph",
+///   "assertion":"1"}]
+pub fn get_statements(select: &Select, pool: &AnyPool) -> Result<Vec<Value>, Error> {
+    let rows = select
+        .fetch_rows_as_json(pool, &HashMap::new())
+        .map_err(Error::SelectError)?;
+    Ok(rows.iter().map(statement_2_json_shape).collect())
+}
+
+/// Given a statement row as JSON, return its JSON shape:
+/// the subject and predicate, the object and annotation parsed as JSON, the datatype,
+/// the graph, and the transaction, which older versions of LDTab call the assertion.
+fn statement_2_json_shape(row: &Map<String, Value>) -> Value {
+    let get = |column: &str| match row.get(column) {
+        Some(Value::String(s)) => s.to_string(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    };
+    let mut shape = match object_2_json_shape(&get("object"), &get("datatype"), &get("annotation"))
+    {
+        Value::Object(shape) => shape,
+        _ => Map::new(),
+    };
+    shape.insert("subject".to_string(), json!(get("subject")));
+    shape.insert("predicate".to_string(), json!(get("predicate")));
+    shape.insert("graph".to_string(), json!(get("graph")));
+    for column in ["transaction", "assertion"] {
+        if let Some(value) = row.get(column) {
+            shape.insert(column.to_string(), value.clone());
+        }
+    }
+    Value::Object(shape)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -940,6 +1031,42 @@ mod tests {
         assert_eq!(prefix_map, expected_prefix_map);
     }
 
+    #[tokio::test]
+    async fn test_get_statements() {
+        let connection = "src/resources/test_data/zfa_excerpt.db";
+        let connection_string = format!("sqlite://{}?mode=rwc", connection);
+        let pool: AnyPool = AnyPoolOptions::new()
+            .max_connections(5)
+            .connect(&connection_string)
+            .await
+            .unwrap();
+
+        let select = statement_select(
+            "statement",
+            Some("obo:ZFA_0000354"),
+            Some("rdfs:label"),
+            None,
+        )
+        .unwrap();
+        let statements = get_statements(&select, &pool).unwrap();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0]["object"], json!("gill"));
+        assert_eq!(statements[0]["datatype"], json!("xsd:string"));
+
+        let select = statement_select(
+            "statement",
+            Some("obo:ZFA_0000354"),
+            Some("oboInOwl:hasExactSynonym"),
+            Some("gills"),
+        )
+        .unwrap();
+        let statements = get_statements(&select, &pool).unwrap();
+        assert_eq!(
+            statements[0]["annotation"]["oboInOwl:hasSynonymType"][0]["object"],
+            json!("obo:zfa#PLURAL")
+        );
+    }
+
     #[test]
     fn test_build_label_query_for() {
         let mut curies = HashSet::new();
@@ -1,8 +1,99 @@
+//! Nanobot serves a database of VALVE tables as a website for browsing and editing,
+//! with a command-line tool for the same tasks.
+//!
+//! The `nanobot` binary is a thin wrapper around this library,
+//! so other Rust projects can do the same without shelling out to it:
+//! build a [Config], load VALVE with [config::build_valve],
+//! query rows with [get::get_rows], and either start a server with [serve::Serve]
+//! or mount the [axum] router from [build_router] in an application of their own,
+//! e.g. under a path with `Router::nest`.
+//! For tests against Nanobot, the `test-utils` feature adds the `testing` module,
+//! which builds a temporary database from tables defined in the test and serves it.
+//!
+//! ```no_run
+//! use nanobot::{build_router, config::build_valve, get, Config, NanobotError};
+//! use ontodev_sqlrest::Select;
+//! use std::net::SocketAddr;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), NanobotError> {
+//!     let mut config = Config::from_path("nanobot.toml").await?;
+//!     config.connection("build/nanobot.db").port(3001);
+//!     build_valve(&mut config).await?;
+//!
+//!     let select = Select::new("\"table\"");
+//!     let options = get::GetRowsOptions::default();
+//!     let rows = get::get_rows(&config, &select, "value_rows", "json", &options).await?;
+//!     println!("{}", rows);
+//!
+//!     config.base_path = String::from("/nanobot");
+//!     let nanobot = build_router(config).await?;
+//!     let app = axum::Router::new().nest("/nanobot", nanobot);
+//!     axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 3001)))
+//!         .serve(app.into_make_service())
+//!         .await
+//!         .map_err(|e| NanobotError::GeneralError(e.to_string()))
+//! }
+//! ```
+
+/// Run the commands of the `[actions]` in nanobot.toml.
+pub mod action;
+/// Time queries for `nanobot bench`.
+pub mod bench;
+/// Keep rendered responses until the tables they come from change.
+pub mod cache;
+/// The class hierarchy of an ontology table, for the `/tree` pages.
+pub mod class_tree;
+/// Read nanobot.toml and the environment into a [Config].
 pub mod config;
+/// Batches of changes to the rows of the tables that are all kept or all undone.
+pub mod edit;
 pub mod error;
+/// Export every table to files, for backups and snapshots.
+pub mod export;
+/// Get rows from the database in each of the supported shapes and formats, and render pages.
 pub mod get;
+/// Import CSV and TSV files into the tables they are named after.
+pub mod import;
+/// Create the configuration, schema, and database of a new project.
+pub mod init;
+/// Run actions as background jobs, with their output kept for the job pages.
+pub mod job;
+/// Serve requests as an AWS Lambda function, with the `lambda` feature.
+#[cfg(feature = "lambda")]
+pub mod lambda;
+/// Browse ontology terms in LDTab tables.
 pub mod ldtab;
+/// Load tables from their TSV files into an existing database.
+pub mod load;
+/// Export tables in the style of ROBOT templates as OWL.
+pub mod owl;
+/// Expand CURIEs to IRIs and contract IRIs to CURIEs using the prefix table.
+pub mod prefix;
+/// Save tables from the database back to their TSV files.
+pub mod save;
+/// Parse the cron-style schedules of actions.
+pub mod schedule;
+/// Read and check the VALVE schema tables.
+pub mod schema;
+/// Search the IDs and labels of the term tables.
+pub mod search;
+/// The HTTP server: its routes, pages, and API.
 pub mod serve;
+/// The interactive `nanobot shell`.
+pub mod shell;
+/// Queries that sqlrest does not cover.
 pub mod sql;
 pub mod test;
+/// Temporary databases and servers for tests, with the `test-utils` feature.
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
 pub mod tree_view;
+/// Bring older projects up to date with this version of Nanobot.
+pub mod upgrade;
+/// Report the validation messages for the tables.
+pub mod validate;
+
+pub use config::Config;
+pub use error::NanobotError;
+pub use serve::{build_app, build_router, build_router_for, AppState};
@@ -0,0 +1,50 @@
+use crate::config::{check_table_names, redact_password, Config};
+use crate::error::NanobotError;
+
+/// Reload the given tables, or all of the tables, from their TSV files into the existing database,
+/// replacing their rows and validating them, without deleting the database.
+/// With `initial_load`, VALVE uses faster SQLite settings that are unsafe if the load is
+/// interrupted, so it is only meant for a database that could be rebuilt.
+pub async fn load(
+    config: &mut Config,
+    tables: &[String],
+    initial_load: bool,
+) -> Result<String, NanobotError> {
+    check_table_names(config, tables)?;
+    let database = redact_password(&config.connection);
+    let is_postgres = config.is_postgres();
+    let valve = config
+        .valve
+        .as_mut()
+        .ok_or("Valve is not initialized.".to_string())?;
+
+    if initial_load {
+        if is_postgres {
+            tracing::warn!("--initial-load only affects SQLite databases");
+        } else {
+            valve
+                .set_initial_load(true)
+                .await
+                .map_err(|e| format!("Could not configure '{}' for loading: {:?}", database, e))?;
+        }
+    }
+
+    if tables.is_empty() {
+        valve
+            .load_all_tables(true)
+            .await
+            .map_err(|e| format!("VALVE error while loading '{}': {:?}", database, e))?;
+        Ok(format!("Loaded all tables into '{}'", database))
+    } else {
+        let table_list = tables.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+        valve
+            .load_tables(&table_list, true)
+            .await
+            .map_err(|e| format!("VALVE error while loading '{}': {:?}", database, e))?;
+        Ok(format!(
+            "Loaded '{}' into '{}'",
+            table_list.join("', '"),
+            database
+        ))
+    }
+}
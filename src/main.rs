@@ -1,59 +1,89 @@
-use crate::{config::Config, error::NanobotError, serve::build_app, sql::get_table_from_pool};
-use axum_test_helper::{TestClient, TestResponse};
-use clap::{arg, command, value_parser, Command};
+use axum::http::header;
+use axum_test_helper::TestClient;
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use enquote::unquote;
+use nanobot::{
+    action, bench,
+    config::{self, build_valve, Config, LoggingFormat},
+    error::NanobotError,
+    export, get, import, init, load, owl, save, schema,
+    serve::{self, build_router_for},
+    shell,
+    sql::{get_rows_from_sql, get_table_from_pool},
+    upgrade, validate,
+};
 use ontodev_sqlrest::Select;
 use ontodev_valve::valve::Valve;
-use std::path::Path;
-use std::sync::Arc;
-use std::{collections::HashMap, env, io};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{collections::HashMap, env, fs, io};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    EnvFilter,
+};
 use url::Url;
 
-pub mod config;
-pub mod error;
-pub mod get;
-pub mod init;
-pub mod ldtab;
-pub mod serve;
-pub mod sql;
-pub mod tree_view;
-
-#[async_std::main]
-async fn main() -> Result<(), NanobotError> {
-    // initialize configuration
-    let mut config: Config = Config::new().await?;
-
-    let level = match config.logging_level {
-        config::LoggingLevel::DEBUG => tracing::Level::DEBUG,
-        config::LoggingLevel::INFO => tracing::Level::INFO,
-        config::LoggingLevel::WARN => tracing::Level::WARN,
-        config::LoggingLevel::ERROR => tracing::Level::ERROR,
-    };
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    // initialize tracing
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(level)
-        .with_writer(io::stderr)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+async fn run() -> Result<(), NanobotError> {
+    // On AWS Lambda, the runtime sets AWS_LAMBDA_RUNTIME_API and runs the binary without arguments.
+    #[cfg(feature = "lambda")]
+    if env::var("AWS_LAMBDA_RUNTIME_API").is_ok() && env::args().len() == 1 {
+        let config: Config = Config::new().await?;
+        start_tracing(&config)?;
+        return nanobot::lambda::run(config).await;
+    }
 
-    if let Some(vars) = cgi_vars() {
+    // Without arguments, run as a CGI script when the web server sets GATEWAY_INTERFACE.
+    // With arguments, use `nanobot serve --cgi`.
+    if let Some(vars) = cgi_vars().filter(|_| env::args().len() == 1) {
+        let mut config: Config = Config::new().await?;
+        start_tracing(&config)?;
         build_valve(&mut config).await?;
-        return match handle_cgi(&vars, &mut config) {
-            Err(x) => {
-                tracing::error!("{}", x);
-                std::process::exit(1)
-            }
-            Ok(x) => {
-                println!("{}", x);
-                Ok(())
-            }
-        };
+        return handle_cgi(&vars, &mut config).await;
     }
 
     let matches = command!() // requires `cargo` feature
         .propagate_version(true)
         .subcommand_required(false)
         .arg_required_else_help(true)
+        .arg(
+            arg!(--config <PATH> "Read the configuration from this file instead of nanobot.toml")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--profile <NAME> "Apply the settings from the [profile.NAME] section")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--database <NAME> "Use the database from the [databases.NAME] section")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--connection <URL> "Override the database connection URL or file")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--port <PORT> "Override the port to serve on")
+                .required(false)
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            arg!(--"valve-path" <PATH> "Override the path to the VALVE table table")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
         .subcommand(
             Command::new("init")
                 .about("Initialises things")
@@ -65,15 +95,152 @@ async fn main() -> Result<(), NanobotError> {
                     .value_parser(value_parser!(String)),
                 )
                 .arg(arg!(--create_only "Only create VALVE tables").required(false))
-                .arg(arg!(--initial_load "Use unsafe SQLite optimizations").required(false)),
+                .arg(arg!(--initial_load "Use unsafe SQLite optimizations").required(false))
+                .arg(
+                    arg!(--force "Recreate the configuration, schema, and database from scratch")
+                        .required(false)
+                        .conflicts_with("merge"),
+                )
+                .arg(
+                    arg!(--merge "Only add missing files and tables, keeping existing data")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--demo "Create an example project with data tables to explore")
+                        .required(false)
+                        .conflicts_with_all(["from", "template"]),
+                )
+                .arg(
+                    arg!(--template <NAME> "Start from a preset schema: ontology, registry, or survey")
+                        .required(false)
+                        .value_parser(|name: &str| name.parse::<init::ProjectTemplate>())
+                        .conflicts_with("from"),
+                )
+                .arg(
+                    arg!(--from <DIR> "Create a table for each CSV or TSV file in a directory")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--schema <URL> "Download the schema from the URL of a table table")
+                        .required(false)
+                        .value_parser(value_parser!(Url))
+                        .conflicts_with_all(["demo", "from", "template"]),
+                )
+                .arg(
+                    arg!(--deploy <TARGET> "Create deployment files: systemd, docker, or cgi")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(|name: &str| name.parse::<init::DeployTarget>()),
+                ),
+        )
+        .subcommand(
+            Command::new("load")
+                .about("Reloads table data from the TSV files into the existing database")
+                .arg(
+                    arg!([TABLE] ... "The tables to load, or all of the tables if none are given")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--"initial-load" "Use faster SQLite settings that are unsafe if interrupted")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("save")
+                .about("Writes table data from the database back to the TSV files")
+                .arg(
+                    arg!([TABLE] ... "The tables to save, or all of the tables if none are given")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--"save-dir" <DIR> "Write the files to this directory instead")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Validates and loads CSV and TSV files into the tables they are named after")
+                .arg(
+                    arg!(<PATH> ... "Directories of files, or files, e.g. penguin.tsv")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--replace "Replace the rows of each table instead of appending to them")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Exports every table from the database to files in a directory")
+                .arg(
+                    arg!(<DIR> "The directory to write the files to")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!([TABLE] ... "The tables to export, or all of the tables if none are given")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-f --format <FORMAT> "The format of the files")
+                        .required(false)
+                        .default_value("tsv")
+                        .value_parser(export::EXPORT_FORMATS),
+                )
+                .arg(
+                    arg!(--"no-meta" "Leave out the VALVE schema tables and the message and history tables")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("Validates tables and reports their errors, failing if there are any")
+                .arg(
+                    arg!([TABLE] ... "The tables to validate, or all of the tables if none are given")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--reload "Reload the tables from their TSV files first, replacing unsaved changes")
+                        .required(false),
+                )
+                .arg(
+                    arg!(-f --format <FORMAT> "The format of the report: text or json")
+                        .required(false)
+                        .default_value("text")
+                        .value_parser(["text", "json"]),
+                ),
+        )
+        .subcommand(Command::new("undo").about("Undoes the last change to the data"))
+        .subcommand(Command::new("redo").about("Redoes the last change that was undone"))
+        .subcommand(
+            Command::new("config").about("Configures things").arg(
+                arg!(--check "Report unknown keys and invalid values, and fail if there are any")
+                    .required(false),
+            ),
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Migrates nanobot.toml and the VALVE meta tables to the current version")
+                .arg(arg!(--"dry-run" "Report the changes without making them").required(false)),
         )
-        .subcommand(Command::new("config").about("Configures things"))
         .subcommand(
             Command::new("get")
                 .about("Gets things from a table")
                 .arg(
-                    arg!(<TABLE> "A database table")
-                        .required(true)
+                    arg!([TABLE] "A database table")
+                        .required_unless_present("query")
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-q --query <FILE> "Run a query saved in a JSON or TOML file")
+                        .required(false)
                         .value_parser(value_parser!(String)),
                 )
                 .arg(
@@ -81,6 +248,59 @@ async fn main() -> Result<(), NanobotError> {
                         .required(false)
                         .value_parser(value_parser!(String)),
                 )
+                .arg(
+                    arg!(-c --columns <COLUMNS> "Get only these columns, e.g. table,path")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--filter <FILTER> "Filter the rows, e.g. type=eq.table")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--order <ORDER> "Sort the rows, e.g. type.desc,table.asc")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-l --limit <LIMIT> "The maximum number of rows to get")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--offset <OFFSET> "The number of rows to skip")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--all "Get all of the rows")
+                        .required(false)
+                        .conflicts_with("limit"),
+                )
+                .arg(
+                    arg!(-o --output <FILE> "Write to this file, inferring the format from its extension")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--"dry-run" "Print the SQL query without running it").required(false),
+                )
+                .arg(
+                    arg!(--"include-meta" "Include each row's messages and last change in JSON")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--iris "Expand the CURIEs in 'curie' columns to IRIs using the prefix table")
+                        .required(false),
+                )
+                .arg(arg!(--count "Print the number of matching rows").required(false))
+                .arg(
+                    arg!(-k --key <COLUMN> "The column to key the 'map' shape on")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
                 .arg(
                     arg!(-f --format <FORMAT> "Specifies an output format, e.g. json")
                         .required(false)
@@ -88,17 +308,118 @@ async fn main() -> Result<(), NanobotError> {
                 ),
         )
         .subcommand(
-            Command::new("serve").about("Run HTTP server").arg(
-                arg!(
-                    -c --connection <URL> "Specifies a database connection URL or file"
+            Command::new("sql")
+                .about("Runs a read-only SQL query, such as a SELECT, and prints its rows")
+                .arg(
+                    arg!(<SQL> "The query, or '-' to read it from STDIN")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
                 )
-                .required(false)
-                .value_parser(value_parser!(String)),
-            ),
+                .arg(
+                    arg!(-f --format <FORMAT> "Specifies an output format, e.g. json")
+                        .required(false)
+                        .default_value("text")
+                        .value_parser(get::VALUE_ROWS_FORMATS),
+                ),
+        )
+        .subcommand(
+            Command::new("shell")
+                .about("Explores tables, filters, and SQL queries interactively"),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Times queries and reports their latencies and row throughput")
+                .arg(
+                    arg!([TABLE] ... "Time the default page of these tables, or of every table")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-q --query <FILE> "Time a query saved in a JSON or TOML file")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-n --runs <RUNS> "The number of timed runs of each query")
+                        .required(false)
+                        .default_value("20")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--warmup <RUNS> "The number of untimed runs before the timed runs")
+                        .required(false)
+                        .default_value("2")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(-s --shape <SHAPE> "Get a 'page' with its counts, or just the 'value_rows'")
+                        .required(false)
+                        .default_value("page")
+                        .value_parser(["page", "value_rows"]),
+                )
+                .arg(
+                    arg!(-f --format <FORMAT> "The format of the report: text or json")
+                        .required(false)
+                        .default_value("text")
+                        .value_parser(["text", "json"]),
+                ),
+        )
+        .subcommand(
+            Command::new("action")
+                .about("Runs a configured action")
+                .arg(
+                    arg!(<NAME> "The name of an action")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-i --input <INPUT> "An input for the action, e.g. branch_name=fix")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!(--"dry-run" "Print the commands without running them").required(false))
+                .arg(arg!(-y --yes "Run without asking for confirmation").required(false)),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Run HTTP server")
+                .arg(
+                    arg!(
+                        -c --connection <URL> "Specifies a database connection URL or file"
+                    )
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--dev "Reload nanobot.toml, templates, and assets when they change")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"read-only" "Disable editing, uploads, and actions")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--watch "Reload tables into the database when their TSV files change")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--socket <PATH> "Listen on a Unix socket at this path instead of the port")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--cgi "Handle one CGI request from the environment and STDIN, then exit")
+                        .action(ArgAction::SetTrue),
+                ),
         )
         .get_matches();
 
-    let exit_result = match matches.subcommand() {
+    let mut config = load_config(&matches).await?;
+    start_tracing(&config)?;
+
+    let exit_result: Result<String, NanobotError> = match matches.subcommand() {
         Some(("init", sub_matches)) => {
             if let Some(c) = sub_matches.get_one::<String>("connection") {
                 config.connection(c);
@@ -111,61 +432,371 @@ async fn main() -> Result<(), NanobotError> {
             if path.exists() {
                 tracing::warn!("Initializing existing database: '{}'", path.display());
             }
-            init::init(&mut config).await
+            let mode = if sub_matches.get_flag("force") {
+                init::InitMode::Force
+            } else if sub_matches.get_flag("merge") {
+                init::InitMode::Merge
+            } else {
+                init::InitMode::Default
+            };
+            let source = if sub_matches.get_flag("demo") {
+                init::InitSource::Demo
+            } else if let Some(dir) = sub_matches.get_one::<PathBuf>("from") {
+                init::InitSource::Directory(dir.clone())
+            } else if let Some(template) = sub_matches.get_one::<init::ProjectTemplate>("template")
+            {
+                init::InitSource::Template(*template)
+            } else if let Some(url) = sub_matches.get_one::<Url>("schema") {
+                init::InitSource::Url(url.clone())
+            } else {
+                init::InitSource::Empty
+            };
+            let deploy = sub_matches
+                .get_many::<init::DeployTarget>("deploy")
+                .unwrap_or_default()
+                .copied()
+                .collect::<Vec<_>>();
+            init::init(&mut config, mode, &source, &deploy).await
+        }
+        Some(("load", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let tables = sub_matches
+                .get_many::<String>("TABLE")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            load::load(&mut config, &tables, sub_matches.get_flag("initial-load")).await
         }
-        Some(("config", _sub_matches)) => {
+        Some(("save", sub_matches)) => {
             build_valve(&mut config).await?;
-            Ok(config.to_string())
+            let tables = sub_matches
+                .get_many::<String>("TABLE")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let save_dir = sub_matches.get_one::<String>("save-dir");
+            save::save(&config, &tables, save_dir.map(|d| d.as_str()))
+        }
+        Some(("import", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let paths = sub_matches
+                .get_many::<String>("PATH")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            import::import(&config, &paths, sub_matches.get_flag("replace")).await
+        }
+        Some(("export", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let tables = sub_matches
+                .get_many::<String>("TABLE")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let format = sub_matches.get_one::<String>("format").unwrap();
+            let meta = !sub_matches.get_flag("no-meta");
+            export::export(&config, dir, format, &tables, meta).await
+        }
+        Some(("validate", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let tables = sub_matches
+                .get_many::<String>("TABLE")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let reload = sub_matches.get_flag("reload");
+            let format = sub_matches.get_one::<String>("format").unwrap();
+            match validate::validate(&mut config, &tables, reload, format).await? {
+                (report, true) => Ok(report),
+                // The report is the error, so that it is printed and the exit code is 1.
+                (report, false) => return Err(report.into()),
+            }
+        }
+        Some(("undo", _)) => {
+            build_valve(&mut config).await?;
+            undo(&config, false).await
+        }
+        Some(("redo", _)) => {
+            build_valve(&mut config).await?;
+            undo(&config, true).await
+        }
+        Some(("upgrade", sub_matches)) => {
+            upgrade::upgrade(&config, sub_matches.get_flag("dry-run"))
+        }
+        Some(("config", sub_matches)) => {
+            if sub_matches.get_flag("check") {
+                check_config(&config)
+            } else {
+                build_valve(&mut config).await?;
+                Ok(config.to_string())
+            }
         }
         Some(("get", sub_matches)) => {
             build_valve(&mut config).await?;
-            let table = match sub_matches.get_one::<String>("TABLE") {
+            // Start from the saved query, if any, then apply the other options.
+            let mut query = match sub_matches.get_one::<String>("query") {
+                Some(path) => get::GetQuery::load(path)?,
+                None => get::GetQuery::default(),
+            };
+            if let Some(table) = sub_matches.get_one::<String>("TABLE") {
+                query.table = table.to_string();
+            }
+            if let Some(columns) = sub_matches.get_one::<String>("columns") {
+                query.columns = columns.split(',').map(|c| c.to_string()).collect();
+            }
+            query.filters.extend(
+                sub_matches
+                    .get_many::<String>("filter")
+                    .unwrap_or_default()
+                    .cloned(),
+            );
+            if let Some(order) = sub_matches.get_one::<String>("order") {
+                query.order = Some(order.to_string());
+            }
+            if let Some(limit) = sub_matches.get_one::<usize>("limit") {
+                query.limit = Some(*limit);
+            }
+            if let Some(offset) = sub_matches.get_one::<usize>("offset") {
+                query.offset = Some(*offset);
+            }
+            let table = unquote(&query.table).unwrap_or(query.table.to_string());
+
+            // Without a --format, use the extension of the --output file, if any,
+            // then the table's default_format.
+            let output = sub_matches.get_one::<String>("output");
+            let default_format = match config.default_format_for(&table) {
+                Some("txt") | None => "text",
                 Some(x) => x,
-                _ => panic!("No table given"),
             };
+            let format = match (sub_matches.get_one::<String>("format"), output) {
+                (Some(x), _) => x.as_str(),
+                (None, Some(path)) => get::format_from_path(path).unwrap_or(default_format),
+                _ => default_format,
+            };
+            // HTML is rendered from a page, using the same template as `serve`.
             let shape = match sub_matches.get_one::<String>("shape") {
                 Some(x) => x,
+                _ if format == "html" => "page",
                 _ => "value_rows",
             };
-            let format = match sub_matches.get_one::<String>("format") {
-                Some(x) => x,
-                _ => "text",
+
+            let mut select = query.to_select()?;
+            let limit_max = config.max_results_per_page_for(&table);
+            let all = sub_matches.get_flag("all");
+            if all {
+                // Count the rows of the table's view, which includes conflicting rows.
+                let count = get::get_count(&config, &select).await?;
+                select.limit(count);
+            } else if select.limit.unwrap_or(0) > limit_max {
+                tracing::warn!(
+                    "Limiting {} rows to max_results_per_page {}: use --all to get every row",
+                    select.limit.unwrap_or(0),
+                    limit_max
+                );
+                select.limit(limit_max);
+            }
+            let streaming = shape == "value_rows" || shape == "objects";
+            let include_meta = sub_matches.get_flag("include-meta");
+            let iris = sub_matches.get_flag("iris");
+            if sub_matches.get_flag("count") {
+                match get::get_count(&config, &select).await {
+                    Ok(count) => Ok(count.to_string()),
+                    Err(x) => Err(x.into()),
+                }
+            } else if sub_matches.get_flag("dry-run") {
+                get::get_sql(&config, &select).map_err(NanobotError::from)
+            } else if owl::OWL_FORMATS.contains(&format) {
+                // The whole table is the template, so filters and limits don't apply.
+                let owl = owl::get_owl(&config, &table, format).await?;
+                match output {
+                    Some(path) => match fs::write(path, owl) {
+                        Ok(_) => Ok(String::new()),
+                        Err(e) => Err(format!("Could not write '{}': {}", path, e).into()),
+                    },
+                    None => Ok(owl),
+                }
+            } else if format == "xlsx" {
+                // Workbooks are binary, so write them to a file instead of STDOUT.
+                let path = match output {
+                    Some(path) => path.to_string(),
+                    None => format!("{}.xlsx", table),
+                };
+                match get::get_xlsx(&config, &[select], all).await {
+                    Ok(workbook) => match fs::write(&path, workbook) {
+                        Ok(_) if output.is_some() => Ok(String::new()),
+                        Ok(_) => Ok(format!("Wrote {}", path)),
+                        Err(e) => Err(format!("Could not write '{}': {}", path, e).into()),
+                    },
+                    Err(x) => Err(x.into()),
+                }
+            } else if !include_meta
+                && !iris
+                && get::STREAM_FORMATS.contains(&format)
+                && (format == "jsonl" || (output.is_some() && streaming))
+                && !(format.starts_with("json") && get::has_label_fields(&config, &table))
+            {
+                // Write each row as soon as it is fetched, so large tables don't have to fit in
+                // memory. Only JSON Lines streams the whole table by default.
+                if format != "jsonl" && select.limit.is_none() {
+                    select.limit(config.results_per_page_for(&table));
+                }
+                let result = match output {
+                    Some(path) => match fs::File::create(path) {
+                        Ok(file) => {
+                            let writer = io::BufWriter::new(file);
+                            get::write_value_rows(&config, &select, format, writer).await
+                        }
+                        Err(e) => Err(e.into()),
+                    },
+                    None => get::write_value_rows(&config, &select, format, io::stdout()).await,
+                };
+                result.map(|_| String::new()).map_err(NanobotError::from)
+            } else {
+                let options = get::GetRowsOptions {
+                    key: sub_matches.get_one::<String>("key").cloned(),
+                    meta: include_meta,
+                    iris,
+                    all,
+                };
+                match (
+                    get::get_rows(&config, &select, shape, format, &options).await,
+                    output,
+                ) {
+                    (Ok(x), Some(path)) => match fs::write(path, x) {
+                        Ok(_) => Ok(String::new()),
+                        Err(e) => Err(format!("Could not write '{}': {}", path, e).into()),
+                    },
+                    (Ok(x), None) => Ok(x),
+                    (Err(x), _) => Err(x.into()),
+                }
+            }
+        }
+        Some(("sql", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let sql = match sub_matches.get_one::<String>("SQL").unwrap().as_str() {
+                "-" => {
+                    let mut sql = String::new();
+                    io::stdin().read_to_string(&mut sql)?;
+                    sql
+                }
+                sql => sql.to_string(),
             };
-            let result = match get::get_table(&config, table, shape, format).await {
-                Ok(x) => x,
-                Err(x) => format!("ERROR: {:?}", x),
+            let pool = config.pool.as_ref().ok_or("No connection pool")?;
+            let rows = get_rows_from_sql(pool, &sql).await?;
+            let format = sub_matches.get_one::<String>("format").unwrap();
+            get::format_value_rows(&rows, format).map_err(NanobotError::from)
+        }
+        Some(("shell", _)) => {
+            build_valve(&mut config).await?;
+            shell::shell(&config).await
+        }
+        Some(("bench", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let tables = sub_matches
+                .get_many::<String>("TABLE")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let queries = sub_matches
+                .get_many::<String>("query")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            // Saved queries replace the default pages, unless tables are also given.
+            let mut benchmarks = match (tables.is_empty(), queries.is_empty()) {
+                (true, false) => vec![],
+                _ => bench::table_benchmarks(&config, &tables)?,
             };
-            Ok(result)
+            for path in queries {
+                benchmarks.push(bench::query_benchmark(&config, path)?);
+            }
+            let shape = sub_matches.get_one::<String>("shape").unwrap();
+            let runs = *sub_matches.get_one::<usize>("runs").unwrap();
+            let warmup = *sub_matches.get_one::<usize>("warmup").unwrap();
+            let results = bench::run_benchmarks(&config, &benchmarks, shape, runs, warmup).await?;
+            bench::report(&results, sub_matches.get_one::<String>("format").unwrap())
+        }
+        Some(("action", sub_matches)) => {
+            build_valve(&mut config).await?;
+            let name = sub_matches
+                .get_one::<String>("NAME")
+                .ok_or("No action given: use `nanobot action NAME`")?;
+            let inputs = sub_matches
+                .get_many::<String>("input")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let dry_run = sub_matches.get_flag("dry-run");
+            let confirmed = sub_matches.get_flag("yes");
+            action::run_action(&config, name, &inputs, dry_run, confirmed)
+        }
+        Some(("serve", sub_matches)) if sub_matches.get_flag("cgi") => {
+            prepare_serve(&mut config, sub_matches).await?;
+            handle_cgi(&read_cgi_vars(), &mut config).await?;
+            Ok(String::new())
         }
         Some(("serve", sub_matches)) => {
-            if let Some(c) = sub_matches.get_one::<String>("connection") {
-                config.connection(c);
-            }
-            if config.connection == ":memory:" {
-                (config.valve, config.pool) = {
-                    let valve = Valve::build(&config.valve_path, &config.connection).await?;
-                    let pool = valve.pool.clone();
-                    let _ = valve.load_all_tables(true).await;
-                    let table_select = Select::new("\"table\"");
-                    config.table = get_table_from_pool(&pool, &table_select)
-                        .await
-                        .unwrap()
-                        .clone();
-                    let datatype_select = Select::new("\"datatype\"");
-                    config.datatype = get_table_from_pool(&pool, &datatype_select)
-                        .await
-                        .unwrap()
-                        .clone();
-                    (Some(valve), Some(pool))
+            prepare_serve(&mut config, sub_matches).await?;
+            let dev = sub_matches.get_flag("dev");
+            loop {
+                let mut server = match serve::Serve::new(config.clone()).dev(dev).start().await {
+                    Ok(server) => server,
+                    Err(e) => break Err(e),
                 };
-            } else {
-                build_valve(&mut config).await?;
+                println!(
+                    "Running Nanobot server at {}{}/",
+                    server.listener(),
+                    config.base_path
+                );
+                if dev {
+                    println!(
+                        "Watching for changes to {} and the templates and assets.",
+                        config.config_path
+                    );
+                }
+                if config.watch {
+                    println!("Watching for changes to the TSV files of the tables.");
+                }
+                println!("Press Control-C to quit.");
+                let shutdown = tokio::select! {
+                    shutdown = server.wait() => shutdown,
+                    _ = serve::shutdown_signal() => {
+                        server.shutdown();
+                        server.wait().await
+                    }
+                };
+                match shutdown {
+                    Ok(reason @ serve::Shutdown::ConfigChanged)
+                    | Ok(reason @ serve::Shutdown::SchemaChanged) => {
+                        println!("Reloading {}...", config.config_path);
+                        let reloaded = async {
+                            let mut new_config = load_config(&matches).await?;
+                            prepare_serve(&mut new_config, sub_matches).await?;
+                            // The tables must be loaded again for the new schema.
+                            if reason == serve::Shutdown::SchemaChanged {
+                                let message = load::load(&mut new_config, &[], false).await?;
+                                tracing::info!("{}", message);
+                            }
+                            Ok::<Config, NanobotError>(new_config)
+                        };
+                        match reloaded.await {
+                            Ok(new_config) => config = new_config,
+                            Err(e) => tracing::error!(
+                                "Could not reload, keeping the previous configuration: {:?}",
+                                e
+                            ),
+                        }
+                    }
+                    Ok(serve::Shutdown::Quit) => {
+                        break Ok(String::from("Stopping Nanobot server..."))
+                    }
+                    Err(e) => break Err(e),
+                }
             }
-            serve::app(&config)
         }
-        _ => Err(String::from(
-            "Unrecognised or missing subcommand, but CGI environment vars are undefined",
-        )),
+        _ => {
+            Err("Unrecognised or missing subcommand, but CGI environment vars are undefined".into())
+        }
     };
 
     //print exit message
@@ -176,43 +807,215 @@ async fn main() -> Result<(), NanobotError> {
         }
 
         Ok(x) => {
-            println!("{}", x);
+            if !x.is_empty() {
+                println!("{}", x);
+            }
             Ok(())
         }
     }
 }
 
-/// Builds and assigns a Valve struct to the field `config.valve` and a copy of valve's
-/// connection pool to the field `config.pool`.
-async fn build_valve(config: &mut Config) -> Result<(), NanobotError> {
-    (config.valve, config.pool) = {
-        let valve = Valve::build(&config.valve_path, &config.connection).await?;
-        let pool = valve.pool.clone();
-        let table_select = Select::new("\"table\"");
-        config.table = get_table_from_pool(&pool, &table_select)
-            .await
-            .unwrap()
-            .clone();
-        let datatype_select = Select::new("\"datatype\"");
-        config.datatype = get_table_from_pool(&pool, &datatype_select)
-            .await
-            .unwrap()
-            .clone();
-        tracing::info!("TABLE ORDER {:?}", valve.config.table_order);
-        tracing::info!("SORTED TABLES {:?}", valve.sorted_table_list);
-        (Some(valve), Some(pool))
+/// Read the configuration, letting the global flags override nanobot.toml.
+async fn load_config(matches: &ArgMatches) -> Result<Config, NanobotError> {
+    let config_path = match matches.get_one::<String>("config") {
+        Some(path) => path.as_str(),
+        None => config::DEFAULT_CONFIG_PATH,
     };
+    let profile = matches.get_one::<String>("profile").map(|p| p.as_str());
+    let mut config: Config = Config::from_path_and_profile(config_path, profile).await?;
+    if let Some(database) = matches.get_one::<String>("database") {
+        config.database(database)?;
+    }
+    if let Some(connection) = matches.get_one::<String>("connection") {
+        config.connection(connection);
+    }
+    if let Some(port) = matches.get_one::<u16>("port") {
+        config.port(*port);
+    }
+    if let Some(valve_path) = matches.get_one::<String>("valve-path") {
+        config.valve_path(valve_path);
+    }
+    Ok(config)
+}
+
+/// Apply the `serve` options and load the database. An in-memory database is loaded from the
+/// VALVE tables each time, since it starts out empty.
+async fn prepare_serve(config: &mut Config, sub_matches: &ArgMatches) -> Result<(), NanobotError> {
+    if let Some(c) = sub_matches.get_one::<String>("connection") {
+        config.connection(c);
+    }
+    if sub_matches.get_flag("read-only") {
+        config.read_only(true);
+    }
+    if sub_matches.get_flag("watch") {
+        config.watch(true);
+    }
+    if let Some(socket) = sub_matches.get_one::<String>("socket") {
+        config.socket(socket);
+    }
+    if config.connection == ":memory:" {
+        (config.valve, config.pool) = {
+            let valve = Valve::build(&config.valve_path, &config.connection).await?;
+            let pool = valve.pool.clone();
+            let _ = valve.load_all_tables(true).await;
+            let table_select = Select::new("\"table\"");
+            config.table = get_table_from_pool(&pool, &table_select)
+                .await
+                .map_err(|e| format!("Could not read the table table: {}", e))?;
+            let datatype_select = Select::new("\"datatype\"");
+            config.datatype = get_table_from_pool(&pool, &datatype_select)
+                .await
+                .map_err(|e| format!("Could not read the datatype table: {}", e))?;
+            (Some(valve), Some(pool))
+        };
+    } else {
+        build_valve(config).await?;
+    }
     Ok(())
 }
 
-#[tokio::main]
-async fn handle_cgi(vars: &HashMap<String, String>, config: &mut Config) -> Result<String, String> {
+/// Undo the last change to the data, whether it was made in the browser, the API, or the CLI,
+/// or with `redo`, redo the last change that was undone. Describe the change.
+async fn undo(config: &Config, redo: bool) -> Result<String, NanobotError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let (verb, done) = if redo {
+        ("redo", "Redid")
+    } else {
+        ("undo", "Undid")
+    };
+    let change = if redo {
+        valve.get_change_to_redo().await
+    } else {
+        valve.get_change_to_undo().await
+    }
+    .map_err(|e| format!("Could not get the change to {}: {:?}", verb, e))?;
+    let change = match change {
+        Some(change) => change,
+        None => return Ok(format!("Nothing to {}", verb)),
+    };
+    let result = if redo {
+        valve.redo().await
+    } else {
+        valve.undo().await
+    };
+    result.map_err(|e| format!("Could not {} '{}': {:?}", verb, change.message, e))?;
+    Ok(format!("{} '{}'", done, change.message))
+}
+
+/// Check the configuration and the VALVE schema, failing when there are any problems.
+fn check_config(config: &Config) -> Result<String, NanobotError> {
+    let mut problems = config::check_config(config)?;
+    if Path::new(&config.valve_path).is_file() {
+        problems.extend(schema::check_schema(&config.valve_path)?);
+    }
+    if problems.is_empty() {
+        Ok(format!(
+            "Checked '{}': no problems found",
+            config.config_path
+        ))
+    } else {
+        Err(format!(
+            "Found {} problem(s) in the configuration:\n{}",
+            problems.len(),
+            problems.join("\n")
+        )
+        .into())
+    }
+}
+
+/// Start logging to STDERR at the configured level.
+fn start_tracing(config: &Config) -> Result<(), NanobotError> {
+    // The global level, followed by the levels for particular modules, e.g. "WARN,sqlx=ERROR".
+    let mut directives = vec![config.logging_level.to_string()];
+    for (module, level) in &config.logging_modules {
+        directives.push(format!("{}={}", module, level));
+    }
+    let filter = EnvFilter::try_new(directives.join(","))
+        .map_err(|e| NanobotError::GeneralError(format!("Invalid [logging.modules]: {}", e)))?;
+    let writer = match &config.logging_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    NanobotError::GeneralError(format!(
+                        "Could not open the log file '{}': {}",
+                        path, e
+                    ))
+                })?;
+            BoxMakeWriter::new(Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.logging_file.is_none())
+        // Log how long each span took when it closes, e.g. the queries for a page.
+        .with_span_events(FmtSpan::CLOSE);
+    let result = match config.logging_format {
+        LoggingFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LoggingFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+    };
+    result.map_err(|e| format!("Could not start logging: {}", e))?;
+
+    if let Some(profile) = &config.profile {
+        tracing::info!("Using profile '{}' from {}", profile, config.config_path);
+    }
+
+    if config.config_version < config::DEFAULT_CONFIG_VERSION {
+        tracing::warn!(
+            "{} uses config_version {}: run `nanobot upgrade` to migrate it to {}",
+            config.config_path,
+            config.config_version,
+            config::DEFAULT_CONFIG_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Handle one CGI request: build it from the CGI variables and the body on STDIN,
+/// send it through the server's routes, and write the response to STDOUT.
+/// Errors are written as a response too, so that the web server can show them.
+async fn handle_cgi(
+    vars: &HashMap<String, String>,
+    config: &mut Config,
+) -> Result<(), NanobotError> {
     tracing::debug!("Processing CGI request with vars: {:?}", vars);
+    let response = match cgi_response(vars, config).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("{}", e);
+            format!(
+                "Status: 500 Internal Server Error\ncontent-type: text/plain\n\n{}\n",
+                e
+            )
+            .into_bytes()
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&response)?;
+    stdout.flush()?;
+    Ok(())
+}
 
-    let shared_state = Arc::new(serve::AppState {
-        config: config.clone(),
-    });
-    let app = build_app(shared_state);
+async fn cgi_response(
+    vars: &HashMap<String, String>,
+    config: &mut Config,
+) -> Result<Vec<u8>, NanobotError> {
+    // Links in the pages start with the path of the CGI script, e.g. /cgi-bin/nanobot.cgi
+    if config.base_path.is_empty() {
+        if let Some(script_name) = vars.get("SCRIPT_NAME") {
+            config.base_path = script_name.trim_end_matches('/').to_string();
+        }
+    }
+    // The same routes as `serve`, including those of the `[databases]`.
+    let shared_state = Arc::new(serve::AppState::new(config.clone()));
+    let app = build_router_for(shared_state).await?;
     let client = TestClient::new(app);
 
     let request_method = vars
@@ -231,75 +1034,99 @@ async fn handle_cgi(vars: &HashMap<String, String>, config: &mut Config) -> Resu
     if !query_string.is_empty() {
         url.push_str(&format!("?{}", query_string));
     }
-    tracing::info!("In CGI mode, processing URL: {}", url);
+    tracing::info!("In CGI mode, processing {} {}", request_method, url);
 
-    async fn generate_html(res: TestResponse) -> String {
-        let mut html = format!("status: {}\n", res.status());
-        for (hname, hval) in res.headers().iter() {
-            html.push_str(&format!(
-                "{}: {}\n",
-                hname,
-                hval.to_str().unwrap_or_default()
-            ));
+    let mut request = match request_method.to_uppercase().as_str() {
+        "GET" => client.get(&url),
+        "HEAD" => client.head(&url),
+        "POST" => {
+            // The server gives the length of the body, which may not end with a newline.
+            let mut body = vec![];
+            match vars
+                .get("CONTENT_LENGTH")
+                .and_then(|l| l.parse::<usize>().ok())
+            {
+                Some(length) => {
+                    body.resize(length, 0);
+                    io::stdin().read_exact(&mut body)?;
+                }
+                None => {
+                    io::stdin().read_to_end(&mut body)?;
+                }
+            }
+            client.post(&url).body(body)
         }
-        let html_body = &res.text().await;
-        html.push_str(&format!("\n{}", html_body));
-        html
+        method => {
+            return Ok(format!(
+                "Status: 405 Method Not Allowed\ncontent-type: text/plain\n\n\
+                 Unsupported request method: {}\n",
+                method
+            )
+            .into_bytes())
+        }
+    };
+    // Pass on the request headers, which CGI gives as HTTP_ACCEPT, CONTENT_TYPE, etc.
+    for (var, value) in vars {
+        let name = match var.as_str() {
+            "CONTENT_TYPE" => "content-type".to_string(),
+            "HTTP_HOST" | "HTTP_CONNECTION" | "HTTP_CONTENT_LENGTH" => continue,
+            var => match var.strip_prefix("HTTP_") {
+                Some(name) => name.to_lowercase().replace('_', "-"),
+                None => continue,
+            },
+        };
+        request = request.header(name.as_str(), value.as_str());
     }
+    let response = request.send().await;
 
-    match request_method.to_lowercase().as_str() {
-        "post" => {
-            let query_str = std::io::stdin()
-                .lines()
-                .map(|l| l.unwrap())
-                .collect::<Vec<_>>()
-                .join("\n");
-            let query_str = format!("http://example.com?{}", query_str);
-            // TODO: Check to see if this is provided by Axum so that we can remove the extra
-            // library dependency (on Url).
-            let example_url = Url::try_from(query_str.as_str()).map_err(|e| e.to_string())?;
-            let mut form = vec![];
-            for (key, val) in example_url.query_pairs() {
-                form.push((key.to_string(), val.to_string()));
-            }
-            tracing::debug!("In CGI mode, processing form for POST: {:?}", form);
-            let res = client.post(&url).form(&form).send().await;
-            Ok(generate_html(res).await)
+    let status = response.status();
+    let mut head = format!("Status: {}\n", status);
+    for (name, value) in response.headers() {
+        if name == header::TRANSFER_ENCODING || name == header::CONNECTION {
+            continue;
         }
-        "get" => {
-            let res = client.get(&url).send().await;
-            Ok(generate_html(res).await)
-        }
-        _ => Err(format!(
-            "Content-Type: text/html\nStatus: 400\nUnrecognized request method: {}",
-            request_method
-        )),
+        head.push_str(&format!(
+            "{}: {}\n",
+            name,
+            value.to_str().unwrap_or_default()
+        ));
     }
+    head.push('\n');
+    let mut output = head.into_bytes();
+    output.extend_from_slice(&response.bytes().await);
+    Ok(output)
 }
 
+/// When the web server runs Nanobot as a CGI script, get the CGI variables.
 fn cgi_vars() -> Option<HashMap<String, String>> {
-    let mut vars = match env::var_os("GATEWAY_INTERFACE").and_then(|p| Some(p.into_string())) {
-        Some(Ok(s)) if s == "CGI/1.1" => HashMap::new(),
-        _ => return None,
-    };
-
-    for var in vec!["REQUEST_METHOD", "PATH_INFO", "QUERY_STRING"] {
-        match env::var_os(var).and_then(|p| Some(p.into_string())) {
-            Some(Ok(s)) => vars.insert(var.to_string(), s),
-            _ => match var {
-                "REQUEST_METHOD" => vars.insert(var.to_string(), "GET".to_string()),
-                "PATH_INFO" => vars.insert(var.to_string(), "/table".to_string()),
-                "QUERY_STRING" => vars.insert(var.to_string(), String::new()),
-                _ => {
-                    // This should never happen since all possible cases should be handled above:
-                    unreachable!(
-                        "CGI mode enabled but environment variable: {} is undefined. Exiting.",
-                        var
-                    );
-                }
-            },
-        };
+    match env::var("GATEWAY_INTERFACE") {
+        Ok(s) if s == "CGI/1.1" => Some(read_cgi_vars()),
+        _ => None,
     }
+}
 
-    Some(vars)
+/// Read the CGI variables from the environment: the request method, path, and query,
+/// with defaults for a GET request of the table table, and the headers and script name.
+fn read_cgi_vars() -> HashMap<String, String> {
+    let mut vars = HashMap::from([
+        ("REQUEST_METHOD".to_string(), "GET".to_string()),
+        ("PATH_INFO".to_string(), "/table".to_string()),
+        ("QUERY_STRING".to_string(), String::new()),
+    ]);
+    for (var, value) in env::vars() {
+        if var.starts_with("HTTP_")
+            || [
+                "REQUEST_METHOD",
+                "PATH_INFO",
+                "QUERY_STRING",
+                "CONTENT_TYPE",
+                "CONTENT_LENGTH",
+                "SCRIPT_NAME",
+            ]
+            .contains(&var.as_str())
+        {
+            vars.insert(var, value);
+        }
+    }
+    vars
 }
@@ -0,0 +1,501 @@
+use crate::config::Config;
+use crate::error::NanobotError;
+use crate::prefix::{expand_curie, get_prefix_map};
+use crate::sql::get_table_from_pool;
+use indexmap::map::IndexMap;
+use ontodev_sqlrest::Select;
+use serde_json::{Map, Value};
+use std::fmt::Write;
+
+/// The OWL formats that a template table can be exported as, named by their file extensions:
+/// functional syntax and Turtle.
+pub const OWL_FORMATS: [&str; 2] = ["ofn", "ttl"];
+
+/// The prefixes that OWL needs, declared unless the prefix table has them.
+const STANDARD_PREFIXES: [(&str, &str); 4] = [
+    ("owl", "http://www.w3.org/2002/07/owl#"),
+    ("rdf", "http://www.w3.org/1999/02/22-rdf-syntax-ns#"),
+    ("rdfs", "http://www.w3.org/2000/01/rdf-schema#"),
+    ("xsd", "http://www.w3.org/2001/XMLSchema#"),
+];
+
+/// Export a table in the style of a ROBOT template as OWL, in one of the OWL_FORMATS.
+/// The first row of the table is the template row, with an "ID" cell for the column of IDs,
+/// and every other row is an entity.
+/// The Template enum lists the template strings that are supported.
+pub async fn get_owl(config: &Config, table: &str, format: &str) -> Result<String, NanobotError> {
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Pool"))?;
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    if !valve.config.table.contains_key(table) {
+        return Err(format!("Invalid table '{}'", table).into());
+    }
+    // The template row is not valid data, so read the text view to get it as it is.
+    let select = Select::new(format!("\"{}_text_view\"", table));
+    let rows = get_table_from_pool(pool, &select)
+        .await
+        .map_err(|e| format!("Could not get the rows of '{}': {}", table, e))?;
+    let prefixes = get_prefix_map(config).await?;
+    render_owl(&prefixes, &rows, format)
+        .map_err(|e| NanobotError::GeneralError(format!("Could not export '{}': {}", table, e)))
+}
+
+/// A template string from the template row of a table, for the cells of one column.
+/// These are the template strings of ROBOT templates that Nanobot supports:
+///
+/// - `ID`: the CURIE or IRI of the entity
+/// - `LABEL`: an `rdfs:label`
+/// - `TYPE`: the type of the entity, e.g. `owl:Class` (the default) or `owl:NamedIndividual`
+/// - `A CURIE`: an annotation with a string, e.g. `A IAO:0000115` for a definition;
+///   `AT CURIE^^DATATYPE` for a typed literal, `AL CURIE@LANGUAGE` for a language tag,
+///   and `AI CURIE` for an IRI
+/// - `SC %`, `EC %`, and `DC %`: a named superclass, equivalent class, or disjoint class,
+///   or `SC PROPERTY some %` and `SC PROPERTY only %` for a restriction on an object property
+///
+/// Each can end with `SPLIT=|` to split the cells on a separator into several values.
+#[derive(Clone, Debug, PartialEq)]
+enum Template {
+    Id,
+    Label,
+    Type,
+    Annotation(String, Literal),
+    Class(ClassAxiom, ClassExpression),
+    Ignore,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Plain,
+    Typed(String),
+    Language(String),
+    Iri,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClassAxiom {
+    SubClassOf,
+    EquivalentClass,
+    DisjointWith,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ClassExpression {
+    Named,
+    Some(String),
+    Only(String),
+}
+
+/// Parse a template string into a template and an optional separator.
+fn parse_template(template: &str) -> Result<(Template, Option<String>), String> {
+    let template = template.trim();
+    let (template, split) = match template.split_once("SPLIT=") {
+        Some((template, split)) => (template.trim(), Some(split.to_string())),
+        None => (template, None),
+    };
+    let parsed = match template.split_once(' ') {
+        None => match template {
+            "" => Template::Ignore,
+            "ID" => Template::Id,
+            "LABEL" => Template::Label,
+            "TYPE" => Template::Type,
+            _ => return Err(format!("Unsupported template string '{}'", template)),
+        },
+        Some((kind, rest)) => {
+            let rest = rest.trim();
+            match kind {
+                "A" => Template::Annotation(rest.to_string(), Literal::Plain),
+                "AI" => Template::Annotation(rest.to_string(), Literal::Iri),
+                "AT" => match rest.split_once("^^") {
+                    Some((property, datatype)) => Template::Annotation(
+                        property.trim().to_string(),
+                        Literal::Typed(datatype.trim().to_string()),
+                    ),
+                    None => return Err(format!("Expected '^^DATATYPE' in '{}'", template)),
+                },
+                "AL" => match rest.split_once('@') {
+                    Some((property, language)) => Template::Annotation(
+                        property.trim().to_string(),
+                        Literal::Language(language.trim().to_string()),
+                    ),
+                    None => return Err(format!("Expected '@LANGUAGE' in '{}'", template)),
+                },
+                "SC" | "EC" | "DC" => {
+                    let axiom = match kind {
+                        "SC" => ClassAxiom::SubClassOf,
+                        "EC" => ClassAxiom::EquivalentClass,
+                        _ => ClassAxiom::DisjointWith,
+                    };
+                    let words = rest.split_whitespace().collect::<Vec<_>>();
+                    let expression = match words.as_slice() {
+                        ["%"] => ClassExpression::Named,
+                        [property, "some", "%"] => ClassExpression::Some(property.to_string()),
+                        [property, "only", "%"] => ClassExpression::Only(property.to_string()),
+                        _ => {
+                            return Err(format!(
+                                "Unsupported class expression '{}' in '{}'",
+                                rest, template
+                            ))
+                        }
+                    };
+                    Template::Class(axiom, expression)
+                }
+                _ => return Err(format!("Unsupported template string '{}'", template)),
+            }
+        }
+    };
+    Ok((parsed, split))
+}
+
+/// The statements about one entity, in the order of the columns.
+#[derive(Debug, Default)]
+struct Entity {
+    id: String,
+    entity_type: String,
+    annotations: Vec<(String, String, Literal)>,
+    axioms: Vec<(ClassAxiom, ClassExpression, String)>,
+}
+
+/// Render the rows of a template table as OWL in one of the OWL_FORMATS.
+fn render_owl(
+    prefixes: &IndexMap<String, String>,
+    rows: &[Map<String, Value>],
+    format: &str,
+) -> Result<String, String> {
+    let mut prefixes = prefixes.clone();
+    for (prefix, base) in STANDARD_PREFIXES {
+        if !prefixes.contains_key(prefix) {
+            prefixes.insert(prefix.to_string(), base.to_string());
+        }
+    }
+
+    let template_row = rows.first().ok_or("The table has no template row")?;
+    let mut templates = vec![];
+    for (column, value) in template_row {
+        if ["row_number", "row_order", "message", "history"].contains(&column.as_str()) {
+            continue;
+        }
+        let (template, split) = parse_template(value.as_str().unwrap_or_default())
+            .map_err(|e| format!("{} in column '{}'", e, column))?;
+        templates.push((column.to_string(), template, split));
+    }
+    if !templates.iter().any(|(_, t, _)| *t == Template::Id) {
+        return Err("The first row is not a template row with an 'ID' cell".to_string());
+    }
+
+    let mut entities = vec![];
+    for (number, row) in rows.iter().enumerate().skip(1) {
+        let mut entity = Entity {
+            entity_type: String::from("owl:Class"),
+            ..Default::default()
+        };
+        for (column, template, split) in &templates {
+            let cell = row.get(column).and_then(|v| v.as_str()).unwrap_or_default();
+            let values = match split {
+                Some(split) => cell.split(split.as_str()).collect::<Vec<_>>(),
+                None => vec![cell],
+            };
+            for value in values.into_iter().map(str::trim).filter(|v| !v.is_empty()) {
+                match template {
+                    Template::Id => entity.id = value.to_string(),
+                    Template::Label => entity.annotations.push((
+                        String::from("rdfs:label"),
+                        value.to_string(),
+                        Literal::Plain,
+                    )),
+                    Template::Type => entity.entity_type = value.to_string(),
+                    Template::Annotation(property, literal) => entity.annotations.push((
+                        property.to_string(),
+                        value.to_string(),
+                        literal.clone(),
+                    )),
+                    Template::Class(axiom, expression) => {
+                        entity
+                            .axioms
+                            .push((*axiom, expression.clone(), value.to_string()))
+                    }
+                    Template::Ignore => (),
+                }
+            }
+        }
+        if entity.id.is_empty() {
+            // Skip empty rows, but not rows that say something about an entity without an ID.
+            if entity.annotations.is_empty() && entity.axioms.is_empty() {
+                continue;
+            }
+            return Err(format!("Row {} has no ID", number + 1));
+        }
+        let declaration = declaration(&entity.entity_type)
+            .ok_or(format!("Unsupported type '{}'", entity.entity_type))?;
+        if !entity.axioms.is_empty() && declaration != "Class" {
+            return Err(format!(
+                "'{}' has class axioms but is not a class",
+                entity.id
+            ));
+        }
+        entities.push(entity);
+    }
+
+    match format {
+        "ofn" => render_functional(&prefixes, &entities),
+        "ttl" => render_turtle(&prefixes, &entities),
+        _ => Err(format!("Unsupported OWL format '{}'", format)),
+    }
+}
+
+/// The functional syntax declaration for an entity type.
+fn declaration(entity_type: &str) -> Option<&'static str> {
+    match entity_type {
+        "owl:Class" | "class" => Some("Class"),
+        "owl:NamedIndividual" | "individual" => Some("NamedIndividual"),
+        "owl:ObjectProperty" | "object property" => Some("ObjectProperty"),
+        "owl:DatatypeProperty" | "owl:DataProperty" | "data property" => Some("DataProperty"),
+        "owl:AnnotationProperty" | "annotation property" => Some("AnnotationProperty"),
+        _ => None,
+    }
+}
+
+/// A CURIE with a known prefix, or an IRI, as an OWL IRI.
+fn owl_iri(prefixes: &IndexMap<String, String>, id: &str) -> Result<String, String> {
+    if id.starts_with("http://") || id.starts_with("https://") {
+        return Ok(format!("<{}>", id));
+    }
+    match expand_curie(prefixes, id) {
+        Some(_) => Ok(id.to_string()),
+        None => Err(format!("Unknown prefix for '{}'", id)),
+    }
+}
+
+/// A CURIE with a known prefix, or an IRI, as a Turtle IRI,
+/// using the full IRI when the local ID is not a valid Turtle name.
+fn turtle_iri(prefixes: &IndexMap<String, String>, id: &str) -> Result<String, String> {
+    let iri = owl_iri(prefixes, id)?;
+    if iri.starts_with('<') {
+        return Ok(iri);
+    }
+    let (_, local_id) = id.split_once(':').unwrap_or_default();
+    let valid = local_id
+        .chars()
+        .all(|c| c.is_alphanumeric() || ['_', '-', '.'].contains(&c))
+        && !local_id.ends_with('.');
+    match valid {
+        true => Ok(iri),
+        false => Ok(format!(
+            "<{}>",
+            expand_curie(prefixes, id).unwrap_or_default()
+        )),
+    }
+}
+
+/// Quote a string, escaping it for both OWL functional syntax and Turtle.
+fn quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+fn literal(
+    prefixes: &IndexMap<String, String>,
+    value: &str,
+    literal: &Literal,
+    iri: fn(&IndexMap<String, String>, &str) -> Result<String, String>,
+) -> Result<String, String> {
+    Ok(match literal {
+        Literal::Plain => quote(value),
+        Literal::Typed(datatype) => format!("{}^^{}", quote(value), iri(prefixes, datatype)?),
+        Literal::Language(language) => format!("{}@{}", quote(value), language),
+        Literal::Iri => iri(prefixes, value)?,
+    })
+}
+
+fn render_functional(
+    prefixes: &IndexMap<String, String>,
+    entities: &[Entity],
+) -> Result<String, String> {
+    let mut owl = String::new();
+    for (prefix, base) in prefixes {
+        writeln!(owl, "Prefix({}:=<{}>)", prefix, base).unwrap_or_default();
+    }
+    owl.push_str("\nOntology(\n");
+    for entity in entities {
+        let id = owl_iri(prefixes, &entity.id)?;
+        let declaration = declaration(&entity.entity_type).unwrap_or("Class");
+        writeln!(owl, "Declaration({}({}))", declaration, id).unwrap_or_default();
+        for (property, value, kind) in &entity.annotations {
+            writeln!(
+                owl,
+                "AnnotationAssertion({} {} {})",
+                owl_iri(prefixes, property)?,
+                id,
+                literal(prefixes, value, kind, owl_iri)?
+            )
+            .unwrap_or_default();
+        }
+        for (axiom, expression, value) in &entity.axioms {
+            let class = owl_iri(prefixes, value)?;
+            let expression = match expression {
+                ClassExpression::Named => class,
+                ClassExpression::Some(property) => format!(
+                    "ObjectSomeValuesFrom({} {})",
+                    owl_iri(prefixes, property)?,
+                    class
+                ),
+                ClassExpression::Only(property) => format!(
+                    "ObjectAllValuesFrom({} {})",
+                    owl_iri(prefixes, property)?,
+                    class
+                ),
+            };
+            let axiom = match axiom {
+                ClassAxiom::SubClassOf => "SubClassOf",
+                ClassAxiom::EquivalentClass => "EquivalentClasses",
+                ClassAxiom::DisjointWith => "DisjointClasses",
+            };
+            writeln!(owl, "{}({} {})", axiom, id, expression).unwrap_or_default();
+        }
+    }
+    owl.push_str(")\n");
+    Ok(owl)
+}
+
+fn render_turtle(
+    prefixes: &IndexMap<String, String>,
+    entities: &[Entity],
+) -> Result<String, String> {
+    let mut ttl = String::new();
+    for (prefix, base) in prefixes {
+        writeln!(ttl, "@prefix {}: <{}> .", prefix, base).unwrap_or_default();
+    }
+    for entity in entities {
+        let declaration = declaration(&entity.entity_type).unwrap_or("Class");
+        let entity_type = match declaration {
+            "DataProperty" => "owl:DatatypeProperty".to_string(),
+            _ => format!("owl:{}", declaration),
+        };
+        let mut statements = vec![format!("a {}", entity_type)];
+        for (property, value, kind) in &entity.annotations {
+            statements.push(format!(
+                "{} {}",
+                turtle_iri(prefixes, property)?,
+                literal(prefixes, value, kind, turtle_iri)?
+            ));
+        }
+        for (axiom, expression, value) in &entity.axioms {
+            let class = turtle_iri(prefixes, value)?;
+            let object = match expression {
+                ClassExpression::Named => class,
+                ClassExpression::Some(property) => format!(
+                    "[ a owl:Restriction ; owl:onProperty {} ; owl:someValuesFrom {} ]",
+                    turtle_iri(prefixes, property)?,
+                    class
+                ),
+                ClassExpression::Only(property) => format!(
+                    "[ a owl:Restriction ; owl:onProperty {} ; owl:allValuesFrom {} ]",
+                    turtle_iri(prefixes, property)?,
+                    class
+                ),
+            };
+            let predicate = match axiom {
+                ClassAxiom::SubClassOf => "rdfs:subClassOf",
+                ClassAxiom::EquivalentClass => "owl:equivalentClass",
+                ClassAxiom::DisjointWith => "owl:disjointWith",
+            };
+            statements.push(format!("{} {}", predicate, object));
+        }
+        write!(
+            ttl,
+            "\n{}\n  {} .\n",
+            turtle_iri(prefixes, &entity.id)?,
+            statements.join(" ;\n  ")
+        )
+        .unwrap_or_default();
+    }
+    Ok(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rows() -> Vec<Map<String, Value>> {
+        let rows = json!([
+            {"row_number": 1, "id": "ID", "label": "LABEL", "parent": "SC %",
+             "part_of": "SC BFO:0000050 some % SPLIT=|", "definition": "A IAO:0000115"},
+            {"row_number": 2, "id": "EX:1", "label": "fin", "parent": "EX:0",
+             "part_of": "EX:2|EX:3", "definition": "A \"fin\"."},
+        ]);
+        serde_json::from_value(rows).unwrap()
+    }
+
+    fn prefixes() -> IndexMap<String, String> {
+        IndexMap::from([
+            ("EX".to_string(), "http://example.com/EX_".to_string()),
+            (
+                "BFO".to_string(),
+                "http://purl.obolibrary.org/obo/BFO_".to_string(),
+            ),
+            (
+                "IAO".to_string(),
+                "http://purl.obolibrary.org/obo/IAO_".to_string(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(
+            parse_template("AT rdfs:comment^^xsd:string SPLIT=|"),
+            Ok((
+                Template::Annotation(
+                    "rdfs:comment".to_string(),
+                    Literal::Typed("xsd:string".to_string())
+                ),
+                Some("|".to_string())
+            ))
+        );
+        assert!(parse_template("SC % and %").is_err());
+        assert!(parse_template("C %").is_err());
+    }
+
+    #[test]
+    fn test_render_functional() {
+        let owl = render_owl(&prefixes(), &rows(), "ofn").unwrap();
+        assert!(owl.starts_with("Prefix(EX:=<http://example.com/EX_>)\n"));
+        assert!(owl.contains("Declaration(Class(EX:1))\n"));
+        assert!(owl.contains("AnnotationAssertion(rdfs:label EX:1 \"fin\")\n"));
+        assert!(owl.contains("AnnotationAssertion(IAO:0000115 EX:1 \"A \\\"fin\\\".\")\n"));
+        assert!(owl.contains("SubClassOf(EX:1 EX:0)\n"));
+        assert!(owl.contains("SubClassOf(EX:1 ObjectSomeValuesFrom(BFO:0000050 EX:3))\n"));
+        assert!(owl.ends_with(")\n"));
+    }
+
+    #[test]
+    fn test_render_turtle() {
+        let ttl = render_owl(&prefixes(), &rows(), "ttl").unwrap();
+        assert!(ttl.contains("@prefix owl: <http://www.w3.org/2002/07/owl#> .\n"));
+        assert!(ttl.contains("\nEX:1\n  a owl:Class ;\n  rdfs:label \"fin\" ;\n"));
+        assert!(ttl.contains(
+            "rdfs:subClassOf [ a owl:Restriction ; \
+             owl:onProperty BFO:0000050 ; owl:someValuesFrom EX:2 ]"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_prefix() {
+        let mut rows = rows();
+        rows[1].insert("parent".to_string(), json!("UNKNOWN:1"));
+        assert_eq!(
+            render_owl(&prefixes(), &rows, "ofn"),
+            Err("Unknown prefix for 'UNKNOWN:1'".to_string())
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::config::Config;
+use crate::error::NanobotError;
+use crate::sql::get_table_from_pool;
+use indexmap::map::IndexMap;
+use ontodev_sqlrest::Select;
+
+/// The table of prefixes, with a `prefix` column and a `base` column.
+pub const PREFIX_TABLE: &str = "prefix";
+
+/// The datatype of the columns whose values are CURIEs.
+pub const CURIE_DATATYPE: &str = "curie";
+
+/// Get the map from each prefix to its base from the prefix table, in the order of the table,
+/// or an empty map when the schema does not have a prefix table.
+pub async fn get_prefix_map(config: &Config) -> Result<IndexMap<String, String>, NanobotError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    let mut prefixes = IndexMap::new();
+    if !valve.config.table.contains_key(PREFIX_TABLE) {
+        return Ok(prefixes);
+    }
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Pool"))?;
+    let mut select = Select::new(format!("\"{}\"", PREFIX_TABLE));
+    select.select(vec!["\"prefix\"", "\"base\""]);
+    let rows = get_table_from_pool(pool, &select)
+        .await
+        .map_err(|e| format!("Could not get the prefixes: {}", e))?;
+    for row in rows {
+        let prefix = row.get("prefix").and_then(|p| p.as_str());
+        let base = row.get("base").and_then(|b| b.as_str());
+        if let (Some(prefix), Some(base)) = (prefix, base) {
+            prefixes.insert(prefix.to_string(), base.to_string());
+        }
+    }
+    Ok(prefixes)
+}
+
+/// Expand a CURIE with a known prefix to an IRI, e.g. "obo:ZFA_0000354" to
+/// "http://purl.obolibrary.org/obo/ZFA_0000354".
+pub fn expand_curie(prefixes: &IndexMap<String, String>, curie: &str) -> Option<String> {
+    if curie.contains(char::is_whitespace) {
+        return None;
+    }
+    let (prefix, local_id) = curie.split_once(':')?;
+    prefixes
+        .get(prefix)
+        .map(|base| format!("{}{}", base, local_id))
+}
+
+/// Contract an IRI to a CURIE, using the prefix with the longest base that the IRI starts with.
+pub fn contract_iri(prefixes: &IndexMap<String, String>, iri: &str) -> Option<String> {
+    prefixes
+        .iter()
+        .filter(|(_, base)| !base.is_empty() && iri.starts_with(base.as_str()))
+        .max_by_key(|(_, base)| base.len())
+        .map(|(prefix, base)| format!("{}:{}", prefix, &iri[base.len()..]))
+}
+
+/// Whether a datatype is the CURIE datatype or one of its descendants.
+pub fn is_curie_datatype(config: &Config, datatype: &str) -> bool {
+    let valve = match config.valve.as_ref() {
+        Some(valve) => valve,
+        None => return false,
+    };
+    let mut datatype = datatype;
+    // The datatypes form a tree, but guard against a cycle in a bad schema.
+    for _ in 0..valve.config.datatype.len() + 1 {
+        if datatype == CURIE_DATATYPE {
+            return true;
+        }
+        datatype = match valve.config.datatype.get(datatype) {
+            Some(dt_config) if !dt_config.parent.is_empty() => &dt_config.parent,
+            _ => return false,
+        };
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes() -> IndexMap<String, String> {
+        IndexMap::from([
+            (
+                "obo".to_string(),
+                "http://purl.obolibrary.org/obo/".to_string(),
+            ),
+            (
+                "ZFA".to_string(),
+                "http://purl.obolibrary.org/obo/ZFA_".to_string(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_expand_curie() {
+        assert_eq!(
+            expand_curie(&prefixes(), "ZFA:0000354"),
+            Some("http://purl.obolibrary.org/obo/ZFA_0000354".to_string())
+        );
+        assert_eq!(expand_curie(&prefixes(), "EX:1"), None);
+        assert_eq!(expand_curie(&prefixes(), "obo: gill"), None);
+        assert_eq!(expand_curie(&prefixes(), "gill"), None);
+    }
+
+    #[test]
+    fn test_contract_iri() {
+        assert_eq!(
+            contract_iri(&prefixes(), "http://purl.obolibrary.org/obo/ZFA_0000354"),
+            Some("ZFA:0000354".to_string())
+        );
+        assert_eq!(
+            contract_iri(&prefixes(), "http://purl.obolibrary.org/obo/BFO_0000050"),
+            Some("obo:BFO_0000050".to_string())
+        );
+        assert_eq!(contract_iri(&prefixes(), "https://example.com/1"), None);
+    }
+}
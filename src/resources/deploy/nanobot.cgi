@@ -0,0 +1,6 @@
+#!/bin/sh
+# A CGI wrapper for the Nanobot project '{name}'.
+# Copy or link it into the cgi-bin directory of your web server.
+# Each request runs Nanobot once, with the request in the CGI variables and on STDIN.
+cd '{project}' || exit 1
+exec '{nanobot}' serve --cgi
@@ -0,0 +1,30 @@
+use crate::config::{check_table_names, Config};
+use crate::error::NanobotError;
+
+/// Write the rows of the given tables, or of all of the tables, from the database back to their
+/// TSV files, keeping their column order, so that edits made in Nanobot can be committed.
+/// With `save_dir`, write the files to that directory instead of their configured paths.
+pub fn save(
+    config: &Config,
+    tables: &[String],
+    save_dir: Option<&str>,
+) -> Result<String, NanobotError> {
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Valve"))?;
+    check_table_names(config, tables)?;
+    let save_dir = save_dir.map(|d| d.to_string());
+    if tables.is_empty() {
+        valve
+            .save_all_tables(&save_dir)
+            .map_err(|e| format!("VALVE error while saving: {:?}", e))?;
+        Ok(String::from("Saved all tables"))
+    } else {
+        let table_list = tables.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+        valve
+            .save_tables(&table_list, &save_dir)
+            .map_err(|e| format!("VALVE error while saving: {:?}", e))?;
+        Ok(format!("Saved '{}'", table_list.join("', '")))
+    }
+}
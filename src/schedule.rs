@@ -0,0 +1,188 @@
+use chrono::{Datelike, Timelike};
+
+/// A cron-like schedule with five fields: minute, hour, day of month, month, and day of week.
+/// Each field may be `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`,
+/// or a comma-separated list of these. Day of week is 0-6 starting on Sunday (7 is also Sunday).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // Cron matches either the day of month or the day of week when both are restricted.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Schedule, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Schedule '{}' must have 5 fields: minute hour day month weekday",
+                expression
+            ));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    /// Check whether the given time falls in a minute that this schedule runs in.
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        let day = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes[time.minute() as usize]
+            && self.hours[time.hour() as usize]
+            && self.months[time.month() as usize]
+            && day
+    }
+}
+
+/// Parse the given schedules, each named after the action that it runs.
+/// A schedule that does not parse is left out, with a warning.
+pub fn parse_schedules<'a, I>(schedules: I) -> Vec<(String, Schedule)>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut parsed = vec![];
+    for (name, expression) in schedules {
+        match Schedule::parse(expression) {
+            Ok(schedule) => parsed.push((name.to_string(), schedule)),
+            Err(e) => tracing::warn!("Action '{}' will not be scheduled: {}", name, e),
+        }
+    }
+    parsed
+}
+
+/// The names of the schedules that run in the minute of the given time.
+pub fn due<'a, T: Datelike + Timelike>(
+    schedules: &'a [(String, Schedule)],
+    time: &T,
+) -> Vec<&'a str> {
+    schedules
+        .iter()
+        .filter(|(_, schedule)| schedule.matches(time))
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// Parse one field of a schedule into a list of flags indexed by value from 0 to `max`.
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut flags = vec![false; max + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step in '{}'", part)),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, max)?, parse_value(end, min, max)?)
+        } else {
+            let value = parse_value(range, min, max)?;
+            // A single value with a step, e.g. 5/15, runs from that value to the end.
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(format!("Invalid range '{}'", range));
+        }
+        for value in (start..=end).step_by(step) {
+            flags[value] = true;
+        }
+    }
+    Ok(flags)
+}
+
+fn parse_value(value: &str, min: usize, max: usize) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(v) if v >= min && v <= max => Ok(v),
+        _ => Err(format!(
+            "Invalid value '{}': expected a number from {} to {}",
+            value, min, max
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn time(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Schedule::parse("* * * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("5-1 * * * *").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        // Nightly at 02:30
+        let schedule = Schedule::parse("30 2 * * *").unwrap();
+        assert!(schedule.matches(&time(2023, 6, 1, 2, 30)));
+        assert!(!schedule.matches(&time(2023, 6, 1, 2, 31)));
+
+        // Every 15 minutes during working hours on weekdays
+        let schedule = Schedule::parse("*/15 9-17 * * 1-5").unwrap();
+        // 2023-06-02 is a Friday, 2023-06-03 is a Saturday
+        assert!(schedule.matches(&time(2023, 6, 2, 9, 45)));
+        assert!(!schedule.matches(&time(2023, 6, 2, 9, 50)));
+        assert!(!schedule.matches(&time(2023, 6, 3, 9, 45)));
+
+        // The first of the month or any Sunday (7 is Sunday)
+        let schedule = Schedule::parse("0 0 1 * 7").unwrap();
+        assert!(schedule.matches(&time(2023, 6, 1, 0, 0)));
+        assert!(schedule.matches(&time(2023, 6, 4, 0, 0)));
+        assert!(!schedule.matches(&time(2023, 6, 5, 0, 0)));
+    }
+
+    #[test]
+    fn test_due() {
+        let schedules = parse_schedules([
+            ("nightly", "30 2 * * *"),
+            ("hourly", "0 * * * *"),
+            ("broken", "every day"),
+        ]);
+        let names = schedules
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["nightly", "hourly"]);
+
+        assert_eq!(due(&schedules, &time(2023, 6, 1, 2, 30)), ["nightly"]);
+        assert_eq!(due(&schedules, &time(2023, 6, 1, 3, 0)), ["hourly"]);
+        assert!(due(&schedules, &time(2023, 6, 1, 3, 1)).is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// A TSV file, split into a header and rows of cells.
+#[derive(Clone, Debug)]
+pub struct Tsv {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Tsv {
+    pub fn read(path: &Path) -> Result<Tsv, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        Ok(Tsv::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Tsv {
+        let mut lines = contents.lines().filter(|line| !line.is_empty());
+        let split = |line: &str| line.split('\t').map(|s| s.to_string()).collect::<Vec<_>>();
+        Tsv {
+            header: lines.next().map(split).unwrap_or_default(),
+            rows: lines.map(split).collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let mut contents = self.header.join("\t");
+        contents.push('\n');
+        for row in &self.rows {
+            contents.push_str(&row.join("\t"));
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+            .map_err(|e| format!("Could not write '{}': {}", path.display(), e))
+    }
+
+    pub fn get<'a>(&self, row: &'a [String], column: &str) -> &'a str {
+        match self.header.iter().position(|h| h == column) {
+            Some(i) => row.get(i).map(|s| s.as_str()).unwrap_or_default(),
+            None => "",
+        }
+    }
+
+    /// Add any columns of the current layout that are missing, in the order of the layout,
+    /// keeping any other columns after them. Return the names of the added columns.
+    pub fn add_missing_columns(&mut self, layout: &[String]) -> Vec<String> {
+        let added = layout
+            .iter()
+            .filter(|c| !self.header.contains(c))
+            .cloned()
+            .collect::<Vec<_>>();
+        if added.is_empty() {
+            return added;
+        }
+        let mut header = layout.to_vec();
+        header.extend(self.header.iter().filter(|c| !layout.contains(c)).cloned());
+        self.rows = self
+            .rows
+            .iter()
+            .map(|row| {
+                header
+                    .iter()
+                    .map(|column| self.get(row, column).to_string())
+                    .collect()
+            })
+            .collect();
+        self.header = header;
+        added
+    }
+}
+
+/// Check the VALVE schema tables for problems that would otherwise make VALVE fail
+/// without saying how to fix them: tables whose files are missing, columns of unknown tables,
+/// unknown datatypes, structures that refer to unknown tables or columns,
+/// and data files whose headers do not match the columns declared for them.
+/// Return a list of the problems found, which is empty when the schema looks right.
+pub fn check_schema(valve_path: &str) -> Result<Vec<String>, String> {
+    let table = Tsv::read(Path::new(valve_path))?;
+    let mut problems = vec![];
+    for column in ["table", "path"] {
+        if !table.header.iter().any(|h| h == column) {
+            problems.push(format!(
+                "'{}' has no '{}' column: check that it is a VALVE table table",
+                valve_path, column
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    let tables = table
+        .rows
+        .iter()
+        .map(|row| table.get(row, "table"))
+        .collect::<HashSet<_>>();
+    let find_path = |table_type: &str| {
+        table
+            .rows
+            .iter()
+            .find(|row| table.get(row, "type") == table_type)
+            .map(|row| table.get(row, "path"))
+    };
+    let (column_path, datatype_path) = match (find_path("column"), find_path("datatype")) {
+        (Some(c), Some(d)) => (c, d),
+        _ => {
+            return Ok(vec![format!(
+                "'{}' must have rows with the types 'column' and 'datatype'",
+                valve_path
+            )])
+        }
+    };
+    let column = Tsv::read(Path::new(column_path))?;
+    let datatype = Tsv::read(Path::new(datatype_path))?;
+    let datatypes = datatype
+        .rows
+        .iter()
+        .map(|row| datatype.get(row, "datatype"))
+        .collect::<HashSet<_>>();
+
+    // Datatypes must have known parents.
+    for (i, row) in datatype.rows.iter().enumerate() {
+        let parent = datatype.get(row, "parent");
+        if !parent.is_empty() && !datatypes.contains(parent) {
+            problems.push(format!(
+                "{} line {}: datatype '{}' has parent '{}', which is not defined in {}",
+                datatype_path,
+                i + 2,
+                datatype.get(row, "datatype"),
+                parent,
+                datatype_path
+            ));
+        }
+    }
+
+    // Columns must belong to known tables, use known datatypes,
+    // and have structures that refer to known tables and columns.
+    let columns = column
+        .rows
+        .iter()
+        .map(|row| (column.get(row, "table"), column.get(row, "column")))
+        .collect::<HashSet<_>>();
+    let from = Regex::new(r"^from\(\s*([^.\s]+)\.([^)\s]+)\s*\)$").unwrap();
+    let tree = Regex::new(r"^tree\(\s*([^)\s]+)\s*\)$").unwrap();
+    for (i, row) in column.rows.iter().enumerate() {
+        let location = format!("{} line {}", column_path, i + 2);
+        let (t, c) = (column.get(row, "table"), column.get(row, "column"));
+        if !tables.contains(t) {
+            problems.push(format!(
+                "{}: column '{}' belongs to table '{}', which is not defined in {}",
+                location, c, t, valve_path
+            ));
+        }
+        for field in ["datatype", "nulltype"] {
+            let name = column.get(row, field);
+            if !name.is_empty() && !datatypes.contains(name) {
+                problems.push(format!(
+                    "{}: column '{}.{}' has {} '{}', which is not defined in {}",
+                    location, t, c, field, name, datatype_path
+                ));
+            }
+        }
+        let structure = column.get(row, "structure").trim();
+        if let Some(captures) = from.captures(structure) {
+            let (ft, fc) = (&captures[1], &captures[2]);
+            if !tables.contains(ft) {
+                problems.push(format!(
+                    "{}: column '{}.{}' refers to table '{}', which is not defined in {}",
+                    location, t, c, ft, valve_path
+                ));
+            } else if !columns.contains(&(ft, fc)) {
+                problems.push(format!(
+                    "{}: column '{}.{}' refers to column '{}.{}', which is not defined in {}",
+                    location, t, c, ft, fc, column_path
+                ));
+            }
+        } else if let Some(captures) = tree.captures(structure) {
+            if !columns.contains(&(t, &captures[1])) {
+                problems.push(format!(
+                    "{}: column '{}.{}' is a tree of column '{}.{}', which is not defined in {}",
+                    location, t, c, t, &captures[1], column_path
+                ));
+            }
+        }
+    }
+
+    // The header of each table's file must match the columns declared for it.
+    for row in &table.rows {
+        let (t, path) = (table.get(row, "table"), table.get(row, "path"));
+        let declared = column
+            .rows
+            .iter()
+            .filter(|r| column.get(r, "table") == t)
+            .map(|r| column.get(r, "column"))
+            .collect::<Vec<_>>();
+        if declared.is_empty() {
+            problems.push(format!(
+                "Table '{}' has no columns: add them to {}",
+                t, column_path
+            ));
+        }
+        if path.is_empty() || !path.ends_with(".tsv") {
+            continue;
+        }
+        if !Path::new(path).is_file() {
+            problems.push(format!(
+                "Table '{}' has path '{}', which does not exist: create the file or fix the path in {}",
+                t, path, valve_path
+            ));
+            continue;
+        }
+        let header = Tsv::read(Path::new(path))?.header;
+        for name in &header {
+            if !declared.contains(&name.as_str()) {
+                problems.push(format!(
+                    "{} has column '{}', which is not declared for table '{}' in {}",
+                    path, name, t, column_path
+                ));
+            }
+        }
+        for name in &declared {
+            if !header.iter().any(|h| h == name) {
+                problems.push(format!(
+                    "Column '{}.{}' is declared in {} but is missing from {}",
+                    t, name, column_path, path
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const TABLE: &str = "table\tpath\ttype
+table\t{dir}/table.tsv\ttable
+column\t{dir}/column.tsv\tcolumn
+datatype\t{dir}/datatype.tsv\tdatatype
+penguin\t{dir}/penguin.tsv\t
+species\t{dir}/species.tsv\t
+";
+
+    const COLUMN: &str = "table\tcolumn\tdatatype\tnulltype\tstructure
+table\ttable\ttext\t\t
+table\tpath\ttext\t\t
+table\ttype\ttext\tempty\t
+column\ttable\ttext\t\t
+column\tcolumn\ttext\t\t
+column\tdatatype\ttext\t\t
+column\tnulltype\ttext\tempty\t
+column\tstructure\ttext\tempty\t
+datatype\tdatatype\ttext\t\t
+datatype\tparent\ttext\tempty\t
+penguin\tid\ttext\t\tprimary
+penguin\tspecies\ttext\t\tfrom(species.name)
+species\tname\ttext\t\t
+species\tparent\ttext\tempty\ttree(name)
+";
+
+    const DATATYPE: &str = "datatype\tparent
+text\t
+empty\ttext
+";
+
+    /// Write the schema to a new directory, with the given files in place of the defaults,
+    /// and return the path of its table table.
+    fn write_schema(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nanobot-schema-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let defaults = [
+            ("table.tsv", TABLE),
+            ("column.tsv", COLUMN),
+            ("datatype.tsv", DATATYPE),
+            ("penguin.tsv", "id\tspecies\n1\tAdelie\n"),
+            ("species.tsv", "name\tparent\nAdelie\t\n"),
+        ];
+        for (file, contents) in defaults {
+            let contents = files
+                .iter()
+                .find(|(f, _)| *f == file)
+                .map_or(contents, |(_, c)| c);
+            let contents = contents.replace("{dir}", dir.to_str().unwrap());
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir.join("table.tsv")
+    }
+
+    fn problems(name: &str, files: &[(&str, &str)]) -> Vec<String> {
+        let path = write_schema(name, files);
+        let problems = check_schema(path.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        problems
+    }
+
+    #[test]
+    fn test_tsv() {
+        let mut tsv = Tsv::parse("b\tx\n1\t2\n\n3\n");
+        assert_eq!(tsv.header, ["b", "x"]);
+        assert_eq!(tsv.get(&tsv.rows[1], "b"), "3");
+        assert_eq!(tsv.get(&tsv.rows[1], "x"), "");
+        assert_eq!(tsv.get(&tsv.rows[0], "y"), "");
+
+        let layout = ["a".to_string(), "b".to_string()];
+        assert_eq!(tsv.add_missing_columns(&layout), ["a"]);
+        assert_eq!(tsv.header, ["a", "b", "x"]);
+        assert_eq!(tsv.rows, [["", "1", "2"], ["", "3", ""]]);
+        assert!(tsv.add_missing_columns(&layout).is_empty());
+
+        let path = std::env::temp_dir().join(format!("nanobot-tsv-{}.tsv", std::process::id()));
+        tsv.write(&path).unwrap();
+        let read = Tsv::read(&path).unwrap();
+        assert_eq!((read.header, read.rows), (tsv.header, tsv.rows));
+        fs::remove_file(&path).unwrap();
+        assert!(Tsv::read(&path).is_err());
+    }
+
+    #[test]
+    fn test_check_schema_valid() {
+        assert!(problems("valid", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_check_schema_errors() {
+        let cases: [(&str, (&str, String), &str); 12] = [
+            (
+                "no-path",
+                (
+                    "table.tsv",
+                    "table\tfile\npenguin\tpenguin.tsv\n".to_string(),
+                ),
+                "has no 'path' column",
+            ),
+            (
+                "no-types",
+                ("table.tsv", TABLE.replace("\tcolumn\n", "\t\n")),
+                "must have rows with the types 'column' and 'datatype'",
+            ),
+            (
+                "parent",
+                (
+                    "datatype.tsv",
+                    DATATYPE.replace("empty\ttext", "empty\tword"),
+                ),
+                "datatype 'empty' has parent 'word', which is not defined",
+            ),
+            (
+                "table",
+                ("column.tsv", format!("{}krill\tid\ttext\t\t\n", COLUMN)),
+                "column 'id' belongs to table 'krill', which is not defined",
+            ),
+            (
+                "datatype",
+                (
+                    "column.tsv",
+                    COLUMN.replace("penguin\tid\ttext", "penguin\tid\tinteger"),
+                ),
+                "column 'penguin.id' has datatype 'integer', which is not defined",
+            ),
+            (
+                "nulltype",
+                (
+                    "column.tsv",
+                    COLUMN.replace("table\ttype\ttext\tempty", "table\ttype\ttext\tnull"),
+                ),
+                "column 'table.type' has nulltype 'null', which is not defined",
+            ),
+            (
+                "from-table",
+                (
+                    "column.tsv",
+                    COLUMN.replace("from(species.name)", "from(krill.name)"),
+                ),
+                "column 'penguin.species' refers to table 'krill', which is not defined",
+            ),
+            (
+                "from-column",
+                (
+                    "column.tsv",
+                    COLUMN.replace("from(species.name)", "from(species.label)"),
+                ),
+                "column 'penguin.species' refers to column 'species.label', which is not defined",
+            ),
+            (
+                "tree",
+                ("column.tsv", COLUMN.replace("tree(name)", "tree(label)")),
+                "column 'species.parent' is a tree of column 'species.label', which is not defined",
+            ),
+            (
+                "no-columns",
+                ("table.tsv", format!("{}krill\t\t\n", TABLE)),
+                "Table 'krill' has no columns",
+            ),
+            ("missing-file", ("penguin.tsv", String::new()), "has path"),
+            (
+                "header",
+                ("penguin.tsv", "id\tisland\n1\tDream\n".to_string()),
+                "has column 'island', which is not declared for table 'penguin'",
+            ),
+        ];
+        for (name, (file, contents), expected) in cases {
+            let found = if name == "missing-file" {
+                let path = write_schema(name, &[]);
+                fs::remove_file(path.with_file_name(file)).unwrap();
+                let found = check_schema(path.to_str().unwrap()).unwrap();
+                fs::remove_dir_all(path.parent().unwrap()).unwrap();
+                found
+            } else {
+                problems(name, &[(file, contents.as_str())])
+            };
+            assert!(
+                found.iter().any(|p| p.contains(expected)),
+                "{}: expected '{}' in {:?}",
+                name,
+                expected,
+                found
+            );
+        }
+
+        // A declared column that is missing from the file is reported too.
+        let found = problems("missing-column", &[("penguin.tsv", "id\n1\n")]);
+        assert_eq!(
+            found.len(),
+            1,
+            "expected only the missing column in {:?}",
+            found
+        );
+        assert!(found[0].starts_with("Column 'penguin.species' is declared in"));
+    }
+}
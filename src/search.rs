@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::error::NanobotError;
+use ontodev_sqlrest::{Filter, Select, SelectColumn};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// The number of matches returned when the request does not have a `limit`.
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+/// How well a term matches the search text, best first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Exact,
+    Prefix,
+    Substring,
+}
+
+impl Rank {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rank::Exact => "exact",
+            Rank::Prefix => "prefix",
+            Rank::Substring => "substring",
+        }
+    }
+}
+
+/// Search the IDs and labels of the tables configured with `[tables.NAME.search]`,
+/// ignoring case, and return at most `limit` matches:
+/// exact matches first, then matches at the start of the ID or label, then the rest,
+/// with shorter labels first within each of these.
+/// Each match has its `table`, `id`, `label`, and the kind of `match`.
+pub fn search_terms(config: &Config, text: &str, limit: usize) -> Result<Vec<Value>, NanobotError> {
+    let text = text.trim().to_lowercase();
+    if text.is_empty() || limit == 0 {
+        return Ok(vec![]);
+    }
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or(NanobotError::NotInitialized("Pool"))?;
+
+    let mut matches: Vec<(Rank, String, String, String)> = vec![];
+    for (table, table_config) in &config.tables {
+        let search = match &table_config.search {
+            Some(search) => search,
+            None => continue,
+        };
+        // The best matches for either column are among the shortest values that contain the text.
+        for column in [&search.id, &search.label] {
+            let length = format!("LENGTH({})", column);
+            let mut select = Select::new(format!("\"{}\"", table));
+            select
+                .add_explicit_select(&SelectColumn::new(&search.id, Some("id"), None))
+                .add_explicit_select(&SelectColumn::new(&search.label, Some("label"), None))
+                .filter(vec![Filter::new(
+                    column,
+                    "ilike",
+                    json!(format!("\"%{}%\"", text.replace('"', ""))),
+                )?])
+                .order_by(vec![length.as_str(), column.as_str()])
+                .limit(limit);
+            let rows = select
+                .fetch_rows_as_json(pool, &HashMap::new())
+                .map_err(|e| format!("Could not search '{}': {}", table, e))?;
+            for row in rows {
+                let id = cell_string(&row, "id");
+                if matches.iter().any(|(_, t, i, _)| t == table && i == &id) {
+                    continue;
+                }
+                let label = cell_string(&row, "label");
+                matches.push((rank(&text, &id, &label), table.clone(), id, label));
+            }
+        }
+    }
+
+    matches.sort_by(|a, b| match a.0.cmp(&b.0) {
+        Ordering::Equal => (a.3.len(), &a.3).cmp(&(b.3.len(), &b.3)),
+        order => order,
+    });
+    Ok(matches
+        .into_iter()
+        .take(limit)
+        .map(|(rank, table, id, label)| {
+            json!({
+                "table": table,
+                "id": id,
+                "label": label,
+                "match": rank.as_str(),
+            })
+        })
+        .collect())
+}
+
+/// Rank a term for the lowercase search text.
+fn rank(text: &str, id: &str, label: &str) -> Rank {
+    let id = id.to_lowercase();
+    let label = label.to_lowercase();
+    if id == text || label == text {
+        Rank::Exact
+    } else if id.starts_with(text) || label.starts_with(text) {
+        Rank::Prefix
+    } else {
+        Rank::Substring
+    }
+}
+
+fn cell_string(row: &Map<String, Value>, column: &str) -> String {
+    match row.get(column) {
+        Some(Value::String(s)) => s.to_string(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
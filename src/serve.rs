@@ -1,29 +1,77 @@
-use crate::{config::Config, get, ldtab, tree_view};
+use crate::{
+    action::{self, CommandOutput},
+    cache::{CachedResponse, ResponseCache},
+    class_tree,
+    config::{build_valve, Config},
+    edit::{self, Edits},
+    error::NanobotError,
+    get,
+    import::read_rows,
+    job::{JobQueue, JOB_DIR},
+    ldtab, load, owl, prefix, save,
+    schedule::{due, parse_schedules},
+    search::{search_terms, DEFAULT_SEARCH_LIMIT},
+    sql::{
+        get_all_row_numbers_from_pool, get_message_counts_from_pool, get_message_summary_from_pool,
+        get_total_from_pool,
+    },
+    tree_view,
+    validate::get_validation_errors,
+};
 use ansi_to_html;
 use axum::{
-    extract::{Form, Path, Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json, Redirect},
-    routing::get,
+    body::{Bytes, StreamBody},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Form, Multipart, OriginalUri, Path, Query, State,
+    },
+    http::{header, HeaderMap, Method, Request, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Json, Redirect, Response,
+    },
+    routing::{get, post},
     Router,
 };
-use chrono::prelude::Local;
+use chrono::{prelude::Local, Timelike};
 use enquote::unquote;
-use futures::executor::block_on;
+use futures::{
+    future::{self, BoxFuture},
+    stream, FutureExt, Stream, StreamExt,
+};
 use html_escape::encode_text_to_string;
+use indexmap::map::IndexMap;
 use ontodev_hiccup::hiccup;
 use ontodev_sqlrest::{parse, Filter, Select, SelectColumn};
 use ontodev_valve::{
     ast::Expression,
     valve::{Valve, ValveColumnConfig},
 };
-use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as SerdeValue};
 use std::{
-    collections::HashMap, collections::HashSet, net::SocketAddr, process::Command, sync::Arc,
+    any::Any,
+    collections::HashMap,
+    collections::HashSet,
+    convert::Infallible,
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
-use tokio::signal;
-use tower_http::services::ServeDir;
+use tokio::{
+    signal,
+    sync::{
+        broadcast::{self, error::RecvError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tower_http::{catch_panic::CatchPanicLayer, services::ServeDir};
 use wiring_rs::util::signature;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -35,6 +83,115 @@ enum RequestType {
 #[derive(Debug)]
 pub struct AppState {
     pub config: Config,
+    pub events: broadcast::Sender<TableEvent>,
+    pub progress: broadcast::Sender<ProgressEvent>,
+    pub jobs: Arc<JobQueue>,
+    pub cache: ResponseCache,
+}
+
+impl AppState {
+    pub fn new(config: Config) -> AppState {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let jobs = JobQueue::shared(std::path::Path::new(JOB_DIR));
+        let progress = jobs.progress();
+        let cache = ResponseCache::new(config.cache_size);
+        AppState {
+            config,
+            events,
+            progress,
+            jobs,
+            cache,
+        }
+    }
+
+    /// Create the state for another database served by this instance.
+    /// Table events are separate, but the job queue and progress events are shared.
+    pub fn for_database(&self, config: Config) -> AppState {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let cache = ResponseCache::new(config.cache_size);
+        AppState {
+            config,
+            events,
+            progress: self.progress.clone(),
+            jobs: self.jobs.clone(),
+            cache,
+        }
+    }
+
+    /// Send the given event to every connected WebSocket client. Having no clients is not an
+    /// error, so the result of the send is ignored. Cached responses for the table are dropped.
+    pub fn notify(&self, event: TableEvent) {
+        tracing::debug!("EVENT {:?}", event);
+        match &event {
+            TableEvent::Insert { table, .. }
+            | TableEvent::Update { table, .. }
+            | TableEvent::Delete { table, .. }
+            | TableEvent::Reload { table: Some(table) } => self.cache.bump(table),
+            TableEvent::Reload { table: None } => self.cache.bump_all(),
+        }
+        let _ = self.events.send(event);
+    }
+
+    /// Report that the given operation has completed `step` of `total` steps to every client
+    /// listening on the `/events` stream.
+    pub fn report(&self, operation: &str, step: usize, total: usize, message: &str) {
+        let progress = ProgressEvent::new(operation, step, total, message);
+        tracing::debug!("PROGRESS {:?}", progress);
+        let _ = self.progress.send(progress);
+    }
+}
+
+/// The number of events buffered for each WebSocket client before the oldest are dropped.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1000;
+
+/// A change to the contents of a table, pushed to clients connected to the `/ws` endpoint.
+/// A `Reload` without a table indicates that any table may have changed (e.g. after an undo).
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TableEvent {
+    Insert { table: String, row: u32 },
+    Update { table: String, row: u32 },
+    Delete { table: String, row: u32 },
+    Reload { table: Option<String> },
+}
+
+/// Progress of a long-running operation such as an action, a save, or a validation, streamed
+/// to clients connected to the `/events` endpoint as server-sent events.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProgressEvent {
+    pub operation: String,
+    pub step: usize,
+    pub total: usize,
+    pub message: String,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<CommandOutput>,
+}
+
+impl ProgressEvent {
+    pub fn new(operation: &str, step: usize, total: usize, message: &str) -> ProgressEvent {
+        ProgressEvent {
+            operation: operation.to_string(),
+            step,
+            total,
+            message: message.to_string(),
+            done: step >= total,
+            output: None,
+        }
+    }
+
+    /// A line of output written by the running step of the given operation.
+    pub fn output(
+        operation: &str,
+        step: usize,
+        total: usize,
+        output: &CommandOutput,
+    ) -> ProgressEvent {
+        ProgressEvent {
+            output: Some(output.clone()),
+            ..ProgressEvent::new(operation, step, total, "")
+        }
+    }
 }
 
 pub type RequestParams = HashMap<String, String>;
@@ -49,11 +206,35 @@ pub fn build_app(shared_state: Arc<AppState>) -> Router {
     // build our application with a route
     let router = Router::new()
         .route("/", get(root))
+        .route("/ws", get(get_ws))
+        .route("/events", get(get_events))
+        .route("/action/:name", get(get_action).post(post_action))
+        .route("/job/:id", get(get_job))
+        .route("/messages", get(get_messages_summary))
+        .route("/messages.json", get(get_messages_summary))
+        .route("/messages.pretty.json", get(get_messages_summary))
+        .route("/webhook/:name", post(post_webhook))
+        .route("/save", post(post_save))
+        .route("/datatype.json", get(get_datatypes))
+        .route("/search", get(get_search))
+        .route("/prefix.json", get(get_prefixes))
+        .route("/tree", get(get_class_tree))
+        .route("/tree/:term", get(get_class_tree))
         .route("/:table", get(get_table).post(post_table))
+        .route("/:table/bulk", post(post_bulk))
+        .route("/:table/validate", post(post_validate))
+        .route("/:table/columns.json", get(get_columns_json))
+        .route("/:table/tree", get(get_hierarchy))
+        .route("/:table/conflicts", get(get_conflicts).post(post_conflicts))
+        .route("/:table/upload", get(get_upload).post(post_upload))
+        .route("/:table/statements.json", get(get_statements))
         .route("/:table/:subject", get(get_tree))
         .route("/:table/row/:row_number", get(get_row).post(post_row))
-        .with_state(shared_state);
-    if let Some(asset_path) = asset_path {
+        .route("/:table/row/:row_number/history", get(get_row_history))
+        .route("/:table/row/:row_number/history.json", get(get_row_history))
+        .fallback(not_found)
+        .with_state(shared_state.clone());
+    let router = if let Some(asset_path) = asset_path {
         let serve_dir = ServeDir::new(asset_path);
         tracing::debug!("Serving static assets from {:?}", serve_dir);
         Router::new()
@@ -61,35 +242,740 @@ pub fn build_app(shared_state: Arc<AppState>) -> Router {
             .merge(router)
     } else {
         router
+    };
+    router
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            reject_changes_when_read_only,
+        ))
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::from_fn(render_error_json))
+        .layer(middleware::from_fn_with_state(
+            shared_state,
+            render_error_page,
+        ))
+}
+
+const READ_ONLY_MESSAGE: &str = "This Nanobot is read-only";
+
+/// In read-only mode, refuse every request that could change the data or run an action.
+/// Only GET and HEAD requests are served, and the pages for uploads and actions are refused too.
+async fn reject_changes_when_read_only<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if state.config.read_only && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return (StatusCode::FORBIDDEN, READ_ONLY_MESSAGE).into_response();
     }
+    next.run(request).await
 }
 
-#[tokio::main]
-pub async fn app(config: &Config) -> Result<String, String> {
-    let shared_state = Arc::new(AppState {
-        //TODO: use &config instead of config.clone()?
-        config: config.clone(),
+async fn not_found(uri: Uri) -> impl IntoResponse {
+    (
+        StatusCode::NOT_FOUND,
+        format!("No page at '{}'", uri.path()),
+    )
+}
+
+/// Turn a panic in a handler into a 500 response, instead of dropping the connection.
+fn handle_panic(error: Box<dyn Any + Send + 'static>) -> Response {
+    let details = if let Some(s) = error.downcast_ref::<String>() {
+        s.to_string()
+    } else if let Some(s) = error.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "Unknown panic".to_string()
+    };
+    tracing::error!("Request handler panicked: {}", details);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Internal server error: {}", details),
+    )
+        .into_response()
+}
+
+/// When a browser gets an error response with a short text or HTML message,
+/// render the message using the error template, keeping the status code.
+async fn render_error_page<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let wants_html = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("text/html"))
+        .unwrap_or(false);
+    let response = next.run(request).await;
+    let status = response.status();
+    if !wants_html || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/"))
+        .unwrap_or(true);
+    if !is_text {
+        return response;
+    }
+
+    let body = match hyper::body::to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return (status, e.to_string()).into_response(),
+    };
+    let message = String::from_utf8_lossy(&body).to_string();
+    // Leave complete pages alone:
+    if message.trim_start().to_lowercase().starts_with("<!doctype") {
+        return (status, Html(message)).into_response();
+    }
+    error_page(&state, status, &message)
+}
+
+/// When an API request gets an error response with a short text or HTML message,
+/// send the message as JSON instead: `{"error": "Not Found", "detail": "...", "status": 404}`.
+/// API requests are those for a JSON format, by extension or Accept header, or with a JSON body.
+async fn render_error_json<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = request.uri().path();
+    let wants_json = path.ends_with(".json")
+        || path.ends_with(".jsonl")
+        || matches!(
+            negotiate_format(path, request.headers()),
+            Some("json") | Some("jsonl")
+        )
+        || request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/json"))
+            .unwrap_or(false);
+    let response = next.run(request).await;
+    let status = response.status();
+    if !wants_json || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/"))
+        .unwrap_or(true);
+    if !is_text {
+        return response;
+    }
+
+    let body = match hyper::body::to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return (status, e.to_string()).into_response(),
+    };
+    let detail = String::from_utf8_lossy(&body).trim().to_string();
+    let error = json!({
+        "error": status.canonical_reason().unwrap_or_default(),
+        "detail": detail,
+        "status": status.as_u16(),
+    });
+    (status, Json(error)).into_response()
+}
+
+fn error_page(state: &AppState, status: StatusCode, message: &str) -> Response {
+    let page = json!({
+        "page": page_context(state, &format!("{}/", state.config.base_path)),
+        "title": status.to_string(),
+        "status": status.as_u16(),
+        "reason": status.canonical_reason().unwrap_or_default(),
+        "message": message,
     });
+    match get::page_to_html(&state.config, "error", &page) {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(e) => {
+            tracing::warn!("Could not render error page: {}", e);
+            (status, message.to_string()).into_response()
+        }
+    }
+}
+
+/// The part of the page JSON that the header and menus of every page template use,
+/// with `root` as the path from the page back to the top of the site.
+fn page_context(state: &AppState, root: &str) -> SerdeValue {
+    json!({
+        "root": root,
+        "project_name": "Nanobot",
+        "tables": get::get_table_map(&state.config),
+        "undo": get::get_undo_message(&state.config),
+        "redo": get::get_redo_message(&state.config),
+        "actions": get::get_action_map(&state.config).unwrap_or_default(),
+        "repo": get::get_repo_details().unwrap_or_default(),
+    })
+}
+
+/// Why the server stopped.
+#[derive(Debug, PartialEq)]
+pub enum Shutdown {
+    Quit,
+    ConfigChanged,
+    SchemaChanged,
+}
 
-    let app = build_app(shared_state);
+/// Build the router for the given configuration, for applications that already run axum,
+/// e.g. `Router::new().nest("/data", build_router(config).await?)` with their own middleware.
+/// Set the configuration's `base_path` to the same path, for the links that need it.
+/// The tables of each of the `[databases]` are under `/db/NAME/`,
+/// and the actions with a `schedule` start running.
+pub async fn build_router(config: Config) -> Result<Router, NanobotError> {
+    let shared_state = Arc::new(AppState::new(config));
+    // The scheduler runs for as long as the application does.
+    start_scheduler(shared_state.clone());
+    build_router_for(shared_state).await
+}
+
+/// Build the router for the given state, as [build_router] does, but without starting the
+/// scheduled actions, e.g. for a CGI request that only lasts as long as its response.
+pub async fn build_router_for(shared_state: Arc<AppState>) -> Result<Router, NanobotError> {
+    start_cache_invalidation(shared_state.clone());
+    let config = &shared_state.config;
+    let mut app = build_app(shared_state.clone());
+    for name in config.databases.keys() {
+        let mut database_config = config.clone();
+        database_config.database(name)?;
+        build_valve(&mut database_config)
+            .await
+            .map_err(|e| NanobotError::DatabaseError {
+                name: name.to_string(),
+                source: Box::new(e),
+            })?;
+        tracing::info!("Serving database '{}' under /db/{}/", name, name);
+        let database_state = Arc::new(shared_state.for_database(database_config));
+        start_cache_invalidation(database_state.clone());
+        app = app.nest(&format!("/db/{}", name), build_app(database_state));
+    }
+    Ok(app)
+}
+
+/// Configure and start a Nanobot server, e.g.
+/// `Serve::new(config).host([127, 0, 0, 1]).port(3000).read_only(true).start().await?`.
+/// By default it listens on all interfaces at the configured port.
+pub struct Serve {
+    config: Config,
+    host: IpAddr,
+    dev: bool,
+}
+
+impl Serve {
+    pub fn new(config: Config) -> Serve {
+        Serve {
+            config,
+            host: IpAddr::from([0, 0, 0, 0]),
+            dev: false,
+        }
+    }
+
+    pub fn host<A: Into<IpAddr>>(mut self, host: A) -> Serve {
+        self.host = host.into();
+        self
+    }
+
+    /// Use port 0 to listen on any free port, then get it from [ServerHandle::local_addr].
+    pub fn port(mut self, port: u16) -> Serve {
+        self.config.port(port);
+        self
+    }
+
+    /// Listen on a Unix socket at this path instead of the host and port,
+    /// e.g. for nginx to `proxy_pass http://unix:/run/nanobot.sock`.
+    pub fn socket<S: Into<String>>(mut self, path: S) -> Serve {
+        self.config.socket(path);
+        self
+    }
+
+    pub fn read_only(mut self, value: bool) -> Serve {
+        self.config.read_only(value);
+        self
+    }
+
+    /// Reload tables when their TSV files change, and stop with [Shutdown::SchemaChanged]
+    /// when the schema changes, so that the caller can rebuild VALVE and start again.
+    pub fn watch(mut self, value: bool) -> Serve {
+        self.config.watch(value);
+        self
+    }
+
+    /// Development mode: reload pages in the browser when the templates or assets change,
+    /// and stop with [Shutdown::ConfigChanged] when the configuration file changes,
+    /// so that the caller can reload it and start again.
+    pub fn dev(mut self, value: bool) -> Serve {
+        self.dev = value;
+        self
+    }
+
+    /// Bind the address and start serving in a new task.
+    pub async fn start(self) -> Result<ServerHandle, NanobotError> {
+        let Serve { config, host, dev } = self;
+        let shared_state = Arc::new(AppState::new(config.clone()));
+        let mut app = build_router_for(shared_state.clone()).await?;
+        // Stopped with the server, so that restarting it does not run actions twice.
+        let scheduler = start_scheduler(shared_state.clone());
+        let (reload, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        if dev {
+            let reload_sender = reload.clone();
+            let reload_script =
+                RELOAD_SCRIPT.replace("/dev/reload", &format!("{}/dev/reload", config.base_path));
+            app = app
+                .route(
+                    "/dev/reload",
+                    get(move || get_dev_reload(reload_sender.subscribe())),
+                )
+                .layer(middleware::from_fn_with_state(
+                    reload_script,
+                    inject_reload_script,
+                ));
+        }
+        // Behind a reverse proxy at a subpath, e.g. https://example.com/nanobot/
+        if !config.base_path.is_empty() {
+            tracing::info!("Serving under {}/", config.base_path);
+            app = Router::new().nest(&config.base_path, app);
+        }
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let stopped = stopped.map(|_| ()).shared();
+        // Open connections, such as WebSockets, would delay a graceful shutdown,
+        // so in development and watch mode, stop serving as soon as the server is stopped.
+        let graceful = !dev && !config.watch;
+        let signal = {
+            let stopped = stopped.clone();
+            async move {
+                if graceful {
+                    stopped.await
+                } else {
+                    future::pending().await
+                }
+            }
+        };
+        let (server, listener) =
+            bind(&config, host, app, signal).map_err(NanobotError::ServerError)?;
+        tracing::info!(
+            "Running Nanobot server at {}{}/",
+            listener,
+            config.base_path
+        );
+
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                result = server => result
+                    .map(|_| Shutdown::Quit)
+                    .map_err(|e| NanobotError::ServerError(e.to_string())),
+                _ = stopped, if !graceful => Ok(Shutdown::Quit),
+                _ = watch_for_changes(&config, reload), if dev => Ok(Shutdown::ConfigChanged),
+                _ = watch_tables(shared_state), if config.watch => Ok(Shutdown::SchemaChanged),
+            }
+        });
+        Ok(ServerHandle {
+            listener,
+            stop: Some(stop),
+            task: Some(task),
+            scheduler,
+        })
+    }
+}
+
+/// Where a server listens.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(std::path::PathBuf),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "http://{}", addr),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Listen on the configured Unix socket, or else on the host and the configured port,
+/// and serve the app until the signal.
+fn bind<F>(
+    config: &Config,
+    host: IpAddr,
+    app: Router,
+    signal: F,
+) -> Result<(BoxFuture<'static, hyper::Result<()>>, Listener), String>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    if let Some(path) = &config.socket {
+        return bind_socket(std::path::Path::new(path), app, signal);
+    }
+    let addr = SocketAddr::new(host, config.port);
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|e| format!("Could not listen on {}: {}", addr, e))?
+        .serve(app.into_make_service());
+    let listener = Listener::Tcp(server.local_addr());
+    Ok((server.with_graceful_shutdown(signal).boxed(), listener))
+}
 
-    // run our app with hyper
-    // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    println!("Running Nanobot server at http://{addr}");
-    println!("Press Control-C to quit.");
-    if let Err(e) = axum::Server::bind(&addr)
+#[cfg(unix)]
+fn bind_socket<F>(
+    path: &std::path::Path,
+    app: Router,
+    signal: F,
+) -> Result<(BoxFuture<'static, hyper::Result<()>>, Listener), String>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    use std::os::unix::fs::FileTypeExt;
+
+    // The socket of a server that stopped is left behind, and would make binding fail.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("'{}' exists and is not a socket", path.display()));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| format!("Could not remove '{}': {}", path.display(), e))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| format!("Could not listen on '{}': {}", path.display(), e))?;
+    let server = axum::Server::builder(UnixAccept(listener))
         .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-    {
-        return Err(e.to_string());
+        .with_graceful_shutdown(signal);
+    Ok((server.boxed(), Listener::Unix(path.to_path_buf())))
+}
+
+#[cfg(not(unix))]
+fn bind_socket<F>(
+    path: &std::path::Path,
+    _app: Router,
+    _signal: F,
+) -> Result<(BoxFuture<'static, hyper::Result<()>>, Listener), String> {
+    Err(format!(
+        "Could not listen on '{}': Unix sockets are not supported on this platform",
+        path.display()
+    ))
+}
+
+/// Accept connections to a Unix socket for hyper.
+#[cfg(unix)]
+struct UnixAccept(tokio::net::UnixListener);
+
+#[cfg(unix)]
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        match self.0.poll_accept(cx) {
+            Poll::Ready(Ok((stream, _))) => Poll::Ready(Some(Ok(stream))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A running server, from [Serve::start]. Dropping the handle stops the server.
+pub struct ServerHandle {
+    listener: Listener,
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<Result<Shutdown, NanobotError>>>,
+    scheduler: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Where the server is listening, including the port it chose for port 0.
+    pub fn listener(&self) -> &Listener {
+        &self.listener
+    }
+
+    /// The address the server is listening on, including the port it chose for port 0,
+    /// or None for a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(addr) => Some(*addr),
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Ask the server to stop. It finishes the requests in progress first,
+    /// except in development or watch mode, where it stops right away.
+    pub fn shutdown(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    /// Wait for the server to stop, and return why it stopped.
+    pub async fn wait(&mut self) -> Result<Shutdown, NanobotError> {
+        let task = self.task.as_mut().ok_or(NanobotError::ServerError(
+            "The server has already stopped".to_string(),
+        ))?;
+        let result = task.await;
+        self.task = None;
+        self.stop_scheduler();
+        result.map_err(|e| NanobotError::ServerError(format!("The server failed: {}", e)))?
+    }
+
+    fn stop_scheduler(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.abort();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop_scheduler();
+    }
+}
+
+/// The script added to each page in development mode, which reloads the page when the server
+/// sends a reload event or restarts.
+const RELOAD_SCRIPT: &str = r#"<script>
+  (function () {
+    let lost = false;
+    const source = new EventSource("/dev/reload");
+    source.onmessage = () => location.reload();
+    source.onerror = () => { lost = true; };
+    source.onopen = () => { if (lost) location.reload(); };
+  })();
+</script>"#;
+
+/// Send an event to the browser each time the templates or assets change.
+async fn get_dev_reload(
+    receiver: broadcast::Receiver<()>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(()) | Err(RecvError::Lagged(_)) => {
+                Some((Ok(Event::default().data("reload")), receiver))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Add the reload script to the end of each HTML page.
+async fn inject_reload_script<B>(
+    State(script): State<String>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    if !is_html {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let html = String::from_utf8_lossy(&body);
+    let html = match html.rfind("</body>") {
+        Some(i) => format!("{}{}\n{}", &html[..i], script, &html[i..]),
+        None => format!("{}{}", html, script),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, axum::body::boxed(axum::body::Full::from(html)))
+}
+
+/// Poll the configuration file, templates, and assets for changes.
+/// Tell the browsers to reload when the templates or assets change,
+/// and return when the configuration file changes.
+async fn watch_for_changes(config: &Config, reload: broadcast::Sender<()>) {
+    let config_path = std::path::PathBuf::from(&config.config_path);
+    let config_modified = last_modified(&config_path);
+    let paths = [&config.template_path, &config.asset_path]
+        .into_iter()
+        .flatten()
+        .map(std::path::PathBuf::from)
+        .collect::<Vec<_>>();
+    let mut modified = paths.iter().map(|p| last_modified(p)).collect::<Vec<_>>();
+    loop {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        if last_modified(&config_path) != config_modified {
+            tracing::info!("{} changed", config_path.display());
+            return;
+        }
+        let now_modified = paths.iter().map(|p| last_modified(p)).collect::<Vec<_>>();
+        if now_modified != modified {
+            tracing::info!("Templates or assets changed, reloading pages");
+            modified = now_modified;
+            get::clear_templates();
+            let _ = reload.send(());
+        }
+    }
+}
+
+/// The types of the VALVE tables that define the schema, rather than holding data.
+const SCHEMA_TABLE_TYPES: [&str; 3] = ["table", "column", "datatype"];
+
+/// Poll the TSV files of the tables for changes, load each table whose file changed into the
+/// database again, and tell the browsers showing it to reload.
+/// Return when the file of the table, column, or datatype table changes, since VALVE must be
+/// rebuilt for the new schema. Saving tables from Nanobot also reloads them, which is harmless.
+async fn watch_tables(state: Arc<AppState>) {
+    let paths = match &state.config.valve {
+        Some(valve) => valve
+            .config
+            .table
+            .iter()
+            .filter(|(_, t)| !t.path.is_empty())
+            .map(|(name, t)| {
+                let schema = SCHEMA_TABLE_TYPES.contains(&t.table_type.as_str());
+                (name.to_string(), std::path::PathBuf::from(&t.path), schema)
+            })
+            .collect::<Vec<_>>(),
+        None => return std::future::pending().await,
+    };
+    let mut modified = paths
+        .iter()
+        .map(|(_, p, _)| last_modified(p))
+        .collect::<Vec<_>>();
+    loop {
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        let now_modified = paths
+            .iter()
+            .map(|(_, p, _)| last_modified(p))
+            .collect::<Vec<_>>();
+        let changed = paths
+            .iter()
+            .zip(modified.iter().zip(&now_modified))
+            .filter(|(_, (before, now))| before != now)
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        modified = now_modified;
+        if changed.is_empty() {
+            continue;
+        }
+        if let Some((table, _, _)) = changed.iter().find(|(_, _, schema)| *schema) {
+            tracing::info!("The schema table '{}' changed, rebuilding VALVE", table);
+            return;
+        }
+
+        let tables = changed
+            .iter()
+            .map(|(table, _, _)| table.to_string())
+            .collect::<Vec<_>>();
+        let mut config = state.config.clone();
+        match load::load(&mut config, &tables, false).await {
+            Ok(message) => {
+                tracing::info!("{}", message);
+                for table in tables {
+                    state.notify(TableEvent::Reload { table: Some(table) });
+                }
+            }
+            Err(e) => tracing::error!("Could not reload '{}': {}", tables.join("', '"), e),
+        }
+    }
+}
+
+/// The latest modification time of the given file, or of any file under the given directory,
+/// or None if there is no such file.
+fn last_modified(path: &std::path::Path) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let mut latest = metadata.modified().ok();
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path).ok()?.flatten() {
+            latest = latest.max(last_modified(&entry.path()));
+        }
+    }
+    latest
+}
+
+/// Actions can change any table, so drop the cached responses whenever a job finishes.
+fn start_cache_invalidation(state: Arc<AppState>) {
+    if !state.cache.is_enabled() {
+        return;
+    }
+    let mut progress = state.progress.subscribe();
+    // The job queue outlives the server, so stop when the server's state is dropped.
+    let state = Arc::downgrade(&state);
+    tokio::spawn(async move {
+        loop {
+            let event = progress.recv().await;
+            let state = match state.upgrade() {
+                Some(state) => state,
+                None => break,
+            };
+            match event {
+                Ok(event) if event.done && event.operation.starts_with("job:") => {
+                    state.cache.bump_all()
+                }
+                Ok(_) => (),
+                Err(broadcast::error::RecvError::Lagged(_)) => state.cache.bump_all(),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Start a task that submits each action with a `schedule` to the job queue
+/// at the start of every minute that its schedule matches, until the task is aborted.
+/// Nothing is scheduled for a read-only server, since actions can change the tables.
+fn start_scheduler(state: Arc<AppState>) -> Option<JoinHandle<()>> {
+    let schedules = parse_schedules(
+        state
+            .config
+            .actions
+            .iter()
+            .filter_map(|(name, action)| Some((name.as_str(), action.schedule.as_deref()?))),
+    );
+    if schedules.is_empty() {
+        return None;
     }
+    if state.config.read_only {
+        tracing::info!("Scheduled actions will not run, because the server is read-only");
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        loop {
+            // Wake up just after the start of the next minute.
+            let second = Local::now().second() as u64;
+            tokio::time::sleep(Duration::from_secs(60 - second.min(59))).await;
+            for name in due(&schedules, &Local::now()) {
+                run_scheduled_action(&state, name);
+            }
+        }
+    }))
+}
 
-    Ok("Stopping Nanobot server...".into())
+/// Submit a scheduled action to the job queue, using the defaults for its inputs.
+fn run_scheduled_action(state: &AppState, name: &str) {
+    let action = match state.config.actions.get(name) {
+        Some(action) => action,
+        None => return,
+    };
+    let mut values = action::get_default_values();
+    if let Err(e) = action::collect_inputs_or_defaults(action, &HashMap::new(), &mut values) {
+        tracing::warn!("Skipping scheduled action '{}': {}", name, e);
+        return;
+    }
+    let errors = action::validate_inputs(action, &values, state.config.valve.as_ref());
+    if !errors.is_empty() {
+        tracing::warn!("Skipping scheduled action '{}': {:?}", name, errors);
+        return;
+    }
+    match state.jobs.submit(name, action, values) {
+        Ok(id) => tracing::info!("Started scheduled action '{}' as job {}", name, id),
+        Err(e) => tracing::error!("Could not start scheduled action '{}': {}", name, e),
+    }
 }
 
-async fn shutdown_signal() {
+/// Wait for Control-C, or for SIGTERM on Unix.
+pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -113,9 +999,144 @@ async fn shutdown_signal() {
     }
 }
 
-async fn root() -> impl IntoResponse {
+/// Redirect to the configured `default_table`, if there is one,
+/// otherwise list every table with its description, row count, and validation status.
+async fn root(
+    State(state): State<Arc<AppState>>,
+    OriginalUri(uri): OriginalUri,
+) -> axum::response::Result<Response> {
     tracing::info!("request root");
-    Redirect::permanent("table")
+    // Links are relative, so a nested root such as /db/NAME needs its trailing slash.
+    if !uri.path().ends_with('/') {
+        let path = match uri.query() {
+            Some(query) => format!("{}/?{}", uri.path(), query),
+            None => format!("{}/", uri.path()),
+        };
+        return Ok(Redirect::permanent(&path).into_response());
+    }
+    if let Some(default_table) = &state.config.default_table {
+        let path = default_table.trim_start_matches('/');
+        return Ok(Redirect::temporary(path).into_response());
+    }
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let pool = state
+        .config
+        .pool
+        .as_ref()
+        .ok_or("Pool is not initialized.".to_string())?;
+
+    let mut tables = vec![];
+    for table in &valve.config.table_order {
+        if table == "history" || state.config.tables.get(table).map_or(false, |t| t.hidden) {
+            continue;
+        }
+        let table_config = match valve.config.table.get(table) {
+            Some(table_config) => table_config,
+            None => continue,
+        };
+        let conflict = table_config.options.contains("edit");
+        let count = get_total_from_pool(pool, table, conflict)
+            .await
+            .map_err(|e| e.to_string())?;
+        let messages = get_message_counts_from_pool(pool, table)
+            .await
+            .map_err(|e| e.to_string())?;
+        tables.push(json!({
+            "table": table,
+            "label": get::get_table_label(&state.config, table),
+            "href": table,
+            "description": json!(table_config).get("description"),
+            "type": table_config.table_type,
+            "count": count,
+            "messages": messages,
+        }));
+    }
+
+    let page = json!({
+        "page": page_context(&state, ""),
+        "title": "Tables",
+        "tables": tables,
+    });
+    let page_html = match get::page_to_html(&state.config, "index", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+async fn get_ws(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    tracing::info!("request ws");
+    ws.on_upgrade(move |socket| push_events(socket, state))
+}
+
+/// Forward table events to the given WebSocket as JSON text messages until the client
+/// disconnects.
+async fn push_events(mut socket: WebSocket, state: Arc<AppState>) {
+    let mut receiver = state.events.subscribe();
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    let text = match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            tracing::warn!("Could not serialize event {:?}: {}", event, e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("WebSocket client lagged, skipped {} events", skipped);
+                    // Tell the client to refresh everything since it has missed some changes.
+                    let text = json!({"event": "reload", "table": null}).to_string();
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => (),
+            },
+        }
+    }
+    tracing::debug!("WebSocket client disconnected");
+}
+
+async fn get_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    tracing::info!("request events");
+    let receiver = state.progress.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(progress) => {
+                    let name = match progress.output {
+                        Some(_) => "output",
+                        None => "progress",
+                    };
+                    match Event::default().event(name).json_data(&progress) {
+                        Ok(event) => return Some((Ok(event), receiver)),
+                        Err(e) => tracing::warn!("Could not serialize {:?}: {}", progress, e),
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Event stream client lagged, skipped {} events", skipped)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 async fn post_table(
@@ -131,208 +1152,1087 @@ async fn post_table(
         form_params
     );
     let mut request_type = RequestType::POST;
-    let valve = state
-        .config
-        .valve
-        .as_ref()
-        .ok_or("Valve is not initialized.".to_string())?;
+    let valve = state.config.valve.as_ref().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Valve is not initialized.".to_string(),
+    ))?;
     if form_params.contains_key("save") {
         tracing::info!("SAVE");
-        valve
-            .save_all_tables(&None)
-            .map_err(|e| format!("{:?}", e))?;
+        state.report("save", 0, 1, "Saving all tables");
+        let message = save_tables(&state, vec![]).await?;
+        state.report("save", 1, 1, &message);
         request_type = RequestType::GET;
     } else if form_params.contains_key("undo") {
         tracing::info!("UNDO");
-        block_on(valve.undo()).expect("Undo should succeed");
+        let _lock = edit::lock().await;
+        valve.undo().await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not undo the last change: {:?}", e),
+            )
+        })?;
+        state.notify(TableEvent::Reload { table: None });
         request_type = RequestType::GET;
     } else if form_params.contains_key("redo") {
         tracing::info!("REDO");
-        block_on(valve.redo()).expect("Redo should succeed");
+        let _lock = edit::lock().await;
+        valve.redo().await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not redo the last change: {:?}", e),
+            )
+        })?;
+        state.notify(TableEvent::Reload { table: None });
         request_type = RequestType::GET;
     }
     table(&path, &state, &query_params, &form_params, request_type).await
 }
 
-async fn get_table(
-    Path(path): Path<String>,
+/// Save the tables in the comma-separated `table` query parameter, or all of the tables,
+/// back to their TSV files, e.g. `POST /save?table=penguin,island`.
+async fn post_save(
     State(state): State<Arc<AppState>>,
     Query(query_params): Query<RequestParams>,
 ) -> axum::response::Result<impl IntoResponse> {
-    tracing::info!("request table GET {:?} {:?}", path, query_params);
-    table(
+    tracing::info!("request save POST {:?}", query_params);
+    let tables = query_params
+        .get("table")
+        .map(|t| {
+            t.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    state.report("save", 0, 1, "Saving tables");
+    let message = save_tables(&state, tables)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state.report("save", 1, 1, &message);
+    Ok(Json(json!({ "message": message })))
+}
+
+/// Save the tables, or all of the tables if none are given, back to their TSV files.
+/// The edit lock is held so that a batch of changes is never saved half done,
+/// and VALVE's save runs on a blocking thread, since it does not yield while writing.
+async fn save_tables(state: &Arc<AppState>, tables: Vec<String>) -> Result<String, String> {
+    let _lock = edit::lock().await;
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || {
+        save::save(&state.config, &tables, None).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| format!("Could not save the tables: {}", e))?
+}
+
+async fn get_table(
+    Path(path): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(mut query_params): Query<RequestParams>,
+    method: Method,
+    headers: HeaderMap,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request table {} {:?} {:?}", method, path, query_params);
+    // HEAD requests get the count of matching rows in the Content-Range header, without the rows.
+    if method == Method::HEAD {
+        query_params.insert("count".to_string(), "only".to_string());
+    }
+    // Without a format in the path or the Accept header, use the table's default format.
+    let path = match negotiate_format(&path, &headers) {
+        Some(extension) => format!("{}.{}", path, extension),
+        None => match state.config.default_format_for(&path) {
+            Some(format) if format != "html" => format!("{}.{}", path, format),
+            _ => path,
+        },
+    };
+    let cache = match method == Method::GET && state.cache.is_enabled() {
+        true => cache_dependencies(&state.config, &path, &query_params),
+        false => None,
+    };
+    let key = cache.as_ref().map(|_| cache_key(&path, &query_params));
+    if let Some(key) = &key {
+        if let Some(cached) = state.cache.get(key) {
+            tracing::debug!("CACHED {}", key);
+            let content_type = [(header::CONTENT_TYPE, cached.content_type)];
+            return Ok(([(header::VARY, "Accept")], content_type, cached.body).into_response());
+        }
+    }
+    // Take the stamp before rendering, so that an edit while rendering isn't hidden by the cache.
+    let stamp = cache.map(|tables| state.cache.stamp(tables.as_deref()));
+    let response = table(
         &path,
         &state,
         &query_params,
         &RequestParams::new(),
         RequestType::GET,
     )
-    .await
+    .await?
+    .into_response();
+    let (key, stamp) = match (key, stamp) {
+        (Some(key), Some(stamp)) if response.status() == StatusCode::OK => (key, stamp),
+        _ => return Ok(([(header::VARY, "Accept")], response).into_response()),
+    };
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some(content_type) = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+    {
+        let cached = CachedResponse {
+            content_type: content_type.to_string(),
+            body: body.clone(),
+        };
+        state.cache.insert(&key, stamp, cached);
+    }
+    Ok(([(header::VARY, "Accept")], (parts, body)).into_response())
 }
 
-fn action(
-    path: &String,
-    state: &Arc<AppState>,
+/// The tables that a table request's response is rendered from, if it can be cached:
+/// `Some(None)` for HTML pages, which depend on every table, since they show the last change,
+/// and `Some(Some(tables))` for JSON. Other formats, which can be large, are not cached.
+fn cache_dependencies(
+    config: &Config,
+    path: &str,
     query_params: &RequestParams,
+) -> Option<Option<Vec<String>>> {
+    if query_params.contains_key("view") {
+        return None;
+    }
+    let table = match path.rsplit_once('.') {
+        None => return Some(None),
+        Some((table, "json")) => table.trim_end_matches(".pretty"),
+        Some(_) => return None,
+    };
+    let mut tables = vec![table.to_string()];
+    if let Some(table_config) = config.tables.get(table) {
+        for labels in table_config
+            .formats
+            .values()
+            .filter_map(|f| f.labels.as_ref())
+        {
+            tables.push(labels.table.to_string());
+        }
+    }
+    if query_params.get("iris").map(|i| i.as_str()) == Some("true") {
+        tables.push(prefix::PREFIX_TABLE.to_string());
+    }
+    Some(Some(tables))
+}
+
+/// The path and the query parameters in order, so that the same request has the same key.
+fn cache_key(path: &str, query_params: &RequestParams) -> String {
+    let mut params = query_params
+        .iter()
+        .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+        .collect::<Vec<_>>();
+    params.sort();
+    format!("{}?{}", path, params.join("&"))
+}
+
+/// The file extensions for the table formats, and the media types that they are served as.
+const TABLE_FORMATS: [(&str, &str); 8] = [
+    ("json", "application/json"),
+    ("jsonl", "application/x-ndjson"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("txt", "text/plain"),
+    ("xlsx", XLSX_MEDIA_TYPE),
+    ("ofn", "text/owl-functional"),
+    ("ttl", "text/turtle"),
+];
+
+const XLSX_MEDIA_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// When the path has no format extension, choose a format using the request's Accept header,
+/// returning the extension for that format, or None for HTML.
+fn negotiate_format(path: &str, headers: &HeaderMap) -> Option<&'static str> {
+    if path.ends_with(".html")
+        || TABLE_FORMATS
+            .iter()
+            .any(|(extension, _)| path.ends_with(&format!(".{}", extension)))
+    {
+        return None;
+    }
+    let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+
+    // Pick the acceptable media type with the highest quality, preferring earlier types on ties.
+    let mut best: Option<(&str, f32)> = None;
+    for media_range in accept.split(',') {
+        let mut parts = media_range.split(';');
+        let media_type = parts.next().unwrap_or_default().trim().to_lowercase();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let extension = if media_type == "text/html" || media_type == "*/*" {
+            "html"
+        } else {
+            match TABLE_FORMATS.iter().find(|(_, t)| *t == media_type) {
+                Some((extension, _)) => *extension,
+                None => continue,
+            }
+        };
+        if quality > 0.0 && best.map(|(_, q)| quality > q).unwrap_or(true) {
+            best = Some((extension, quality));
+        }
+    }
+    match best {
+        Some(("html", _)) | None => None,
+        Some((extension, _)) => Some(extension),
+    }
+}
+
+async fn get_action(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(query_params): Query<RequestParams>,
+) -> axum::response::Result<Response> {
+    tracing::info!("request action GET {:?} {:?}", name, query_params);
+    if state.config.read_only {
+        return Ok((StatusCode::FORBIDDEN, READ_ONLY_MESSAGE).into_response());
+    }
+    action(&name, &state, &query_params, RequestType::GET).map(|r| r.into_response())
+}
+
+async fn post_action(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Form(form_params): Form<RequestParams>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let action_name = match query_params.get("user.action") {
-        Some(a) => a,
-        None => {
-            return Err((StatusCode::BAD_REQUEST, Html("No user action specified"))
-                .into_response()
-                .into())
+    tracing::info!("request action POST {:?} {:?}", name, form_params);
+    action(&name, &state, &form_params, RequestType::POST)
+}
+
+/// Run the named action as a job when the request is signed with the action's `webhook_secret`,
+/// e.g. from a GitHub push webhook. Inputs are taken from the query string or their defaults.
+/// Actions without a `webhook_secret` cannot be triggered this way.
+async fn post_webhook(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(query_params): Query<RequestParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request webhook POST {:?} {:?}", name, query_params);
+    let action = match state.config.actions.get(&name) {
+        Some(action) if action.webhook_secret.is_some() => action,
+        _ => {
+            let message = format!("No webhook for action '{}'", name);
+            return Err((StatusCode::NOT_FOUND, message).into_response().into());
         }
     };
 
+    let signature = headers
+        .get("X-Hub-Signature-256")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let secret = action.webhook_secret.as_deref().unwrap_or_default();
+    if !action::verify_signature(secret, &body, signature) {
+        tracing::warn!("Rejected webhook for action '{}': bad signature", name);
+        let message = "Invalid signature".to_string();
+        return Err((StatusCode::UNAUTHORIZED, message).into_response().into());
+    }
+
+    let mut values = action::get_default_values();
+    if let Err(e) = action::collect_inputs_or_defaults(action, &query_params, &mut values) {
+        return Err((StatusCode::BAD_REQUEST, e).into_response().into());
+    }
+    let errors = action::validate_inputs(action, &values, state.config.valve.as_ref());
+    if !errors.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, Json(errors))
+            .into_response()
+            .into());
+    }
+
+    let id = state.jobs.submit(&name, action, values)?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "job": id,
+            "url": format!("{}/job/{}", state.config.base_path, id)
+        })),
+    ))
+}
+
+/// Redirect a legacy `?user.action=NAME` request to the action's own page.
+fn redirect_to_action(root: &str, params: &RequestParams) -> axum::response::Result<Response> {
+    let action_name = params
+        .get("user.action")
+        .ok_or("No user action specified".to_string())?;
+    let url = format!("{}action/{}", root, action_name);
+    Ok(Redirect::to(&url).into_response())
+}
+
+/// Render the form for the named action on GET, and run the action's commands on POST,
+/// rendering their results.
+fn action(
+    path: &str,
+    state: &Arc<AppState>,
+    params: &RequestParams,
+    request_type: RequestType,
+) -> axum::response::Result<impl IntoResponse> {
+    let action_name = path.trim_end_matches(".json").trim_end_matches(".pretty");
     let action = match &state.config.actions.get(action_name) {
         Some(a) => a.to_owned(),
         None => {
             let message = format!("Undefined user action '{}'", action_name);
-            return Err((StatusCode::BAD_REQUEST, Html(message))
+            return Err((StatusCode::NOT_FOUND, Html(message))
                 .into_response()
                 .into());
         }
     };
 
-    let mut values: HashMap<String, String> = HashMap::new();
-    if let Ok(output) = Command::new("gh")
-        .args(["api", "user", "--jq", ".login"])
-        .output()
-    {
-        let stdout = std::str::from_utf8(&output.stdout)
+    let mut values = action::get_default_values();
+    let satisfied = action::collect_inputs(&action, params, &mut values);
+    tracing::info!("VALUES {values:?}");
+
+    let inputs = action::fill_inputs(&action, &values);
+    let mut messages = HashMap::new();
+    let mut errors = HashMap::new();
+    let mut commands = vec![];
+    if request_type == RequestType::POST {
+        if satisfied {
+            errors = action::validate_inputs(&action, &values, state.config.valve.as_ref());
+        }
+        let confirmed = params.get("confirmed").map(|c| c.as_str()) == Some("true");
+        if !errors.is_empty() {
+            messages.insert("error", vec!["Some of the inputs are not valid."]);
+        } else if satisfied && action.confirm.unwrap_or_default() && !confirmed {
+            // Show the commands that will be run and ask the user to approve them:
+            commands = action::get_command_lines(&action, &values);
+            messages.insert(
+                "warn",
+                vec!["Please confirm that these commands should be run."],
+            );
+        } else if satisfied {
+            // Run the action in the background and send the user to the page for the new job:
+            let id = state.jobs.submit(action_name, &action, values)?;
+            return Ok(Redirect::to(&format!("../job/{}", id)).into_response());
+        } else {
+            messages.insert("error", vec!["Please fill in all of the inputs."]);
+        }
+    }
+
+    let page = json!({
+        "page": page_context(state, "../"),
+        "title": action.label,
+        "action_name": action_name,
+        "action": action,
+        "inputs": inputs,
+        "errors": errors,
+        "commands": commands,
+        "messages": messages,
+    });
+
+    if path.ends_with(".pretty.json") {
+        Ok(serde_json::to_string_pretty(&page)
             .unwrap_or_default()
-            .trim();
-        values.insert("username".into(), stdout.into());
-    }
-    values.insert("today".into(), Local::now().format("%Y-%m-%d").to_string());
-
-    let satisfied = match action.inputs {
-        Some(_) => {
-            let mut result = true;
-            for input in action.inputs.clone().unwrap_or_default() {
-                match query_params.get(&input.name) {
-                    Some(v) => {
-                        if !v.trim().is_empty() {
-                            values.insert(input.name, v.into());
-                            ()
-                        } else {
-                            result = false
-                        }
-                    }
-                    None => result = false,
+            .into_response())
+    } else if path.ends_with(".json") {
+        Ok(Json(page).into_response())
+    } else {
+        let page_html = match get::page_to_html(&state.config, "action", &page) {
+            Ok(p) => p,
+            Err(e) => return Err(e.to_string().into()),
+        };
+        Ok(Html(page_html).into_response())
+    }
+}
+
+/// A single change to a row, as submitted to the bulk edit endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BulkOperation {
+    Insert { row: SerdeMap },
+    Update { row_number: u32, row: SerdeMap },
+    Delete { row_number: u32 },
+}
+
+/// Apply a JSON array of insert, update, and delete operations to the table, all or nothing.
+/// Every inserted and updated row is validated first, and if any has a validation error then
+/// nothing is changed and the combined validation report is returned with status 422.
+/// The operations are validated and applied while holding the edit lock, so no other edit can
+/// come between them, and if one fails then the operations that were already applied are undone.
+async fn post_bulk(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(operations): Json<Vec<BulkOperation>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!(
+        "request bulk POST {:?} {} operations",
+        table,
+        operations.len()
+    );
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    if !valve.config.table.contains_key(&table) || ["message", "history"].contains(&table.as_str())
+    {
+        let message = format!("Editing the '{}' table is not possible", table);
+        return Err((StatusCode::NOT_FOUND, message).into_response().into());
+    }
+
+    // Hold the edit lock while validating, so that no other edit can change what the rows are
+    // validated against before they are applied.
+    let mut edits = Edits::begin(valve).await;
+    let mut errors = vec![];
+    for (i, operation) in operations.iter().enumerate() {
+        let (row, row_number) = match operation {
+            BulkOperation::Insert { row } => (row, None),
+            BulkOperation::Update { row_number, row } => (row, Some(*row_number)),
+            BulkOperation::Delete { .. } => continue,
+        };
+        for mut error in get_validation_errors(&table, row, row_number, valve).await? {
+            error.insert("operation".to_string(), json!(i));
+            errors.push(error);
+        }
+    }
+    if !errors.is_empty() {
+        let report = json!({"table": table, "applied": 0, "errors": errors});
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)));
+    }
+
+    for (i, operation) in operations.iter().enumerate() {
+        let result = match operation {
+            BulkOperation::Insert { row } => edits.insert(&table, row).await.map(|_| ()),
+            BulkOperation::Update { row_number, row } => {
+                edits.update(&table, row_number, row).await
+            }
+            BulkOperation::Delete { row_number } => edits.delete(&table, row_number).await,
+        };
+        if let Err(e) = result {
+            let message = edits
+                .rollback(format!("Operation {} failed: {}", i, e))
+                .await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, message)
+                .into_response()
+                .into());
+        }
+    }
+    let applied = edits.commit();
+    state.notify(TableEvent::Reload {
+        table: Some(table.to_string()),
+    });
+
+    let report = json!({"table": table, "applied": applied, "errors": errors});
+    Ok((StatusCode::OK, Json(report)))
+}
+
+async fn get_upload(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request upload GET {:?}", table);
+    if state.config.read_only {
+        return Ok((StatusCode::FORBIDDEN, READ_ONLY_MESSAGE).into_response());
+    }
+    upload_page(&table, &state, None, HashMap::new())
+}
+
+/// Accept a multipart form with a CSV or TSV `file` and a `mode` of either "append" or "replace".
+/// Every row is validated before the table is changed, and the validation errors are reported
+/// for each row. Rows with errors are still loaded, just as they are when a form is submitted.
+async fn post_upload(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request upload POST {:?}", table);
+    let mut file = None;
+    let mut replace = false;
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("file") => {
+                let filename = field.file_name().unwrap_or_default().to_string();
+                file = Some((filename, field.bytes().await?));
+            }
+            Some("mode") => replace = field.text().await? == "replace",
+            _ => (),
+        }
+    }
+
+    let mut messages = HashMap::new();
+    let report = match file {
+        Some((filename, data)) if !data.is_empty() => {
+            match upload_table(&table, &state, replace, &filename, &data).await {
+                Ok(report) => Some(report),
+                Err(e) => {
+                    messages.insert("error", vec![e]);
+                    None
                 }
             }
-            result
         }
-        None => true,
+        _ => {
+            messages.insert("error", vec!["Please choose a file to upload.".to_string()]);
+            None
+        }
     };
+    upload_page(&table, &state, report, messages)
+}
 
-    tracing::info!("VALUES {values:?}");
+fn upload_page(
+    table: &str,
+    state: &Arc<AppState>,
+    report: Option<SerdeValue>,
+    messages: HashMap<&str, Vec<String>>,
+) -> axum::response::Result<Response> {
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    if !valve.config.table.contains_key(table) || ["message", "history"].contains(&table) {
+        let message = format!("Uploading to the '{}' table is not possible", table);
+        return Err((StatusCode::NOT_FOUND, Html(message))
+            .into_response()
+            .into());
+    }
+    let page = json!({
+        "page": page_context(state, "../"),
+        "title": format!("Upload to {}", table),
+        "table": table,
+        "columns": get_columns(table, valve)?,
+        "report": report,
+        "messages": messages,
+    });
+    let page_html = match get::page_to_html(&state.config, "upload", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
 
-    let re = Regex::new(r"\{(\w+)\}").unwrap();
-    let mut inputs = vec![];
-    let mut results = vec![];
+/// The datatype hierarchy, with the condition of each datatype, e.g. for validating forms in
+/// the browser. This takes the place of the datatype table's own JSON; its rows are still
+/// available in the other formats, such as `/datatype.jsonl`.
+async fn get_datatypes(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    tracing::info!("request datatypes");
+    Json(json!({ "datatypes": get::get_datatype_tree(&state.config) }))
+}
 
-    if !satisfied {
-        for mut input in action.inputs.clone().unwrap_or_default() {
-            if let Some(v) = values.get(&input.name) {
-                if !v.trim().is_empty() {
-                    input.value = Some(v.to_string());
-                }
-            } else if let Some(default) = input.default.clone() {
-                tracing::debug!("INPUT DEFAULT {:?}", input.clone());
-                let subbed =
-                    re.replace_all(&default, |caps: &Captures| match values.get(&caps[1]) {
-                        Some(v) => v,
-                        None => "",
-                    });
-                input.value = Some(subbed.to_string());
-            };
-            inputs.push(input);
+/// The structure of each column of the table, with its datatypes and their conditions.
+async fn get_columns_json(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request columns {:?}", table);
+    let columns = get::get_column_metadata(&state.config, &table)
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Ok(Json(json!({ "table": table, "columns": columns })))
+}
+
+/// Validate a candidate row without saving it, e.g. while the user is typing in an edit form.
+/// The body is a JSON object of column values; missing columns are empty.
+/// Include a "row_number" to validate the row as an update of that row, e.g. for unique columns.
+/// The response lists the column, value, level, and message of each validation error.
+async fn post_validate(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(mut row): Json<SerdeMap>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request validate POST {:?} {:?}", table, row);
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    if !valve.config.table.contains_key(&table) || ["message", "history"].contains(&table.as_str())
+    {
+        let message = format!("Validating rows of the '{}' table is not possible", table);
+        return Err((StatusCode::NOT_FOUND, message).into_response().into());
+    }
+    let row_number = match row.remove("row_number") {
+        None | Some(SerdeValue::Null) => None,
+        Some(n) => match n.as_u64().and_then(|n| u32::try_from(n).ok()) {
+            Some(n) => Some(n),
+            None => {
+                let message = format!("Invalid row_number {}", n);
+                return Err((StatusCode::BAD_REQUEST, message).into_response().into());
+            }
+        },
+    };
+    let mut candidate = SerdeMap::new();
+    for column in get_columns(&table, valve)? {
+        if ["row_number", "row_order"].contains(&column.as_str()) {
+            continue;
         }
+        let value = row.remove(&column).unwrap_or(json!(""));
+        candidate.insert(column, value);
+    }
+    if let Some(column) = row.keys().next() {
+        let message = format!("The '{}' table has no column '{}'", table, column);
+        return Err((StatusCode::BAD_REQUEST, message).into_response().into());
+    }
+    let errors = get_validation_errors(&table, &candidate, row_number, valve).await?;
+    Ok(Json(json!({
+        "table": table,
+        "valid": errors.is_empty(),
+        "errors": errors,
+    })))
+}
+
+/// Read the rows of a CSV file (or TSV file, depending on the file name) and validate each of them
+/// against the given table, then load them into the table, first deleting its existing rows
+/// when `replace` is true. If a change fails, the changes before it are undone, so the table is
+/// either fully replaced or left as it was. Return a report with the number of rows deleted and
+/// inserted, and the validation errors for each row.
+async fn upload_table(
+    table: &str,
+    state: &Arc<AppState>,
+    replace: bool,
+    filename: &str,
+    data: &[u8],
+) -> Result<SerdeValue, String> {
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let delimiter = if filename.ends_with(".tsv") || filename.ends_with(".tab") {
+        b'\t'
     } else {
-        for command in action.commands.iter() {
-            tracing::debug!("RUNNING {:?}", command);
-            let mut run = Command::new(&command[0]);
-            let mut parts = vec![command[0].to_string()];
-            for arg in &command[1..] {
-                let subbed = re.replace_all(arg, |caps: &Captures| match values.get(&caps[1]) {
-                    Some(v) => v,
-                    None => "",
-                });
-                run.arg(subbed.to_string());
-                parts.push(subbed.to_string());
+        b','
+    };
+    let rows = read_rows(valve, table, delimiter, data)?;
+
+    // Validate every row before making any changes to the table, holding the edit lock so that
+    // nothing changes between validating the rows and inserting them:
+    let mut edits = Edits::begin(valve).await;
+    let operation = format!("upload:{}", table);
+    let total = rows.len() * 2;
+    let mut errors = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        state.report(&operation, i, total, &format!("Validating row {}", i + 1));
+        for mut error in get_validation_errors(table, row, None, valve).await? {
+            error.insert("row".to_string(), json!(i + 1));
+            errors.push(error);
+        }
+    }
+
+    let mut deleted = 0;
+    if replace {
+        let pool = state
+            .config
+            .pool
+            .as_ref()
+            .ok_or("Pool is not initialized.".to_string())?;
+        let row_numbers = get_all_row_numbers_from_pool(pool, table)
+            .await
+            .map_err(|e| e.to_string())?;
+        for row_number in row_numbers {
+            if let Err(e) = edits.delete(table, &row_number).await {
+                return Err(edits.rollback(e).await);
             }
-            tracing::debug!("COMMAND {:?}", run);
-            let output = run.output().expect("Command failed!");
-            tracing::debug!("OUTPUT {:?}", output);
-            let status = output
-                .status
-                .code()
-                .ok_or("Bad exit status")
-                .unwrap_or_default();
-            let stdout = std::str::from_utf8(&output.stdout).unwrap_or_default();
-            let stderr = std::str::from_utf8(&output.stderr).unwrap_or_default();
-            let result = json!({
-                "command": parts.join(" "),
-                "status": status,
-                "stdout": ansi_to_html::convert_escaped(stdout).unwrap(),
-                "stderr": ansi_to_html::convert_escaped(stderr).unwrap(),
-            });
-            results.push(result);
-            if status != 0 {
-                break;
+            deleted += 1;
+        }
+    }
+    for (i, row) in rows.iter().enumerate() {
+        state.report(
+            &operation,
+            rows.len() + i,
+            total,
+            &format!("Inserting row {}", i + 1),
+        );
+        if let Err(e) = edits.insert(table, row).await {
+            return Err(edits.rollback(e).await);
+        }
+    }
+    edits.commit();
+    state.report(&operation, total, total, "Done");
+    state.notify(TableEvent::Reload {
+        table: Some(table.to_string()),
+    });
+
+    Ok(json!({
+        "mode": if replace { "replace" } else { "append" },
+        "deleted": deleted,
+        "inserted": rows.len(),
+        "errors": errors,
+    }))
+}
+
+/// Summarize the validation messages for all tables, with counts by level and rule for each table
+/// and links to the messages and the rows that have them.
+async fn get_messages_summary(
+    uri: Uri,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request messages GET {:?}", uri.path());
+    let pool = state
+        .config
+        .pool
+        .as_ref()
+        .ok_or("Pool is not initialized.".to_string())?;
+    let summary = get_message_summary_from_pool(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut tables = IndexMap::new();
+    for mut entry in summary {
+        let table = entry
+            .get("table")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let level = entry
+            .get("level")
+            .and_then(|l| l.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let rule = entry
+            .get("rule")
+            .and_then(|r| r.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let count = entry.get("count").and_then(|c| c.as_u64()).unwrap_or(0);
+        let href = format!(
+            "message?table=eq.{}&level=eq.{}&rule=eq.{}",
+            urlencoding::encode(&table),
+            urlencoding::encode(&level),
+            urlencoding::encode(&rule)
+        );
+        entry.insert("href".to_string(), json!(href));
+
+        let summary = tables.entry(table.to_string()).or_insert_with(|| {
+            json!({
+                "table": table,
+                "href": table,
+                "rows_href": format!("{}?message=not_is.null", table),
+                "count": 0,
+                "levels": {},
+                "rules": [],
+            })
+        });
+        summary["count"] = json!(summary["count"].as_u64().unwrap_or(0) + count);
+        let level_count = summary["levels"][&level].as_u64().unwrap_or(0);
+        summary["levels"][&level] = json!(level_count + count);
+        if let Some(rules) = summary["rules"].as_array_mut() {
+            rules.push(json!(entry));
+        }
+    }
+    let tables = tables.into_values().collect::<Vec<_>>();
+
+    let path = uri.path();
+    if path.ends_with(".pretty.json") {
+        return Ok(serde_json::to_string_pretty(&tables)
+            .unwrap_or_default()
+            .into_response());
+    } else if path.ends_with(".json") {
+        return Ok(Json(tables).into_response());
+    }
+
+    let page = json!({
+        "page": page_context(&state, ""),
+        "title": "Messages",
+        "tables": tables,
+    });
+    let page_html = match get::page_to_html(&state.config, "messages", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+/// Show the rows of a table with a parent column as a collapsible tree.
+async fn get_hierarchy(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request hierarchy GET {:?}", table);
+    let tree = get::get_hierarchy(&state.config, &table)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let page = json!({
+        "page": page_context(&state, "../"),
+        "title": get::get_table_label(&state.config, &table),
+        "table_name": table,
+        "tree": tree,
+    });
+    let page_html = match get::page_to_html(&state.config, "hierarchy", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+/// List the rows of a table that conflict with its primary or unique keys.
+async fn get_conflicts(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request conflicts GET {:?}", table);
+    conflicts(&table, &state, None).await
+}
+
+/// Resolve a conflicting row: "promote" validates the row again, moving it into the table
+/// when it no longer conflicts, e.g. after the row it conflicted with was fixed,
+/// and "delete" removes it. To fix the values first, edit the row, which promotes it too.
+async fn post_conflicts(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Form(form_params): Form<RequestParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request conflicts POST {:?} {:?}", table, form_params);
+    let row_number = form_params
+        .get("row_number")
+        .and_then(|r| r.parse::<u32>().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "Missing row_number".to_string()))?;
+    let conflict = get::get_conflict_rows(&state.config, &table)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?
+        .into_iter()
+        .find(|r| r["row_number"] == json!(row_number))
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Row {} of '{}' is not in conflict", row_number, table),
+        ))?;
+
+    let message = match form_params.get("action").map(|a| a.as_str()) {
+        Some("promote") => {
+            let row = conflict["values"].as_object().cloned().unwrap_or_default();
+            update_table_row(&table, &row, &row_number, &state).await?;
+            let still_conflicts = get::get_conflict_rows(&state.config, &table)
+                .await
+                .map_err(|e| e.to_string())?
+                .iter()
+                .any(|r| r["row_number"] == json!(row_number));
+            if still_conflicts {
+                format!("Row {} still conflicts with another row", row_number)
+            } else {
+                format!("Moved row {} into the '{}' table", row_number, table)
             }
         }
+        Some("delete") => {
+            delete_table_row(&table, &row_number, &state).await?;
+            format!("Deleted row {}", row_number)
+        }
+        _ => {
+            let message = "Action must be 'promote' or 'delete'".to_string();
+            return Err((StatusCode::BAD_REQUEST, message).into_response().into());
+        }
+    };
+    conflicts(&table, &state, Some(message)).await
+}
+
+async fn conflicts(
+    table: &str,
+    state: &Arc<AppState>,
+    message: Option<String>,
+) -> axum::response::Result<Response> {
+    let rows = get::get_conflict_rows(&state.config, table)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let page = json!({
+        "page": page_context(state, "../"),
+        "title": format!("{} conflicts", get::get_table_label(&state.config, table)),
+        "table_name": table,
+        "message": message,
+        "rows": rows,
+    });
+    let page_html = match get::page_to_html(&state.config, "conflicts", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+async fn get_job(
+    Path(path): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request job GET {:?}", path);
+    let id = path.trim_end_matches(".json").trim_end_matches(".pretty");
+    let job = match id.parse::<u64>().ok().and_then(|id| state.jobs.get(id)) {
+        Some(job) => job,
+        None => {
+            let message = format!("No such job '{}'", id);
+            return Err((StatusCode::NOT_FOUND, Html(message))
+                .into_response()
+                .into());
+        }
+    };
+
+    if path.ends_with(".pretty.json") {
+        return Ok(serde_json::to_string_pretty(&job)
+            .unwrap_or_default()
+            .into_response());
+    } else if path.ends_with(".json") {
+        return Ok(Json(job).into_response());
     }
 
-    let root = if path.contains("/") { "../../" } else { "" };
-    tracing::debug!("ROOT! {root} {path}");
-    let valve = &state
-        .config
-        .valve
-        .as_ref()
-        .ok_or("Valve is not initialized.".to_string())?;
-    let table_map = {
-        let mut table_map = SerdeMap::new();
-        for table in &valve.config.table_order {
-            if table == "history" {
-                continue;
-            }
-            table_map.insert(table.to_string(), json!(table.clone()));
+    let results = job
+        .results
+        .iter()
+        .map(|result| {
+            json!({
+                "command": result.command,
+                "status": result.status,
+                "stdout": ansi_to_html::convert_escaped(&result.stdout).unwrap_or_default(),
+                "stderr": ansi_to_html::convert_escaped(&result.stderr).unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let page = json!({
+        "page": page_context(&state, "../"),
+        "title": format!("Job {}", job.id),
+        "job": job,
+        "results": results,
+    });
+    let page_html = match get::page_to_html(&state.config, "job", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+/// The class hierarchy of an ontology, from its roots down to a class and its subclasses,
+/// with the number of classes under each class, e.g. `/tree/obo:ZFA_0000354`.
+/// The `table` is a VALVE table with a parent column that refers to the same table,
+/// or an LDTab table; by default the first VALVE table with a parent column,
+/// or else the LDTab "statement" table.
+async fn get_class_tree(
+    term: Option<Path<String>>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RequestParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    let term = term.map(|Path(term)| term);
+    tracing::info!("request class tree GET {:?} {:?}", term, params);
+    let valve = state.config.valve.as_ref().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Valve is not initialized.".to_string(),
+    ))?;
+    let table = match params.get("table") {
+        Some(table) => table.to_string(),
+        None => valve
+            .config
+            .table_order
+            .iter()
+            .find(|t| get::get_hierarchy_columns(&state.config, t).is_some())
+            .cloned()
+            .unwrap_or(String::from("statement")),
+    };
+    let tree = class_tree::get_class_tree(&state.config, &table)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    if let Some(term) = &term {
+        if !tree.contains(term) {
+            let message = format!("No class '{}' in '{}'", term, table);
+            return Err((StatusCode::NOT_FOUND, message).into());
         }
-        json!(table_map)
+    }
+    let label = term.as_ref().and_then(|t| tree.label(t));
+    let query = match params.get("table") {
+        Some(table) => format!("?table={}", urlencoding::encode(table)),
+        None => String::new(),
     };
     let page = json!({
-        "page": {
-            "root": root,
-            "project_name": "Nanobot",
-            "tables": table_map,
-            "undo": get::get_undo_message(&state.config),
-            "redo": get::get_redo_message(&state.config),
-            "actions": get::get_action_map(&state.config).unwrap_or_default(),
-            "repo": get::get_repo_details().unwrap_or_default(),
-        },
-        "title": action.label,
-        "action_name": action_name,
-        "action": action,
-        "inputs": inputs,
-        "results": results,
+        "page": page_context(&state, if term.is_some() { "../" } else { "./" }),
+        "title": "Classes",
+        "table_name": table,
+        "term": term,
+        "label": label,
+        "query": query,
+        "tree": tree.to_json(term.as_deref()),
     });
+    let page_html = match get::page_to_html(&state.config, "class_tree", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
 
-    if path.ends_with(".pretty.json") {
-        Ok(serde_json::to_string_pretty(&page)
-            .unwrap_or_default()
-            .into_response())
-    } else if path.ends_with(".json") {
-        Ok(Json(page).into_response())
-    } else {
-        let page_html = match get::page_to_html(&state.config, "action", &page) {
-            Ok(p) => p,
-            Err(e) => return Err(e.to_string().into()),
-        };
-        Ok(Html(page_html).into_response())
+/// The map from each prefix to its base from the prefix table, as a JSON object.
+async fn get_prefixes(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request prefixes GET");
+    let prefixes = prefix::get_prefix_map(&state.config)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(prefixes))
+}
+
+/// Search the term tables for an autocomplete widget,
+/// e.g. `/search?text=gill&limit=10`, returning a JSON array of the best matches.
+async fn get_search(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RequestParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request search GET {:?}", params);
+    let text = params.get("text").map(|t| t.as_str()).unwrap_or_default();
+    let limit = match params.get("limit") {
+        Some(limit) => limit.parse::<usize>().map_err(|e| {
+            let message = format!("Invalid limit '{}': {}", limit, e);
+            (StatusCode::BAD_REQUEST, message)
+        })?,
+        None => DEFAULT_SEARCH_LIMIT,
+    }
+    .min(state.config.max_results_per_page as usize);
+    let matches = search_terms(&state.config, text, limit)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(matches))
+}
+
+/// The statements of an LDTab table as JSON, leaving out the retracted ones,
+/// filtered by the `subject`, `predicate`, and `object` query parameters,
+/// e.g. `/statement/statements.json?subject=obo:ZFA_0000354&predicate=rdfs:label`.
+/// Use `limit` and `offset` to page through them.
+async fn get_statements(
+    Path(table): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RequestParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    tracing::info!("request statements GET {:?} {:?}", table, params);
+    let valve = state.config.valve.as_ref().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Valve is not initialized.".to_string(),
+    ))?;
+    let pool = state.config.pool.as_ref().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Pool is not initialized.".to_string(),
+    ))?;
+    if valve.config.table.contains_key(&table)
+        || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        let message = format!("'{}' is not an LDTab table", table);
+        return Err((StatusCode::NOT_FOUND, message).into());
     }
+    let number = |name: &str, default: usize| match params.get(name) {
+        Some(n) => n.parse::<usize>().map_err(|e| {
+            let message = format!("Invalid {} '{}': {}", name, n, e);
+            (StatusCode::BAD_REQUEST, message)
+        }),
+        None => Ok(default),
+    };
+    let limit = number("limit", state.config.results_per_page as usize)?
+        .min(state.config.max_results_per_page as usize);
+    let offset = number("offset", 0)?;
+
+    let param = |name: &str| params.get(name).map(|p| p.as_str());
+    let mut select = ldtab::statement_select(
+        &table,
+        param("subject"),
+        param("predicate"),
+        param("object"),
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:?}", e)))?;
+    select.limit(limit).offset(offset);
+    let statements = ldtab::get_statements(&select, pool).map_err(|e| {
+        let message = format!("Could not get the statements of '{}': {:?}", table, e);
+        (StatusCode::NOT_FOUND, message)
+    })?;
+    Ok(Json(json!({
+        "table": table,
+        "limit": limit,
+        "offset": offset,
+        "statements": statements,
+    })))
 }
 
 async fn get_tree(
@@ -351,10 +2251,8 @@ async fn tree(
     subject: &str,
     params: &RequestParams,
 ) -> axum::response::Result<impl IntoResponse> {
-    // TODO: Just hacking!
     if params.contains_key("user.action") {
-        let result = action(&table.into(), state, params)?;
-        return Ok(result.into_response());
+        return redirect_to_action("../", params);
     }
 
     if table.contains(" ") {
@@ -378,7 +2276,9 @@ async fn tree(
             .unwrap_or_default();
         let mut iris = HashSet::from([term_id.into()]);
         signature::get_iris(&content, &mut iris);
-        let prefixes = ldtab::get_prefix_hash_map(&iris, &pool).await.unwrap();
+        let prefixes = ldtab::get_prefix_hash_map(&iris, &pool)
+            .await
+            .map_err(|e| format!("Could not get the prefixes for '{}': {}", term_id, e))?;
         let mut context = SerdeMap::new();
         for prefix in prefixes.keys() {
             context.insert(
@@ -404,15 +2304,17 @@ async fn tree(
         let mut result = SerdeMap::new();
         result.insert("@context".into(), json!(context));
         result.insert("@id".into(), json!(term_id));
-        let content = content.as_object().unwrap();
-        for key in content.keys() {
-            let values = content.get(key).unwrap().as_array().unwrap();
+        let content = content.as_object().cloned().unwrap_or_default();
+        for (key, values) in &content {
             let mut list = vec![];
-            for value in values {
-                let value = value.as_object().unwrap();
+            for value in values.as_array().into_iter().flatten() {
+                let value = match value.as_object() {
+                    Some(value) => value,
+                    None => continue,
+                };
                 if let Some(object) = value.get("object") {
                     if let Some(datatype) = value.get("datatype") {
-                        let datatype = datatype.as_str().unwrap();
+                        let datatype = datatype.as_str().unwrap_or_default();
                         match datatype {
                             "_IRI" => {
                                 if specials.contains(&key.as_str()) {
@@ -441,10 +2343,14 @@ async fn tree(
                     }
                 }
             }
-            if list.len() > 1 {
-                result.insert(key.into(), json!(list));
-            } else {
-                result.insert(key.into(), json!(list[0]));
+            match list.len() {
+                0 => (),
+                1 => {
+                    result.insert(key.into(), list.remove(0));
+                }
+                _ => {
+                    result.insert(key.into(), json!(list));
+                }
             }
         }
         if subject.ends_with(".pretty.json") {
@@ -514,33 +2420,11 @@ async fn tree(
     let empty = String::new();
     let label = labels.get(subject).unwrap_or(&empty);
 
-    let table_map = {
-        let mut table_map = SerdeMap::new();
-        let valve = &state
-            .config
-            .valve
-            .as_ref()
-            .ok_or("Valve is not initialized.".to_string())?;
-        for table in &valve.config.table_order {
-            if table == "history" {
-                continue;
-            }
-            table_map.insert(table.to_string(), json!(table.clone()));
-        }
-        json!(table_map)
-    };
     let elapsed = start.elapsed().as_millis() as usize;
+    let mut context = page_context(state, "../");
+    context["elapsed"] = json!(elapsed);
     let page = json!({
-        "page": {
-            "root": "../",
-            "project_name": "Nanobot",
-            "tables": table_map,
-            "undo": get::get_undo_message(&state.config),
-            "redo": get::get_redo_message(&state.config),
-            "actions": get::get_action_map(&state.config).unwrap_or_default(),
-            "repo": get::get_repo_details().unwrap_or_default(),
-            "elapsed": elapsed,
-        },
+        "page": context,
         "title": "table",
         "table_name": table,
         "subject": subject,
@@ -568,7 +2452,9 @@ async fn tree2(
     tracing::info!("TREE 2 '{table}' {subject}");
     let start = std::time::Instant::now();
 
-    let (table1, table2) = table.split_once(" ").unwrap();
+    let (table1, table2) = table
+        .split_once(' ')
+        .ok_or(format!("Expected two tables to compare, got '{}'", table))?;
 
     let pool = &state
         .config
@@ -601,10 +2487,9 @@ async fn tree2(
     let tree1 = hiccup::insert_href(&tree1, &format!("../{table}/{{curie}}")).unwrap_or_default();
     let tree1 = hiccup::render(&tree1).unwrap_or_default();
 
-    let tree2 =
-        tree_view::get_hiccup_term_tree(subject, table2, &state.config.pool.as_ref().unwrap())
-            .await
-            .unwrap_or_default();
+    let tree2 = tree_view::get_hiccup_term_tree(subject, table2, &pool)
+        .await
+        .unwrap_or_default();
     let tree2 = hiccup::insert_href(&tree2, &format!("../{table}/{{curie}}")).unwrap_or_default();
     let tree2 = hiccup::render(&tree2).unwrap_or_default();
 
@@ -657,33 +2542,11 @@ async fn tree2(
     let empty = String::new();
     let label = labels.get(subject).unwrap_or(&empty);
 
-    let table_map = {
-        let mut table_map = SerdeMap::new();
-        let valve = &state
-            .config
-            .valve
-            .as_ref()
-            .ok_or("Valve is not initialized.".to_string())?;
-        for table in &valve.config.table_order {
-            if table == "history" {
-                continue;
-            }
-            table_map.insert(table.to_string(), json!(table.clone()));
-        }
-        json!(table_map)
-    };
     let elapsed = start.elapsed().as_millis() as usize;
+    let mut context = page_context(state, "../");
+    context["elapsed"] = json!(elapsed);
     let page = json!({
-        "page": {
-            "root": "../",
-            "project_name": "Nanobot",
-            "tables": table_map,
-            "undo": get::get_undo_message(&state.config),
-            "redo": get::get_redo_message(&state.config),
-            "actions": get::get_action_map(&state.config).unwrap_or_default(),
-            "repo": get::get_repo_details().unwrap_or_default(),
-            "elapsed": elapsed,
-        },
+        "page": context,
         "title": "table",
         "table_name": table,
         "table1_name": table1,
@@ -709,10 +2572,8 @@ async fn table(
     form_params: &RequestParams,
     request_type: RequestType,
 ) -> axum::response::Result<impl IntoResponse> {
-    // TODO: Just hacking!
     if query_params.contains_key("user.action") {
-        let result = action(path, state, query_params)?;
-        return Ok(result.into_response());
+        return redirect_to_action("", query_params);
     }
 
     let (table, format, shape);
@@ -720,6 +2581,23 @@ async fn table(
     sqlrest_params.remove("shape");
     sqlrest_params.remove("view");
     sqlrest_params.remove("format");
+    // Like "meta=true", "count=only" is not a valid filter.
+    let count_only = query_params.get("count").map(|c| c.as_str()) == Some("only");
+    if count_only {
+        sqlrest_params.remove("count");
+    }
+    // "meta=true" is not a valid filter, so it can't be meant for a "meta" column.
+    if query_params.get("meta").map(|m| m.as_str()) == Some("true") {
+        sqlrest_params.remove("meta");
+    }
+    let iris = query_params.get("iris").map(|i| i.as_str()) == Some("true");
+    if iris {
+        sqlrest_params.remove("iris");
+    }
+    // For the map shape, "key" chooses the key column rather than filtering on a "key" column.
+    if query_params.get("shape").map(|s| s.as_str()) == Some("map") {
+        sqlrest_params.remove("key");
+    }
     for key in sqlrest_params.clone().keys() {
         if key.starts_with("nb.") {
             sqlrest_params.remove(key);
@@ -748,20 +2626,42 @@ async fn table(
         table = path.replace(".csv", "");
         format = "csv";
         shape = "value_rows";
+    } else if path.ends_with(".jsonl") {
+        table = path.replace(".jsonl", "");
+        format = "jsonl";
+        shape = "value_rows";
+    } else if path.ends_with(".xlsx") {
+        table = path.replace(".xlsx", "");
+        format = "xlsx";
+        shape = "value_rows";
+    } else if let Some(owl_format) = owl::OWL_FORMATS
+        .iter()
+        .find(|f| path.ends_with(&format!(".{}", f)))
+    {
+        table = path
+            .trim_end_matches(&format!(".{}", owl_format))
+            .to_string();
+        format = *owl_format;
+        shape = "value_rows";
     } else if path.ends_with(".txt") {
         table = path.replace(".txt", "");
         format = "text";
         shape = "value_rows";
+    } else if path.ends_with(".html") {
+        // HTML is the default, but this overrides a table's default_format.
+        table = path.replace(".html", "");
+        format = "html";
+        shape = "page";
     } else {
         table = path.clone();
         format = "html";
         shape = "page";
     }
     let config = &state.config;
-    let valve = config
-        .valve
-        .as_ref()
-        .ok_or("Valve is not initialized.".to_string())?;
+    let valve = config.valve.as_ref().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Valve is not initialized.".to_string(),
+    ))?;
     let mut view = match query_params.get("view") {
         Some(view) => view.to_string(),
         None => "".to_string(),
@@ -769,6 +2669,10 @@ async fn table(
 
     // TODO: properly detect LDTab tables
     if !valve.config.table_order.contains(&table) {
+        if format != "html" {
+            let message = format!("No table '{}'", table);
+            return Err((StatusCode::NOT_FOUND, message).into_response().into());
+        }
         let url = format!("{table}/owl:Class");
         return Ok(Redirect::permanent(&url).into_response());
     }
@@ -776,11 +2680,16 @@ async fn table(
     // Handle actions such as filtering.
     if query_params.contains_key("nb.action") {
         tracing::debug!("ACTION {:?}", query_params);
-        let action = query_params.get("nb.action").unwrap();
+        let action = &query_params["nb.action"];
         if action == "filter" {
-            let column = query_params.get("nb.column").unwrap();
-            let operator = query_params.get("nb.operator").unwrap();
-            let constraint = query_params.get("nb.constraint").unwrap();
+            let param = |name: &str| {
+                query_params
+                    .get(name)
+                    .ok_or((StatusCode::BAD_REQUEST, format!("Missing '{}'", name)))
+            };
+            let column = param("nb.column")?;
+            let operator = param("nb.operator")?;
+            let constraint = param("nb.constraint")?;
             tracing::debug!("FILTER {}, {}, {}", column, operator, constraint);
             sqlrest_params.insert(column.into(), format!("{}.{}", operator, constraint));
             tracing::debug!("SQLREST {:?}", sqlrest_params);
@@ -832,7 +2741,7 @@ async fn table(
 
     // Handle a POST request to validate or submit a new row for insertion into the table:
     let mut form_map = None;
-    let columns = get_columns(&table, valve)?;
+    let columns = get_columns(&table, valve).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
     if request_type == RequestType::POST {
         if view == "" {
             view = String::from("form");
@@ -860,9 +2769,9 @@ async fn table(
         };
 
         if action == "validate" {
-            let validated_row = match validate_table_row(&table, &new_row, &None, state) {
+            let validated_row = match validate_table_row(&table, &new_row, &None, state).await {
                 Ok(v) => v,
-                Err(e) => return Err(e.into()),
+                Err(e) => return Err((StatusCode::UNPROCESSABLE_ENTITY, e).into_response().into()),
             };
             // If this is a validate action, fill in form_map which will then be handled below.
             match get_row_as_form_map(config, &table, &validated_row) {
@@ -877,21 +2786,23 @@ async fn table(
             // containing a javascript redirect as a response which points back to the last
             // page of the table:
             let offset = {
-                let row_number = match insert_table_row(&table, &new_row, state) {
+                let row_number = match insert_table_row(&table, &new_row, state).await {
                     Ok(n) => n,
-                    Err(e) => return Err(e.to_string().into()),
+                    Err(e) => {
+                        return Err((StatusCode::UNPROCESSABLE_ENTITY, e).into_response().into())
+                    }
                 };
-                let pages = row_number / state.config.results_per_page as u32;
-                pages * state.config.results_per_page as u32
+                let results_per_page = state.config.results_per_page_for(&table) as u32;
+                (row_number / results_per_page) * results_per_page
             };
             let html = format!(
                 r#"<script>
                       var timer = setTimeout(function() {{
-                        window.location.replace("/{table}?offset={offset}");
+                        window.location.replace("{table}?offset={offset}");
                       }}, 1000);
                    </script>
                    The insert operation succeeded. If you are not automatically redirected, click
-                   <a href="/{table}?offset={offset}">here</a> to go back to {table}"#,
+                   <a href="{table}?offset={offset}">here</a> to go back to {table}"#,
                 table = table,
                 offset = offset,
             );
@@ -921,7 +2832,8 @@ async fn table(
                     // Since this is supposed to be a new row, the initial value of this cell should
                     // match the nulltype (if it exists) of its associated datatype in order to be
                     // valid. Otherwise we mark it as invalid.
-                    let valid = matches_nulltype(&table, &column, &value, valve)?;
+                    let valid = matches_nulltype(&table, &column, &value, valve)
+                        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
                     new_row.insert(
                         column.to_string(),
                         json!({
@@ -942,32 +2854,14 @@ async fn table(
         }
 
         // Used to display a drop-down or menu of some kind containing all the available tables:
-        let table_map = {
-            let mut table_map = SerdeMap::new();
-            for table in &valve.config.table_order {
-                if table == "history" {
-                    continue;
-                }
-                table_map.insert(table.to_string(), json!(table.clone()));
-            }
-            json!(table_map)
-        };
 
         // Fill in the page JSON containing all of the configuration parameters that we will be
         // passing (through page_to_html()) to the minijinja template:
         let page = json!({
-            "page": {
-                "root": "",
-                "project_name": "Nanobot",
-                "tables": table_map,
-                "undo": get::get_undo_message(&state.config),
-                "redo": get::get_redo_message(&state.config),
-                "actions": get::get_action_map(&state.config).unwrap_or_default(),
-                "repo": get::get_repo_details().unwrap_or_default(),
-            },
+            "page": page_context(state, ""),
             "title": "table",
             "table_name": table,
-            "subtitle": format!(r#"<a href="/{}">Return to table</a>"#, table),
+            "subtitle": format!(r#"<a href="{}">Return to table</a>"#, table),
             "messages": [],
             "form_map": form_map,
         });
@@ -979,6 +2873,22 @@ async fn table(
     } else {
         // In this case the request is to view the database contents represented by the request URL,
         // row by row.
+        // The HTML view uses the columns and order from the table's [tables.NAME] section,
+        // unless the request chooses its own.
+        if format == "html" {
+            if let Some(table_config) = config.tables.get(&table) {
+                if let Some(columns) = &table_config.columns {
+                    if !sqlrest_params.contains_key("select") {
+                        sqlrest_params.insert("select".to_string(), columns.join(","));
+                    }
+                }
+                if let Some(sort) = &table_config.sort {
+                    if !sqlrest_params.contains_key("order") {
+                        sqlrest_params.insert("order".to_string(), sort.to_string());
+                    }
+                }
+            }
+        }
         let url = {
             let url = sqlrest_params
                 .iter()
@@ -991,9 +2901,120 @@ async fn table(
             }
         };
         tracing::info!("URL: {}", url);
-        let select = parse(&url)?;
+        let mut select = parse(&url).map_err(|e| {
+            let message = format!("Invalid query '{}': {}", url, e);
+            (StatusCode::BAD_REQUEST, message)
+        })?;
+        check_select_columns(&select, &table, &columns)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let limit_max = config.max_results_per_page_for(&table);
+        if select.limit.unwrap_or(0) > limit_max {
+            select.limit(limit_max);
+        }
         tracing::info!("SELECT {:?}", select);
-        match get::get_rows(&state.config, &select, &shape, &format).await {
+        if count_only {
+            return match get::get_count(&state.config, &select).await {
+                Ok(count) => Ok((
+                    [
+                        (header::CONTENT_TYPE, "text/plain".to_string()),
+                        (header::CONTENT_RANGE, format!("*/{}", count)),
+                    ],
+                    count.to_string(),
+                )
+                    .into_response()),
+                Err(x) => Err((StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+                    .into_response()
+                    .into()),
+            };
+        }
+        if query_params.get("nb.sql").map(|s| s.as_str()) == Some("true") {
+            // Show the SQL for debugging, instead of running it.
+            return match get::get_sql(&state.config, &select) {
+                Ok(sql) => Ok(([(header::CONTENT_TYPE, "text/plain")], sql).into_response()),
+                Err(x) => Err((StatusCode::BAD_REQUEST, x.to_string())
+                    .into_response()
+                    .into()),
+            };
+        }
+        let key = query_params.get("key").map(|k| k.as_str());
+        let meta = query_params.get("meta").map(|m| m.as_str()) == Some("true");
+        if get::STREAM_FORMATS.contains(&format)
+            && (shape == "value_rows" || shape == "objects")
+            && !iris
+            && !meta
+            && !(format.starts_with("json") && get::has_label_fields(config, &table))
+        {
+            // Stream the rows with chunked transfer encoding, so that large exports are not
+            // buffered in memory. Only JSON Lines streams the whole table by default.
+            if format != "jsonl" && select.limit.is_none() {
+                select.limit(config.results_per_page_for(&table));
+            }
+            return match get::stream_value_text(&state.config, &select, format) {
+                Ok(chunks) => {
+                    let media_type = match format {
+                        "csv" => "text/csv",
+                        "tsv" => "text/tab-separated-values",
+                        "json" => "application/json; charset=utf-8",
+                        _ => "application/x-ndjson",
+                    };
+                    Ok((
+                        [(header::CONTENT_TYPE, media_type)],
+                        StreamBody::new(chunks),
+                    )
+                        .into_response())
+                }
+                Err(x) => {
+                    tracing::info!("Get Error: {:?}", x);
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+                        .into_response()
+                        .into())
+                }
+            };
+        }
+        if owl::OWL_FORMATS.contains(&format) {
+            let media_type = TABLE_FORMATS
+                .iter()
+                .find(|(extension, _)| *extension == format)
+                .map(|(_, media_type)| *media_type)
+                .unwrap_or("text/plain");
+            return match owl::get_owl(&state.config, &table, format).await {
+                Ok(owl) => Ok(([(header::CONTENT_TYPE, media_type)], owl).into_response()),
+                Err(x) => {
+                    tracing::info!("Get Error: {:?}", x);
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+                        .into_response()
+                        .into())
+                }
+            };
+        }
+        if format == "xlsx" {
+            return match get::get_xlsx(&state.config, &[select], false).await {
+                Ok(workbook) => Ok((
+                    [
+                        (header::CONTENT_TYPE, XLSX_MEDIA_TYPE.to_string()),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{}.xlsx\"", table),
+                        ),
+                    ],
+                    workbook,
+                )
+                    .into_response()),
+                Err(x) => {
+                    tracing::info!("Get Error: {:?}", x);
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+                        .into_response()
+                        .into())
+                }
+            };
+        }
+        let options = get::GetRowsOptions {
+            key: key.map(|k| k.to_string()),
+            meta,
+            iris,
+            ..Default::default()
+        };
+        match get::get_rows(&state.config, &select, &shape, &format, &options).await {
             Ok(x) => match format {
                 "tsv" => Ok(([("content-type", "text/tab-separated-values")], x).into_response()),
                 "csv" => Ok(([("content-type", "text/csv")], x).into_response()),
@@ -1003,11 +3024,14 @@ async fn table(
                     Ok(([("content-type", "application/json; charset=utf-8")], x).into_response())
                 }
                 "pretty.json" => Ok(x.into_response()),
+                "jsonl" => Ok(([("content-type", "application/x-ndjson")], x).into_response()),
                 _ => unreachable!("Unsupported format"),
             },
             Err(x) => {
                 tracing::info!("Get Error: {:?}", x);
-                Ok((StatusCode::NOT_FOUND, Html("404 Not Found".to_string())).into_response())
+                Err((StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+                    .into_response()
+                    .into())
             }
         }
     }
@@ -1033,6 +3057,7 @@ async fn post_row(
         &form_params,
         RequestType::POST,
     )
+    .await
 }
 
 async fn get_row(
@@ -1043,9 +3068,7 @@ async fn get_row(
     tracing::info!("request row GET {:?} {:?} {:?}", table, row_number, params);
 
     if params.contains_key("user.action") {
-        let path = &format!("{table}/row/{row_number}");
-        let result = action(&path, &state, &params)?;
-        return Ok(result.into_response());
+        return redirect_to_action("../../", &params);
     }
 
     let row = row(
@@ -1054,11 +3077,55 @@ async fn get_row(
         &params,
         &RequestParams::new(),
         RequestType::GET,
-    )?;
+    )
+    .await?;
     Ok(row.into_response())
 }
 
-fn row(
+/// Show the changes to a row, newest first, as an HTML timeline,
+/// or as JSON for `history.json` or an Accept header that prefers JSON.
+async fn get_row_history(
+    Path((table, row_number)): Path<(String, String)>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<Response> {
+    tracing::info!("request row history GET {:?} {:?}", table, row_number);
+    let row_number = row_number.parse::<u32>().map_err(|e| {
+        let error = format!(
+            "Unable to parse row_number '{}' due to error: {}",
+            row_number, e
+        );
+        (StatusCode::BAD_REQUEST, error)
+    })?;
+    let history = get::get_row_history(&state.config, &table, row_number)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    if uri.path().ends_with(".json") || negotiate_format(uri.path(), &headers) == Some("json") {
+        return Ok(Json(json!({
+            "table": table,
+            "row_number": row_number,
+            "history": history,
+        }))
+        .into_response());
+    }
+
+    let page = json!({
+        "page": page_context(&state, "../../../"),
+        "title": format!("{} row {} history", table, row_number),
+        "table_name": table,
+        "row_number": row_number,
+        "history": history.iter().rev().collect::<Vec<_>>(),
+    });
+    let page_html = match get::page_to_html(&state.config, "history", &page) {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string().into()),
+    };
+    Ok(Html(page_html).into_response())
+}
+
+async fn row(
     Path((table, row_number)): Path<(String, String)>,
     state: &Arc<AppState>,
     query_params: &RequestParams,
@@ -1086,9 +3153,10 @@ fn row(
         form_params,
         request_type,
     )
+    .await
 }
 
-fn render_row_from_database(
+async fn render_row_from_database(
     table: &str,
     row_number: u32,
     state: &Arc<AppState>,
@@ -1128,11 +3196,14 @@ fn render_row_from_database(
                     .into_response()
                     .into())
             }
-            Some(column_name) => match block_on(valve.get_matching_values(
-                &table,
-                column_name,
-                query_params.get("text").and_then(|t| Some(t.as_str())),
-            )) {
+            Some(column_name) => match valve
+                .get_matching_values(
+                    &table,
+                    column_name,
+                    query_params.get("text").and_then(|t| Some(t.as_str())),
+                )
+                .await
+            {
                 Ok(r) => return Ok(Json(r).into_response()),
                 Err(e) => {
                     return Err((StatusCode::BAD_REQUEST, Html(format!("{:?}", e)))
@@ -1171,16 +3242,16 @@ fn render_row_from_database(
             Some(v) => v,
         };
         if action == "validate" {
-            let validated_row = match validate_table_row(table, &new_row, &Some(row_number), state)
-            {
-                Ok(v) => {
-                    let mut tmp = SerdeMap::new();
-                    tmp.insert("row_number".to_string(), json!(row_number));
-                    tmp.extend(v);
-                    tmp
-                }
-                Err(e) => return Err(e.into()),
-            };
+            let validated_row =
+                match validate_table_row(table, &new_row, &Some(row_number), state).await {
+                    Ok(v) => {
+                        let mut tmp = SerdeMap::new();
+                        tmp.insert("row_number".to_string(), json!(row_number));
+                        tmp.extend(v);
+                        tmp
+                    }
+                    Err(e) => return Err(e.into()),
+                };
             match get_row_as_form_map(config, table, &validated_row) {
                 Ok(f) => form_map = Some(f),
                 Err(e) => {
@@ -1189,7 +3260,7 @@ fn render_row_from_database(
                 }
             };
         } else if action == "submit" {
-            if let Err(e) = update_table_row(table, &new_row, &row_number, state) {
+            if let Err(e) = update_table_row(table, &new_row, &row_number, state).await {
                 return Err(e.to_string().into());
             }
 
@@ -1209,7 +3280,7 @@ fn render_row_from_database(
                 );
             }
         } else if action == "delete" {
-            if let Err(e) = delete_table_row(table, &row_number, state) {
+            if let Err(e) = delete_table_row(table, &row_number, state).await {
                 return Err(e.to_string().into());
             }
             tracing::debug!("DELETED ROW {table} {row_number}, redirecting to ../../{table}");
@@ -1262,36 +3333,36 @@ fn render_row_from_database(
     };
 
     // Used to display a drop-down or menu containing all of the tables:
-    let table_map = {
-        let mut table_map = SerdeMap::new();
-        for table in &valve.config.table_order {
-            if table == "history" {
-                continue;
-            }
-            table_map.insert(table.to_string(), json!(table.clone()));
-        }
-        json!(table_map)
-    };
+
+    // The rows of other tables that refer to this one, linked to those rows:
+    let mut references = get::get_row_references(config, table, row_number)
+        .await
+        .map_err(|e| e.to_string())?;
+    for reference in references.iter_mut() {
+        let href = format!(
+            "{}?{}=eq.{}",
+            reference["table"].as_str().unwrap_or_default(),
+            reference["column"].as_str().unwrap_or_default(),
+            urlencoding::encode(&format!(
+                "\"{}\"",
+                reference["value"].as_str().unwrap_or_default()
+            ))
+        );
+        reference["href"] = json!(href);
+    }
 
     // Fill in the page JSON which contains all of the parameters that we will be passing to our
     // minijinja template (through page_to_html()):
     let page = json!({
-        "page": {
-            "root": "../../",
-            "project_name": "Nanobot",
-            "tables": table_map,
-            "undo": get::get_undo_message(&state.config),
-            "redo": get::get_redo_message(&state.config),
-            "actions": get::get_action_map(&state.config).unwrap_or_default(),
-            "repo": get::get_repo_details().unwrap_or_default(),
-        },
+        "page": page_context(state, "../../"),
         "title": "table",
         "table_name": table,
         "row_number": row_number,
         "offset": row_number - 1,
-        "subtitle": format!(r#"<a href="/{}/row/{}">Return to row</a>"#, table, row_number),
+        "subtitle": format!(r#"<a href="{}">Return to row</a>"#, row_number),
         "messages": messages,
         "form_map": form_map,
+        "references": references,
     });
     let page_html = match get::page_to_html(&state.config, &view, &page) {
         Ok(p) => p,
@@ -1300,6 +3371,23 @@ fn render_row_from_database(
     Ok(Html(page_html).into_response())
 }
 
+/// Check that the columns that the query selects, filters, and sorts on belong to the table.
+fn check_select_columns(select: &Select, table: &str, columns: &[String]) -> Result<(), String> {
+    let selected = select.select.iter().map(|s| &s.expression);
+    let filtered = select.filter.iter().map(|f| &f.lhs);
+    let sorted = select.order_by.iter().map(|o| &o.column);
+    for column in selected.chain(filtered).chain(sorted) {
+        let column = column.trim_matches('"');
+        if column != "*"
+            && !["row_number", "row_order"].contains(&column)
+            && !columns.iter().any(|c| c == column)
+        {
+            return Err(format!("The '{}' table has no column '{}'", table, column));
+        }
+    }
+    Ok(())
+}
+
 fn matches_nulltype(table: &str, column: &str, value: &str, valve: &Valve) -> Result<bool, String> {
     let column_config = get_column_config(table, column, valve)?;
     if column_config.nulltype == "" {
@@ -1419,7 +3507,10 @@ fn get_html_type_and_values(
     datatype: &str,
     values: &Option<Vec<String>>,
 ) -> Result<(Option<String>, Option<Vec<String>>), String> {
-    let valve = config.valve.as_ref().unwrap();
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
     let dt_config = match valve.config.datatype.get(datatype) {
         Some(o) => o,
         None => {
@@ -1465,7 +3556,9 @@ fn get_html_type_and_values(
 
     let rows = &config.datatype;
     for row in rows.iter() {
-        let name = row.get("datatype").unwrap();
+        let name = row
+            .get("datatype")
+            .ok_or(format!("No 'datatype' in datatype row {:?}", row))?;
         if name == datatype {
             if let Some(html_type) = row.get("html_type") {
                 if let Some(html_type) = html_type.as_str() {
@@ -1483,81 +3576,118 @@ fn get_html_type_and_values(
     Ok((None, None))
 }
 
-fn insert_table_row(
+async fn insert_table_row(
     table_name: &str,
     row_data: &SerdeMap,
     state: &Arc<AppState>,
 ) -> Result<u32, String> {
-    let (row_num, _) = block_on(
-        state
-            .config
-            .valve
-            .as_ref()
-            .ok_or("Valve is not initialized.".to_string())?
-            .insert_row(&table_name, &row_data),
-    )
-    .unwrap();
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let _lock = edit::lock().await;
+    let (row_num, _) = valve
+        .insert_row(table_name, row_data)
+        .await
+        .map_err(|e| format!("Could not insert a row into '{}': {:?}", table_name, e))?;
+    state.notify(TableEvent::Insert {
+        table: table_name.to_string(),
+        row: row_num,
+    });
     Ok(row_num)
 }
 
-fn update_table_row(
+async fn update_table_row(
     table_name: &str,
     row_data: &SerdeMap,
     row_number: &u32,
     state: &Arc<AppState>,
 ) -> Result<(), String> {
-    block_on(
-        state
-            .config
-            .valve
-            .as_ref()
-            .ok_or("Valve is not initialized.".to_string())?
-            .update_row(&table_name, row_number, &row_data),
-    )
-    .unwrap();
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let _lock = edit::lock().await;
+    valve
+        .update_row(table_name, row_number, row_data)
+        .await
+        .map_err(|e| {
+            format!(
+                "Could not update row {} of '{}': {:?}",
+                row_number, table_name, e
+            )
+        })?;
+    state.notify(TableEvent::Update {
+        table: table_name.to_string(),
+        row: *row_number,
+    });
     Ok(())
 }
 
-fn delete_table_row(
+async fn delete_table_row(
     table_name: &str,
     row_number: &u32,
     state: &Arc<AppState>,
 ) -> Result<(), String> {
-    block_on(
-        state
-            .config
-            .valve
-            .as_ref()
-            .ok_or("Valve is not initialized.".to_string())?
-            .delete_row(&table_name, row_number),
-    )
-    .unwrap();
+    let valve = state
+        .config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
+    let _lock = edit::lock().await;
+    valve
+        .delete_row(table_name, row_number)
+        .await
+        .map_err(|e| {
+            format!(
+                "Could not delete row {} of '{}': {:?}",
+                row_number, table_name, e
+            )
+        })?;
+    state.notify(TableEvent::Delete {
+        table: table_name.to_string(),
+        row: *row_number,
+    });
     Ok(())
 }
 
-fn validate_table_row(
+async fn validate_table_row(
     table_name: &str,
     row_data: &SerdeMap,
     row_number: &Option<u32>,
     state: &Arc<AppState>,
 ) -> Result<SerdeMap, String> {
+    state.report(
+        "validate",
+        0,
+        1,
+        &format!("Validating row for '{}'", table_name),
+    );
     let validated_row = {
         let mut result_row = SerdeMap::new();
         for (column, value) in row_data.iter() {
             result_row.insert(column.to_string(), value.clone());
         }
-        match block_on(
-            state
-                .config
-                .valve
-                .as_ref()
-                .ok_or("Valve is not initialized.".to_string())?
-                .validate_row(table_name, &result_row, *row_number),
-        ) {
+        match state
+            .config
+            .valve
+            .as_ref()
+            .ok_or("Valve is not initialized.".to_string())?
+            .validate_row(table_name, &result_row, *row_number)
+            .await
+        {
             Ok(r) => r,
             Err(e) => return Err(format!("{:?}", e)),
         }
     };
+    state.report(
+        "validate",
+        1,
+        1,
+        &format!("Validated row for '{}'", table_name),
+    );
     Ok(validated_row
         .contents_to_rich_json()
         .map_err(|e| format!("{:?}", e))?)
@@ -1644,7 +3774,10 @@ fn get_row_as_form_map(
     table_name: &str,
     row_data: &SerdeMap,
 ) -> Result<SerdeMap, String> {
-    let valve = config.valve.as_ref().unwrap();
+    let valve = config
+        .valve
+        .as_ref()
+        .ok_or("Valve is not initialized.".to_string())?;
     let mut result = SerdeMap::new();
     let mut row_valid = None;
     let mut form_row_id = 0;
@@ -2034,3 +4167,47 @@ fn get_hiccup_form_row(
         json!(value_col),
     ])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_format() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(
+            negotiate_format("/penguin", &accept("application/json")),
+            Some("json")
+        );
+        assert_eq!(
+            negotiate_format("/penguin", &accept("text/csv")),
+            Some("csv")
+        );
+        // Browsers ask for HTML first.
+        assert_eq!(
+            negotiate_format(
+                "/penguin",
+                &accept("text/html,application/xml;q=0.9,*/*;q=0.8")
+            ),
+            None
+        );
+        // The highest quality wins, and unknown or refused types are skipped.
+        assert_eq!(
+            negotiate_format(
+                "/penguin",
+                &accept("text/html;q=0.5, text/csv;q=0, image/png, text/tab-separated-values")
+            ),
+            Some("tsv")
+        );
+        // An extension in the path takes precedence over the header.
+        assert_eq!(
+            negotiate_format("/penguin.csv", &accept("application/json")),
+            None
+        );
+        assert_eq!(negotiate_format("/penguin", &HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::Config;
+use crate::error::NanobotError;
+use crate::get::{self, GetQuery, VALUE_ROWS_FORMATS};
+use crate::sql::{check_read_only, get_rows_from_sql};
+use enquote::unquote;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+/// The file that the shell keeps its history in, in the current directory,
+/// so that each project has its own.
+pub const HISTORY_FILE: &str = ".nanobot_history";
+
+const HELP: &str = "\
+TABLE [FILTER ...]   Get rows of a table, e.g. penguin species=eq.Adelie order=island limit=5
+                     FILTERs are COLUMN=OPERATOR.VALUE, as in `nanobot get --filter`,
+                     with select=COLUMNS, order=ORDER, limit=N, and offset=N
+TABLE?QUERY          The same, with the query string that `nanobot serve` uses
+SELECT ...           Run a read-only SQL query, as `nanobot sql` does
+.tables              List the tables
+.format [FORMAT]     Show or set the output format: text (default), csv, json, ...
+.help                Show this help
+.quit                Leave the shell (or press Ctrl-D)";
+
+/// A line of input to the shell.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Empty,
+    Get(GetQuery),
+    Sql(String),
+    Tables,
+    Format(Option<String>),
+    Help,
+    Quit,
+}
+
+/// Parse a line of input: a dot command, a read-only SQL statement,
+/// or a table with filters and options.
+pub fn parse_input(line: &str) -> Result<Input, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(Input::Empty);
+    }
+    if let Some(command) = line.strip_prefix('.') {
+        let mut words = command.split_whitespace();
+        return match (words.next(), words.next()) {
+            (Some("tables"), None) => Ok(Input::Tables),
+            (Some("format"), format) => Ok(Input::Format(format.map(|f| f.to_string()))),
+            (Some("help"), None) => Ok(Input::Help),
+            (Some("quit") | Some("exit"), None) => Ok(Input::Quit),
+            _ => Err(format!("Unknown command '{}': try .help", line)),
+        };
+    }
+    if check_read_only(line).is_ok() {
+        return Ok(Input::Sql(line.to_string()));
+    }
+
+    let (table, rest) = match line.split_once('?') {
+        Some((table, query)) => (table.trim(), query.split('&').collect::<Vec<_>>()),
+        None => {
+            let mut words = split_words(line).into_iter();
+            (words.next().unwrap_or_default(), words.collect())
+        }
+    };
+    let mut query = GetQuery {
+        table: table.to_string(),
+        ..Default::default()
+    };
+    for word in rest {
+        let number = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number in '{}'", word))
+        };
+        match word.split_once('=') {
+            Some(("select", columns)) => {
+                query.columns = columns.split(',').map(|c| c.to_string()).collect()
+            }
+            Some(("order", order)) => query.order = Some(order.to_string()),
+            Some(("limit", limit)) => query.limit = Some(number(limit)?),
+            Some(("offset", offset)) => query.offset = Some(number(offset)?),
+            Some(_) => query.filters.push(word.to_string()),
+            None => {
+                return Err(format!(
+                    "Invalid filter '{}': expected COLUMN=OPERATOR.VALUE",
+                    word
+                ))
+            }
+        }
+    }
+    Ok(Input::Get(query))
+}
+
+/// Split a line on whitespace, except inside double quotes, keeping the quotes,
+/// so that a filter such as `species=eq."Adelie Penguin"` is one word.
+fn split_words(line: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                start.get_or_insert(i);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(s) = start.take() {
+                    words.push(&line[s..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(s) = start {
+        words.push(&line[s..]);
+    }
+    words
+}
+
+/// Run the input and get the text to print.
+async fn run_input(
+    config: &Config,
+    input: Input,
+    format: &mut String,
+) -> Result<String, NanobotError> {
+    match input {
+        Input::Empty | Input::Quit => Ok(String::new()),
+        Input::Help => Ok(HELP.to_string()),
+        Input::Tables => Ok(config
+            .valve
+            .as_ref()
+            .ok_or(NanobotError::NotInitialized("Valve"))?
+            .config
+            .table_order
+            .join("\n")),
+        Input::Format(None) => Ok(format.to_string()),
+        Input::Format(Some(new_format)) if VALUE_ROWS_FORMATS.contains(&new_format.as_str()) => {
+            *format = new_format;
+            Ok(String::new())
+        }
+        Input::Format(Some(new_format)) => Err(format!(
+            "Invalid format '{}': use one of {}",
+            new_format,
+            VALUE_ROWS_FORMATS.join(", ")
+        )
+        .into()),
+        Input::Sql(sql) => {
+            let pool = config
+                .pool
+                .as_ref()
+                .ok_or(NanobotError::NotInitialized("Pool"))?;
+            let rows = get_rows_from_sql(pool, &sql).await?;
+            Ok(get::format_value_rows(&rows, format)?)
+        }
+        Input::Get(query) => {
+            let mut select = query.to_select()?;
+            let table = unquote(&select.table).unwrap_or(select.table.to_string());
+            let limit = select
+                .limit
+                .unwrap_or(config.results_per_page_for(&table))
+                .min(config.max_results_per_page_for(&table));
+            select.limit(limit);
+            Ok(get::get_rows(config, &select, "value_rows", format, &Default::default()).await?)
+        }
+    }
+}
+
+/// Read tables, filters, and SQL from the terminal, and print their rows,
+/// until the user quits. The history is kept in HISTORY_FILE.
+pub async fn shell(config: &Config) -> Result<String, NanobotError> {
+    let mut editor = DefaultEditor::new().map_err(|e| e.to_string())?;
+    // There is no history the first time.
+    let _ = editor.load_history(HISTORY_FILE);
+    let mut format = String::from("text");
+    println!("Enter a table, a SELECT, or .help for help");
+    loop {
+        let line = match editor.readline("nanobot> ") {
+            Ok(line) => line,
+            // Ctrl-C clears the line, as in other shells.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.to_string().into()),
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        let input = match parse_input(&line) {
+            Ok(Input::Quit) => break,
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match run_input(config, input, &mut format).await {
+            Ok(text) if text.is_empty() => (),
+            Ok(text) => println!("{}", text.trim_end()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if let Err(e) = editor.save_history(HISTORY_FILE) {
+        tracing::warn!("Could not save the history to '{}': {}", HISTORY_FILE, e);
+    }
+    Ok(String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(parse_input("  "), Ok(Input::Empty));
+        assert_eq!(parse_input(".tables"), Ok(Input::Tables));
+        assert_eq!(
+            parse_input(".format csv"),
+            Ok(Input::Format(Some("csv".to_string())))
+        );
+        assert!(parse_input(".drop").is_err());
+        assert_eq!(
+            parse_input("select * from penguin"),
+            Ok(Input::Sql("select * from penguin".to_string()))
+        );
+
+        let query = GetQuery {
+            table: "penguin".to_string(),
+            columns: vec!["species".to_string(), "island".to_string()],
+            filters: vec!["species=eq.\"Adelie Penguin\"".to_string()],
+            order: Some("island".to_string()),
+            limit: Some(5),
+            offset: None,
+        };
+        assert_eq!(
+            parse_input(
+                "penguin species=eq.\"Adelie Penguin\" select=species,island order=island limit=5"
+            ),
+            Ok(Input::Get(query.clone()))
+        );
+        assert_eq!(
+            parse_input(
+                "penguin?species=eq.\"Adelie Penguin\"&select=species,island&order=island&limit=5"
+            ),
+            Ok(Input::Get(query))
+        );
+        assert!(parse_input("penguin limit=five").is_err());
+        assert!(parse_input("penguin island").is_err());
+    }
+}
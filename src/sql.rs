@@ -4,12 +4,10 @@ use futures::TryStreamExt;
 use ontodev_sqlrest::{get_db_type, Filter, Select};
 use serde_json::{from_str, json, Map, Value};
 use sqlx::any::{AnyKind, AnyPool};
-use sqlx::Row;
+use sqlx::{Column, Row};
 use std::collections::HashMap;
 use std::error::Error;
 
-pub const LIMIT_MAX: usize = 10000;
-
 // TODO: Possibly delete this function since it seems like it is superseded by the Valve API?
 pub async fn save_table(
     pool: &AnyPool,
@@ -26,8 +24,8 @@ pub async fn save_table(
     // let path = format!("build/{path}");
     // tracing::debug!("SAVE to {path} using {select:?}");
 
-    let dbtype = get_db_type(&pool).unwrap();
-    let sql = select.to_sql(&dbtype).unwrap();
+    let dbtype = get_db_type(&pool)?;
+    let sql = select.to_sql(&dbtype)?;
     // tracing::debug!("SQL {sql}");
 
     let mut writer = WriterBuilder::new().delimiter(b'\t').from_path(path)?;
@@ -50,6 +48,18 @@ pub async fn get_table_from_pool(
     pool: &AnyPool,
     select: &Select,
 ) -> Result<Vec<Map<String, Value>>, String> {
+    let select = prepare_select(select)?;
+    select.fetch_rows_as_json(pool, &HashMap::new())
+}
+
+/// Get the SQL that get_table_from_pool() runs for the select, without running it.
+pub fn get_sql_from_pool(pool: &AnyPool, select: &Select) -> Result<String, String> {
+    let select = prepare_select(select)?;
+    let db_type = get_db_type(pool).map_err(|e| e.to_string())?;
+    select.to_sql(&db_type).map_err(|e| e.to_string())
+}
+
+fn prepare_select(select: &Select) -> Result<Select, String> {
     let mut select = select.clone();
     // Order by row_number/row by default
     let default_order_by;
@@ -75,7 +85,26 @@ pub async fn get_table_from_pool(
             _ => (),
         };
     }
+    Ok(select)
+}
 
+/// Fetch one batch of rows using a real OFFSET, so that a whole table can be read
+/// one batch at a time in a stable order.
+pub async fn get_batch_from_pool(
+    pool: &AnyPool,
+    select: &Select,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<Map<String, Value>>, String> {
+    let mut select = select.clone();
+    if select.order_by.len() == 0 {
+        if unquote(&select.table).unwrap_or(select.table.to_string()) == "message" {
+            select.order_by(vec!["message_id"]);
+        } else {
+            select.order_by(vec!["row_number"]);
+        }
+    }
+    select.limit(limit).offset(offset);
     select.fetch_rows_as_json(pool, &HashMap::new())
 }
 
@@ -157,8 +186,7 @@ pub async fn get_message_counts_from_pool(
     } else {
         let sql = {
             if pool.any_kind() == AnyKind::Sqlite {
-                format!(
-                    r#"SELECT json_object(
+                r#"SELECT json_object(
                   'message', COUNT(),
                   'message_row', COUNT(DISTINCT row),
                   'error', SUM(level = 'error'),
@@ -167,12 +195,9 @@ pub async fn get_message_counts_from_pool(
                   'update', SUM(level = 'update')
                 ) AS json_result
                 FROM message
-                WHERE "table" = '{}'"#,
-                    table
-                )
+                WHERE "table" = $1"#
             } else {
-                format!(
-                    r#"SELECT JSON_ARRAY_ELEMENTS("json_agg")::TEXT AS "json_result"
+                r#"SELECT JSON_ARRAY_ELEMENTS("json_agg")::TEXT AS "json_result"
                    FROM (
                        SELECT JSON_AGG(t1) AS "json_agg"
                        FROM (
@@ -184,14 +209,12 @@ pub async fn get_message_counts_from_pool(
                                SUM((level = 'info')::INT) AS "info",
                                SUM((level = 'update')::INT) AS "update"
                            FROM "message"
-                           WHERE "table" = '{}'
+                           WHERE "table" = $1
                        ) t1
-                   ) t2"#,
-                    table
-                )
+                   ) t2"#
             }
         };
-        let row = match sqlx::query(&sql).fetch_one(pool).await {
+        let row = match sqlx::query(sql).bind(table.as_str()).fetch_one(pool).await {
             Ok(row) => row,
             Err(e) => return Err(e),
         };
@@ -204,6 +227,360 @@ pub async fn get_message_counts_from_pool(
     }
 }
 
+/// Count the messages in the message table by table, level, and rule,
+/// along with the number of distinct rows that have each kind of message.
+pub async fn get_message_summary_from_pool(
+    pool: &AnyPool,
+) -> Result<Vec<Map<String, Value>>, sqlx::Error> {
+    let sql = r#"SELECT
+          "table",
+          "level",
+          "rule",
+          COUNT(1) AS "count",
+          COUNT(DISTINCT "row") AS "rows"
+        FROM "message"
+        GROUP BY "table", "level", "rule"
+        ORDER BY "table", "level", "rule""#;
+    let mut summary = vec![];
+    for row in sqlx::query(sql).fetch_all(pool).await? {
+        let mut map = Map::new();
+        for column in ["table", "level", "rule"] {
+            let value: Option<String> = row.try_get(column)?;
+            map.insert(column.to_string(), json!(value.unwrap_or_default()));
+        }
+        for column in ["count", "rows"] {
+            let value: i64 = row.try_get(column)?;
+            map.insert(column.to_string(), json!(value));
+        }
+        summary.push(map);
+    }
+    Ok(summary)
+}
+
+/// Get the messages for the given rows of the table, as lists of JSON objects keyed by row number,
+/// in the same form as the 'message' column of the table's view.
+pub async fn get_row_messages_from_pool(
+    pool: &AnyPool,
+    table: &str,
+    rows: &Vec<i64>,
+) -> Result<HashMap<i64, Vec<Value>>, sqlx::Error> {
+    let mut messages = HashMap::new();
+    if rows.is_empty() {
+        return Ok(messages);
+    }
+    let rows = pad_values(rows);
+    let sql = format!(
+        r#"SELECT "message_id", "row", "column", "value", "level", "rule", "message"
+        FROM "message"
+        WHERE "table" = $1 AND "row" IN ({})
+        ORDER BY "message_id""#,
+        placeholders(2, rows.len())
+    );
+    let mut query = sqlx::query(&sql).bind(table);
+    for row in rows {
+        query = query.bind(row);
+    }
+    for row in query.fetch_all(pool).await? {
+        let row_number: i64 = row.try_get("row")?;
+        let message_id: i64 = row.try_get("message_id")?;
+        let mut message = Map::new();
+        message.insert("message_id".to_string(), json!(message_id));
+        for column in ["column", "value", "level", "rule", "message"] {
+            let value: Option<String> = row.try_get(column)?;
+            message.insert(column.to_string(), json!(value.unwrap_or_default()));
+        }
+        messages
+            .entry(row_number)
+            .or_insert_with(Vec::new)
+            .push(Value::Object(message));
+    }
+    Ok(messages)
+}
+
+/// Get the labels for the given IDs from the ID and label columns of a table.
+/// IDs without a label are left out.
+pub async fn get_labels_from_pool(
+    pool: &AnyPool,
+    table: &str,
+    id_column: &str,
+    label_column: &str,
+    ids: &[String],
+) -> Result<HashMap<String, String>, sqlx::Error> {
+    let mut labels = HashMap::new();
+    if ids.is_empty() {
+        return Ok(labels);
+    }
+    let ids = pad_values(ids);
+    let sql = format!(
+        r#"SELECT CAST("{id}" AS TEXT) AS "id", CAST("{label}" AS TEXT) AS "label"
+        FROM "{table}"
+        WHERE CAST("{id}" AS TEXT) IN ({ids})"#,
+        id = id_column.replace('"', "\"\""),
+        label = label_column.replace('"', "\"\""),
+        table = table.replace('"', "\"\""),
+        ids = placeholders(1, ids.len())
+    );
+    let mut query = sqlx::query(&sql);
+    for id in ids {
+        query = query.bind(id);
+    }
+    for row in query.fetch_all(pool).await? {
+        let id: Option<String> = row.try_get("id")?;
+        let label: Option<String> = row.try_get("label")?;
+        if let (Some(id), Some(label)) = (id, label) {
+            if !label.is_empty() {
+                labels.insert(id, label);
+            }
+        }
+    }
+    Ok(labels)
+}
+
+/// Get the row numbers of the rows of a table with the given values in a column.
+/// Values that are not in the table are left out.
+pub async fn get_row_numbers_from_pool(
+    pool: &AnyPool,
+    table: &str,
+    column: &str,
+    values: &[String],
+) -> Result<HashMap<String, i64>, sqlx::Error> {
+    let mut row_numbers = HashMap::new();
+    if values.is_empty() {
+        return Ok(row_numbers);
+    }
+    let values = pad_values(values);
+    let sql = format!(
+        r#"SELECT CAST("{column}" AS TEXT) AS "value", "row_number"
+        FROM "{table}"
+        WHERE CAST("{column}" AS TEXT) IN ({values})"#,
+        column = column.replace('"', "\"\""),
+        table = table.replace('"', "\"\""),
+        values = placeholders(1, values.len())
+    );
+    let mut query = sqlx::query(&sql);
+    for value in values {
+        query = query.bind(value);
+    }
+    for row in query.fetch_all(pool).await? {
+        let value: Option<String> = row.try_get("value")?;
+        let row_number: i64 = row.try_get("row_number")?;
+        if let Some(value) = value {
+            row_numbers.entry(value).or_insert(row_number);
+        }
+    }
+    Ok(row_numbers)
+}
+
+/// Get the row numbers of every row of a table, including its conflict rows, in order.
+pub async fn get_all_row_numbers_from_pool(
+    pool: &AnyPool,
+    table: &str,
+) -> Result<Vec<u32>, sqlx::Error> {
+    let sql = format!(
+        r#"SELECT "row_number" FROM "{}_view" ORDER BY "row_number""#,
+        table.replace('"', "\"\"")
+    );
+    let mut row_numbers = vec![];
+    for row in sqlx::query(&sql).fetch_all(pool).await? {
+        let row_number: i64 = row.try_get("row_number")?;
+        row_numbers.push(row_number as u32);
+    }
+    Ok(row_numbers)
+}
+
+/// Get the messages for the given table, ordered by row number and then by message ID.
+pub async fn get_table_messages_from_pool(
+    pool: &AnyPool,
+    table: &str,
+) -> Result<Vec<Map<String, Value>>, sqlx::Error> {
+    let sql = r#"SELECT "message_id", "row", "column", "value", "level", "rule", "message"
+        FROM "message"
+        WHERE "table" = $1
+        ORDER BY "row", "message_id""#;
+    let mut messages = vec![];
+    for row in sqlx::query(sql).bind(table).fetch_all(pool).await? {
+        let row_number: i64 = row.try_get("row")?;
+        let mut message = Map::new();
+        message.insert("row".to_string(), json!(row_number));
+        for column in ["column", "value", "level", "rule", "message"] {
+            let value: Option<String> = row.try_get(column)?;
+            message.insert(column.to_string(), json!(value.unwrap_or_default()));
+        }
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Get the history records for one row of a table, oldest first.
+/// Each record has the row before and after the change ("from" and "to", null for inserts and
+/// deletes), a summary of the changed cells, the user, the timestamp, and who undid it, if anyone.
+pub async fn get_row_history_from_pool(
+    pool: &AnyPool,
+    table: &str,
+    row_number: u32,
+) -> Result<Vec<Map<String, Value>>, sqlx::Error> {
+    let sql = r#"SELECT "history_id", "from", "to", "summary", "user", "undone_by",
+          CAST("timestamp" AS TEXT) AS "timestamp"
+        FROM "history"
+        WHERE "table" = $1 AND "row" = $2
+        ORDER BY "history_id""#;
+    let mut records = vec![];
+    let query = sqlx::query(sql).bind(table).bind(i64::from(row_number));
+    for row in query.fetch_all(pool).await? {
+        let history_id: i64 = row.try_get("history_id")?;
+        let mut record = Map::new();
+        record.insert("history_id".to_string(), json!(history_id));
+        for column in ["from", "to", "summary"] {
+            let value: Option<String> = row.try_get(column)?;
+            let value = value
+                .and_then(|v| from_str::<Value>(&v).ok())
+                .unwrap_or(Value::Null);
+            record.insert(column.to_string(), value);
+        }
+        for column in ["user", "undone_by", "timestamp"] {
+            let value: Option<String> = row.try_get(column)?;
+            record.insert(column.to_string(), json!(value));
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// The statements that `get_rows_from_sql` runs.
+const READ_STATEMENTS: [&str; 3] = ["select", "with", "values"];
+
+/// Check that the SQL is a single SELECT, WITH, or VALUES statement.
+/// Comments are skipped, and a semicolon may only end the statement.
+pub fn check_read_only(sql: &str) -> Result<(), String> {
+    let mut code = String::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                code.push(' ');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                code.push(' ');
+            }
+            // Keep quoted strings and identifiers, but replace their contents,
+            // so that a semicolon or keyword inside them is not mistaken for SQL.
+            '\'' | '"' => {
+                code.push(c);
+                for next in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                }
+                code.push(c);
+            }
+            c => code.push(c),
+        }
+    }
+    let code = code.trim().trim_end_matches(';').trim_end();
+    if code.contains(';') {
+        return Err("Only one statement can be run at a time".to_string());
+    }
+    let keyword = code
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if !READ_STATEMENTS.contains(&keyword.as_str()) {
+        return Err(format!(
+            "Only {} statements can be run",
+            READ_STATEMENTS.join(", ").to_uppercase()
+        ));
+    }
+    Ok(())
+}
+
+/// Run a read-only statement, see `check_read_only`, and get its rows as JSON objects.
+/// The statement also runs in a read-only transaction, so that a statement that slips through
+/// the check, such as a data-modifying WITH in Postgres, cannot change the database.
+pub async fn get_rows_from_sql(
+    pool: &AnyPool,
+    sql: &str,
+) -> Result<Vec<Map<String, Value>>, String> {
+    check_read_only(sql)?;
+    let is_sqlite = pool.any_kind() == AnyKind::Sqlite;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    let read_only = match is_sqlite {
+        true => "PRAGMA query_only = ON",
+        false => "SET TRANSACTION READ ONLY",
+    };
+    sqlx::query(read_only)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    let rows = sqlx::query(sql).fetch_all(&mut tx).await;
+    // The pragma belongs to the connection, which goes back to the pool.
+    if is_sqlite {
+        sqlx::query("PRAGMA query_only = OFF")
+            .execute(&mut tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    tx.rollback().await.map_err(|e| e.to_string())?;
+    let rows = rows.map_err(|e| e.to_string())?;
+
+    let mut json_rows = vec![];
+    for row in rows {
+        let mut json_row = Map::new();
+        for column in row.columns() {
+            let i = column.ordinal();
+            // Any only decodes these types, so try each of them in turn.
+            let value = if let Ok(v) = row.try_get::<Option<i64>, _>(i) {
+                json!(v)
+            } else if let Ok(v) = row.try_get::<Option<i32>, _>(i) {
+                json!(v)
+            } else if let Ok(v) = row.try_get::<Option<f64>, _>(i) {
+                json!(v)
+            } else if let Ok(v) = row.try_get::<Option<bool>, _>(i) {
+                json!(v)
+            } else if let Ok(v) = row.try_get::<Option<String>, _>(i) {
+                json!(v)
+            } else {
+                return Err(format!(
+                    "Could not read column '{}': CAST it to TEXT",
+                    column.name()
+                ));
+            };
+            json_row.insert(column.name().to_string(), value);
+        }
+        json_rows.push(json_row);
+    }
+    Ok(json_rows)
+}
+
+/// Numbered placeholders for `count` bind parameters from `start`, e.g. "$2, $3, $4",
+/// which both SQLite and Postgres accept.
+fn placeholders(start: usize, count: usize) -> String {
+    (start..start + count)
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Pad a list of values for an `IN (...)` list to the next power of two by repeating the last one.
+/// The SQL of a query then only depends on the size class of the list, so that its prepared
+/// statement can be reused from the connection's statement cache.
+fn pad_values<T: Clone>(values: &[T]) -> Vec<T> {
+    let mut padded = values.to_vec();
+    if let Some(last) = values.last() {
+        padded.resize(values.len().next_power_of_two(), last.clone());
+    }
+    padded
+}
+
 pub fn rows_to_map(
     rows: Vec<Map<String, Value>>,
     column: &str,
@@ -216,9 +593,10 @@ pub fn rows_to_map(
         let mut key = String::from("");
         for (k, v) in row.iter() {
             if k == column {
-                key = match v.as_str() {
-                    Some(k) => k.to_string(),
-                    None => return Err(format!("Unable to convert '{}' to str", v)),
+                key = match v {
+                    Value::String(k) => k.to_string(),
+                    Value::Number(k) => k.to_string(),
+                    _ => return Err(format!("Unable to convert '{}' to str", v)),
                 };
             } else {
                 r.insert(k.to_string(), v.clone());
@@ -228,3 +606,89 @@ pub fn rows_to_map(
     }
     Ok(map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestSchema;
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(placeholders(1, 1), "$1");
+        assert_eq!(placeholders(2, 3), "$2, $3, $4");
+        assert_eq!(placeholders(1, 0), "");
+    }
+
+    #[test]
+    fn test_pad_values() {
+        assert_eq!(pad_values::<i64>(&[]), Vec::<i64>::new());
+        assert_eq!(pad_values(&[1]), [1]);
+        assert_eq!(pad_values(&[1, 2, 3]), [1, 2, 3, 3]);
+        assert_eq!(pad_values(&[1, 2, 3, 4]), [1, 2, 3, 4]);
+        assert_eq!(pad_values(&[1, 2, 3, 4, 5]), [1, 2, 3, 4, 5, 5, 5, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_bound_values() {
+        // Quotes are bound as they are, and the padding does not repeat any rows.
+        let database = TestSchema::new()
+            .table("term", "id\tlabel\nEX:1\tgill\nEX:2\tO'Brien\nEX:3\t\n")
+            .table(
+                "penguin",
+                "id\tspecies\n1\tAdelie\nx\tGentoo\n3\tChinstrap\n",
+            )
+            .column("penguin", "id", "integer", "")
+            .datatype("integer", "text", "match(/-?\\d+/)", "INT")
+            .build()
+            .await
+            .unwrap();
+        let pool = database.config.pool.as_ref().unwrap();
+        let ids = ["EX:1", "EX:2", "EX:3", "EX:9"].map(String::from);
+        let labels = get_labels_from_pool(pool, "term", "id", "label", &ids)
+            .await
+            .unwrap();
+        assert_eq!(
+            labels,
+            HashMap::from([
+                ("EX:1".to_string(), "gill".to_string()),
+                ("EX:2".to_string(), "O'Brien".to_string()),
+            ])
+        );
+        let values = ["gill", "O'Brien", "fin"].map(String::from);
+        let row_numbers = get_row_numbers_from_pool(pool, "term", "label", &values)
+            .await
+            .unwrap();
+        assert_eq!(
+            row_numbers,
+            HashMap::from([("gill".to_string(), 1), ("O'Brien".to_string(), 2)])
+        );
+
+        let messages = get_row_messages_from_pool(pool, "penguin", &vec![1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(messages.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(messages[&2].len(), 1);
+        assert_eq!(messages[&2][0]["value"], json!("x"));
+        let table_messages = get_table_messages_from_pool(pool, "penguin").await.unwrap();
+        assert_eq!(table_messages.len(), 1);
+        assert_eq!(table_messages[0]["row"], json!(2));
+        let counts = get_message_counts_from_pool(pool, &"penguin".to_string())
+            .await
+            .unwrap();
+        assert_eq!(counts["message"], json!(1));
+    }
+
+    #[test]
+    fn test_check_read_only() {
+        assert!(check_read_only("SELECT * FROM penguin").is_ok());
+        assert!(check_read_only("  select count(*) from penguin;  ").is_ok());
+        assert!(check_read_only("-- islands\nWITH i AS (SELECT 1) SELECT * FROM i").is_ok());
+        assert!(check_read_only("/* 1 */ VALUES (1)").is_ok());
+        assert!(check_read_only("SELECT 'a;b', \"c;\" FROM penguin").is_ok());
+        assert!(check_read_only("DELETE FROM penguin").is_err());
+        assert!(check_read_only("SELECT 1; DROP TABLE penguin").is_err());
+        assert!(check_read_only("/* SELECT */ UPDATE penguin SET x = 1").is_err());
+        assert!(check_read_only("-- SELECT\nINSERT INTO penguin VALUES (1)").is_err());
+        assert!(check_read_only("").is_err());
+    }
+}
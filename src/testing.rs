@@ -0,0 +1,239 @@
+//! Fixtures for testing against Nanobot: a temporary SQLite database built from a schema
+//! defined in the test, and a server for it on a free port.
+//!
+//! ```no_run
+//! use nanobot::testing::TestSchema;
+//!
+//! # async fn example() -> Result<(), nanobot::NanobotError> {
+//! let database = TestSchema::new()
+//!     .table("penguin", "id\tspecies\n1\tAdelie\n2\tGentoo\n")
+//!     .column("penguin", "id", "integer", "primary")
+//!     .datatype("integer", "text", "match(/-?\\d+/)", "INT")
+//!     .build()
+//!     .await?;
+//! let server = database.serve().await?;
+//! let rows = reqwest::get(server.url("penguin.json")).await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::{build_valve, Config};
+use crate::error::NanobotError;
+use crate::load::load;
+use crate::schema::Tsv;
+use crate::serve::{Serve, ServerHandle};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tests in the same process each get their own directory.
+static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+/// The tables of a test database, with their rows, and the columns and datatypes
+/// that differ from the defaults. Columns are optional text unless they are defined.
+#[derive(Clone, Debug, Default)]
+pub struct TestSchema {
+    tables: Vec<(String, Tsv)>,
+    columns: Vec<[String; 4]>,
+    datatypes: Vec<[String; 4]>,
+}
+
+impl TestSchema {
+    pub fn new() -> TestSchema {
+        TestSchema::default()
+    }
+
+    /// Add a table, with a header line and rows of tab-separated values.
+    pub fn table(mut self, name: &str, tsv: &str) -> TestSchema {
+        self.tables.push((name.to_string(), Tsv::parse(tsv)));
+        self
+    }
+
+    /// Define a required column of a table with a datatype and a structure,
+    /// e.g. "primary" or "from(island.name)". The structure may be empty.
+    pub fn column(
+        mut self,
+        table: &str,
+        column: &str,
+        datatype: &str,
+        structure: &str,
+    ) -> TestSchema {
+        self.columns.push([
+            table.to_string(),
+            column.to_string(),
+            datatype.to_string(),
+            structure.to_string(),
+        ]);
+        self
+    }
+
+    /// Add a datatype with a parent, a VALVE condition, and a SQL type, any of which may be empty.
+    pub fn datatype(
+        mut self,
+        name: &str,
+        parent: &str,
+        condition: &str,
+        sql_type: &str,
+    ) -> TestSchema {
+        self.datatypes.push([
+            name.to_string(),
+            parent.to_string(),
+            condition.to_string(),
+            sql_type.to_string(),
+        ]);
+        self
+    }
+
+    /// Write the schema and the tables to a new temporary directory,
+    /// then create a SQLite database there and load the tables into it.
+    pub async fn build(&self) -> Result<TestDatabase, NanobotError> {
+        let dir = std::env::temp_dir().join(format!(
+            "nanobot-test-{}-{}",
+            std::process::id(),
+            NEXT_DIRECTORY.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir)?;
+        // Dropping the database removes the directory again if the schema is invalid.
+        let mut database = TestDatabase {
+            config: Config::from_path(&path_string(&dir.join("nanobot.toml"))).await?,
+            dir,
+        };
+        self.write(&database.dir)?;
+        database
+            .config
+            .connection(path_string(&database.dir.join("nanobot.db")))
+            .valve_path(path_string(&database.dir.join("table.tsv")));
+        build_valve(&mut database.config).await?;
+        load(&mut database.config, &[], true).await?;
+        Ok(database)
+    }
+
+    /// Write table.tsv, column.tsv, and datatype.tsv, with the meta tables first,
+    /// then a TSV file for each of the tables.
+    fn write(&self, dir: &Path) -> Result<(), String> {
+        let mut table = Tsv::parse(include_str!("resources/table.tsv"));
+        for row in table.rows.iter_mut() {
+            let name = format!("{}.tsv", row[0]);
+            row[1] = path_string(&dir.join(name));
+        }
+        let mut column = Tsv::parse(include_str!("resources/column.tsv"));
+        let mut datatype = Tsv::parse(include_str!("resources/datatype.tsv"));
+
+        for (name, tsv) in &self.tables {
+            let path = dir.join(format!("{}.tsv", name));
+            tsv.write(&path)?;
+            table.rows.push(to_row(
+                &table.header,
+                &[
+                    ("table", name.as_str()),
+                    ("path", path_string(&path).as_str()),
+                ],
+            ));
+            for header in &tsv.header {
+                let defined = self
+                    .columns
+                    .iter()
+                    .find(|c| &c[0] == name && &c[1] == header);
+                let cells = match defined {
+                    Some([_, _, datatype, structure]) => [
+                        ("table", name.as_str()),
+                        ("column", header.as_str()),
+                        ("nulltype", ""),
+                        ("datatype", datatype.as_str()),
+                        ("structure", structure.as_str()),
+                    ],
+                    None => [
+                        ("table", name.as_str()),
+                        ("column", header.as_str()),
+                        ("nulltype", "empty"),
+                        ("datatype", "text"),
+                        ("structure", ""),
+                    ],
+                };
+                column.rows.push(to_row(&column.header, &cells));
+            }
+        }
+        for [name, parent, condition, sql_type] in &self.datatypes {
+            let cells = [
+                ("datatype", name.as_str()),
+                ("parent", parent.as_str()),
+                ("condition", condition.as_str()),
+                ("sql_type", sql_type.as_str()),
+            ];
+            datatype.rows.push(to_row(&datatype.header, &cells));
+        }
+
+        table.write(&dir.join("table.tsv"))?;
+        column.write(&dir.join("column.tsv"))?;
+        datatype.write(&dir.join("datatype.tsv"))
+    }
+}
+
+/// A SQLite database in a temporary directory, with its TSV files.
+/// Dropping it deletes the directory.
+pub struct TestDatabase {
+    /// The configuration for the database, with VALVE and the pool ready to use.
+    pub config: Config,
+    dir: PathBuf,
+}
+
+impl TestDatabase {
+    /// The temporary directory, with the database, the schema, and a TSV file for each table.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Start a server for the database on a free port of localhost.
+    /// Keep the database until the server is dropped.
+    pub async fn serve(&self) -> Result<TestServer, NanobotError> {
+        let handle = Serve::new(self.config.clone())
+            .host(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .port(0)
+            .start()
+            .await?;
+        Ok(TestServer { handle })
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            tracing::warn!("Could not remove '{}': {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// A server for a [TestDatabase]. Dropping it stops the server.
+pub struct TestServer {
+    pub handle: ServerHandle,
+}
+
+impl TestServer {
+    /// The URL for a path on the server, e.g. `server.url("penguin.json?limit=1")`.
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.handle.listener(),
+            path.trim_start_matches('/')
+        )
+    }
+}
+
+/// A row with the given cells in the order of the header, leaving the other cells empty.
+fn to_row(header: &[String], cells: &[(&str, &str)]) -> Vec<String> {
+    header
+        .iter()
+        .map(|h| {
+            cells
+                .iter()
+                .find(|(column, _)| column == h)
+                .map(|(_, value)| value.to_string())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
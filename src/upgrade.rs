@@ -0,0 +1,333 @@
+use crate::config::{Config, DEFAULT_CONFIG_VERSION};
+use crate::error::NanobotError;
+use crate::schema::Tsv;
+use std::fs;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// Keys that belonged at the top level of nanobot.toml before the `[nanobot]` section.
+const LEGACY_NANOBOT_KEYS: [&str; 3] = ["port", "results_per_page", "default_table"];
+
+/// Bring nanobot.toml and the VALVE meta tables up to date with this version of Nanobot,
+/// returning a report of the changes. With `dry_run`, report the changes without making them.
+pub fn upgrade(config: &Config, dry_run: bool) -> Result<String, NanobotError> {
+    let mut report = vec![];
+    upgrade_config(Path::new(&config.config_path), dry_run, &mut report)?;
+    let meta_changed = upgrade_meta_tables(&config.valve_path, dry_run, &mut report)?;
+
+    if report.is_empty() {
+        return Ok(format!(
+            "Already up to date with config_version {}",
+            DEFAULT_CONFIG_VERSION
+        ));
+    }
+    if dry_run {
+        report.push(String::from("Dry run: no files were changed"));
+    } else if meta_changed {
+        report.push(String::from(
+            "Run `nanobot init --merge` or `nanobot init` to update the database",
+        ));
+    }
+    Ok(report.join("\n"))
+}
+
+/// Migrate nanobot.toml from older config versions.
+/// The original file is kept as nanobot.toml.bak, since comments are not preserved.
+fn upgrade_config(path: &Path, dry_run: bool, report: &mut Vec<String>) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    let mut toml = contents
+        .parse::<Table>()
+        .map_err(|e| format!("Could not parse '{}': {}", path.display(), e))?;
+
+    let version = toml
+        .get("nanobot")
+        .and_then(|n| n.get("config_version"))
+        .and_then(|v| v.as_integer())
+        .unwrap_or(0);
+    if version > DEFAULT_CONFIG_VERSION as i64 {
+        return Err(format!(
+            "'{}' has config_version {}, but this version of Nanobot only supports up to {}",
+            path.display(),
+            version,
+            DEFAULT_CONFIG_VERSION
+        ));
+    }
+    if version == DEFAULT_CONFIG_VERSION as i64 {
+        return Ok(());
+    }
+
+    // Version 0: settings were at the top level and there was no config_version.
+    let mut nanobot = match toml.remove("nanobot") {
+        Some(Value::Table(nanobot)) => nanobot,
+        _ => Table::new(),
+    };
+    for key in LEGACY_NANOBOT_KEYS {
+        if let Some(value) = toml.remove(key) {
+            if !nanobot.contains_key(key) {
+                nanobot.insert(key.to_string(), value);
+            }
+            report.push(format!("Moved '{}' into the [nanobot] section", key));
+        }
+    }
+    nanobot.insert(
+        "config_version".to_string(),
+        Value::Integer(DEFAULT_CONFIG_VERSION as i64),
+    );
+    report.push(format!(
+        "Changed config_version from {} to {} in '{}'",
+        version,
+        DEFAULT_CONFIG_VERSION,
+        path.display()
+    ));
+
+    toml.insert("nanobot".to_string(), Value::Table(nanobot));
+
+    if !dry_run {
+        let backup = path.with_extension("toml.bak");
+        fs::copy(path, &backup)
+            .map_err(|e| format!("Could not back up '{}': {}", path.display(), e))?;
+        let contents = toml::to_string(&toml).map_err(|e| e.to_string())?;
+        fs::write(path, contents)
+            .map_err(|e| format!("Could not write '{}': {}", path.display(), e))?;
+        report.push(format!(
+            "Saved the original configuration as '{}'",
+            backup.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Update the table, column, and datatype tables to the current VALVE layout:
+/// add missing columns, and add the rows describing the meta tables that are missing.
+/// Return true when any of the files changed.
+fn upgrade_meta_tables(
+    valve_path: &str,
+    dry_run: bool,
+    report: &mut Vec<String>,
+) -> Result<bool, String> {
+    let table_path = Path::new(valve_path);
+    if !table_path.exists() {
+        return Ok(false);
+    }
+    let current_table = Tsv::parse(include_str!("resources/table.tsv"));
+    let current_column = Tsv::parse(include_str!("resources/column.tsv"));
+    let current_datatype = Tsv::parse(include_str!("resources/datatype.tsv"));
+
+    // Older layouts have no 'type' column, so fall back to the names of the meta tables.
+    let mut table = Tsv::read(table_path)?;
+    let find_path = |table_type: &str| -> Option<String> {
+        table
+            .rows
+            .iter()
+            .find(|row| table.get(row, "type") == table_type)
+            .or_else(|| {
+                table
+                    .rows
+                    .iter()
+                    .find(|row| table.get(row, "table") == table_type)
+            })
+            .map(|row| table.get(row, "path").to_string())
+    };
+    let column_path = find_path("column").ok_or(format!(
+        "Could not find the column table in '{}'",
+        valve_path
+    ))?;
+    let datatype_path = find_path("datatype").ok_or(format!(
+        "Could not find the datatype table in '{}'",
+        valve_path
+    ))?;
+    let mut column = Tsv::read(Path::new(&column_path))?;
+    let mut datatype = Tsv::read(Path::new(&datatype_path))?;
+
+    let mut changed = vec![];
+    for (path, tsv, current) in [
+        (valve_path, &mut table, &current_table),
+        (column_path.as_str(), &mut column, &current_column),
+        (datatype_path.as_str(), &mut datatype, &current_datatype),
+    ] {
+        let added = tsv.add_missing_columns(&current.header);
+        if !added.is_empty() {
+            report.push(format!(
+                "Added columns '{}' to '{}'",
+                added.join("', '"),
+                path
+            ));
+            changed.push(path.to_string());
+        }
+    }
+
+    // Describe the columns of the meta tables, as VALVE expects.
+    let mut added = vec![];
+    for row in &current_column.rows {
+        let (t, c) = (
+            current_column.get(row, "table"),
+            current_column.get(row, "column"),
+        );
+        let exists = column
+            .rows
+            .iter()
+            .any(|r| column.get(r, "table") == t && column.get(r, "column") == c);
+        if !exists {
+            let new_row = column
+                .header
+                .iter()
+                .map(|h| current_column.get(row, h).to_string())
+                .collect();
+            column.rows.push(new_row);
+            added.push(format!("{}.{}", t, c));
+        }
+    }
+    if !added.is_empty() {
+        report.push(format!(
+            "Added rows for '{}' to '{}'",
+            added.join("', '"),
+            column_path
+        ));
+        changed.push(column_path.clone());
+    }
+
+    // Add the built-in datatypes that the meta tables use.
+    let mut added = vec![];
+    for row in &current_datatype.rows {
+        let name = current_datatype.get(row, "datatype");
+        if !datatype
+            .rows
+            .iter()
+            .any(|r| datatype.get(r, "datatype") == name)
+        {
+            let new_row = datatype
+                .header
+                .iter()
+                .map(|h| current_datatype.get(row, h).to_string())
+                .collect();
+            datatype.rows.push(new_row);
+            added.push(name.to_string());
+        }
+    }
+    if !added.is_empty() {
+        report.push(format!(
+            "Added datatypes '{}' to '{}'",
+            added.join("', '"),
+            datatype_path
+        ));
+        changed.push(datatype_path.clone());
+    }
+
+    if !dry_run {
+        for (path, tsv) in [
+            (valve_path, &table),
+            (column_path.as_str(), &column),
+            (datatype_path.as_str(), &datatype),
+        ] {
+            if changed.iter().any(|c| c == path) {
+                tsv.write(Path::new(path))?;
+            }
+        }
+    }
+    Ok(!changed.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project from before config_version 1, with the VALVE layout of the time.
+    fn write_old_project(dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("nanobot.toml"),
+            "port = 3001\nresults_per_page = 10\n\n[nanobot]\nport = 4000\n\n[logging]\nlevel = \"DEBUG\"\n",
+        )
+        .unwrap();
+        let table = "table\tpath\ntable\t{dir}/table.tsv\ncolumn\t{dir}/column.tsv\n\
+                     datatype\t{dir}/datatype.tsv\npenguin\t{dir}/penguin.tsv\n";
+        fs::write(
+            dir.join("table.tsv"),
+            table.replace("{dir}", dir.to_str().unwrap()),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("column.tsv"),
+            "table\tcolumn\tdatatype\tnulltype\npenguin\tspecies\tspecies_name\t\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("datatype.tsv"),
+            "datatype\tparent\tcondition\nspecies_name\ttext\texclude(/\\s/)\n",
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let dir = std::env::temp_dir().join(format!("nanobot-upgrade-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        write_old_project(&dir);
+        let mut config = Config::from_path("missing.toml").await.unwrap();
+        config.config_path = dir.join("nanobot.toml").to_str().unwrap().to_string();
+        config.valve_path = dir.join("table.tsv").to_str().unwrap().to_string();
+        let read = |file: &str| fs::read_to_string(dir.join(file)).unwrap();
+        let before = ["nanobot.toml", "table.tsv", "column.tsv", "datatype.tsv"].map(read);
+
+        // A dry run reports the changes without making them.
+        let report = upgrade(&config, true).unwrap();
+        assert!(report.contains("Moved 'port' into the [nanobot] section"));
+        assert!(report.contains("Changed config_version from 0 to 1"));
+        assert!(report.ends_with("Dry run: no files were changed"));
+        let after = ["nanobot.toml", "table.tsv", "column.tsv", "datatype.tsv"].map(read);
+        assert_eq!(before, after);
+
+        let report = upgrade(&config, false).unwrap();
+        assert!(report.contains("Added columns 'type', 'description' to"));
+        assert!(report.ends_with("to update the database"));
+        assert_eq!(read("nanobot.toml.bak"), before[0]);
+
+        // The settings at the top level are moved into [nanobot], without replacing its own.
+        let toml = read("nanobot.toml").parse::<Table>().unwrap();
+        let nanobot = toml["nanobot"].as_table().unwrap();
+        assert_eq!(nanobot["port"].as_integer(), Some(4000));
+        assert_eq!(nanobot["results_per_page"].as_integer(), Some(10));
+        assert_eq!(nanobot["config_version"].as_integer(), Some(1));
+        assert!(!toml.contains_key("port"));
+        assert_eq!(toml["logging"]["level"].as_str(), Some("DEBUG"));
+
+        // The meta tables have the current columns, and the rows that were there come first.
+        let table = Tsv::read(&dir.join("table.tsv")).unwrap();
+        assert_eq!(table.header, ["table", "path", "type", "description"]);
+        assert_eq!(table.rows.len(), 4);
+        assert_eq!(table.get(&table.rows[3], "table"), "penguin");
+        let current_column = Tsv::parse(include_str!("resources/column.tsv"));
+        let column = Tsv::read(&dir.join("column.tsv")).unwrap();
+        assert_eq!(column.header, current_column.header);
+        assert_eq!(column.rows.len(), current_column.rows.len() + 1);
+        assert_eq!(column.get(&column.rows[0], "datatype"), "species_name");
+        let current_datatype = Tsv::parse(include_str!("resources/datatype.tsv"));
+        let datatype = Tsv::read(&dir.join("datatype.tsv")).unwrap();
+        assert_eq!(datatype.header, current_datatype.header);
+        assert_eq!(datatype.rows.len(), current_datatype.rows.len() + 1);
+        assert_eq!(
+            datatype.get(&datatype.rows[0], "condition"),
+            "exclude(/\\s/)"
+        );
+
+        assert_eq!(
+            upgrade(&config, false).unwrap(),
+            "Already up to date with config_version 1"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_newer_config() {
+        let path =
+            std::env::temp_dir().join(format!("nanobot-upgrade-{}-newer.toml", std::process::id()));
+        fs::write(&path, "[nanobot]\nconfig_version = 99\n").unwrap();
+        let error = upgrade_config(&path, false, &mut vec![]).unwrap_err();
+        assert!(error.contains("only supports up to 1"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::{check_table_names, Config};
+use crate::error::NanobotError;
+use crate::load::load;
+use crate::sql::get_table_messages_from_pool;
+use ontodev_valve::valve::Valve;
+use serde_json::{json, Map, Value};
+
+/// Tables that hold Nanobot's own records rather than data to validate.
+const SKIPPED_TABLES: [&str; 2] = ["message", "history"];
+
+/// Report the errors and warnings in the database for the given tables, or all of the tables,
+/// with their row numbers. With `reload`, first validate them again by reloading them from
+/// their TSV files, which replaces any changes to them that have not been saved.
+/// Return the report in the given format, "text" or "json",
+/// and whether the tables are free of errors.
+pub async fn validate(
+    config: &mut Config,
+    tables: &[String],
+    reload: bool,
+    format: &str,
+) -> Result<(String, bool), NanobotError> {
+    check_table_names(config, tables)?;
+    if reload {
+        load(config, tables, false).await?;
+    }
+    let pool = config
+        .pool
+        .as_ref()
+        .ok_or("Pool is not initialized.".to_string())?;
+    let tables = match tables {
+        [] => config
+            .valve
+            .as_ref()
+            .ok_or("Valve is not initialized.".to_string())?
+            .config
+            .table_order
+            .iter()
+            .filter(|t| !SKIPPED_TABLES.contains(&t.as_str()))
+            .cloned()
+            .collect::<Vec<_>>(),
+        tables => tables.to_vec(),
+    };
+
+    let mut reports = vec![];
+    for table in &tables {
+        let messages = get_table_messages_from_pool(pool, table)
+            .await
+            .map_err(|e| format!("Could not get the messages for '{}': {}", table, e))?;
+        let count = |level: &str| messages.iter().filter(|m| m["level"] == level).count();
+        reports.push(json!({
+            "table": table,
+            "error": count("error"),
+            "warn": count("warn"),
+            "info": count("info"),
+            "messages": messages,
+        }));
+    }
+    let errors = reports
+        .iter()
+        .map(|r| r["error"].as_u64().unwrap_or_default())
+        .sum::<u64>();
+    let warnings = reports
+        .iter()
+        .map(|r| r["warn"].as_u64().unwrap_or_default())
+        .sum::<u64>();
+
+    let report = match format {
+        "json" => serde_json::to_string_pretty(&json!({
+            "valid": errors == 0,
+            "error": errors,
+            "warn": warnings,
+            "tables": reports,
+        }))
+        .map_err(|e| e.to_string())?,
+        "text" => {
+            let mut lines = vec![];
+            for report in &reports {
+                lines.push(format!(
+                    "{}: {}, {}",
+                    report["table"].as_str().unwrap_or_default(),
+                    plural(report["error"].as_u64().unwrap_or_default(), "error"),
+                    plural(report["warn"].as_u64().unwrap_or_default(), "warning")
+                ));
+                for message in report["messages"].as_array().into_iter().flatten() {
+                    lines.push(format_message(message.as_object().unwrap()));
+                }
+            }
+            lines.push(format!(
+                "Found {} and {} in {}",
+                plural(errors, "error"),
+                plural(warnings, "warning"),
+                plural(tables.len() as u64, "table")
+            ));
+            lines.join("\n")
+        }
+        _ => return Err(format!("Invalid format '{}': use one of text, json", format).into()),
+    };
+    Ok((report, errors == 0))
+}
+
+/// Validate the row against the given table and return a map with the column, value, level and
+/// message of each validation error.
+pub async fn get_validation_errors(
+    table: &str,
+    row: &Map<String, Value>,
+    row_number: Option<u32>,
+    valve: &Valve,
+) -> Result<Vec<Map<String, Value>>, String> {
+    let validated = valve
+        .validate_row(table, row, row_number)
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .contents_to_rich_json()
+        .map_err(|e| format!("{:?}", e))?;
+    let mut errors = vec![];
+    for (column, cell) in validated.iter() {
+        if cell.get("valid") != Some(&json!(false)) {
+            continue;
+        }
+        let cell_messages = match cell.get("messages") {
+            Some(Value::Array(m)) => m.to_vec(),
+            _ => vec![],
+        };
+        for message in cell_messages {
+            let mut error = Map::new();
+            error.insert("column".to_string(), json!(column));
+            error.insert("value".to_string(), json!(cell.get("value")));
+            error.insert("level".to_string(), json!(message.get("level")));
+            error.insert("message".to_string(), json!(message.get("message")));
+            errors.push(error);
+        }
+    }
+    Ok(errors)
+}
+
+/// Format a message as one indented line, e.g.
+/// "  row 3, column 'species', value 'Adelie': error key:foreign: ...".
+fn format_message(message: &Map<String, Value>) -> String {
+    let get = |key: &str| {
+        message
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+    };
+    format!(
+        "  row {}, column '{}', value '{}': {} {}: {}",
+        message.get("row").unwrap_or(&Value::Null),
+        get("column"),
+        get("value"),
+        get("level"),
+        get("rule"),
+        get("message")
+    )
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
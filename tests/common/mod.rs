@@ -0,0 +1,29 @@
+//! Helpers shared by the integration tests.
+
+use nanobot::config::Config;
+use nanobot::get::{get_rows, GetRowsOptions};
+use nanobot::testing::TestSchema;
+use ontodev_sqlrest::Select;
+use serde_json::Value;
+
+/// A small penguin table with an integer primary key.
+pub fn penguins() -> TestSchema {
+    TestSchema::new()
+        .table(
+            "penguin",
+            "id\tspecies\tisland\n1\tAdelie\tTorgersen\n2\tGentoo\tBiscoe\n",
+        )
+        .column("penguin", "id", "integer", "primary")
+        .datatype("integer", "text", "match(/-?\\d+/)", "INT")
+}
+
+/// Get the selected rows as JSON objects, as `get_rows` returns them for the "value_rows" shape.
+pub async fn json_rows(config: &Config, select: &Select, options: &GetRowsOptions) -> Vec<Value> {
+    let rows = get_rows(config, select, "value_rows", "json", options)
+        .await
+        .unwrap();
+    match serde_json::from_str(&rows).unwrap() {
+        Value::Array(rows) => rows,
+        rows => panic!("Expected an array of rows, got {}", rows),
+    }
+}
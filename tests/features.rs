@@ -0,0 +1,295 @@
+//! Tests of Nanobot's features against small databases built with TestSchema.
+
+mod common;
+
+use common::{json_rows, penguins};
+use futures::StreamExt;
+use nanobot::config::{ColumnFormat, LabelsConfig, SearchConfig, TableConfig};
+use nanobot::edit::Edits;
+use nanobot::export::export;
+use nanobot::get::{get_rows, stream_value_text, GetQuery, GetRowsOptions, STREAM_FORMATS};
+use nanobot::import::import;
+use nanobot::search::search_terms;
+use nanobot::testing::TestSchema;
+use ontodev_sqlrest::Select;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn test_get_all_rows() {
+    let mut database = penguins().build().await.unwrap();
+    database.config.max_results_per_page = 1;
+    let mut select = Select::new("\"penguin\"");
+    select.limit(2);
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows.len(), 1);
+    let all = GetRowsOptions {
+        all: true,
+        ..Default::default()
+    };
+    assert_eq!(json_rows(&database.config, &select, &all).await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_stream_value_text() {
+    let database = penguins().build().await.unwrap();
+    let mut select = Select::new("\"penguin\"");
+    select.limit(10);
+    for format in STREAM_FORMATS {
+        let chunks = stream_value_text(&database.config, &select, format)
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let text = chunks
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .join("");
+        let rows = get_rows(
+            &database.config,
+            &select,
+            "value_rows",
+            format,
+            &GetRowsOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(text.trim_end(), rows.trim_end(), "{}", format);
+    }
+    assert!(stream_value_text(&database.config, &select, "yaml").is_err());
+}
+
+#[tokio::test]
+async fn test_saved_query() {
+    let database = penguins().build().await.unwrap();
+    let json_path = database.dir().join("query.json");
+    std::fs::write(
+        &json_path,
+        r#"{"table": "penguin", "columns": ["species"], "filters": ["island=eq.Biscoe"]}"#,
+    )
+    .unwrap();
+    let toml_path = database.dir().join("query.toml");
+    std::fs::write(
+        &toml_path,
+        "table = \"penguin\"\ncolumns = [\"species\"]\nfilters = [\"island=eq.Biscoe\"]\n",
+    )
+    .unwrap();
+
+    let query = GetQuery::load(json_path.to_str().unwrap()).unwrap();
+    assert_eq!(query, GetQuery::load(toml_path.to_str().unwrap()).unwrap());
+    let mut select = query.to_select().unwrap();
+    select.limit(10);
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["species"], json!("Gentoo"));
+
+    let missing = database.dir().join("missing.json");
+    assert!(GetQuery::load(missing.to_str().unwrap()).is_err());
+    let query = GetQuery {
+        table: "penguin".to_string(),
+        filters: vec!["island".to_string()],
+        ..Default::default()
+    };
+    assert!(query.to_select().is_err());
+}
+
+#[tokio::test]
+async fn test_export() {
+    let database = penguins().build().await.unwrap();
+    let dir = database.dir().join("export");
+    let report = export(&database.config, dir.to_str().unwrap(), "tsv", &[], false)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("penguin.tsv")).unwrap(),
+        "id\tspecies\tisland\n1\tAdelie\tTorgersen\n2\tGentoo\tBiscoe\n"
+    );
+    assert!(report.contains("Exported 2 rows of 'penguin'"));
+    assert!(!dir.join("column.tsv").exists());
+    assert!(!dir.join("message.tsv").exists());
+
+    export(&database.config, dir.to_str().unwrap(), "xlsx", &[], true)
+        .await
+        .unwrap();
+    assert!(dir.join("tables.xlsx").is_file());
+    assert!(
+        export(&database.config, dir.to_str().unwrap(), "yaml", &[], true)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_export_conflicts() {
+    // The second row with the primary key 1 is a conflict, which VALVE keeps apart from
+    // the other rows, but it is still part of the table.
+    let database = TestSchema::new()
+        .table(
+            "penguin",
+            "id\tspecies\n1\tAdelie\n2\tGentoo\n1\tChinstrap\n",
+        )
+        .column("penguin", "id", "integer", "primary")
+        .datatype("integer", "text", "match(/-?\\d+/)", "INT")
+        .build()
+        .await
+        .unwrap();
+    let dir = database.dir().join("export");
+    let report = export(&database.config, dir.to_str().unwrap(), "tsv", &[], false)
+        .await
+        .unwrap();
+    assert!(report.contains("Exported 3 rows of 'penguin'"));
+    let tsv = std::fs::read_to_string(dir.join("penguin.tsv")).unwrap();
+    let mut lines = tsv.lines().collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(
+        lines,
+        ["1\tAdelie", "1\tChinstrap", "2\tGentoo", "id\tspecies"]
+    );
+}
+
+#[tokio::test]
+async fn test_import() {
+    let database = penguins().build().await.unwrap();
+    let dir = database.dir().join("import");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("penguin.csv"),
+        "id,species,island\n3,Chinstrap,Dream\nx,Adelie,Dream\n",
+    )
+    .unwrap();
+    let paths = vec![dir.to_str().unwrap().to_string()];
+    let report = import(&database.config, &paths, false).await.unwrap();
+    assert!(report.contains("penguin: inserted 1 row, rejected 1 row"));
+    assert!(report.contains("row 2, column 'id', value 'x'"));
+    let select = Select::new("\"penguin\"");
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows.len(), 3);
+
+    std::fs::write(dir.join("krill.tsv"), "id\n1\n").unwrap();
+    assert!(import(&database.config, &paths, false).await.is_err());
+}
+
+#[tokio::test]
+async fn test_import_failure() {
+    // There is no krill table, so penguin.tsv is not imported either.
+    let database = penguins().build().await.unwrap();
+    let dir = database.dir().join("import");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("penguin.tsv"), "id\tspecies\n3\tChinstrap\n").unwrap();
+    std::fs::write(dir.join("krill.tsv"), "id\n1\n").unwrap();
+    let paths = vec![dir.to_str().unwrap().to_string()];
+    assert!(import(&database.config, &paths, true).await.is_err());
+    let select = Select::new("\"penguin\"");
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows.len(), 2);
+}
+
+#[tokio::test]
+async fn test_edits_rollback() {
+    // Deleting a row that does not exist fails, and the rows inserted before it are undone,
+    // keeping the error that caused the rollback.
+    let database = penguins().build().await.unwrap();
+    let valve = database.config.valve.as_ref().unwrap();
+    let mut edits = Edits::begin(valve).await;
+    let row = json!({"id": "3", "species": "Chinstrap", "island": "Dream"});
+    edits
+        .insert("penguin", row.as_object().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(edits.applied(), 1);
+    let error = edits.delete("penguin", &99).await.unwrap_err();
+    let message = edits.rollback(error).await;
+    assert!(message.starts_with("Could not delete row 99 of 'penguin'"));
+    let select = Select::new("\"penguin\"");
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows.len(), 2);
+    assert!(rows.iter().all(|r| r["species"] != "Chinstrap"));
+}
+
+#[tokio::test]
+async fn test_search_terms() {
+    let mut database = TestSchema::new()
+        .table(
+            "term",
+            "id\tlabel\nEX:1\tgill\nEX:2\tgill filament\nEX:3\tpharyngeal gill\nEX:4\tfin\n",
+        )
+        .build()
+        .await
+        .unwrap();
+    let search = SearchConfig {
+        id: String::from("id"),
+        label: String::from("label"),
+    };
+    database.config.tables.insert(
+        String::from("term"),
+        TableConfig {
+            search: Some(search),
+            ..Default::default()
+        },
+    );
+
+    let matches = search_terms(&database.config, "Gill", 10).unwrap();
+    let labels = matches
+        .iter()
+        .map(|m| (m["label"].clone(), m["match"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        vec![
+            (json!("gill"), json!("exact")),
+            (json!("gill filament"), json!("prefix")),
+            (json!("pharyngeal gill"), json!("substring")),
+        ]
+    );
+    assert_eq!(
+        search_terms(&database.config, "ex:4", 10).unwrap()[0]["label"],
+        json!("fin")
+    );
+    assert_eq!(search_terms(&database.config, "gill", 1).unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_label_fields() {
+    let mut database = TestSchema::new()
+        .table("island", "id\tname\nI:1\tTorgersen\nI:2\tBiscoe\n")
+        .table("penguin", "id\tisland\n1\tI:2\n2\tI:3\n")
+        .build()
+        .await
+        .unwrap();
+    let labels = LabelsConfig {
+        table: String::from("island"),
+        id: String::from("id"),
+        label: String::from("name"),
+        json: true,
+    };
+    let mut table_config = TableConfig::default();
+    table_config.formats.insert(
+        String::from("island"),
+        ColumnFormat {
+            labels: Some(labels),
+            ..Default::default()
+        },
+    );
+    database
+        .config
+        .tables
+        .insert(String::from("penguin"), table_config);
+
+    let select = Select::new("\"penguin\"");
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    assert_eq!(rows[0]["island_label"], json!("Biscoe"));
+    assert_eq!(rows[1]["island_label"], Value::Null);
+    let columns = rows[0].as_object().unwrap().keys().collect::<Vec<_>>();
+    let island = columns.iter().position(|c| *c == "island").unwrap();
+    assert_eq!(columns[island + 1], "island_label");
+
+    let rows = get_rows(
+        &database.config,
+        &select,
+        "value_rows",
+        "tsv",
+        &GetRowsOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(!rows.contains("Biscoe"));
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::{json_rows, penguins};
+use ontodev_sqlrest::Select;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn test_build_database() {
+    let database = penguins().build().await.unwrap();
+    assert!(database.dir().join("penguin.tsv").is_file());
+
+    let select = Select::new("\"penguin\"");
+    let rows = json_rows(&database.config, &select, &Default::default()).await;
+    let species = rows
+        .iter()
+        .map(|row| row["species"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(species, vec![json!("Adelie"), json!("Gentoo")]);
+
+    let dir = database.dir().to_path_buf();
+    drop(database);
+    assert!(!dir.exists());
+}
+
+#[tokio::test]
+async fn test_serve_database() {
+    let database = penguins().build().await.unwrap();
+    let server = database.serve().await.unwrap();
+
+    let response = reqwest::get(server.url("penguin.json?shape=value_rows&id=eq.2"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let rows: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(rows[0]["species"], json!("Gentoo"));
+
+    let response = reqwest::get(server.url("missing.json")).await.unwrap();
+    assert_eq!(response.status(), 404);
+    let error: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(error["status"], json!(404));
+}